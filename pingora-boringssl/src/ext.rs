@@ -15,14 +15,17 @@
 //! the extended functionalities that are yet exposed via the [`boring`] APIs
 
 use boring::error::ErrorStack;
+use boring::ex_data::Index;
 use boring::pkey::{HasPrivate, PKeyRef};
-use boring::ssl::{Ssl, SslAcceptor, SslRef};
+use boring::ssl::{Ssl, SslAcceptor, SslContext, SslContextBuilder, SslRef, SslSessionCacheMode};
 use boring::x509::store::X509StoreRef;
 use boring::x509::verify::X509VerifyParamRef;
 use boring::x509::X509Ref;
 use foreign_types_shared::ForeignTypeRef;
 use libc::*;
 use std::ffi::CString;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 fn cvt(r: c_int) -> Result<c_int, ErrorStack> {
     if r != 1 {
@@ -183,6 +186,154 @@ pub fn is_suspended_for_cert(error: &boring::ssl::Error) -> bool {
     error.code().as_raw() == boring_sys::SSL_ERROR_WANT_X509_LOOKUP
 }
 
+/// The secrets of a session ticket key
+#[derive(Clone, PartialEq, Eq)]
+pub struct TicketKey {
+    /// The name that identifies the key in the tickets it encrypts
+    pub name: [u8; 16],
+    /// The HMAC-SHA256 secret
+    pub hmac_secret: [u8; 32],
+    /// The AES-256-CBC key
+    pub aes_key: [u8; 32],
+}
+
+type TicketKeysFn = Box<dyn Fn() -> Arc<[TicketKey]> + Send + Sync>;
+
+fn ticket_keys_index() -> Index<SslContext, TicketKeysFn> {
+    static INDEX: OnceLock<Index<SslContext, TicketKeysFn>> = OnceLock::new();
+    *INDEX.get_or_init(|| SslContext::new_ex_index().unwrap())
+}
+
+/// Encrypt and decrypt the session tickets of `ctx` with the keys returned by `keys`
+///
+/// The first key encrypts new tickets and all of them decrypt. The tickets decrypted with a key
+/// other than the first one are renewed. `keys` is called for every ticket so it should be cheap.
+///
+/// See [SSL_CTX_set_tlsext_ticket_key_cb](https://www.openssl.org/docs/man1.1.1/man3/SSL_CTX_set_tlsext_ticket_key_cb.html).
+pub fn ssl_ctx_set_ticket_keys<F>(ctx: &mut SslContextBuilder, keys: F)
+where
+    F: Fn() -> Arc<[TicketKey]> + Send + Sync + 'static,
+{
+    let keys: TicketKeysFn = Box::new(keys);
+    ctx.set_ex_data(ticket_keys_index(), keys);
+    unsafe {
+        boring_sys::SSL_CTX_set_tlsext_ticket_key_cb(ctx.as_ptr(), Some(raw_ticket_key_cb));
+    }
+}
+
+unsafe extern "C" fn raw_ticket_key_cb(
+    ssl: *mut boring_sys::SSL,
+    key_name: *mut c_uchar,
+    iv: *mut c_uchar,
+    cipher_ctx: *mut boring_sys::EVP_CIPHER_CTX,
+    hmac_ctx: *mut boring_sys::HMAC_CTX,
+    enc: c_int,
+) -> c_int {
+    let ssl = SslRef::from_ptr(ssl);
+    let Some(keys_fn) = ssl.ssl_context().ex_data(ticket_keys_index()) else {
+        return -1;
+    };
+    let keys = keys_fn();
+    let key_name = std::slice::from_raw_parts_mut(key_name, 16);
+    let (key, ret) = if enc == 1 {
+        // 0: no ticket is issued
+        let Some(key) = keys.first() else {
+            return 0;
+        };
+        if boring_sys::RAND_bytes(iv, 16) != 1 {
+            return -1;
+        }
+        key_name.copy_from_slice(&key.name);
+        let init = boring_sys::EVP_EncryptInit_ex(
+            cipher_ctx,
+            boring_sys::EVP_aes_256_cbc(),
+            std::ptr::null_mut(),
+            key.aes_key.as_ptr(),
+            iv,
+        );
+        if init != 1 {
+            return -1;
+        }
+        (key, 1)
+    } else {
+        // 0: the ticket cannot be decrypted, fall back to a full handshake
+        let Some(index) = keys.iter().position(|k| k.name[..] == *key_name) else {
+            return 0;
+        };
+        let key = &keys[index];
+        let init = boring_sys::EVP_DecryptInit_ex(
+            cipher_ctx,
+            boring_sys::EVP_aes_256_cbc(),
+            std::ptr::null_mut(),
+            key.aes_key.as_ptr(),
+            iv,
+        );
+        if init != 1 {
+            return -1;
+        }
+        // 2: the ticket is valid but should be renewed with the current key
+        (key, if index == 0 { 1 } else { 2 })
+    };
+    let init = boring_sys::HMAC_Init_ex(
+        hmac_ctx,
+        key.hmac_secret.as_ptr() as *const c_void,
+        key.hmac_secret.len() as _,
+        boring_sys::EVP_sha256(),
+        std::ptr::null_mut(),
+    );
+    if init != 1 {
+        return -1;
+    }
+    ret
+}
+
+/// Set the size limit and the timeout of the server side session ID cache of `ctx`
+///
+/// A `size` of 0 disables the cache.
+///
+/// See [SSL_CTX_sess_set_cache_size](https://www.openssl.org/docs/man1.1.1/man3/SSL_CTX_sess_set_cache_size.html).
+pub fn ssl_ctx_set_session_cache(ctx: &mut SslContextBuilder, size: usize, timeout: Duration) {
+    if size == 0 {
+        ctx.set_session_cache_mode(SslSessionCacheMode::OFF);
+        return;
+    }
+    ctx.set_session_cache_mode(SslSessionCacheMode::SERVER);
+    unsafe {
+        boring_sys::SSL_CTX_sess_set_cache_size(ctx.as_ptr(), size as _);
+        boring_sys::SSL_CTX_set_timeout(ctx.as_ptr(), timeout.as_secs() as _);
+    }
+}
+
+/// Load the CRLs in the PEM file into the cert store of `ctx` and check the certificates of the
+/// whole chain against them.
+///
+/// See [X509_load_crl_file](https://www.openssl.org/docs/man1.1.1/man3/X509_load_crl_file.html).
+/// A `path` containing a NUL byte is an error too.
+pub fn ssl_ctx_load_crl_file(
+    ctx: &mut SslContextBuilder,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = CString::new(path)?;
+    unsafe {
+        let store = boring_sys::SSL_CTX_get_cert_store(ctx.as_ptr());
+        let lookup = boring_sys::X509_STORE_add_lookup(store, boring_sys::X509_LOOKUP_file());
+        if lookup.is_null() {
+            return Err(ErrorStack::get().into());
+        }
+        // the number of the CRLs loaded
+        if boring_sys::X509_load_crl_file(lookup, path.as_ptr(), boring_sys::X509_FILETYPE_PEM as _)
+            <= 0
+        {
+            return Err(ErrorStack::get().into());
+        }
+        cvt(boring_sys::X509_STORE_set_flags(
+            store,
+            (boring_sys::X509_V_FLAG_CRL_CHECK | boring_sys::X509_V_FLAG_CRL_CHECK_ALL) as _,
+        ))?;
+    }
+    Ok(())
+}
+
 #[allow(clippy::mut_from_ref)]
 /// Get a mutable SslRef ouf of SslRef. which is a missing functionality for certain SslStream
 /// # Safety
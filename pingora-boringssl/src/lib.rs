@@ -26,9 +26,12 @@ pub use boring_tokio as tokio_ssl;
 pub mod ext;
 
 // export commonly used libs
+pub use ssl_lib::asn1;
 pub use ssl_lib::error;
+pub use ssl_lib::ex_data;
 pub use ssl_lib::hash;
 pub use ssl_lib::nid;
 pub use ssl_lib::pkey;
 pub use ssl_lib::ssl;
+pub use ssl_lib::stack;
 pub use ssl_lib::x509;
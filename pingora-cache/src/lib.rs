@@ -224,6 +224,8 @@ struct HttpCacheInner {
     pub lock_duration: Option<Duration>,
     // time spent in cache lookup and reading the header
     pub lookup_duration: Option<Duration>,
+    // the status of the asset found in cache
+    pub hit_status: Option<HitStatus>,
     pub traces: trace::CacheTraceCTX,
 }
 
@@ -372,6 +374,7 @@ impl HttpCache {
                     cache_lock,
                     lock_duration: None,
                     lookup_duration: None,
+                    hit_status: None,
                     traces: CacheTraceCTX::new(),
                 }));
             }
@@ -476,6 +479,7 @@ impl HttpCache {
                     eviction.access(&cache_key, 0, meta.0.internal.fresh_until);
                 }
                 inner.traces.start_hit_span(phase, hit_status);
+                inner.hit_status = Some(hit_status);
                 inner.meta = Some(meta);
                 inner.body_reader = Some(hit_handler);
             }
@@ -1054,6 +1058,11 @@ impl HttpCache {
         self.inner.as_ref().and_then(|i| i.lookup_duration)
     }
 
+    /// The [HitStatus] of the asset found in cache, if any
+    pub fn hit_status(&self) -> Option<HitStatus> {
+        self.inner.as_ref().and_then(|i| i.hit_status)
+    }
+
     /// Delete the asset from the cache storage
     /// # Panic
     /// Need to be called after the cache key is set. Panic otherwise.
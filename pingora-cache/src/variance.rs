@@ -1,6 +1,8 @@
 use std::{borrow::Cow, collections::BTreeMap};
 
 use blake2::Digest;
+use pingora_core::protocols::http::compression::{dictionary::DictionaryStore, preferred_encoding};
+use pingora_http::RequestHeader;
 
 use crate::key::{Blake2b128, HashBinary};
 
@@ -35,6 +37,26 @@ impl<'a> VarianceBuilder<'a> {
         self.values.insert(name.into(), Cow::Owned(value));
     }
 
+    /// Add the content encoding that the response to `req` would be compressed with, so that
+    /// each encoding of a response is cached as its own variant. See [preferred_encoding] for
+    /// the negotiation.
+    ///
+    /// The variants are only pre-compressed if the response is compressed before it is cached,
+    /// e.g. by the upstream compression of the proxy. A cache hit is then served as is.
+    pub fn add_encoding(&mut self, req: &RequestHeader, dictionaries: Option<&DictionaryStore>) {
+        let encoding = preferred_encoding(req, dictionaries);
+        self.add_value(
+            "content-encoding",
+            encoding.map_or("identity", |e| e.as_str()),
+        );
+        // the dictionary is part of the encoding
+        if encoding.map_or(false, |e| e.needs_dictionary()) {
+            if let Some(dictionary) = req.headers.get("available-dictionary") {
+                self.add_owned_value("available-dictionary", dictionary.as_bytes().to_vec());
+            }
+        }
+    }
+
     /// Check whether this variance key actually has variance, or just refers to the root asset
     pub fn has_variance(&self) -> bool {
         !self.values.is_empty()
@@ -62,6 +84,28 @@ impl<'a> VarianceBuilder<'a> {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_encoding() {
+        let encoding_key = |accept_encoding: Option<&str>| {
+            let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+            if let Some(ae) = accept_encoding {
+                req.insert_header("accept-encoding", ae).unwrap();
+            }
+            let mut key = VarianceBuilder::new();
+            key.add_encoding(&req, None);
+            key.finalize()
+        };
+        let identity = encoding_key(None);
+        assert!(identity.is_some());
+        assert_eq!(identity, encoding_key(Some("identity")));
+        assert_eq!(
+            encoding_key(Some("gzip")),
+            encoding_key(Some("gzip, deflate"))
+        );
+        assert_ne!(encoding_key(Some("gzip")), encoding_key(Some("br")));
+        assert_ne!(encoding_key(Some("gzip")), identity);
+    }
+
     #[test]
     fn test_basic() {
         let key_empty = VarianceBuilder::new().finalize();
@@ -0,0 +1,507 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An HTTP application to inspect and operate the server.
+//!
+//! | Method | Path | Action |
+//! |--------|------|--------|
+//! | GET | `/services` | list the services and their listeners |
+//! | GET | `/backends` | list the backends of the load balancers and their health |
+//! | POST | `/backends/{name}/{addr}/enable` | enable the backend of the load balancer `name` |
+//! | POST | `/backends/{name}/{addr}/disable` | disable the backend of the load balancer `name` |
+//! | GET | `/pools` | report the connection pool occupancy of the connectors |
+//! | POST | `/cache/purge` | purge the cache key in the request body |
+//! | POST | `/reload` | reload the configuration |
+//! | POST | `/shutdown` | start graceful shutdown |
+
+use async_trait::async_trait;
+use http::{Method, Response, StatusCode};
+use log::{info, warn};
+use parking_lot::RwLock;
+use pingora_error::Result;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::apps::http_app::ServeHttp;
+use crate::connectors::http::Connector as HttpConnector;
+use crate::connectors::limit::ConnectionCounts;
+use crate::connectors::TransportConnector;
+use crate::protocols::http::ServerSession;
+use crate::protocols::l4::socket::SocketAddr;
+use crate::server::lifecycle::{ActionSender, ActionSource, SignalAction};
+use crate::server::Server;
+
+/// A service of the server, as listed by the admin endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceInfo {
+    pub name: String,
+    pub listeners: Vec<String>,
+    pub threads: usize,
+}
+
+/// A load balancing backend, as listed by the admin endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendInfo {
+    pub addr: String,
+    pub weight: usize,
+    pub healthy: bool,
+    pub enabled: bool,
+}
+
+/// A collection of load balancing backends that can be managed through the admin endpoint
+pub trait AdminBackends: Send + Sync {
+    /// List the backends and their health.
+    fn backends(&self) -> Vec<BackendInfo>;
+
+    /// Enable or disable the backend of the given address.
+    ///
+    /// Return false if there is no such backend.
+    fn set_enable(&self, addr: &str, enabled: bool) -> bool;
+}
+
+/// The connections that are reported by the admin endpoint
+pub trait ConnectionPoolStats: Send + Sync {
+    /// The numbers of in use and idle connections to all the peers.
+    fn connection_counts(&self) -> ConnectionCounts;
+}
+
+impl ConnectionPoolStats for TransportConnector {
+    fn connection_counts(&self) -> ConnectionCounts {
+        self.total_connection_counts()
+    }
+}
+
+impl ConnectionPoolStats for HttpConnector {
+    fn connection_counts(&self) -> ConnectionCounts {
+        self.total_connection_counts()
+    }
+}
+
+/// How the admin endpoint purges the cache
+#[cfg_attr(not(doc_async_trait), async_trait)]
+pub trait CachePurge: Send + Sync {
+    /// Purge the given cache key. Return false if it is not cached.
+    async fn purge(&self, key: &str) -> Result<bool>;
+}
+
+/// How the admin endpoint reloads the configuration
+#[cfg_attr(not(doc_async_trait), async_trait)]
+pub trait ConfigReload: Send + Sync {
+    /// Reload the configuration.
+    async fn reload(&self) -> Result<()>;
+}
+
+#[derive(Serialize)]
+struct BackendsInfo<'a> {
+    name: &'a str,
+    backends: Vec<BackendInfo>,
+}
+
+#[derive(Serialize)]
+struct PoolInfo<'a> {
+    name: &'a str,
+    in_use: usize,
+    idle: usize,
+}
+
+/// An HTTP application to inspect and operate the server, see the [module](self) level doc for
+/// the API.
+///
+/// The requests over a Unix domain socket are always allowed, so the permission of the socket
+/// decides who can access it. The requests over TCP are only allowed from loopback addresses
+/// with the token set by [AdminHttpApp::set_token()] as `Authorization: Bearer <token>`.
+pub struct AdminHttpApp {
+    token: Option<String>,
+    services: Arc<RwLock<Vec<ServiceInfo>>>,
+    backends: Vec<(String, Arc<dyn AdminBackends>)>,
+    pools: Vec<(String, Arc<dyn ConnectionPoolStats>)>,
+    cache_purge: Option<Arc<dyn CachePurge>>,
+    config_reload: Option<Arc<dyn ConfigReload>>,
+    actions: ActionSender,
+}
+
+impl AdminHttpApp {
+    /// Create a new [AdminHttpApp] for the given [Server].
+    ///
+    /// The services of the server are listed once the server starts running.
+    pub fn new(server: &Server) -> Self {
+        AdminHttpApp {
+            token: None,
+            services: server.service_info.clone(),
+            backends: vec![],
+            pools: vec![],
+            cache_purge: None,
+            config_reload: None,
+            actions: server.lifecycle.action_sender(),
+        }
+    }
+
+    /// Set the token the TCP clients need to present.
+    pub fn set_token(&mut self, token: &str) {
+        self.token = Some(token.to_string());
+    }
+
+    pub(crate) fn has_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Add a collection of backends to manage under the given name.
+    pub fn add_backends(&mut self, name: &str, backends: Arc<dyn AdminBackends>) {
+        self.backends.push((name.to_string(), backends));
+    }
+
+    /// Add a connector of which to report the connection pool occupancy under the given name.
+    pub fn add_connection_pool(&mut self, name: &str, pool: Arc<dyn ConnectionPoolStats>) {
+        self.pools.push((name.to_string(), pool));
+    }
+
+    /// Set how to purge the cache.
+    pub fn set_cache_purge(&mut self, purge: Arc<dyn CachePurge>) {
+        self.cache_purge = Some(purge);
+    }
+
+    /// Set how to reload the configuration.
+    pub fn set_config_reload(&mut self, reload: Arc<dyn ConfigReload>) {
+        self.config_reload = Some(reload);
+    }
+
+    // whether the client is allowed, otherwise the status to respond with
+    fn authorize(
+        &self,
+        server: Option<&SocketAddr>,
+        client: Option<&SocketAddr>,
+        authorization: Option<&[u8]>,
+    ) -> Result<(), StatusCode> {
+        // the clients of UDS are usually unnamed, so tell by the local address
+        if let Some(SocketAddr::Unix(_)) = server {
+            return Ok(());
+        }
+        match client {
+            Some(SocketAddr::Inet(addr)) if addr.ip().is_loopback() => {}
+            _ => return Err(StatusCode::FORBIDDEN),
+        }
+        let Some(token) = self.token.as_ref() else {
+            return Err(StatusCode::FORBIDDEN);
+        };
+        let presented = authorization.and_then(|a| a.strip_prefix(b"Bearer "));
+        match presented {
+            Some(presented) if constant_time_eq(presented, token.as_bytes()) => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    async fn handle(&self, method: &Method, path: &str, body: &[u8]) -> Response<Vec<u8>> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (&Method::GET, ["services"]) => json(&*self.services.read()),
+            (&Method::GET, ["backends"]) => {
+                let backends: Vec<_> = self
+                    .backends
+                    .iter()
+                    .map(|(name, backends)| BackendsInfo {
+                        name,
+                        backends: backends.backends(),
+                    })
+                    .collect();
+                json(&backends)
+            }
+            (&Method::POST, ["backends", name, addr, action @ ("enable" | "disable")]) => {
+                let found = self.backends.iter().find(|(n, _)| n.as_str() == *name);
+                let Some((_, backends)) = found else {
+                    return text(StatusCode::NOT_FOUND, "no such load balancer");
+                };
+                let enabled = *action == "enable";
+                if !backends.set_enable(addr, enabled) {
+                    return text(StatusCode::NOT_FOUND, "no such backend");
+                }
+                info!("admin: backend {addr} of {name} is {action}d");
+                text(StatusCode::OK, "ok")
+            }
+            (&Method::GET, ["pools"]) => {
+                let pools: Vec<_> = self
+                    .pools
+                    .iter()
+                    .map(|(name, pool)| {
+                        let counts = pool.connection_counts();
+                        PoolInfo {
+                            name,
+                            in_use: counts.in_use,
+                            idle: counts.idle,
+                        }
+                    })
+                    .collect();
+                json(&pools)
+            }
+            (&Method::POST, ["cache", "purge"]) => {
+                let Some(purge) = self.cache_purge.as_ref() else {
+                    return text(StatusCode::NOT_IMPLEMENTED, "cache purge is not set");
+                };
+                let Ok(key) = std::str::from_utf8(body) else {
+                    return text(StatusCode::BAD_REQUEST, "invalid cache key");
+                };
+                match purge.purge(key.trim()).await {
+                    Ok(true) => text(StatusCode::OK, "purged"),
+                    Ok(false) => text(StatusCode::NOT_FOUND, "not cached"),
+                    Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+                }
+            }
+            (&Method::POST, ["reload"]) => {
+                let Some(reload) = self.config_reload.as_ref() else {
+                    return text(StatusCode::NOT_IMPLEMENTED, "config reload is not set");
+                };
+                info!("admin: reloading config");
+                match reload.reload().await {
+                    Ok(()) => text(StatusCode::OK, "reloaded"),
+                    Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+                }
+            }
+            (&Method::POST, ["shutdown"]) => {
+                // the same as a signal mapped to Drain, regardless of the signal mapping
+                info!("admin: starting graceful shutdown");
+                match self
+                    .actions
+                    .send((ActionSource::Admin, SignalAction::Drain))
+                {
+                    Ok(()) => text(StatusCode::ACCEPTED, "shutting down"),
+                    Err(_) => text(StatusCode::INTERNAL_SERVER_ERROR, "server is not running"),
+                }
+            }
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+#[cfg_attr(not(doc_async_trait), async_trait)]
+impl ServeHttp for AdminHttpApp {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let authorization = http_session
+            .get_header(http::header::AUTHORIZATION)
+            .map(|v| v.as_bytes());
+        if let Err(status) = self.authorize(
+            http_session.server_addr(),
+            http_session.client_addr(),
+            authorization,
+        ) {
+            warn!(
+                "admin: rejected request from {:?}",
+                http_session.client_addr()
+            );
+            return text(status, "access denied");
+        }
+        let mut body = vec![];
+        loop {
+            match http_session.read_request_body().await {
+                Ok(Some(bytes)) => body.extend_from_slice(&bytes),
+                Ok(None) => break,
+                Err(e) => return text(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        let req = http_session.req_header();
+        let (method, path) = (req.method.clone(), req.uri.path().to_string());
+        self.handle(&method, &path, &body).await
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn json<T: Serialize + ?Sized>(value: &T) -> Response<Vec<u8>> {
+    // these types always serialize
+    let body = serde_json::to_vec(value).unwrap();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
+fn text(status: StatusCode, msg: &str) -> Response<Vec<u8>> {
+    let body = format!("{msg}\n").into_bytes();
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/plain")
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::lifecycle;
+    use parking_lot::Mutex;
+
+    struct StubBackends(Mutex<Vec<BackendInfo>>);
+
+    impl AdminBackends for StubBackends {
+        fn backends(&self) -> Vec<BackendInfo> {
+            self.0.lock().clone()
+        }
+
+        fn set_enable(&self, addr: &str, enabled: bool) -> bool {
+            let mut backends = self.0.lock();
+            match backends.iter_mut().find(|b| b.addr == addr) {
+                Some(b) => {
+                    b.enabled = enabled;
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    struct StubPurge;
+
+    #[async_trait]
+    impl CachePurge for StubPurge {
+        async fn purge(&self, key: &str) -> Result<bool> {
+            Ok(key == "cached")
+        }
+    }
+
+    fn app() -> AdminHttpApp {
+        let server = Server::new(None).unwrap();
+        *server.service_info.write() = vec![ServiceInfo {
+            name: "proxy".to_string(),
+            listeners: vec!["0.0.0.0:80".to_string()],
+            threads: 2,
+        }];
+        let mut app = AdminHttpApp::new(&server);
+        app.set_token("secret");
+        app
+    }
+
+    fn body(resp: Response<Vec<u8>>) -> String {
+        String::from_utf8(resp.into_body()).unwrap()
+    }
+
+    #[test]
+    fn test_authorize() {
+        let app = app();
+        let local = SocketAddr::Inet("127.0.0.1:6190".parse().unwrap());
+        let loopback = SocketAddr::Inet("127.0.0.1:1234".parse().unwrap());
+        let remote = SocketAddr::Inet("10.0.0.1:1234".parse().unwrap());
+        let uds =
+            SocketAddr::Unix(std::os::unix::net::SocketAddr::from_pathname("/tmp/admin").unwrap());
+
+        assert!(app.authorize(Some(&uds), None, None).is_ok());
+        let secret = Some(&b"Bearer secret"[..]);
+        assert!(app.authorize(Some(&local), Some(&loopback), secret).is_ok());
+        assert_eq!(
+            app.authorize(
+                Some(&local),
+                Some(&loopback),
+                Some(b"Bearer wrong".as_slice())
+            ),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            app.authorize(Some(&local), Some(&loopback), None),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            app.authorize(Some(&local), Some(&remote), secret),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            app.authorize(Some(&local), None, None),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_services_and_pools() {
+        let mut app = app();
+        app.add_connection_pool("upstream", Arc::new(TransportConnector::new(None)));
+
+        let resp = app.handle(&Method::GET, "/services", b"").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            body(resp),
+            r#"[{"name":"proxy","listeners":["0.0.0.0:80"],"threads":2}]"#
+        );
+
+        let resp = app.handle(&Method::GET, "/pools", b"").await;
+        assert_eq!(body(resp), r#"[{"name":"upstream","in_use":0,"idle":0}]"#);
+
+        let resp = app.handle(&Method::GET, "/nothing", b"").await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_backends() {
+        let mut app = app();
+        let backends = Arc::new(StubBackends(Mutex::new(vec![BackendInfo {
+            addr: "1.1.1.1:80".to_string(),
+            weight: 1,
+            healthy: true,
+            enabled: true,
+        }])));
+        app.add_backends("lb", backends.clone());
+
+        let resp = app
+            .handle(&Method::POST, "/backends/lb/1.1.1.1:80/disable", b"")
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!backends.0.lock()[0].enabled);
+
+        let resp = app.handle(&Method::GET, "/backends", b"").await;
+        assert_eq!(
+            body(resp),
+            r#"[{"name":"lb","backends":[{"addr":"1.1.1.1:80","weight":1,"healthy":true,"enabled":false}]}]"#
+        );
+
+        let resp = app
+            .handle(&Method::POST, "/backends/lb/2.2.2.2:80/enable", b"")
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = app
+            .handle(&Method::POST, "/backends/other/1.1.1.1:80/enable", b"")
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_cache_purge() {
+        let mut app = app();
+        let resp = app.handle(&Method::POST, "/cache/purge", b"cached").await;
+        assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+
+        app.set_cache_purge(Arc::new(StubPurge));
+        let resp = app.handle(&Method::POST, "/cache/purge", b"cached").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app.handle(&Method::POST, "/cache/purge", b"missing").await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let mut server = Server::new(None).unwrap();
+        // SIGTERM no longer drains
+        server
+            .lifecycle
+            .map_signal(lifecycle::Signal::SIGTERM, SignalAction::FastShutdown);
+        let app = AdminHttpApp::new(&server);
+        let mut actions = server.lifecycle.listen();
+
+        let resp = app.handle(&Method::POST, "/shutdown", b"").await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            actions.recv().await.unwrap(),
+            (ActionSource::Admin, SignalAction::Drain)
+        );
+    }
+}
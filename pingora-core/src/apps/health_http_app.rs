@@ -0,0 +1,238 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An HTTP application to report the readiness and the liveness of the server, such as for the
+//! probes of Kubernetes.
+//!
+//! | Method | Path | Response |
+//! |--------|------|----------|
+//! | GET | `/ready` | 200 if the server is ready to take traffic, 503 otherwise |
+//! | GET | `/live` | 200 as long as the server is running |
+//!
+//! The server is ready once all of its services have started and all the [ReadinessCheck]s pass.
+//! It is no longer ready as soon as the server leaves [ShutdownPhase::Running], so that it can be
+//! taken out of the load balancer before the grace period runs out.
+//!
+//! Note that the listeners of this app stop accepting new connections once the shutdown starts,
+//! like those of any other service.
+
+use async_trait::async_trait;
+use http::{Method, Response, StatusCode};
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::apps::admin_http_app::ServiceInfo;
+use crate::apps::http_app::ServeHttp;
+use crate::protocols::http::ServerSession;
+use crate::server::lifecycle::ShutdownPhase;
+use crate::server::Server;
+use tokio::sync::watch;
+
+/// A custom check of whether the server is ready to take traffic
+#[cfg_attr(not(doc_async_trait), async_trait)]
+pub trait ReadinessCheck: Send + Sync {
+    /// Return the reason if not ready.
+    async fn check(&self) -> Result<(), String>;
+}
+
+// how long a check can take before the server is reported as not ready
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An HTTP application to report the readiness and the liveness of the server, see the
+/// [module](self) level doc for the API.
+pub struct HealthHttpApp {
+    services: Arc<RwLock<Vec<ServiceInfo>>>,
+    started: Arc<AtomicUsize>,
+    phase: watch::Receiver<ShutdownPhase>,
+    checks: Vec<(String, Arc<dyn ReadinessCheck>)>,
+    check_timeout: Duration,
+}
+
+impl HealthHttpApp {
+    /// Create a new [HealthHttpApp] for the given [Server].
+    pub fn new(server: &Server) -> Self {
+        HealthHttpApp {
+            services: server.service_info.clone(),
+            started: server.started_services.clone(),
+            phase: server.lifecycle.phase(),
+            checks: vec![],
+            check_timeout: CHECK_TIMEOUT,
+        }
+    }
+
+    /// Add a custom check under the given name, which has to pass for the server to be ready.
+    pub fn add_check(&mut self, name: &str, check: Arc<dyn ReadinessCheck>) {
+        self.checks.push((name.to_string(), check));
+    }
+
+    /// Set how long each check can take before the server is reported as not ready.
+    ///
+    /// The default is 5 seconds.
+    pub fn set_check_timeout(&mut self, timeout: Duration) {
+        self.check_timeout = timeout;
+    }
+
+    // Ok if ready, otherwise the reason
+    async fn readiness(&self) -> Result<(), String> {
+        if *self.phase.borrow() > ShutdownPhase::Running {
+            return Err("shutting down".to_string());
+        }
+        // the services are listed once the server starts running
+        let services = self.services.read().len();
+        let started = self.started.load(Ordering::Relaxed);
+        if services == 0 || started < services {
+            return Err(format!("{started} of {services} services started"));
+        }
+        for (name, check) in self.checks.iter() {
+            match pingora_timeout::timeout(self.check_timeout, check.check()).await {
+                Ok(res) => res.map_err(|reason| format!("{name}: {reason}"))?,
+                Err(_) => return Err(format!("{name}: timed out")),
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle(&self, method: &Method, path: &str) -> Response<Vec<u8>> {
+        match (method, path.trim_end_matches('/')) {
+            (&Method::GET, "/ready") => match self.readiness().await {
+                Ok(()) => text(StatusCode::OK, "ready"),
+                Err(reason) => text(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    &format!("not ready: {reason}"),
+                ),
+            },
+            (&Method::GET, "/live") => text(StatusCode::OK, "alive"),
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+#[cfg_attr(not(doc_async_trait), async_trait)]
+impl ServeHttp for HealthHttpApp {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let req = http_session.req_header();
+        let (method, path) = (req.method.clone(), req.uri.path().to_string());
+        self.handle(&method, &path).await
+    }
+}
+
+fn text(status: StatusCode, msg: &str) -> Response<Vec<u8>> {
+    let body = format!("{msg}\n").into_bytes();
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/plain")
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .body(body)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    struct StubCheck(AtomicBool);
+
+    #[async_trait]
+    impl ReadinessCheck for StubCheck {
+        async fn check(&self) -> Result<(), String> {
+            if self.0.load(Ordering::Relaxed) {
+                Ok(())
+            } else {
+                Err("no healthy backend".to_string())
+            }
+        }
+    }
+
+    struct HangingCheck;
+
+    #[async_trait]
+    impl ReadinessCheck for HangingCheck {
+        async fn check(&self) -> Result<(), String> {
+            std::future::pending().await
+        }
+    }
+
+    fn body(resp: Response<Vec<u8>>) -> String {
+        String::from_utf8(resp.into_body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let server = Server::new(None).unwrap();
+        *server.service_info.write() = vec![
+            ServiceInfo {
+                name: "proxy".to_string(),
+                listeners: vec!["0.0.0.0:80".to_string()],
+                threads: 2,
+            },
+            ServiceInfo {
+                name: "health".to_string(),
+                listeners: vec!["0.0.0.0:8080".to_string()],
+                threads: 1,
+            },
+        ];
+        let mut app = HealthHttpApp::new(&server);
+        let check = Arc::new(StubCheck(AtomicBool::new(false)));
+        app.add_check("upstream", check.clone());
+
+        let resp = app.handle(&Method::GET, "/live").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = app.handle(&Method::GET, "/ready").await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(resp), "not ready: 0 of 2 services started\n");
+
+        server.started_services.fetch_add(2, Ordering::Relaxed);
+        let resp = app.handle(&Method::GET, "/ready").await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(resp), "not ready: upstream: no healthy backend\n");
+
+        check.0.store(true, Ordering::Relaxed);
+        let resp = app.handle(&Method::GET, "/ready").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // not ready as soon as the listeners stop accepting
+        server.lifecycle.enter(ShutdownPhase::StopAccepting).await;
+        let resp = app.handle(&Method::GET, "/ready").await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(resp), "not ready: shutting down\n");
+        let resp = app.handle(&Method::GET, "/live").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = app.handle(&Method::POST, "/ready").await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_check_timeout() {
+        let server = Server::new(None).unwrap();
+        *server.service_info.write() = vec![ServiceInfo {
+            name: "proxy".to_string(),
+            listeners: vec!["0.0.0.0:80".to_string()],
+            threads: 1,
+        }];
+        server.started_services.fetch_add(1, Ordering::Relaxed);
+        let mut app = HealthHttpApp::new(&server);
+        app.add_check("upstream", Arc::new(HangingCheck));
+        app.set_check_timeout(Duration::from_millis(10));
+
+        let resp = app.handle(&Method::GET, "/ready").await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(resp), "not ready: upstream: timed out\n");
+    }
+}
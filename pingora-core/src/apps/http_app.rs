@@ -22,6 +22,7 @@ use std::sync::Arc;
 
 use crate::apps::HttpServerApp;
 use crate::modules::http::{HttpModules, ModuleBuilder};
+use crate::protocols::http::v2::server::H2ServerSettings;
 use crate::protocols::http::HttpTask;
 use crate::protocols::http::ServerSession;
 use crate::protocols::Stream;
@@ -111,6 +112,7 @@ where
 pub struct HttpServer<SV> {
     app: SV,
     modules: HttpModules,
+    h2_settings: Option<H2ServerSettings>,
 }
 
 impl<SV> HttpServer<SV> {
@@ -119,6 +121,7 @@ impl<SV> HttpServer<SV> {
         HttpServer {
             app,
             modules: HttpModules::new(),
+            h2_settings: None,
         }
     }
 
@@ -126,6 +129,11 @@ impl<SV> HttpServer<SV> {
     pub fn add_module(&mut self, module: ModuleBuilder) {
         self.modules.add_module(module)
     }
+
+    /// Set the HTTP/2 settings of the downstream connections of this [HttpServer]
+    pub fn set_h2_server_settings(&mut self, settings: H2ServerSettings) {
+        self.h2_settings = Some(settings);
+    }
 }
 
 #[cfg_attr(not(doc_async_trait), async_trait)]
@@ -208,4 +216,8 @@ where
             }
         }
     }
+
+    fn h2_server_settings(&self) -> Option<&H2ServerSettings> {
+        self.h2_settings.as_ref()
+    }
 }
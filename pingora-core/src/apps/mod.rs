@@ -14,6 +14,8 @@
 
 //! The abstraction and implementation interface for service application logic
 
+pub mod admin_http_app;
+pub mod health_http_app;
 pub mod http_app;
 pub mod prometheus_http_app;
 
@@ -77,6 +79,16 @@ pub trait HttpServerApp {
         None
     }
 
+    /// Provide the server side HTTP/2 settings such as flow control windows, concurrency limits
+    /// and keepalive PING. This function will be called every time a new HTTP/2 **connection**
+    /// needs to be established.
+    ///
+    /// The settings are applied on top of the options from [`Self::h2_options()`].
+    /// See [`server::H2ServerSettings`] for more details.
+    fn h2_server_settings(&self) -> Option<&server::H2ServerSettings> {
+        None
+    }
+
     async fn http_cleanup(&self) {}
 }
 
@@ -101,7 +113,15 @@ where
                     socket_digest: stream.get_socket_digest(),
                 });
 
-                let h2_options = self.h2_options();
+                let h2_settings = self.h2_server_settings();
+                let h2_options = match (self.h2_options(), h2_settings) {
+                    (options, Some(settings)) => {
+                        let mut options = options.unwrap_or_default();
+                        settings.apply(&mut options);
+                        Some(options)
+                    }
+                    (options, None) => options,
+                };
                 let h2_conn = server::handshake(stream, h2_options).await;
                 let mut h2_conn = match h2_conn {
                     Err(e) => {
@@ -110,12 +130,38 @@ where
                     }
                     Ok(c) => c,
                 };
+                let mut keepalive = h2_settings.and_then(|s| s.keepalive(&mut h2_conn));
+
+                let mut shutdown_watch = shutdown.clone();
+                let mut going_away = *shutdown_watch.borrow();
+                if going_away {
+                    h2_conn.graceful_shutdown();
+                }
 
                 loop {
                     // this loop ends when the client decides to close the h2 conn
+                    // or all the streams are done after GOAWAY is sent
                     // TODO: add a timeout?
-                    let h2_stream =
-                        server::HttpSession::from_h2_conn(&mut h2_conn, digest.clone()).await;
+                    let new_stream =
+                        server::HttpSession::from_h2_conn(&mut h2_conn, digest.clone());
+                    let h2_stream = tokio::select! {
+                        h2_stream = new_stream => h2_stream,
+                        res = h2_keepalive(&mut keepalive) => {
+                            if let Err(e) = res {
+                                debug!("H2 keepalive failed, closing connection: {e}");
+                            }
+                            return None;
+                        }
+                        res = shutdown_watch.changed(), if !going_away => {
+                            if res.is_err() || *shutdown_watch.borrow() {
+                                // stop accepting new streams but let the in-flight ones finish
+                                debug!("Shutting down, sending GOAWAY to H2 client");
+                                h2_conn.graceful_shutdown();
+                                going_away = true;
+                            }
+                            continue;
+                        }
+                    };
                     let h2_stream = match h2_stream {
                         Err(e) => {
                             // It is common for the client to just disconnect TCP without properly
@@ -145,3 +191,11 @@ where
         self.http_cleanup().await;
     }
 }
+
+// Wait for the keepalive to fail, or forever if keepalive is disabled
+async fn h2_keepalive(keepalive: &mut Option<server::H2Keepalive>) -> pingora_error::Result<()> {
+    match keepalive {
+        Some(k) => k.keepalive().await,
+        None => futures::future::pending().await,
+    }
+}
@@ -14,12 +14,15 @@
 
 //! Connecting to HTTP servers
 
+use crate::connectors::limit::{ConnectionCounts, ConnectionLimits};
 use crate::connectors::ConnectorOptions;
 use crate::protocols::http::client::HttpSession;
 use crate::upstreams::peer::Peer;
 use pingora_error::Result;
+use std::sync::Arc;
 use std::time::Duration;
 
+pub mod prewarm;
 pub mod v1;
 pub mod v2;
 
@@ -30,9 +33,11 @@ pub struct Connector {
 
 impl Connector {
     pub fn new(options: Option<ConnectorOptions>) -> Self {
+        // h1 and h2 connections to the same peer count towards the same limits
+        let limits = Arc::new(ConnectionLimits::from_options(options.as_ref()));
         Connector {
-            h1: v1::Connector::new(options.clone()),
-            h2: v2::Connector::new(options),
+            h1: v1::Connector::with_limits(options.clone(), limits.clone()),
+            h2: v2::Connector::with_limits(options, limits),
         }
     }
 
@@ -46,11 +51,7 @@ impl Connector {
         // NOTE: maybe TODO: we do not yet enforce that only TLS traffic can use h2, which is the
         // de facto requirement for h2, because non TLS traffic lack the negotiation mechanism.
 
-        // We assume no peer option == no ALPN == h1 only
-        let h1_only = peer
-            .get_peer_options()
-            .map_or(true, |o| o.alpn.get_max_http_version() == 1);
-        if h1_only {
+        if h1_only(peer) {
             let (h1, reused) = self.h1.get_http_session(peer).await?;
             Ok((HttpSession::H1(h1), reused))
         } else {
@@ -92,6 +93,54 @@ impl Connector {
     pub fn prefer_h1(&self, peer: &impl Peer) {
         self.h2.prefer_h1(peer);
     }
+
+    /// Make a new connection to the given server [Peer] and put it into the idle pool
+    pub(crate) async fn new_idle_connection<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+    ) -> Result<()> {
+        if h1_only(peer) {
+            return self.h1.new_idle_connection(peer).await;
+        }
+        match self.h2.new_http_session(peer).await? {
+            HttpSession::H2(h2) => self.h2.release_http_session(h2, peer, peer.idle_timeout()),
+            // the server doesn't speak h2
+            HttpSession::H1(h1) => self.h1.release_unused_stream(h1.into_stream(), peer),
+        }
+        Ok(())
+    }
+
+    /// Check the idle connections to the given server [Peer], with PING for h2, and put the live
+    /// ones back to the idle pools, which restarts their idle timeout. Return the number of them.
+    pub(crate) async fn refresh_idle_connections<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+        ping_timeout: Duration,
+    ) -> usize {
+        self.h1.refresh_idle_connections(peer).await
+            + self.h2.refresh_idle_connections(peer, ping_timeout).await
+    }
+
+    /// The numbers of in use and idle connections, both h1 and h2, to the given server [Peer]
+    pub fn connection_counts(&self, peer: &impl Peer) -> ConnectionCounts {
+        // the open connections are counted by the shared limits, so both include all of them
+        let h1 = self.h1.connection_counts(peer);
+        let h2 = self.h2.connection_counts(peer);
+        ConnectionCounts::new(h1.in_use + h1.idle, h1.idle + h2.idle)
+    }
+
+    /// The numbers of in use and idle connections, both h1 and h2, to all the peers
+    pub fn total_connection_counts(&self) -> ConnectionCounts {
+        let h1 = self.h1.total_connection_counts();
+        let h2 = self.h2.total_connection_counts();
+        ConnectionCounts::new(h1.in_use + h1.idle, h1.idle + h2.idle)
+    }
+}
+
+// We assume no peer option == no ALPN == h1 only
+fn h1_only(peer: &impl Peer) -> bool {
+    peer.get_peer_options()
+        .map_or(true, |o| o.alpn.get_max_http_version() == 1)
 }
 
 #[cfg(test)]
@@ -0,0 +1,213 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keep idle connections to the upstream servers ready
+//!
+//! The [ConnectionPrewarmer] is a [BackgroundService] that keeps a minimum number of idle, healthy
+//! connections to each of the given peers in the pools of a [Connector], so that the requests
+//! right after a cold start or a failover don't have to pay for the handshakes.
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{debug, warn};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::Connector;
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+use crate::upstreams::peer::{HttpPeer, Peer};
+
+/// The options of a [ConnectionPrewarmer]
+#[derive(Debug, Clone)]
+pub struct PrewarmOptions {
+    /// The minimum number of idle connections to keep for each peer.
+    pub min_idle: usize,
+    /// How often to check the idle connections of the peers.
+    pub check_interval: Duration,
+    /// How long before the `idle_timeout` of a peer its idle connections are refreshed.
+    ///
+    /// This should be longer than `check_interval` so that no connection expires in between.
+    pub refresh_margin: Duration,
+    /// How often to refresh the idle connections of the peers without `idle_timeout`.
+    pub refresh_interval: Duration,
+    /// How long to wait for the response of the PING sent to check an idle h2 connection.
+    pub ping_timeout: Duration,
+}
+
+impl Default for PrewarmOptions {
+    fn default() -> Self {
+        PrewarmOptions {
+            min_idle: 1,
+            check_interval: Duration::from_secs(1),
+            refresh_margin: Duration::from_secs(2),
+            refresh_interval: Duration::from_secs(30),
+            ping_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A [BackgroundService] to keep idle connections to a set of [HttpPeer]s
+///
+/// The connections are made and pooled by the given [Connector], which should be the same one
+/// that sends the requests to these peers.
+pub struct ConnectionPrewarmer {
+    connector: Arc<Connector>,
+    peers: RwLock<Vec<HttpPeer>>,
+    options: PrewarmOptions,
+}
+
+impl ConnectionPrewarmer {
+    /// Create a new [ConnectionPrewarmer] for the given peers.
+    pub fn new(connector: Arc<Connector>, peers: Vec<HttpPeer>, options: PrewarmOptions) -> Self {
+        ConnectionPrewarmer {
+            connector,
+            peers: RwLock::new(peers),
+            options,
+        }
+    }
+
+    /// Replace the peers to keep connections to.
+    ///
+    /// The idle connections to the removed peers are not closed but no longer refreshed.
+    pub fn set_peers(&self, peers: Vec<HttpPeer>) {
+        *self.peers.write() = peers;
+    }
+
+    fn refresh_period(&self, peer: &HttpPeer) -> Duration {
+        peer.idle_timeout()
+            .map_or(self.options.refresh_interval, |t| {
+                t.saturating_sub(self.options.refresh_margin)
+            })
+    }
+
+    // refresh the idle connections of the peer if asked, then top them up to the minimum
+    async fn warm_peer(&self, peer: &HttpPeer, refresh: bool) {
+        let idle = if refresh {
+            self.connector
+                .refresh_idle_connections(peer, self.options.ping_timeout)
+                .await
+        } else {
+            self.connector.connection_counts(peer).idle
+        };
+        let missing = self.options.min_idle.saturating_sub(idle);
+        if missing == 0 {
+            return;
+        }
+        debug!("prewarming {missing} connections to {peer}");
+        let connects = (0..missing).map(|_| self.connector.new_idle_connection(peer));
+        for res in join_all(connects).await {
+            if let Err(e) = res {
+                warn!("failed to prewarm connection to {peer}: {e}");
+            }
+        }
+    }
+
+    // one round of checks over all the peers, `last_refresh` tracks when each peer was refreshed
+    async fn warm(&self, last_refresh: &mut HashMap<u64, Instant>) {
+        let peers = self.peers.read().clone();
+        let now = Instant::now();
+        let mut work = Vec::with_capacity(peers.len());
+        for peer in peers.iter() {
+            let key = peer.reuse_hash();
+            let refresh = last_refresh.get(&key).map_or(true, |t| {
+                now.duration_since(*t) >= self.refresh_period(peer)
+            });
+            if refresh {
+                last_refresh.insert(key, now);
+            }
+            work.push(self.warm_peer(peer, refresh));
+        }
+        join_all(work).await;
+        // forget the removed peers
+        last_refresh.retain(|key, _| peers.iter().any(|p| p.reuse_hash() == *key));
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ConnectionPrewarmer {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut last_refresh = HashMap::new();
+        let mut interval = tokio::time::interval(self.options.check_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = interval.tick() => self.warm(&mut last_refresh).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // a server that accepts connections and keeps them open
+    async fn idle_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_prewarm() {
+        let addr = idle_server().await;
+        let mut peer = HttpPeer::new(&addr, false, "".into());
+        peer.options.idle_timeout = Some(Duration::from_secs(60));
+        let connector = Arc::new(Connector::new(None));
+        let options = PrewarmOptions {
+            min_idle: 2,
+            ..Default::default()
+        };
+        let prewarmer = ConnectionPrewarmer::new(connector.clone(), vec![peer.clone()], options);
+
+        let mut last_refresh = HashMap::new();
+        prewarmer.warm(&mut last_refresh).await;
+        assert_eq!(connector.connection_counts(&peer).idle, 2);
+
+        // the prewarmed connection is reused
+        let (session, reused) = connector.get_http_session(&peer).await.unwrap();
+        assert!(reused);
+        assert_eq!(connector.connection_counts(&peer).idle, 1);
+        prewarmer.warm(&mut last_refresh).await;
+        assert_eq!(connector.connection_counts(&peer).idle, 2);
+        drop(session);
+
+        // refresh keeps the live connections
+        last_refresh.clear();
+        prewarmer.warm(&mut last_refresh).await;
+        assert_eq!(connector.connection_counts(&peer).idle, 2);
+        assert_eq!(connector.connection_counts(&peer).in_use, 0);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_period() {
+        let connector = Arc::new(Connector::new(None));
+        let prewarmer = ConnectionPrewarmer::new(connector, vec![], PrewarmOptions::default());
+        let mut peer = HttpPeer::new("127.0.0.1:80", false, "".into());
+        assert_eq!(prewarmer.refresh_period(&peer), Duration::from_secs(30));
+        peer.options.idle_timeout = Some(Duration::from_secs(10));
+        assert_eq!(prewarmer.refresh_period(&peer), Duration::from_secs(8));
+    }
+}
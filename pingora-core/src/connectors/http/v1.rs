@@ -12,11 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::connectors::limit::{ConnectionCounts, ConnectionLimits};
 use crate::connectors::{ConnectorOptions, TransportConnector};
 use crate::protocols::http::v1::client::HttpSession;
+use crate::protocols::Stream;
 use crate::upstreams::peer::Peer;
 
 use pingora_error::Result;
+use std::sync::Arc;
 use std::time::Duration;
 
 pub struct Connector {
@@ -30,6 +33,15 @@ impl Connector {
         }
     }
 
+    pub(crate) fn with_limits(
+        options: Option<ConnectorOptions>,
+        limits: Arc<ConnectionLimits>,
+    ) -> Self {
+        Connector {
+            transport: TransportConnector::with_limits(options, limits),
+        }
+    }
+
     pub async fn get_http_session<P: Peer + Send + Sync + 'static>(
         &self,
         peer: &P,
@@ -57,9 +69,56 @@ impl Connector {
     ) {
         if let Some(stream) = session.reuse().await {
             self.transport
-                .release_stream(stream, peer.reuse_hash(), idle_timeout);
+                .release_stream(stream, peer.group_key(), idle_timeout);
         }
     }
+
+    /// The numbers of in use and idle connections to the given server [Peer]
+    pub fn connection_counts(&self, peer: &impl Peer) -> ConnectionCounts {
+        self.transport.connection_counts(peer)
+    }
+
+    /// The numbers of in use and idle connections to all the peers
+    pub fn total_connection_counts(&self) -> ConnectionCounts {
+        self.transport.total_connection_counts()
+    }
+
+    // Put a connection that was never used into the idle pool
+    pub(crate) fn release_unused_stream<P: Peer>(&self, stream: Stream, peer: &P) {
+        self.transport
+            .release_stream(stream, peer.group_key(), peer.idle_timeout());
+    }
+
+    /// Make a new connection to the given server [Peer] and put it into the idle pool
+    pub(crate) async fn new_idle_connection<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+    ) -> Result<()> {
+        let stream = self.transport.new_stream(peer).await?;
+        self.release_unused_stream(stream, peer);
+        Ok(())
+    }
+
+    /// Check the idle connections to the given server [Peer] and put the live ones back to the
+    /// idle pool, which restarts their idle timeout. Return the number of them.
+    pub(crate) async fn refresh_idle_connections<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+    ) -> usize {
+        // take them all out first so that the same one is not checked twice
+        let mut streams = vec![];
+        for _ in 0..self.transport.connection_counts(peer).idle {
+            // the broken ones are dropped here
+            if let Some(stream) = self.transport.reused_stream(peer).await {
+                streams.push(stream);
+            }
+        }
+        let count = streams.len();
+        for stream in streams {
+            self.release_unused_stream(stream, peer);
+        }
+        count
+    }
 }
 
 #[cfg(test)]
@@ -121,4 +180,36 @@ mod tests {
         let (_, reused) = connector.get_http_session(&peer).await.unwrap();
         assert!(reused);
     }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        use crate::connectors::limit::CONNECTION_QUEUE_FULL;
+
+        let mut options = ConnectorOptions::new(4);
+        options.max_connections_per_peer = Some(1);
+        options.connection_queue_size = 0;
+        let connector = Connector::new(Some(options));
+        let peer = HttpPeer::new(("1.1.1.1", 80), false, "".into());
+
+        let (mut http, reused) = connector.get_http_session(&peer).await.unwrap();
+        assert!(!reused);
+        let counts = connector.connection_counts(&peer);
+        assert_eq!((counts.in_use, counts.idle), (1, 0));
+        // no room in the queue
+        let e = connector.get_http_session(&peer).await.err().unwrap();
+        assert_eq!(e.etype(), &CONNECTION_QUEUE_FULL);
+
+        get_http(&mut http, 301).await;
+        connector.release_http_session(http, &peer, None).await;
+        let counts = connector.connection_counts(&peer);
+        assert_eq!((counts.in_use, counts.idle), (0, 1));
+
+        // the idle connection is reused under the limit
+        let (http, reused) = connector.get_http_session(&peer).await.unwrap();
+        assert!(reused);
+        // a closed connection makes room for a new one
+        drop(http);
+        let (_, reused) = connector.get_http_session(&peer).await.unwrap();
+        assert!(!reused);
+    }
 }
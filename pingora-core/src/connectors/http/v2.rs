@@ -13,24 +13,50 @@
 // limitations under the License.
 
 use super::HttpSession;
+use crate::connectors::limit::{ConnectionCounts, ConnectionLimits};
 use crate::connectors::{ConnectorOptions, TransportConnector};
 use crate::protocols::http::v1::client::HttpSession as Http1Session;
-use crate::protocols::http::v2::client::{drive_connection, Http2Session};
+use crate::protocols::http::v2::client::{drive_connection, Http2Session, PING_TIMEDOUT};
 use crate::protocols::{Digest, Stream};
 use crate::upstreams::peer::{Peer, ALPN};
 
 use bytes::Bytes;
 use h2::client::SendRequest;
+use h2::{Ping, PingPong};
 use log::{debug, warn};
 use parking_lot::{Mutex, RwLock};
 use pingora_error::{Error, ErrorType::*, OrErr, Result};
-use pingora_pool::{ConnectionMeta, ConnectionPool, PoolNode};
+use pingora_pool::{ConnectionMeta, ConnectionPool, GroupKey, PoolOptions};
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::watch;
 
+/// How the streams to a peer are spread across h2 connections
+#[derive(Debug, Clone)]
+pub struct H2PoolOptions {
+    /// The number of h2 connections to spread the concurrent streams to each peer over.
+    ///
+    /// New connections are made, instead of adding streams to a busy one, until there are this
+    /// many. Streams are always placed on the connection with the fewest active streams.
+    pub target_connections: usize,
+    /// Stop creating new streams on a connection after it has served this many of them in total.
+    pub max_streams_per_connection: Option<usize>,
+    /// Stop creating new streams on a connection after it has been open for this long.
+    pub max_connection_age: Option<Duration>,
+}
+
+impl Default for H2PoolOptions {
+    fn default() -> Self {
+        H2PoolOptions {
+            target_connections: 1,
+            max_streams_per_connection: None,
+            max_connection_age: None,
+        }
+    }
+}
+
 struct Stub(SendRequest<Bytes>);
 
 impl Stub {
@@ -52,11 +78,18 @@ pub(crate) struct ConnectionRefInner {
     max_streams: usize,
     // how many concurrent streams already active
     current_streams: AtomicUsize,
+    // how many streams were ever created
+    total_streams: AtomicUsize,
+    created: Instant,
+    // no new stream should be created on a retired connection, e.g., after GOAWAY is received
+    retired: AtomicBool,
     // because `SendRequest` doesn't actually have access to the underlying Stream,
     // we log info about timing and tcp info here.
     pub(crate) digest: Digest,
     // To serialize certain operations when trying to release the connect back to the pool,
     pub(crate) release_lock: Arc<Mutex<()>>,
+    // to send PING on demand, only when the connection doesn't already send them periodically
+    ping_pong: Option<tokio::sync::Mutex<PingPong>>,
 }
 
 #[derive(Clone)]
@@ -70,6 +103,7 @@ impl ConnectionRef {
         id: i32,
         max_streams: usize,
         digest: Digest,
+        ping_pong: Option<PingPong>,
     ) -> Self {
         ConnectionRef(Arc::new(ConnectionRefInner {
             connection_stub: Stub(send_req),
@@ -78,8 +112,12 @@ impl ConnectionRef {
             id,
             max_streams,
             current_streams: AtomicUsize::new(0),
+            total_streams: AtomicUsize::new(0),
+            created: Instant::now(),
+            retired: AtomicBool::new(false),
             digest,
             release_lock: Arc::new(Mutex::new(())),
+            ping_pong: ping_pong.map(tokio::sync::Mutex::new),
         }))
     }
     pub fn more_streams_allowed(&self) -> bool {
@@ -90,6 +128,26 @@ impl ConnectionRef {
         self.0.current_streams.load(Ordering::Relaxed) == 0
     }
 
+    pub fn current_streams(&self) -> usize {
+        self.0.current_streams.load(Ordering::Relaxed)
+    }
+
+    /// Stop creating new streams on this connection. The existing streams are not affected.
+    pub fn retire(&self) {
+        self.0.retired.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether this connection should no longer be used for new streams under the given options
+    pub fn should_retire(&self, options: &H2PoolOptions) -> bool {
+        self.0.retired.load(Ordering::Relaxed)
+            || options.max_streams_per_connection.map_or(false, |max| {
+                self.0.total_streams.load(Ordering::Relaxed) >= max
+            })
+            || options
+                .max_connection_age
+                .map_or(false, |age| self.0.created.elapsed() >= age)
+    }
+
     pub fn release_stream(&self) {
         self.0.current_streams.fetch_sub(1, Ordering::SeqCst);
     }
@@ -110,6 +168,24 @@ impl ConnectionRef {
         *self.0.closed.borrow()
     }
 
+    /// Check the liveness of the connection with a PING.
+    ///
+    /// If the connection already sends PINGs periodically, only the outcome of those is checked.
+    pub async fn ping(&self, timeout: Duration) -> Result<()> {
+        if self.is_closed() || self.ping_timedout() {
+            return Error::e_explain(H2Error, "h2 connection is already closed");
+        }
+        let Some(ping_pong) = self.0.ping_pong.as_ref() else {
+            return Ok(());
+        };
+        let mut ping_pong = ping_pong.lock().await;
+        match pingora_timeout::timeout(timeout, ping_pong.ping(Ping::opaque())).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Error::e_because(H2Error, "while sending ping", e),
+            Err(_) => Error::e_explain(PING_TIMEDOUT, format!("no pong in {timeout:?}")),
+        }
+    }
+
     // spawn a stream if more stream is allowed, otherwise return Ok(None)
     pub async fn spawn_stream(&self) -> Result<Option<Http2Session>> {
         // Atomically check if the current_stream is over the limit
@@ -125,6 +201,7 @@ impl ConnectionRef {
             self.0.current_streams.fetch_sub(1, Ordering::SeqCst);
             e
         })?;
+        self.0.total_streams.fetch_add(1, Ordering::Relaxed);
 
         Ok(Some(Http2Session::new(send_req, self.clone())))
     }
@@ -132,7 +209,7 @@ impl ConnectionRef {
 
 struct InUsePool {
     // TODO: use pingora hashmap to shard the lock contention
-    pools: RwLock<HashMap<u64, PoolNode<ConnectionRef>>>,
+    pools: RwLock<HashMap<u64, Mutex<HashMap<i32, ConnectionRef>>>>,
 }
 
 impl InUsePool {
@@ -146,23 +223,38 @@ impl InUsePool {
         {
             let pools = self.pools.read();
             if let Some(pool) = pools.get(&reuse_hash) {
-                pool.insert(conn.id(), conn);
+                pool.lock().insert(conn.id(), conn);
                 return;
             }
         } // drop read lock
 
-        let pool = PoolNode::new();
-        pool.insert(conn.id(), conn);
         let mut pools = self.pools.write();
-        pools.insert(reuse_hash, pool);
+        pools
+            .entry(reuse_hash)
+            .or_default()
+            .get_mut()
+            .insert(conn.id(), conn);
     }
 
-    // retrieve a h2 conn ref to create a new stream
+    // retrieve the least loaded h2 conn ref to create a new stream, skipping the ones to retire
     // the caller should return the conn ref to this pool if there are still
     // capacity left for more streams
-    fn get(&self, reuse_hash: u64) -> Option<ConnectionRef> {
+    fn get(&self, reuse_hash: u64, options: &H2PoolOptions) -> Option<ConnectionRef> {
+        let pools = self.pools.read();
+        let mut pool = pools.get(&reuse_hash)?.lock();
+        // the retired conns are left to their active streams, which will release them
+        pool.retain(|_, conn| !conn.is_closed() && !conn.should_retire(options));
+        let id = pool
+            .values()
+            .min_by_key(|conn| conn.current_streams())
+            .map(|conn| conn.id())?;
+        pool.remove(&id)
+    }
+
+    // the number of the conns in this pool to the given peer
+    fn count(&self, reuse_hash: u64) -> usize {
         let pools = self.pools.read();
-        pools.get(&reuse_hash)?.get_any().map(|v| v.1)
+        pools.get(&reuse_hash).map_or(0, |pool| pool.lock().len())
     }
 
     // release a h2_stream, this functional will cause an ConnectionRef to be returned (if exist)
@@ -170,7 +262,7 @@ impl InUsePool {
     fn release(&self, reuse_hash: u64, id: i32) -> Option<ConnectionRef> {
         let pools = self.pools.read();
         if let Some(pool) = pools.get(&reuse_hash) {
-            pool.remove(id)
+            pool.lock().remove(&id)
         } else {
             None
         }
@@ -187,19 +279,35 @@ pub struct Connector {
     idle_pool: Arc<ConnectionPool<ConnectionRef>>,
     // the pool of h2 connections that have ongoing streams
     in_use_pool: InUsePool,
+    h2_pool: H2PoolOptions,
 }
 
 impl Connector {
     /// Create a new [Connector] from the given [ConnectorOptions]
     pub fn new(options: Option<ConnectorOptions>) -> Self {
+        let limits = ConnectionLimits::from_options(options.as_ref());
+        Self::with_limits(options, Arc::new(limits))
+    }
+
+    pub(crate) fn with_limits(
+        options: Option<ConnectorOptions>,
+        limits: Arc<ConnectionLimits>,
+    ) -> Self {
         let pool_size = options
             .as_ref()
             .map_or(DEFAULT_POOL_SIZE, |o| o.keepalive_pool_size);
+        let pool_options = options
+            .as_ref()
+            .map_or_else(PoolOptions::default, |o| o.pool_options());
+        let h2_pool = options
+            .as_ref()
+            .map_or_else(H2PoolOptions::default, |o| o.h2_pool.clone());
         // connection offload is handled by the [TransportConnector]
         Connector {
-            transport: TransportConnector::new(options),
-            idle_pool: Arc::new(ConnectionPool::new(pool_size)),
+            transport: TransportConnector::with_limits(options, limits),
+            idle_pool: Arc::new(ConnectionPool::new_with_options(pool_size, pool_options)),
             in_use_pool: InUsePool::new(),
+            h2_pool,
         }
     }
 
@@ -239,7 +347,7 @@ impl Connector {
             .spawn_stream()
             .await?
             .expect("newly created connections should have at least one free stream");
-        if conn.more_streams_allowed() {
+        if conn.more_streams_allowed() && !conn.should_retire(&self.h2_pool) {
             self.in_use_pool.insert(peer.reuse_hash(), conn);
         }
         Ok(HttpSession::H2(h2_stream))
@@ -254,7 +362,8 @@ impl Connector {
     ) -> Result<Option<Http2Session>> {
         // check in use pool first so that we use fewer total connections
         // then idle pool
-        let reuse_hash = peer.reuse_hash();
+        let key = peer.group_key();
+        let reuse_hash = key.hash();
 
         // NOTE: We grab a conn from the pools, create a new stream and put the conn back if the
         // conn has more free streams. During this process another caller could arrive but is not
@@ -262,29 +371,60 @@ impl Connector {
         // We accept this false negative to keep the implementation simple. This false negative
         // makes an actual impact when there are only a few connection.
         // Alternative design 1. given each free stream a conn object: a lot of Arc<>
-        // Alternative design 2. mutex the pool, which creates lock contention when concurrency is high
-        // Alternative design 3. do not pop conn from the pool so that multiple callers can grab it
+        // Alternative design 2. do not pop conn from the pool so that multiple callers can grab it
         // which will cause issue where spawn_stream() could return None because others call it
         // first. Thus a caller might have to retry or give up. This issue is more likely to happen
         // when concurrency is high.
-        let maybe_conn = self
-            .in_use_pool
-            .get(reuse_hash)
-            .or_else(|| self.idle_pool.get(&reuse_hash));
-        if let Some(conn) = maybe_conn {
-            let h2_stream = conn.spawn_stream().await?;
-            if h2_stream.is_none() {
-                warn!("connection from the pools should have free stream to allocate, current in use {}, max {}",
-                    conn.0.current_streams.load(Ordering::Relaxed),
-                    conn.0.max_streams);
+        // The in use pool of each peer is behind a mutex in order to find the least loaded conn,
+        // which is only held briefly.
+        while let Some(conn) = self.pick_connection(&key) {
+            match conn.spawn_stream().await {
+                Ok(h2_stream) => {
+                    if h2_stream.is_none() {
+                        warn!("connection from the pools should have free stream to allocate, current in use {}, max {}",
+                            conn.0.current_streams.load(Ordering::Relaxed),
+                            conn.0.max_streams);
+                    }
+                    if conn.more_streams_allowed() && !conn.should_retire(&self.h2_pool) {
+                        self.in_use_pool.insert(reuse_hash, conn);
+                    }
+                    return Ok(h2_stream);
+                }
+                Err(e) => {
+                    // Most likely GOAWAY is received. The streams already on this conn are left
+                    // to finish, the new stream goes to another conn.
+                    debug!("retire h2 connection fd: {}, {e}", conn.id());
+                    conn.retire();
+                }
             }
-            if conn.more_streams_allowed() {
-                self.in_use_pool.insert(reuse_hash, conn);
+        }
+        Ok(None)
+    }
+
+    // Find the conn to place a new stream to the peer on. None means a new conn should be made.
+    fn pick_connection(&self, key: &GroupKey) -> Option<ConnectionRef> {
+        let reuse_hash = key.hash();
+        let Some(conn) = self.in_use_pool.get(reuse_hash, &self.h2_pool) else {
+            return self.get_idle(key);
+        };
+        // the least loaded conn is used unless there are fewer conns than the target
+        let conns = self.in_use_pool.count(reuse_hash) + self.idle_pool.idle_count(key) + 1;
+        if conns >= self.h2_pool.target_connections {
+            return Some(conn);
+        }
+        self.in_use_pool.insert(reuse_hash, conn);
+        self.get_idle(key)
+    }
+
+    // get an idle conn that can still take new streams
+    fn get_idle(&self, key: &GroupKey) -> Option<ConnectionRef> {
+        while let Some(conn) = self.idle_pool.get(key) {
+            if !conn.is_closed() && !conn.should_retire(&self.h2_pool) {
+                return Some(conn);
             }
-            Ok(h2_stream)
-        } else {
-            Ok(None)
+            debug!("drop retired idle h2 connection fd: {}", conn.id());
         }
+        None
     }
 
     /// Release a finished h2 stream.
@@ -300,7 +440,8 @@ impl Connector {
         idle_timeout: Option<Duration>,
     ) {
         let id = session.conn.id();
-        let reuse_hash = peer.reuse_hash();
+        let key = peer.group_key();
+        let reuse_hash = key.hash();
         // get a ref to the connection, which we might need below, before dropping the h2
         let conn = session.conn();
 
@@ -318,33 +459,92 @@ impl Connector {
             // Already dead h2 connection
             return;
         }
+        if conn.should_retire(&self.h2_pool) {
+            // no new stream will be created on it, the remaining streams keep it open until they
+            // are released
+            return;
+        }
         if conn.is_idle() {
             drop(locked);
-            let meta = ConnectionMeta {
-                key: reuse_hash,
-                id,
-            };
-            let closed = conn.0.closed.clone();
-            let (notify_evicted, watch_use) = self.idle_pool.put(&meta, conn);
-            if let Some(to) = idle_timeout {
-                let pool = self.idle_pool.clone(); //clone the arc
-                let rt = pingora_runtime::current_handle();
-                rt.spawn(async move {
-                    pool.idle_timeout(&meta, to, notify_evicted, closed, watch_use)
-                        .await;
-                });
-            }
+            self.put_idle(conn, key, idle_timeout);
         } else {
             self.in_use_pool.insert(reuse_hash, conn);
             drop(locked);
         }
     }
 
+    fn put_idle(&self, conn: ConnectionRef, key: GroupKey, idle_timeout: Option<Duration>) {
+        let meta = ConnectionMeta::new(key, conn.id());
+        let closed = conn.0.closed.clone();
+        let (notify_evicted, watch_use) = self.idle_pool.put(&meta, conn);
+        if let Some(to) = idle_timeout.or(self.idle_pool.max_idle_time()) {
+            let pool = self.idle_pool.clone(); //clone the arc
+            let rt = pingora_runtime::current_handle();
+            rt.spawn(async move {
+                pool.idle_timeout(&meta, to, notify_evicted, closed, watch_use)
+                    .await;
+            });
+        }
+    }
+
+    /// Check the idle h2 connections to the given server [Peer] with PING and put the live ones
+    /// back to the idle pool, which restarts their idle timeout. Return the number of them.
+    pub(crate) async fn refresh_idle_connections<P: Peer>(
+        &self,
+        peer: &P,
+        ping_timeout: Duration,
+    ) -> usize {
+        let key = peer.group_key();
+        // take them all out first so that the same one is not checked twice
+        let mut conns = vec![];
+        for _ in 0..self.idle_pool.idle_count(&key) {
+            match self.idle_pool.get(&key) {
+                Some(conn) => conns.push(conn),
+                None => break,
+            }
+        }
+        let checks = conns.into_iter().map(|conn| async move {
+            match conn.ping(ping_timeout).await {
+                Ok(()) => Some(conn),
+                Err(e) => {
+                    debug!("drop idle h2 connection fd: {}, {e}", conn.id());
+                    None
+                }
+            }
+        });
+        let live: Vec<_> = futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        let count = live.len();
+        for conn in live {
+            self.put_idle(conn, key.clone(), peer.idle_timeout());
+        }
+        count
+    }
+
     /// Tell the connector to always send h1 for ALPN for the given peer in the future.
     pub fn prefer_h1(&self, peer: &impl Peer) {
         self.transport.prefer_h1(peer);
     }
 
+    /// The numbers of in use and idle connections to the given server [Peer]
+    ///
+    /// An h2 connection is idle when it has no active stream.
+    pub fn connection_counts(&self, peer: &impl Peer) -> ConnectionCounts {
+        let counts = self.transport.connection_counts(peer);
+        let h2_idle = self.idle_pool.idle_count(&peer.group_key());
+        ConnectionCounts::new(counts.in_use + counts.idle, counts.idle + h2_idle)
+    }
+
+    /// The numbers of in use and idle connections to all the peers
+    pub fn total_connection_counts(&self) -> ConnectionCounts {
+        let counts = self.transport.total_connection_counts();
+        let h2_idle = self.idle_pool.total_idle_count();
+        ConnectionCounts::new(counts.in_use + counts.idle, counts.idle + h2_idle)
+    }
+
     pub(crate) fn h1_is_preferred(&self, peer: &impl Peer) -> bool {
         self.transport
             .preferred_http_version
@@ -385,7 +585,7 @@ async fn handshake(
         socket_digest: stream.get_socket_digest(),
     };
     // TODO: make these configurable
-    let (send_req, connection) = Builder::new()
+    let (send_req, mut connection) = Builder::new()
         .enable_push(false)
         .initial_max_send_streams(max_streams)
         // The limit for the server. Server push is not allowed, so this value doesn't matter
@@ -409,6 +609,12 @@ async fn handshake(
     }
 
     let (closed_tx, closed_rx) = watch::channel(false);
+    // the PingPong can only be taken once, leave it to drive_connection() for periodic pings
+    let ping_pong = if h2_ping_interval.map_or(true, |i| i.is_zero()) {
+        connection.ping_pong()
+    } else {
+        None
+    };
 
     current_handle().spawn(async move {
         drive_connection(
@@ -427,6 +633,7 @@ async fn handshake(
         id,
         max_allowed_streams,
         digest,
+        ping_pong,
     ))
 }
 
@@ -434,6 +641,7 @@ async fn handshake(
 mod tests {
     use super::*;
     use crate::upstreams::peer::HttpPeer;
+    use pingora_http::RequestHeader;
 
     #[tokio::test]
     async fn test_connect_h2() {
@@ -542,4 +750,146 @@ mod tests {
         let h2_5 = connector.reused_http_session(&peer).await.unwrap().unwrap();
         assert_eq!(id, h2_5.conn.id());
     }
+
+    #[tokio::test]
+    async fn test_h2_target_connections() {
+        let mut options = ConnectorOptions::new(DEFAULT_POOL_SIZE);
+        options.h2_pool.target_connections = 2;
+        let connector = Connector::new(Some(options));
+        let mut peer = HttpPeer::new(("1.1.1.1", 443), true, "one.one.one.one".into());
+        peer.options.set_http_version(2, 2);
+        peer.options.max_h2_streams = 3;
+        let h2_1 = match connector.new_http_session(&peer).await.unwrap() {
+            HttpSession::H1(_) => panic!("expect h2"),
+            HttpSession::H2(h2_stream) => h2_stream,
+        };
+
+        // below the target, a new connection should be made
+        assert!(connector
+            .reused_http_session(&peer)
+            .await
+            .unwrap()
+            .is_none());
+        let h2_2 = match connector.new_http_session(&peer).await.unwrap() {
+            HttpSession::H1(_) => panic!("expect h2"),
+            HttpSession::H2(h2_stream) => h2_stream,
+        };
+        assert_ne!(h2_1.conn.id(), h2_2.conn.id());
+
+        // the streams are spread over the two connections
+        let h2_3 = connector.reused_http_session(&peer).await.unwrap().unwrap();
+        let h2_4 = connector.reused_http_session(&peer).await.unwrap().unwrap();
+        assert_ne!(h2_3.conn.id(), h2_4.conn.id());
+    }
+
+    #[tokio::test]
+    async fn test_h2_retire_connection() {
+        let mut options = ConnectorOptions::new(DEFAULT_POOL_SIZE);
+        options.h2_pool.max_streams_per_connection = Some(2);
+        let connector = Connector::new(Some(options));
+        let mut peer = HttpPeer::new(("1.1.1.1", 443), true, "one.one.one.one".into());
+        peer.options.set_http_version(2, 2);
+        peer.options.max_h2_streams = 3;
+        let h2_1 = match connector.new_http_session(&peer).await.unwrap() {
+            HttpSession::H1(_) => panic!("expect h2"),
+            HttpSession::H2(h2_stream) => h2_stream,
+        };
+        let h2_2 = connector.reused_http_session(&peer).await.unwrap().unwrap();
+        assert_eq!(h2_1.conn.id(), h2_2.conn.id());
+
+        // the connection served 2 streams, no more new streams on it
+        assert!(connector
+            .reused_http_session(&peer)
+            .await
+            .unwrap()
+            .is_none());
+
+        // the in-flight streams are not affected
+        assert!(!h2_1.conn.is_closed());
+        connector.release_http_session(h2_1, &peer, None);
+        connector.release_http_session(h2_2, &peer, None);
+
+        // the retired connection is not pooled
+        assert!(connector
+            .reused_http_session(&peer)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(connector.connection_counts(&peer).idle, 0);
+    }
+
+    // An h2c server which sends GOAWAY on its first connection once the first request arrives.
+    // The requests are never answered. Return its address, the number of connections it accepted
+    // and the notification of the GOAWAY.
+    async fn goaway_server() -> (std::net::SocketAddr, tokio::sync::oneshot::Receiver<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (goaway_tx, goaway_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let mut goaway_tx = Some(goaway_tx);
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let goaway_tx = goaway_tx.take();
+                tokio::spawn(async move {
+                    let mut conn = h2::server::handshake(stream).await.unwrap();
+                    let mut goaway_tx = goaway_tx;
+                    // keep the streams open
+                    let mut requests = vec![];
+                    while let Some(Ok(request)) = conn.accept().await {
+                        requests.push(request);
+                        if let Some(tx) = goaway_tx.take() {
+                            conn.graceful_shutdown();
+                            tx.send(()).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        (addr, goaway_rx)
+    }
+
+    #[tokio::test]
+    async fn test_h2_goaway_retire_connection() {
+        let (addr, goaway) = goaway_server().await;
+        let connector = Connector::new(None);
+        let mut peer = HttpPeer::new(addr, false, "".into());
+        peer.options.set_http_version(2, 2);
+        peer.options.max_h2_streams = 2;
+
+        let mut h2_1 = match connector.new_http_session(&peer).await.unwrap() {
+            HttpSession::H1(_) => panic!("expect h2"),
+            HttpSession::H2(h2_stream) => h2_stream,
+        };
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("host", "pingora.org").unwrap();
+        h2_1.write_request_header(Box::new(req), true).unwrap();
+        goaway.await.unwrap();
+        // let the connection task process the GOAWAY
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // the connection has a free stream, but it is retired instead of being used
+        assert!(connector
+            .reused_http_session(&peer)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(h2_1.conn.0.retired.load(Ordering::Relaxed));
+        // the in-flight stream is not affected
+        assert!(!h2_1.conn.is_closed());
+
+        // the new stream goes to a fresh connection
+        let h2_2 = match connector.new_http_session(&peer).await.unwrap() {
+            HttpSession::H1(_) => panic!("expect h2"),
+            HttpSession::H2(h2_stream) => h2_stream,
+        };
+        assert_ne!(h2_1.conn.id(), h2_2.conn.id());
+        let h2_3 = connector.reused_http_session(&peer).await.unwrap().unwrap();
+        assert_eq!(h2_2.conn.id(), h2_3.conn.id());
+
+        // the retired connection is not pooled once its stream is released
+        connector.release_http_session(h2_1, &peer, None);
+        connector.release_http_session(h2_2, &peer, None);
+        connector.release_http_session(h2_3, &peer, None);
+        assert_eq!(connector.connection_counts(&peer).idle, 1);
+    }
 }
@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::debug;
 use pingora_error::{Context, Error, ErrorType::*, OrErr, Result};
 use rand::seq::SliceRandom;
+use std::collections::VecDeque;
 use std::net::SocketAddr as InetSocketAddr;
 use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use tokio::net::TcpStream;
 
+use super::resolver::Resolver;
 use crate::protocols::l4::ext::{
     connect_uds, connect_with as tcp_connect, set_recv_buf, set_tcp_fastopen_connect,
 };
@@ -27,7 +32,13 @@ use crate::protocols::{GetSocketDigest, SocketDigest};
 use crate::upstreams::peer::Peer;
 
 /// Establish a connection (l4) to the given peer using its settings and an optional bind address.
-pub async fn connect<P>(peer: &P, bind_to: Option<InetSocketAddr>) -> Result<Stream>
+///
+/// If the peer is defined by host name, the name is resolved by the given [Resolver].
+pub async fn connect_with_resolver<P>(
+    peer: &P,
+    bind_to: Option<InetSocketAddr>,
+    resolver: &Resolver,
+) -> Result<Stream>
 where
     P: Peer + Send + Sync,
 {
@@ -37,39 +48,34 @@ where
             .err_context(|| format!("Fail to establish CONNECT proxy: {}", peer));
     }
     let peer_addr = peer.address();
-    let mut stream: Stream = match peer_addr {
+    let (mut stream, connected_addr): (Stream, SocketAddr) = match peer_addr {
         SocketAddr::Inet(addr) => {
-            let connect_future = tcp_connect(addr, bind_to.as_ref(), |socket| {
-                if peer.tcp_fast_open() {
-                    set_tcp_fastopen_connect(socket.as_raw_fd())?;
-                }
-                if let Some(recv_buf) = peer.tcp_recv_buf() {
-                    debug!("Setting recv buf size");
-                    set_recv_buf(socket.as_raw_fd(), recv_buf)?;
+            let resolved;
+            let (primary, additional, bind_to) = match peer.hostname() {
+                Some(host) => {
+                    resolved = resolver
+                        .resolve_socket_addrs(host, addr.port())
+                        .await
+                        .map_err(|e| e.more_context(format!("Fail to connect to {peer}")))?;
+                    // safe to unwrap: the resolver never returns an empty list
+                    let (primary, additional) = resolved.split_first().unwrap();
+                    // the bind address was picked without knowing the address family
+                    let bind_to = bind_to.filter(|b| b.is_ipv4() == primary.is_ipv4());
+                    (*primary, additional, bind_to)
                 }
-                Ok(())
-            });
-            let conn_res = match peer.connection_timeout() {
-                Some(t) => pingora_timeout::timeout(t, connect_future)
-                    .await
-                    .explain_err(ConnectTimedout, |_| {
-                        format!("timeout {t:?} connecting to server {peer}")
-                    })?,
-                None => connect_future.await,
+                None => (*addr, peer.additional_addresses(), bind_to),
             };
-            match conn_res {
-                Ok(socket) => {
-                    debug!("connected to new server: {}", peer.address());
-                    Ok(socket.into())
+            let (socket, addr) = match peer.happy_eyeballs_delay() {
+                Some(delay) if !additional.is_empty() => {
+                    happy_eyeballs_connect(peer, primary, additional, bind_to, delay).await?
                 }
-                Err(e) => {
-                    let c = format!("Fail to connect to {peer}");
-                    match e.etype() {
-                        SocketError | BindError => Error::e_because(InternalError, c, e),
-                        _ => Err(e.more_context(c)),
-                    }
-                }
-            }
+                _ => (
+                    inet_connect(peer, &primary, bind_to.as_ref()).await?,
+                    primary,
+                ),
+            };
+            debug!("connected to new server: {addr}");
+            (socket.into(), SocketAddr::Inet(addr))
         }
         SocketAddr::Unix(addr) => {
             let connect_future = connect_uds(
@@ -84,7 +90,7 @@ where
                     })?,
                 None => connect_future.await,
             };
-            match conn_res {
+            let stream = match conn_res {
                 Ok(socket) => {
                     debug!("connected to new server: {}", peer.address());
                     Ok(socket.into())
@@ -96,9 +102,10 @@ where
                         _ => Err(e.more_context(c)),
                     }
                 }
-            }
+            }?;
+            (stream, peer_addr.clone())
         }
-    }?;
+    };
     let tracer = peer.get_tracer();
     if let Some(t) = tracer {
         t.0.on_connected();
@@ -114,13 +121,126 @@ where
     let digest = SocketDigest::from_raw_fd(stream.as_raw_fd());
     digest
         .peer_addr
-        .set(Some(peer_addr.clone()))
+        .set(Some(connected_addr))
         .expect("newly created OnceCell must be empty");
     stream.set_socket_digest(digest);
 
     Ok(stream)
 }
 
+// connect() to one address of the peer, respecting the peer's connection timeout
+async fn inet_connect<P: Peer>(
+    peer: &P,
+    addr: &InetSocketAddr,
+    bind_to: Option<&InetSocketAddr>,
+) -> Result<TcpStream> {
+    let connect_future = tcp_connect(addr, bind_to, |socket| {
+        if peer.tcp_fast_open() {
+            set_tcp_fastopen_connect(socket.as_raw_fd())?;
+        }
+        if let Some(recv_buf) = peer.tcp_recv_buf() {
+            debug!("Setting recv buf size");
+            set_recv_buf(socket.as_raw_fd(), recv_buf)?;
+        }
+        Ok(())
+    });
+    let conn_res = match peer.connection_timeout() {
+        Some(t) => pingora_timeout::timeout(t, connect_future)
+            .await
+            .explain_err(ConnectTimedout, |_| {
+                format!("timeout {t:?} connecting to server {peer}")
+            })?,
+        None => connect_future.await,
+    };
+    conn_res.map_err(|e| {
+        let c = format!("Fail to connect to {peer}");
+        match e.etype() {
+            SocketError | BindError => Error::because(InternalError, c, e),
+            _ => e.more_context(c),
+        }
+    })
+}
+
+/// Race the connections to all the addresses of the peer, following Happy Eyeballs (RFC 8305).
+///
+/// The addresses are tried one by one, alternating between IPv6 and IPv4. The next attempt starts
+/// when the previous one fails or after `delay`, whichever comes first. The first established
+/// connection wins and the other attempts are cancelled.
+///
+/// `bind_to` only applies to the addresses of its own address family.
+async fn happy_eyeballs_connect<P: Peer>(
+    peer: &P,
+    primary: InetSocketAddr,
+    additional: &[InetSocketAddr],
+    bind_to: Option<InetSocketAddr>,
+    delay: Duration,
+) -> Result<(TcpStream, InetSocketAddr)> {
+    let attempt = |addr: InetSocketAddr| {
+        let bind_to = bind_to.filter(|b| b.is_ipv4() == addr.is_ipv4());
+        async move { (addr, inet_connect(peer, &addr, bind_to.as_ref()).await) }
+    };
+
+    let race = async {
+        let mut addrs = interleave_addresses(primary, additional).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+        loop {
+            if attempts.is_empty() {
+                match addrs.next() {
+                    Some(addr) => attempts.push(attempt(addr)),
+                    None => break,
+                }
+            }
+            let more_addrs = addrs.len() > 0;
+            tokio::select! {
+                // safe to unwrap: attempts is not empty
+                res = attempts.next() => match res.unwrap() {
+                    (addr, Ok(socket)) => return Ok((socket, addr)),
+                    (addr, Err(e)) => {
+                        debug!("Happy Eyeballs attempt to {addr} failed: {e}");
+                        last_error = Some(e);
+                        if let Some(addr) = addrs.next() {
+                            attempts.push(attempt(addr));
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(delay), if more_addrs => {
+                    // safe to unwrap: more_addrs
+                    attempts.push(attempt(addrs.next().unwrap()));
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            Error::explain(ConnectError, format!("no address to connect to {peer}"))
+        }))
+    };
+
+    match peer.total_connection_timeout() {
+        Some(t) => pingora_timeout::timeout(t, race)
+            .await
+            .explain_err(ConnectTimedout, |_| {
+                format!("connecting to server {peer}, total-connection timeout {t:?}")
+            })?,
+        None => race.await,
+    }
+}
+
+// Order the addresses by alternating address families, starting with IPv6 (RFC 8305 section 4)
+fn interleave_addresses(
+    primary: InetSocketAddr,
+    additional: &[InetSocketAddr],
+) -> Vec<InetSocketAddr> {
+    let (mut v6, mut v4): (VecDeque<_>, VecDeque<_>) = std::iter::once(primary)
+        .chain(additional.iter().copied().filter(|addr| *addr != primary))
+        .partition(|addr| addr.is_ipv6());
+    let mut addrs = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        addrs.extend(v6.pop_front());
+        addrs.extend(v4.pop_front());
+    }
+    addrs
+}
+
 pub(crate) fn bind_to_random<P: Peer>(
     peer: &P,
     v4_list: &[InetSocketAddr],
@@ -196,16 +316,18 @@ async fn proxy_connect<P: Peer>(peer: &P) -> Result<Stream> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::connectors::resolver::DEFAULT_RESOLVER;
     use crate::upstreams::peer::{BasicPeer, HttpPeer, Proxy};
     use std::collections::BTreeMap;
     use std::path::PathBuf;
+    use std::sync::Arc;
     use tokio::io::AsyncWriteExt;
     use tokio::net::UnixListener;
 
     #[tokio::test]
     async fn test_conn_error_refused() {
         let peer = BasicPeer::new("127.0.0.1:79"); // hopefully port 79 is not used
-        let new_session = connect(&peer, None).await;
+        let new_session = connect_with_resolver(&peer, None, &DEFAULT_RESOLVER).await;
         assert_eq!(new_session.unwrap_err().etype(), &ConnectRefused)
     }
 
@@ -214,14 +336,19 @@ mod tests {
     #[tokio::test]
     async fn test_conn_error_no_route() {
         let peer = BasicPeer::new("[::3]:79"); // no route
-        let new_session = connect(&peer, None).await;
+        let new_session = connect_with_resolver(&peer, None, &DEFAULT_RESOLVER).await;
         assert_eq!(new_session.unwrap_err().etype(), &ConnectNoRoute)
     }
 
     #[tokio::test]
     async fn test_conn_error_addr_not_avail() {
         let peer = HttpPeer::new("127.0.0.1:121".to_string(), false, "".to_string());
-        let new_session = connect(&peer, Some("192.0.2.2:0".parse().unwrap())).await;
+        let new_session = connect_with_resolver(
+            &peer,
+            Some("192.0.2.2:0".parse().unwrap()),
+            &DEFAULT_RESOLVER,
+        )
+        .await;
         assert_eq!(new_session.unwrap_err().etype(), &InternalError)
     }
 
@@ -230,7 +357,12 @@ mod tests {
         let peer = HttpPeer::new("240.0.0.1:80".to_string(), false, "".to_string()); // non localhost
 
         // create an error: cannot send from src addr: localhost to dst addr: a public IP
-        let new_session = connect(&peer, Some("127.0.0.1:0".parse().unwrap())).await;
+        let new_session = connect_with_resolver(
+            &peer,
+            Some("127.0.0.1:0".parse().unwrap()),
+            &DEFAULT_RESOLVER,
+        )
+        .await;
         let error = new_session.unwrap_err();
         // XXX: some system will allow the socket to bind and connect without error, only to timeout
         assert!(error.etype() == &ConnectError || error.etype() == &ConnectTimedout)
@@ -241,10 +373,120 @@ mod tests {
         // 192.0.2.1 is effectively a blackhole
         let mut peer = BasicPeer::new("192.0.2.1:79");
         peer.options.connection_timeout = Some(std::time::Duration::from_millis(1)); //1ms
-        let new_session = connect(&peer, None).await;
+        let new_session = connect_with_resolver(&peer, None, &DEFAULT_RESOLVER).await;
         assert_eq!(new_session.unwrap_err().etype(), &ConnectTimedout)
     }
 
+    #[test]
+    fn test_interleave_addresses() {
+        let addr = |a: &str| a.parse::<InetSocketAddr>().unwrap();
+        let addrs = interleave_addresses(
+            addr("1.1.1.1:80"),
+            &[
+                addr("1.0.0.1:80"),
+                addr("[2606:4700::1111]:80"),
+                addr("1.1.1.1:80"),
+                addr("[2606:4700::1001]:80"),
+                addr("1.0.0.2:80"),
+            ],
+        );
+        assert_eq!(
+            addrs,
+            vec![
+                addr("[2606:4700::1111]:80"),
+                addr("1.1.1.1:80"),
+                addr("[2606:4700::1001]:80"),
+                addr("1.0.0.1:80"),
+                addr("1.0.0.2:80"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_fallback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        // 192.0.2.1 is effectively a blackhole
+        let mut peer = HttpPeer::new_with_addresses(
+            &["192.0.2.1:79".parse().unwrap(), local_addr],
+            false,
+            "".to_string(),
+        )
+        .unwrap();
+        peer.options.happy_eyeballs_delay = Some(std::time::Duration::from_millis(10));
+        peer.options.total_connection_timeout = Some(std::time::Duration::from_secs(1));
+        let stream = connect_with_resolver(&peer, None, &DEFAULT_RESOLVER)
+            .await
+            .unwrap();
+        let digest = stream.get_socket_digest().unwrap();
+        assert_eq!(digest.peer_addr(), Some(&SocketAddr::Inet(local_addr)));
+        assert!(peer.matches_fd(stream.as_raw_fd()));
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_total_timeout() {
+        let mut peer = HttpPeer::new_with_addresses(
+            &[
+                "192.0.2.1:79".parse().unwrap(),
+                "192.0.2.2:79".parse().unwrap(),
+            ],
+            false,
+            "".to_string(),
+        )
+        .unwrap();
+        peer.options.happy_eyeballs_delay = Some(std::time::Duration::from_millis(10));
+        peer.options.total_connection_timeout = Some(std::time::Duration::from_millis(50));
+        let new_session = connect_with_resolver(&peer, None, &DEFAULT_RESOLVER).await;
+        assert_eq!(new_session.unwrap_err().etype(), &ConnectTimedout)
+    }
+
+    #[tokio::test]
+    async fn test_connect_hostname() {
+        use crate::connectors::resolver::{Resolution, Resolve, ResolverOptions, NAME_NOT_FOUND};
+        use async_trait::async_trait;
+
+        struct MockResolver;
+
+        #[async_trait]
+        impl Resolve for MockResolver {
+            async fn resolve(&self, host: &str) -> Result<Resolution> {
+                if host == "local.example.com" {
+                    Ok(Resolution::Found(vec!["127.0.0.1".parse().unwrap()], None))
+                } else {
+                    Ok(Resolution::NotFound(None))
+                }
+            }
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let resolver = Resolver::new(Arc::new(MockResolver), ResolverOptions::default());
+
+        let peer = HttpPeer::new_from_hostname(
+            "local.example.com",
+            local_addr.port(),
+            false,
+            "".to_string(),
+        );
+        let stream = connect_with_resolver(&peer, None, &resolver).await.unwrap();
+        let digest = stream.get_socket_digest().unwrap();
+        assert_eq!(digest.peer_addr(), Some(&SocketAddr::Inet(local_addr)));
+
+        let peer = HttpPeer::new_from_hostname("nx.example.com", 80, false, "".to_string());
+        let e = connect_with_resolver(&peer, None, &resolver)
+            .await
+            .unwrap_err();
+        assert_eq!(e.etype(), &NAME_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_http_peer_try_new() {
+        let peer = HttpPeer::try_new("127.0.0.1:80", false, "".to_string()).unwrap();
+        assert!(peer.additional_addresses().is_empty());
+        let e = HttpPeer::try_new("not an address", false, "".to_string()).unwrap_err();
+        assert_eq!(e.etype(), &SocketError);
+    }
+
     #[tokio::test]
     async fn test_connect_proxy_fail() {
         let mut peer = HttpPeer::new("1.1.1.1:80".to_string(), false, "".to_string());
@@ -256,7 +498,7 @@ mod tests {
             port: 80,
             headers: BTreeMap::new(),
         });
-        let new_session = connect(&peer, None).await;
+        let new_session = connect_with_resolver(&peer, None, &DEFAULT_RESOLVER).await;
         let e = new_session.unwrap_err();
         assert_eq!(e.etype(), &ConnectError);
         assert!(!e.retry());
@@ -292,7 +534,7 @@ mod tests {
             port: 80,
             headers: BTreeMap::new(),
         });
-        let new_session = connect(&peer, None).await;
+        let new_session = connect_with_resolver(&peer, None, &DEFAULT_RESOLVER).await;
         assert!(new_session.is_ok());
     }
 
@@ -326,7 +568,7 @@ mod tests {
             port: 80,
             headers: BTreeMap::new(),
         });
-        let new_session = connect(&peer, None).await;
+        let new_session = connect_with_resolver(&peer, None, &DEFAULT_RESOLVER).await;
         let err = new_session.unwrap_err();
         assert_eq!(err.etype(), &ConnectionClosed);
         assert!(!err.retry());
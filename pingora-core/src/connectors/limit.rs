@@ -0,0 +1,384 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per peer connection accounting and limits
+//!
+//! Every connection made by a connector holds a [ConnectionPermit] of its peer (by
+//! [Peer::reuse_hash()](crate::upstreams::peer::Peer::reuse_hash)) until it is closed. When a peer
+//! reaches its connection limit, new connection attempts wait in a bounded queue for either a
+//! permit or a connection released for reuse.
+
+use log::{debug, error};
+use parking_lot::RwLock;
+use pingora_error::{Error, ErrorType, Result};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use super::ConnectorOptions;
+
+/// Too many connection attempts are already waiting for the peer to have a free connection.
+pub const CONNECTION_QUEUE_FULL: ErrorType = ErrorType::new("ConnectionQueueFull");
+/// A connection attempt waited too long in the queue for the peer to have a free connection.
+pub const CONNECTION_QUEUE_TIMEDOUT: ErrorType = ErrorType::new("ConnectionQueueTimedout");
+
+/// The numbers of the connections to a peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionCounts {
+    /// The connections that are being used
+    pub in_use: usize,
+    /// The connections that are idle in the keepalive pools
+    pub idle: usize,
+}
+
+impl ConnectionCounts {
+    pub(crate) fn new(open: usize, idle: usize) -> Self {
+        ConnectionCounts {
+            // the counters are not updated atomically together
+            in_use: open.saturating_sub(idle),
+            idle,
+        }
+    }
+}
+
+struct PeerConnections {
+    open: AtomicUsize,
+    queued: AtomicUsize,
+    permits: Option<Arc<Semaphore>>,
+    // notified when a connection to this peer is released for reuse
+    released: Notify,
+}
+
+/// The permit of an open connection to a peer
+///
+/// The permit is returned to the peer when this object is dropped together with the connection.
+pub(crate) struct ConnectionPermit {
+    peer: Arc<PeerConnections>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionPermit {
+    fn new(peer: Arc<PeerConnections>, permit: Option<OwnedSemaphorePermit>) -> Self {
+        peer.open.fetch_add(1, Ordering::Relaxed);
+        ConnectionPermit {
+            peer,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.peer.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Debug for ConnectionPermit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionPermit").finish()
+    }
+}
+
+// A slot in the wait queue of a peer, released when dropped
+struct QueueSlot<'a>(&'a PeerConnections);
+
+impl<'a> QueueSlot<'a> {
+    fn enter(peer: &'a PeerConnections, queue_size: usize) -> Option<Self> {
+        if peer.queued.fetch_add(1, Ordering::Relaxed) >= queue_size {
+            peer.queued.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(QueueSlot(peer))
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The outcome of waiting for a connection to a peer
+pub(crate) enum Acquired<T> {
+    /// A new connection can be made with this permit.
+    Permit(ConnectionPermit),
+    /// A connection is reused.
+    Reused(T),
+}
+
+struct Peers {
+    map: HashMap<u64, Arc<PeerConnections>>,
+    // prune the unused peers when the map grows to this size
+    prune_at: usize,
+}
+
+const MIN_PRUNE_SIZE: usize = 1024;
+
+/// The connection accounting and limits of all the peers of a connector
+pub(crate) struct ConnectionLimits {
+    peers: RwLock<Peers>,
+    max_per_peer: Option<usize>,
+    queue_size: usize,
+    queue_timeout: Option<Duration>,
+}
+
+impl ConnectionLimits {
+    /// A `max_per_peer` of 0 is raised to 1, otherwise no connection could ever be made.
+    pub fn new(
+        max_per_peer: Option<usize>,
+        queue_size: usize,
+        queue_timeout: Option<Duration>,
+    ) -> Self {
+        let max_per_peer = match max_per_peer {
+            Some(0) => {
+                error!("max_connections_per_peer should be at least 1, using 1");
+                Some(1)
+            }
+            max => max,
+        };
+        ConnectionLimits {
+            peers: RwLock::new(Peers {
+                map: HashMap::new(),
+                prune_at: MIN_PRUNE_SIZE,
+            }),
+            max_per_peer,
+            queue_size,
+            queue_timeout,
+        }
+    }
+
+    pub fn from_options(options: Option<&ConnectorOptions>) -> Self {
+        match options {
+            Some(o) => Self::new(
+                o.max_connections_per_peer,
+                o.connection_queue_size,
+                o.connection_queue_timeout,
+            ),
+            None => Self::new(None, 0, None),
+        }
+    }
+
+    fn peer(&self, key: u64) -> Arc<PeerConnections> {
+        if let Some(peer) = self.peers.read().map.get(&key) {
+            return peer.clone();
+        }
+        let mut peers = self.peers.write();
+        if let Some(peer) = peers.map.get(&key) {
+            return peer.clone();
+        }
+        if peers.map.len() >= peers.prune_at {
+            // no permit or waiter holds a reference to these peers
+            peers.map.retain(|_, p| Arc::strong_count(p) > 1);
+            peers.prune_at = (peers.map.len() * 2).max(MIN_PRUNE_SIZE);
+        }
+        let peer = Arc::new(PeerConnections {
+            open: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            permits: self.max_per_peer.map(|max| Arc::new(Semaphore::new(max))),
+            released: Notify::new(),
+        });
+        peers.map.insert(key, peer.clone());
+        peer
+    }
+
+    /// The number of open connections to the peer, both in use and idle
+    pub fn open_connections(&self, key: u64) -> usize {
+        self.peers
+            .read()
+            .map
+            .get(&key)
+            .map_or(0, |p| p.open.load(Ordering::Relaxed))
+    }
+
+    /// The number of open connections to all the peers, both in use and idle
+    pub fn total_open_connections(&self) -> usize {
+        self.peers
+            .read()
+            .map
+            .values()
+            .map(|p| p.open.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Wake up the connection attempts waiting for the peer so that they can try to reuse the
+    /// connection that was just released.
+    pub fn notify_released(&self, key: u64) {
+        if let Some(peer) = self.peers.read().map.get(&key) {
+            if peer.queued.load(Ordering::Relaxed) > 0 {
+                peer.released.notify_waiters();
+            }
+        }
+    }
+
+    /// Get a permit to make a new connection to the peer, or a connection from `reuse`.
+    ///
+    /// `reuse` is tried first and then every time a connection to the peer is released while
+    /// waiting in the queue.
+    pub async fn acquire<T, F, Fut>(&self, key: u64, mut reuse: F) -> Result<Acquired<T>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Option<T>>,
+    {
+        if let Some(t) = reuse().await {
+            return Ok(Acquired::Reused(t));
+        }
+        let peer = self.peer(key);
+        let Some(permits) = peer.permits.clone() else {
+            return Ok(Acquired::Permit(ConnectionPermit::new(peer, None)));
+        };
+        if let Ok(permit) = permits.clone().try_acquire_owned() {
+            return Ok(Acquired::Permit(ConnectionPermit::new(peer, Some(permit))));
+        }
+
+        let Some(_slot) = QueueSlot::enter(&peer, self.queue_size) else {
+            return Error::e_explain(
+                CONNECTION_QUEUE_FULL,
+                format!(
+                    "{} connections and {} queued attempts to the peer",
+                    peer.open.load(Ordering::Relaxed),
+                    self.queue_size
+                ),
+            );
+        };
+        debug!("connection limit reached, waiting in the queue");
+        let wait = async {
+            loop {
+                let released = peer.released.notified();
+                tokio::pin!(released);
+                // register before trying to reuse so that no release is missed in between
+                released.as_mut().enable();
+                if let Some(t) = reuse().await {
+                    return Acquired::Reused(t);
+                }
+                tokio::select! {
+                    permit = permits.clone().acquire_owned() => {
+                        // the semaphore is never closed
+                        let permit = permit.unwrap();
+                        return Acquired::Permit(ConnectionPermit::new(peer.clone(), Some(permit)));
+                    }
+                    _ = released => {}
+                }
+            }
+        };
+        match self.queue_timeout {
+            Some(t) => match pingora_timeout::timeout(t, wait).await {
+                Ok(acquired) => Ok(acquired),
+                Err(_) => Error::e_explain(
+                    CONNECTION_QUEUE_TIMEDOUT,
+                    format!("waiting for a free connection to the peer, timeout {t:?}"),
+                ),
+            },
+            None => Ok(wait.await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn no_reuse() -> Option<()> {
+        None
+    }
+
+    fn permit(acquired: Result<Acquired<()>>) -> ConnectionPermit {
+        match acquired.unwrap() {
+            Acquired::Permit(p) => p,
+            Acquired::Reused(_) => panic!("unexpected reuse"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let limits = ConnectionLimits::new(None, 0, None);
+        let p1 = permit(limits.acquire(1, no_reuse).await);
+        let p2 = permit(limits.acquire(1, no_reuse).await);
+        assert_eq!(limits.open_connections(1), 2);
+        assert_eq!(limits.open_connections(2), 0);
+        drop(p1);
+        drop(p2);
+        assert_eq!(limits.open_connections(1), 0);
+    }
+
+    #[tokio::test]
+    async fn test_queue_full() {
+        let limits = ConnectionLimits::new(Some(1), 0, None);
+        let p1 = permit(limits.acquire(1, no_reuse).await);
+        let e = limits.acquire(1, no_reuse).await.err().unwrap();
+        assert_eq!(e.etype(), &CONNECTION_QUEUE_FULL);
+        // other peers are not affected
+        let _p2 = permit(limits.acquire(2, no_reuse).await);
+        drop(p1);
+        let _p1 = permit(limits.acquire(1, no_reuse).await);
+    }
+
+    #[tokio::test]
+    async fn test_queue_timeout() {
+        let limits = ConnectionLimits::new(Some(1), 1, Some(Duration::from_millis(10)));
+        let _p1 = permit(limits.acquire(1, no_reuse).await);
+        let e = limits.acquire(1, no_reuse).await.err().unwrap();
+        assert_eq!(e.etype(), &CONNECTION_QUEUE_TIMEDOUT);
+    }
+
+    #[tokio::test]
+    async fn test_zero_limit() {
+        // treated as 1
+        let limits = ConnectionLimits::new(Some(0), 0, None);
+        assert_eq!(limits.max_per_peer, Some(1));
+        let _p1 = permit(limits.acquire(1, no_reuse).await);
+        let e = limits.acquire(1, no_reuse).await.err().unwrap();
+        assert_eq!(e.etype(), &CONNECTION_QUEUE_FULL);
+    }
+
+    #[tokio::test]
+    async fn test_queue_wait() {
+        let limits = Arc::new(ConnectionLimits::new(Some(1), 1, None));
+        let p1 = permit(limits.acquire(1, no_reuse).await);
+
+        let limits2 = limits.clone();
+        let waiter = tokio::spawn(async move { limits2.acquire(1, no_reuse).await.is_ok() });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // the queue is full now
+        let e = limits.acquire(1, no_reuse).await.err().unwrap();
+        assert_eq!(e.etype(), &CONNECTION_QUEUE_FULL);
+
+        drop(p1);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_queue_reuse() {
+        let limits = Arc::new(ConnectionLimits::new(Some(1), 1, None));
+        let _p1 = permit(limits.acquire(1, no_reuse).await);
+        let released = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let limits2 = limits.clone();
+        let released2 = released.clone();
+        let waiter = tokio::spawn(async move {
+            let reuse = || {
+                let released = released2.clone();
+                async move { released.load(Ordering::Relaxed).then_some(()) }
+            };
+            matches!(limits2.acquire(1, reuse).await, Ok(Acquired::Reused(())))
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        released.store(true, Ordering::Relaxed);
+        limits.notify_released(1);
+        assert!(waiter.await.unwrap());
+    }
+}
@@ -16,23 +16,28 @@
 
 pub mod http;
 mod l4;
+pub mod limit;
 mod offload;
+pub mod resolver;
 mod tls;
 
-use crate::protocols::Stream;
+use crate::protocols::{ConnFdReusable, Stream};
 use crate::server::configuration::ServerConf;
-use crate::tls::ssl::SslConnector;
 use crate::upstreams::peer::{Peer, ALPN};
 
-use l4::connect as l4_connect;
+use self::http::v2::H2PoolOptions;
+use l4::connect_with_resolver as l4_connect;
+use limit::{Acquired, ConnectionCounts, ConnectionLimits, ConnectionPermit};
 use log::{debug, error, warn};
 use offload::OffloadRuntime;
 use parking_lot::RwLock;
 use pingora_error::{Error, ErrorType::*, OrErr, Result};
-use pingora_pool::{ConnectionMeta, ConnectionPool};
+use pingora_pool::{ConnectionMeta, ConnectionPool, GroupKey, PoolOptions};
+use resolver::{Resolver, DEFAULT_RESOLVER};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
 /// The options to configure a [TransportConnector]
@@ -53,6 +58,11 @@ pub struct ConnectorOptions {
     pub debug_ssl_keylog: bool,
     /// How many connections to keepalive
     pub keepalive_pool_size: usize,
+    /// The maximum number of connections to keepalive for each peer, if any
+    pub keepalive_pool_max_per_peer: Option<usize>,
+    /// The maximum time a connection can be kept alive without being used, regardless of the
+    /// `idle_timeout` of the peer
+    pub keepalive_max_idle_time: Option<Duration>,
     /// Optionally offload the connection establishment to dedicated thread pools
     ///
     /// TCP and TLS connection establishment can be CPU intensive. Sometimes such tasks can slow
@@ -66,6 +76,27 @@ pub struct ConnectorOptions {
     pub bind_to_v4: Vec<SocketAddr>,
     /// Bind to any of the given source IPv4 addresses
     pub bind_to_v6: Vec<SocketAddr>,
+    /// The [Resolver] to resolve the names of the peers that are defined by host name
+    ///
+    /// If `None`, a shared [Resolver] using the resolver of the operating system will be used.
+    pub resolver: Option<Arc<Resolver>>,
+    /// The maximum number of open connections, both in use and idle, to each peer (by
+    /// [Peer::reuse_hash()]). No limit if `None`. 0 is treated as 1.
+    pub max_connections_per_peer: Option<usize>,
+    /// How many connection attempts can wait for a peer that reached
+    /// `max_connections_per_peer`. The attempts beyond it fail with
+    /// [CONNECTION_QUEUE_FULL](limit::CONNECTION_QUEUE_FULL) right away.
+    pub connection_queue_size: usize,
+    /// How long a connection attempt can wait in the queue, after which it fails with
+    /// [CONNECTION_QUEUE_TIMEDOUT](limit::CONNECTION_QUEUE_TIMEDOUT). No timeout if `None`.
+    pub connection_queue_timeout: Option<Duration>,
+    /// How the streams to each peer are spread across h2 connections
+    pub h2_pool: H2PoolOptions,
+    /// How many TLS sessions, one per peer, to keep for resuming the connections to the peers.
+    /// 0 disables session resumption.
+    pub tls_session_cache_size: usize,
+    /// How long a cached TLS session can be used to resume a connection
+    pub tls_session_cache_ttl: Duration,
 }
 
 impl ConnectorOptions {
@@ -101,9 +132,28 @@ impl ConnectorOptions {
             cert_key_file: None, // TODO: use it
             debug_ssl_keylog: server_conf.upstream_debug_ssl_keylog,
             keepalive_pool_size: server_conf.upstream_keepalive_pool_size,
+            keepalive_pool_max_per_peer: None,
+            keepalive_max_idle_time: None,
             offload_threadpool,
             bind_to_v4,
             bind_to_v6,
+            resolver: None,
+            max_connections_per_peer: server_conf.upstream_max_connections_per_peer,
+            connection_queue_size: server_conf.upstream_connection_queue_size,
+            connection_queue_timeout: server_conf
+                .upstream_connection_queue_timeout_seconds
+                .map(Duration::from_secs),
+            h2_pool: H2PoolOptions::default(),
+            tls_session_cache_size: tls::DEFAULT_SESSION_CACHE_SIZE,
+            tls_session_cache_ttl: tls::DEFAULT_SESSION_CACHE_TTL,
+        }
+    }
+
+    /// The [PoolOptions] of the keepalive pools derived from these options
+    pub(crate) fn pool_options(&self) -> PoolOptions {
+        PoolOptions {
+            max_per_group: self.keepalive_pool_max_per_peer,
+            max_idle_time: self.keepalive_max_idle_time,
         }
     }
 
@@ -114,9 +164,18 @@ impl ConnectorOptions {
             cert_key_file: None,
             debug_ssl_keylog: false,
             keepalive_pool_size,
+            keepalive_pool_max_per_peer: None,
+            keepalive_max_idle_time: None,
             offload_threadpool: None,
             bind_to_v4: vec![],
             bind_to_v6: vec![],
+            resolver: None,
+            max_connections_per_peer: None,
+            connection_queue_size: 0,
+            connection_queue_timeout: None,
+            h2_pool: H2PoolOptions::default(),
+            tls_session_cache_size: tls::DEFAULT_SESSION_CACHE_SIZE,
+            tls_session_cache_ttl: tls::DEFAULT_SESSION_CACHE_TTL,
         }
     }
 }
@@ -128,6 +187,8 @@ pub struct TransportConnector {
     offload: Option<OffloadRuntime>,
     bind_to_v4: Vec<SocketAddr>,
     bind_to_v6: Vec<SocketAddr>,
+    resolver: Option<Arc<Resolver>>,
+    limits: Arc<ConnectionLimits>,
     preferred_http_version: PreferredHttpVersion,
 }
 
@@ -135,10 +196,22 @@ const DEFAULT_POOL_SIZE: usize = 128;
 
 impl TransportConnector {
     /// Create a new [TransportConnector] with the given [ConnectorOptions]
-    pub fn new(mut options: Option<ConnectorOptions>) -> Self {
+    pub fn new(options: Option<ConnectorOptions>) -> Self {
+        let limits = ConnectionLimits::from_options(options.as_ref());
+        Self::with_limits(options, Arc::new(limits))
+    }
+
+    // The connection limits can be shared by several connectors to the same peers
+    pub(crate) fn with_limits(
+        mut options: Option<ConnectorOptions>,
+        limits: Arc<ConnectionLimits>,
+    ) -> Self {
         let pool_size = options
             .as_ref()
             .map_or(DEFAULT_POOL_SIZE, |c| c.keepalive_pool_size);
+        let pool_options = options
+            .as_ref()
+            .map_or_else(PoolOptions::default, |o| o.pool_options());
         // Take the offloading setting there because this layer has implement offloading,
         // so no need for stacks at lower layer to offload again.
         let offload = options.as_mut().and_then(|o| o.offload_threadpool.take());
@@ -148,20 +221,36 @@ impl TransportConnector {
         let bind_to_v6 = options
             .as_ref()
             .map_or_else(Vec::new, |o| o.bind_to_v6.clone());
+        let resolver = options.as_ref().and_then(|o| o.resolver.clone());
         TransportConnector {
             tls_ctx: tls::Connector::new(options),
-            connection_pool: Arc::new(ConnectionPool::new(pool_size)),
+            connection_pool: Arc::new(ConnectionPool::new_with_options(pool_size, pool_options)),
             offload: offload.map(|v| OffloadRuntime::new(v.0, v.1)),
             bind_to_v4,
             bind_to_v6,
+            resolver,
+            limits,
             preferred_http_version: PreferredHttpVersion::new(),
         }
     }
 
     /// Connect to the given server [Peer]
     ///
-    /// No connection is reused.
+    /// No connection is reused. If the peer reached its connection limit, this function waits
+    /// for one of its connections to be closed.
     pub async fn new_stream<P: Peer + Send + Sync + 'static>(&self, peer: &P) -> Result<Stream> {
+        let no_reuse = || futures::future::ready(None::<Stream>);
+        match self.limits.acquire(peer.reuse_hash(), no_reuse).await? {
+            Acquired::Permit(permit) => self.new_stream_with_permit(peer, permit).await,
+            Acquired::Reused(s) => Ok(s), // not reachable
+        }
+    }
+
+    async fn new_stream_with_permit<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+        permit: ConnectionPermit,
+    ) -> Result<Stream> {
         let rt = self
             .offload
             .as_ref()
@@ -171,11 +260,32 @@ impl TransportConnector {
         let stream = if let Some(rt) = rt {
             let peer = peer.clone();
             let tls_ctx = self.tls_ctx.clone();
-            rt.spawn(async move { do_connect(&peer, bind_to, alpn_override, &tls_ctx.ctx).await })
+            let resolver = self.resolver.clone();
+            rt.spawn(async move {
+                let resolver = resolver.as_deref().unwrap_or(&DEFAULT_RESOLVER);
+                do_connect(
+                    &peer,
+                    bind_to,
+                    alpn_override,
+                    &tls_ctx,
+                    resolver,
+                    Some(permit),
+                )
                 .await
-                .or_err(InternalError, "offload runtime failure")??
+            })
+            .await
+            .or_err(InternalError, "offload runtime failure")??
         } else {
-            do_connect(peer, bind_to, alpn_override, &self.tls_ctx.ctx).await?
+            let resolver = self.resolver.as_deref().unwrap_or(&DEFAULT_RESOLVER);
+            do_connect(
+                peer,
+                bind_to,
+                alpn_override,
+                &self.tls_ctx,
+                resolver,
+                Some(permit),
+            )
+            .await?
         };
 
         Ok(stream)
@@ -183,7 +293,7 @@ impl TransportConnector {
 
     /// Try to find a reusable connection to the given server [Peer]
     pub async fn reused_stream<P: Peer + Send + Sync>(&self, peer: &P) -> Option<Stream> {
-        match self.connection_pool.get(&peer.reuse_hash()) {
+        match self.connection_pool.get(&peer.group_key()) {
             Some(s) => {
                 debug!("find reusable stream, trying to acquire it");
                 {
@@ -194,7 +304,9 @@ impl TransportConnector {
                         let mut stream = l.into_inner();
                         // test_reusable_stream: we assume server would never actively send data
                         // first on an idle stream.
-                        if peer.matches_fd(stream.id()) && test_reusable_stream(&mut stream) {
+                        if self.matches_fd(peer, stream.id()).await
+                            && test_reusable_stream(&mut stream)
+                        {
                             Some(stream)
                         } else {
                             None
@@ -213,6 +325,25 @@ impl TransportConnector {
         }
     }
 
+    // Whether the fd is connected to the peer. The addresses of a peer defined by name are only
+    // known to the resolver, which should have them cached from the connect.
+    async fn matches_fd<P: Peer + Send + Sync>(&self, peer: &P, fd: i32) -> bool {
+        let (Some(host), None) = (peer.hostname(), peer.get_proxy()) else {
+            return peer.matches_fd(fd);
+        };
+        let Some(port) = peer.address().as_inet().map(|addr| addr.port()) else {
+            return false;
+        };
+        let resolver = self.resolver.as_deref().unwrap_or(&DEFAULT_RESOLVER);
+        match resolver.resolve_socket_addrs(host, port).await {
+            Ok(addrs) => addrs[..].check_fd_match(fd),
+            Err(e) => {
+                debug!("Failed to resolve {host} to match the reusable stream: {e}");
+                false
+            }
+        }
+    }
+
     /// Return the [Stream] to the [TransportConnector] for connection reuse.
     ///
     /// Not all TCP/TLS connections can be reused. It is the caller's responsibility to make sure
@@ -223,13 +354,14 @@ impl TransportConnector {
     pub fn release_stream(
         &self,
         mut stream: Stream,
-        key: u64, // usually peer.reuse_hash()
+        key: GroupKey, // usually peer.group_key()
         idle_timeout: Option<std::time::Duration>,
     ) {
         if !test_reusable_stream(&mut stream) {
             return;
         }
         let id = stream.id();
+        let hash = key.hash();
         let meta = ConnectionMeta::new(key, id);
         debug!("Try to keepalive client session");
         let stream = Arc::new(Mutex::new(stream));
@@ -241,6 +373,8 @@ impl TransportConnector {
             pool.idle_poll(locked_stream, &meta, idle_timeout, notify_close, watch_use)
                 .await;
         });
+        // let the attempts waiting for the connection limit pick it up
+        self.limits.notify_released(hash);
     }
 
     /// Get a stream to the given server [Peer]
@@ -249,19 +383,39 @@ impl TransportConnector {
     /// will be made to the server.
     ///
     /// The returned boolean will indicate whether the stream is reused.
+    ///
+    /// If the peer reached its connection limit, this function waits in the queue of the peer
+    /// for either a connection to be released for reuse or to be closed.
     pub async fn get_stream<P: Peer + Send + Sync + 'static>(
         &self,
         peer: &P,
     ) -> Result<(Stream, bool)> {
-        let reused_stream = self.reused_stream(peer).await;
-        if let Some(s) = reused_stream {
-            Ok((s, true))
-        } else {
-            let s = self.new_stream(peer).await?;
-            Ok((s, false))
+        let reuse = move || self.reused_stream(peer);
+        match self.limits.acquire(peer.reuse_hash(), reuse).await? {
+            Acquired::Reused(s) => Ok((s, true)),
+            Acquired::Permit(permit) => {
+                let s = self.new_stream_with_permit(peer, permit).await?;
+                Ok((s, false))
+            }
         }
     }
 
+    /// The numbers of in use and idle connections to the given server [Peer]
+    pub fn connection_counts(&self, peer: &impl Peer) -> ConnectionCounts {
+        ConnectionCounts::new(
+            self.limits.open_connections(peer.reuse_hash()),
+            self.connection_pool.idle_count(&peer.group_key()),
+        )
+    }
+
+    /// The numbers of in use and idle connections to all the peers
+    pub fn total_connection_counts(&self) -> ConnectionCounts {
+        ConnectionCounts::new(
+            self.limits.total_open_connections(),
+            self.connection_pool.total_idle_count(),
+        )
+    }
+
     /// Tell the connector to always send h1 for ALPN for the given peer in the future.
     pub fn prefer_h1(&self, peer: &impl Peer) {
         self.preferred_http_version.add(peer, 1);
@@ -274,11 +428,13 @@ async fn do_connect<P: Peer + Send + Sync>(
     peer: &P,
     bind_to: Option<SocketAddr>,
     alpn_override: Option<ALPN>,
-    tls_ctx: &SslConnector,
+    tls_ctx: &tls::Connector,
+    resolver: &Resolver,
+    permit: Option<ConnectionPermit>,
 ) -> Result<Stream> {
     // Create the future that does the connections, but don't evaluate it until
     // we decide if we need a timeout or not
-    let connect_future = do_connect_inner(peer, bind_to, alpn_override, tls_ctx);
+    let connect_future = do_connect_inner(peer, bind_to, alpn_override, tls_ctx, resolver, permit);
 
     match peer.total_connection_timeout() {
         Some(t) => match pingora_timeout::timeout(t, connect_future).await {
@@ -297,9 +453,14 @@ async fn do_connect_inner<P: Peer + Send + Sync>(
     peer: &P,
     bind_to: Option<SocketAddr>,
     alpn_override: Option<ALPN>,
-    tls_ctx: &SslConnector,
+    tls_ctx: &tls::Connector,
+    resolver: &Resolver,
+    permit: Option<ConnectionPermit>,
 ) -> Result<Stream> {
-    let stream = l4_connect(peer, bind_to).await?;
+    let mut stream = l4_connect(peer, bind_to, resolver).await?;
+    if let Some(permit) = permit {
+        stream.set_connection_permit(permit);
+    }
     if peer.tls() {
         let tls_stream = tls::connect(stream, peer, alpn_override, tls_ctx).await?;
         Ok(Box::new(tls_stream))
@@ -368,7 +529,6 @@ mod tests {
     use pingora_error::ErrorType;
 
     use super::*;
-    use crate::tls::ssl::SslMethod;
     use crate::upstreams::peer::BasicPeer;
     use tokio::io::AsyncWriteExt;
     use tokio::net::UnixListener;
@@ -382,7 +542,7 @@ mod tests {
         let peer = BasicPeer::new("1.1.1.1:80");
         // make a new connection to 1.1.1.1
         let stream = connector.new_stream(&peer).await.unwrap();
-        connector.release_stream(stream, peer.reuse_hash(), None);
+        connector.release_stream(stream, peer.group_key(), None);
 
         let (_, reused) = connector.get_stream(&peer).await.unwrap();
         assert!(reused);
@@ -396,8 +556,74 @@ mod tests {
         peer.sni = "one.one.one.one".to_string();
         // make a new connection to https://1.1.1.1
         let stream = connector.new_stream(&peer).await.unwrap();
-        connector.release_stream(stream, peer.reuse_hash(), None);
+        connector.release_stream(stream, peer.group_key(), None);
+
+        let (_, reused) = connector.get_stream(&peer).await.unwrap();
+        assert!(reused);
+    }
+
+    #[tokio::test]
+    async fn test_tls_session_resumption() {
+        use tokio::io::AsyncReadExt;
+
+        async fn connect(connector: &TransportConnector, peer: &BasicPeer) -> bool {
+            let mut stream = connector.new_stream(peer).await.unwrap();
+            // TLS 1.3 session tickets are only received after the handshake
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: one.one.one.one\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            stream.get_ssl_digest().unwrap().session_reused
+        }
+
+        let connector = TransportConnector::new(None);
+        let mut peer = BasicPeer::new("1.1.1.1:443");
+        peer.sni = "one.one.one.one".to_string();
+        assert!(!connect(&connector, &peer).await);
+        assert_eq!(connector.tls_ctx.session_cache.as_ref().unwrap().len(), 1);
+        assert!(connect(&connector, &peer).await);
+
+        // opt out
+        peer.options.tls_session_reuse = false;
+        assert!(!connect(&connector, &peer).await);
+
+        // a different SNI doesn't share the session
+        let mut peer = BasicPeer::new("1.1.1.1:443");
+        peer.sni = "cloudflare-dns.com".to_string();
+        assert!(!connect(&connector, &peer).await);
+    }
+
+    #[tokio::test]
+    async fn test_reuse_hostname() {
+        use crate::upstreams::peer::HttpPeer;
+        use async_trait::async_trait;
+        use resolver::{Resolution, Resolve, ResolverOptions};
+
+        struct MockResolver;
+
+        #[async_trait]
+        impl Resolve for MockResolver {
+            async fn resolve(&self, _host: &str) -> Result<Resolution> {
+                Ok(Resolution::Found(vec!["127.0.0.1".parse().unwrap()], None))
+            }
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut options = ConnectorOptions::new(1);
+        options.resolver = Some(Arc::new(Resolver::new(
+            Arc::new(MockResolver),
+            ResolverOptions::default(),
+        )));
+        let connector = TransportConnector::new(Some(options));
+        let peer = HttpPeer::new_from_hostname("local.example.com", port, false, "".to_string());
+        let stream = connector.new_stream(&peer).await.unwrap();
+        connector.release_stream(stream, peer.group_key(), None);
 
+        // matched against the resolved address
         let (_, reused) = connector.get_stream(&peer).await.unwrap();
         assert!(reused);
     }
@@ -428,7 +654,7 @@ mod tests {
         let mut buf = [0; 9];
         let _ = stream.read(&mut buf).await.unwrap();
         assert_eq!(&buf, b"it works!");
-        connector.release_stream(stream, peer.reuse_hash(), None);
+        connector.release_stream(stream, peer.group_key(), None);
 
         let (_, reused) = connector.get_stream(&peer).await.unwrap();
         assert!(reused);
@@ -475,8 +701,8 @@ mod tests {
     /// This assumes that the connection will fail to on the peer and returns
     /// the decomposed error type and message
     async fn get_do_connect_failure_with_peer(peer: &BasicPeer) -> (ErrorType, String) {
-        let ssl_connector = SslConnector::builder(SslMethod::tls()).unwrap().build();
-        let stream = do_connect(peer, None, None, &ssl_connector).await;
+        let tls_ctx = tls::Connector::new(None);
+        let stream = do_connect(peer, None, None, &tls_ctx, &DEFAULT_RESOLVER, None).await;
         match stream {
             Ok(_) => panic!("should throw an error"),
             Err(e) => (
@@ -0,0 +1,326 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Asynchronous name resolution for the peers that are defined by hostname
+//!
+//! The [Resolver] caches the results of a pluggable [Resolve] implementation, including the
+//! negative ones, according to their TTLs.
+
+use async_trait::async_trait;
+use log::debug;
+use once_cell::sync::Lazy;
+use pingora_error::{Error, ErrorTrait, ErrorType, OrErr, Result};
+use pingora_memory_cache::{CacheStatus, Lookup, RTCache};
+use std::ffi::CStr;
+use std::net::{IpAddr, SocketAddr as InetSocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The name cannot be resolved, for example, the DNS server is not reachable.
+pub const RESOLUTION_ERROR: ErrorType = ErrorType::new("ResolutionError");
+/// The name has no address.
+pub const NAME_NOT_FOUND: ErrorType = ErrorType::new("NameNotFound");
+/// The name has no address, according to a cached previous resolution.
+pub const NEGATIVE_CACHE_HIT: ErrorType = ErrorType::new("NegativeCacheHit");
+
+/// The result of a name resolution
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// The addresses of the name and how long they can be cached.
+    Found(Vec<IpAddr>, Option<Duration>),
+    /// The name doesn't exist or has no address, and how long this can be cached.
+    NotFound(Option<Duration>),
+}
+
+/// The interface of a name resolver
+#[async_trait]
+pub trait Resolve: Send + Sync {
+    /// Resolve the given host name to its IP addresses.
+    ///
+    /// [Resolution::NotFound] is cached while errors are not, so errors should only be returned
+    /// when the answer is not known, e.g., the DNS server times out.
+    async fn resolve(&self, host: &str) -> Result<Resolution>;
+}
+
+/// A [Resolve] implementation using the resolver of the operating system (`getaddrinfo()`).
+///
+/// The blocking resolution runs on the blocking thread pool of tokio. No TTL is returned so the
+/// default TTL of the [Resolver] applies.
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolve for SystemResolver {
+    async fn resolve(&self, host: &str) -> Result<Resolution> {
+        let addrs = match tokio::net::lookup_host((host, 0)).await {
+            Ok(addrs) => addrs,
+            Err(e) if is_name_not_found(&e) => return Ok(Resolution::NotFound(None)),
+            Err(e) => {
+                return Err(e).or_err_with(RESOLUTION_ERROR, || format!("while resolving {host}"))
+            }
+        };
+        let ips: Vec<IpAddr> = addrs.map(|addr| addr.ip()).collect();
+        if ips.is_empty() {
+            Ok(Resolution::NotFound(None))
+        } else {
+            Ok(Resolution::Found(ips, None))
+        }
+    }
+}
+
+// Whether the lookup_host() error means that the name doesn't exist or has no address.
+// std only reports the getaddrinfo() failures other than EAI_SYSTEM by their gai_strerror() text.
+fn is_name_not_found(e: &std::io::Error) -> bool {
+    let not_found = [
+        libc::EAI_NONAME,
+        #[cfg(target_os = "linux")]
+        libc::EAI_NODATA,
+    ];
+    let msg = e.to_string();
+    not_found.iter().any(|code| {
+        // safe: gai_strerror() returns a pointer to a static string
+        let reason = unsafe { CStr::from_ptr(libc::gai_strerror(*code)) };
+        msg.contains(reason.to_string_lossy().as_ref())
+    })
+}
+
+/// The options of a [Resolver]
+#[derive(Debug, Clone)]
+pub struct ResolverOptions {
+    /// How many names to cache.
+    pub cache_size: usize,
+    /// The TTL to use when the [Resolve] implementation doesn't provide one.
+    pub default_ttl: Duration,
+    /// The TTL of [Resolution::NotFound] when the [Resolve] implementation doesn't provide one.
+    pub negative_ttl: Duration,
+    /// The TTLs longer than this are capped to it.
+    pub max_ttl: Duration,
+}
+
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        ResolverOptions {
+            cache_size: 1024,
+            default_ttl: Duration::from_secs(30),
+            negative_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum CachedResolution {
+    Found(Arc<[IpAddr]>),
+    NotFound,
+}
+
+struct ResolveCtx {
+    resolve: Arc<dyn Resolve>,
+    options: ResolverOptions,
+}
+
+struct ResolveLookup;
+
+#[async_trait]
+impl Lookup<String, CachedResolution, ResolveCtx> for ResolveLookup {
+    async fn lookup(
+        host: &String,
+        extra: Option<&ResolveCtx>,
+    ) -> Result<(CachedResolution, Option<Duration>), Box<dyn ErrorTrait + Send + Sync>> {
+        // safe to unwrap: Resolver always passes its ctx
+        let ctx = extra.unwrap();
+        let options = &ctx.options;
+        let (resolution, ttl) = match ctx.resolve.resolve(host).await? {
+            Resolution::Found(ips, ttl) if !ips.is_empty() => (
+                CachedResolution::Found(ips.into()),
+                ttl.unwrap_or(options.default_ttl),
+            ),
+            Resolution::Found(_, ttl) | Resolution::NotFound(ttl) => (
+                CachedResolution::NotFound,
+                ttl.unwrap_or(options.negative_ttl),
+            ),
+        };
+        debug!("resolved {host}: {resolution:?}, ttl {ttl:?}");
+        Ok((resolution, Some(ttl.min(options.max_ttl))))
+    }
+}
+
+/// A name resolver with a TTL aware cache
+///
+/// Concurrent resolutions of the same name are coalesced into one call to the [Resolve]
+/// implementation.
+pub struct Resolver {
+    cache: RTCache<String, CachedResolution, ResolveLookup, ResolveCtx>,
+    ctx: ResolveCtx,
+}
+
+impl Resolver {
+    /// Create a new [Resolver] that resolves names through the given [Resolve] implementation.
+    pub fn new(resolve: Arc<dyn Resolve>, options: ResolverOptions) -> Self {
+        Resolver {
+            cache: RTCache::new(options.cache_size, None, None),
+            ctx: ResolveCtx { resolve, options },
+        }
+    }
+
+    /// Create a new [Resolver] that resolves names through the [SystemResolver].
+    pub fn system() -> Self {
+        Self::new(Arc::new(SystemResolver), ResolverOptions::default())
+    }
+
+    /// Resolve the given host name to its IP addresses.
+    ///
+    /// The error type is [RESOLUTION_ERROR] if the name cannot be resolved, [NAME_NOT_FOUND] if the
+    /// name has no address and [NEGATIVE_CACHE_HIT] if that is already known from the cache.
+    pub async fn resolve(&self, host: &str) -> Result<Arc<[IpAddr]>> {
+        // an IP address doesn't need to be resolved
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Arc::new([ip]) as Arc<[IpAddr]>);
+        }
+        let (res, status) = self
+            .cache
+            .get(&host.to_string(), None, Some(&self.ctx))
+            .await;
+        match res {
+            Ok(CachedResolution::Found(ips)) => Ok(ips),
+            Ok(CachedResolution::NotFound) => {
+                let etype = if status == CacheStatus::Hit {
+                    NEGATIVE_CACHE_HIT
+                } else {
+                    NAME_NOT_FOUND
+                };
+                Error::e_explain(etype, format!("no address found for {host}"))
+            }
+            Err(e) => Error::e_because(RESOLUTION_ERROR, format!("while resolving {host}"), e),
+        }
+    }
+
+    /// Resolve the given host name to the socket addresses with the given port.
+    pub async fn resolve_socket_addrs(&self, host: &str, port: u16) -> Result<Vec<InetSocketAddr>> {
+        let ips = self.resolve(host).await?;
+        Ok(ips
+            .iter()
+            .map(|ip| InetSocketAddr::new(*ip, port))
+            .collect())
+    }
+}
+
+/// The [Resolver] that is used when no other one is specified.
+pub(crate) static DEFAULT_RESOLVER: Lazy<Resolver> = Lazy::new(Resolver::system);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct MockResolver {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Resolve for MockResolver {
+        async fn resolve(&self, host: &str) -> Result<Resolution> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match host {
+                "example.com" => Ok(Resolution::Found(
+                    vec![
+                        "2606:4700::1111".parse().unwrap(),
+                        "1.1.1.1".parse().unwrap(),
+                    ],
+                    Some(Duration::from_secs(60)),
+                )),
+                "short.example.com" => Ok(Resolution::Found(
+                    vec!["1.0.0.1".parse().unwrap()],
+                    Some(Duration::from_millis(10)),
+                )),
+                "nx.example.com" => Ok(Resolution::NotFound(None)),
+                _ => Error::e_explain(ErrorType::ConnectTimedout, "DNS server timed out"),
+            }
+        }
+    }
+
+    fn mock_resolver() -> (Resolver, Arc<MockResolver>) {
+        let mock = Arc::new(MockResolver {
+            calls: AtomicUsize::new(0),
+        });
+        (
+            Resolver::new(mock.clone(), ResolverOptions::default()),
+            mock,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_resolve_cached() {
+        let (resolver, mock) = mock_resolver();
+        let addrs = resolver
+            .resolve_socket_addrs("example.com", 443)
+            .await
+            .unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "[2606:4700::1111]:443".parse().unwrap(),
+                "1.1.1.1:443".parse().unwrap()
+            ]
+        );
+        resolver.resolve("example.com").await.unwrap();
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 1);
+
+        // IPs are not resolved
+        let ips = resolver.resolve("127.0.0.1").await.unwrap();
+        assert_eq!(&ips[..], &["127.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ttl() {
+        let (resolver, mock) = mock_resolver();
+        resolver.resolve("short.example.com").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        resolver.resolve("short.example.com").await.unwrap();
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_negative_cache() {
+        let (resolver, mock) = mock_resolver();
+        let e = resolver.resolve("nx.example.com").await.unwrap_err();
+        assert_eq!(e.etype(), &NAME_NOT_FOUND);
+        let e = resolver.resolve("nx.example.com").await.unwrap_err();
+        assert_eq!(e.etype(), &NEGATIVE_CACHE_HIT);
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_system_resolver_not_found() {
+        // .invalid is guaranteed to never resolve, see RFC 6761
+        let res = SystemResolver.resolve("pingora.invalid").await.unwrap();
+        assert_eq!(res, Resolution::NotFound(None));
+
+        let resolver = Resolver::system();
+        let e = resolver.resolve("pingora.invalid").await.unwrap_err();
+        assert_eq!(e.etype(), &NAME_NOT_FOUND);
+        let e = resolver.resolve("pingora.invalid").await.unwrap_err();
+        assert_eq!(e.etype(), &NEGATIVE_CACHE_HIT);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_error_not_cached() {
+        let (resolver, mock) = mock_resolver();
+        let e = resolver.resolve("timeout.example.com").await.unwrap_err();
+        assert_eq!(e.etype(), &RESOLUTION_ERROR);
+        let e = resolver.resolve("timeout.example.com").await.unwrap_err();
+        assert_eq!(e.etype(), &RESOLUTION_ERROR);
+        assert_eq!(mock.calls.load(Ordering::Relaxed), 2);
+    }
+}
@@ -12,14 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use ahash::AHasher;
 use log::debug;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use pingora_error::{Error, ErrorType::*, OrErr, Result};
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
 use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
 
 use super::ConnectorOptions;
 use crate::protocols::ssl::client::handshake;
 use crate::protocols::ssl::SslStream;
 use crate::protocols::IO;
+use crate::tls::ex_data::Index;
 use crate::tls::ext::{
     add_host, clear_error_stack, ssl_add_chain_cert, ssl_set_groups_list,
     ssl_set_renegotiate_mode_freely, ssl_set_verify_cert_store, ssl_use_certificate,
@@ -27,7 +35,10 @@ use crate::tls::ext::{
 };
 #[cfg(feature = "boringssl")]
 use crate::tls::ssl::SslCurve;
-use crate::tls::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode, SslVersion};
+use crate::tls::ssl::{
+    Ssl, SslConnector, SslFiletype, SslMethod, SslSession, SslSessionCacheMode, SslVerifyMode,
+    SslVersion,
+};
 use crate::tls::x509::store::X509StoreBuilder;
 use crate::upstreams::peer::{Peer, ALPN};
 
@@ -76,6 +87,9 @@ const BORINGSSL_CURVE_LIST: &[SslCurve] = &[
     SslCurve::SECP521R1,
 ];
 
+pub(crate) const DEFAULT_SESSION_CACHE_SIZE: usize = 1024;
+pub(crate) const DEFAULT_SESSION_CACHE_TTL: Duration = Duration::from_secs(300);
+
 static INIT_CA_ENV: Once = Once::new();
 fn init_ssl_cert_env_vars() {
     // this sets env vars to pick up the root certs
@@ -83,9 +97,64 @@ fn init_ssl_cert_env_vars() {
     INIT_CA_ENV.call_once(openssl_probe::init_ssl_cert_env_vars);
 }
 
+// The key of the session cache of the peer that a TLS connection is made to
+static SESSION_KEY_INDEX: Lazy<Index<Ssl, u64>> = Lazy::new(|| Ssl::new_ex_index().unwrap());
+
+/// A cache of the TLS sessions of the peers to resume the connections to them
+///
+/// Only the latest session of each peer is kept.
+pub(crate) struct SessionCache {
+    sessions: Mutex<LruCache<u64, (SslSession, Instant)>>,
+    ttl: Duration,
+}
+
+impl SessionCache {
+    pub fn new(size: NonZeroUsize, ttl: Duration) -> Self {
+        SessionCache {
+            sessions: Mutex::new(LruCache::new(size)),
+            ttl,
+        }
+    }
+
+    fn get(&self, key: u64) -> Option<SslSession> {
+        let mut sessions = self.sessions.lock();
+        let (session, created) = sessions.get(&key)?;
+        if created.elapsed() < self.ttl {
+            return Some(session.clone());
+        }
+        sessions.pop(&key);
+        None
+    }
+
+    fn put(&self, key: u64, session: SslSession) {
+        self.sessions.lock().put(key, (session, Instant::now()));
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.sessions.lock().len()
+    }
+}
+
+// Sessions are only resumed with the same peer under the same verification settings, a session
+// can't be used to skip the verification that the new connection would have done.
+fn session_key<P: Peer>(peer: &P) -> u64 {
+    let mut hasher = AHasher::default();
+    peer.address().hash(&mut hasher);
+    peer.sni().hash(&mut hasher);
+    peer.verify_cert().hash(&mut hasher);
+    peer.verify_hostname().hash(&mut hasher);
+    peer.alternative_cn().hash(&mut hasher);
+    // the CA list is set once per peer config, so its address identifies it
+    peer.get_ca().map(Arc::as_ptr).hash(&mut hasher);
+    peer.get_client_cert_key().hash(&mut hasher);
+    hasher.finish()
+}
+
 #[derive(Clone)]
 pub struct Connector {
     pub(crate) ctx: Arc<SslConnector>, // Arc to support clone
+    pub(crate) session_cache: Option<Arc<SessionCache>>,
 }
 
 impl Connector {
@@ -141,8 +210,29 @@ impl Connector {
             builder.set_default_verify_paths().unwrap();
         }
 
+        let (cache_size, cache_ttl) = options.as_ref().map_or(
+            (DEFAULT_SESSION_CACHE_SIZE, DEFAULT_SESSION_CACHE_TTL),
+            |o| (o.tls_session_cache_size, o.tls_session_cache_ttl),
+        );
+        let session_cache = NonZeroUsize::new(cache_size).map(|size| {
+            let cache = Arc::new(SessionCache::new(size, cache_ttl));
+            let cache_clone = cache.clone();
+            // TLS 1.3 tickets arrive after the handshake, so they are collected by the callback
+            // instead of right after connect()
+            builder.set_session_cache_mode(
+                SslSessionCacheMode::CLIENT | SslSessionCacheMode::NO_INTERNAL_STORE,
+            );
+            builder.set_new_session_callback(move |ssl, session| {
+                if let Some(key) = ssl.ex_data(*SESSION_KEY_INDEX) {
+                    cache_clone.put(*key, session);
+                }
+            });
+            cache
+        });
+
         Connector {
             ctx: Arc::new(builder.build()),
+            session_cache,
         }
     }
 }
@@ -178,13 +268,13 @@ pub(crate) async fn connect<T, P>(
     stream: T,
     peer: &P,
     alpn_override: Option<ALPN>,
-    tls_ctx: &SslConnector,
+    tls_ctx: &Connector,
 ) -> Result<SslStream<T>>
 where
     T: IO,
     P: Peer + Send + Sync,
 {
-    let mut ssl_conf = tls_ctx.configure().unwrap();
+    let mut ssl_conf = tls_ctx.ctx.configure().unwrap();
 
     ssl_set_renegotiate_mode_freely(&mut ssl_conf);
 
@@ -269,6 +359,20 @@ where
         ssl_conf.set_alpn_protos(alpn.to_wire_preference()).unwrap();
     }
 
+    let session_reuse = peer
+        .get_peer_options()
+        .map_or(true, |o| o.tls_session_reuse);
+    if let Some(cache) = tls_ctx.session_cache.as_ref().filter(|_| session_reuse) {
+        let key = session_key(peer);
+        if let Some(session) = cache.get(key) {
+            // SAFETY: the session is created by the same SSL_CTX
+            unsafe { ssl_conf.set_session(&session) }
+                .or_err(InternalError, "failed to set TLS session")?;
+        }
+        // ask the new session callback to cache the sessions of this connection
+        ssl_conf.set_ex_data(*SESSION_KEY_INDEX, key);
+    }
+
     clear_error_stack();
     let connect_future = handshake(ssl_conf, peer.sni(), stream);
 
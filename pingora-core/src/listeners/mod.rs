@@ -15,6 +15,9 @@
 //! The listening endpoints (TCP and TLS) and their configurations.
 
 mod l4;
+#[cfg(not(feature = "boringssl"))]
+mod ocsp;
+mod ticket_keys;
 mod tls;
 
 use crate::protocols::Stream;
@@ -28,7 +31,10 @@ use tls::Acceptor;
 
 pub use crate::protocols::ssl::server::TlsAccept;
 pub use l4::{ServerAddress, TcpSocketOptions};
-pub use tls::{TlsSettings, ALPN};
+#[cfg(not(feature = "boringssl"))]
+pub use ocsp::{OcspFetcher, OcspFile, OcspStapler, OCSP_ERR};
+pub use ticket_keys::{TicketKey, TicketKeys};
+pub use tls::{ClientCertMode, ClientCertSettings, TlsSettings, ALPN};
 
 struct TransportStackBuilder {
     l4: ServerAddress,
@@ -58,7 +64,11 @@ impl TransportStack {
     }
 
     pub async fn listen(&mut self) -> Result<()> {
-        self.l4.listen(self.upgrade_listeners.take()).await
+        let upgrade_listeners = self.upgrade_listeners.take();
+        if let (Some(tls), Some(fds)) = (self.tls.as_ref(), upgrade_listeners.as_ref()) {
+            tls.inherit_ticket_keys(self.l4.as_str(), &mut *fds.lock().await)?;
+        }
+        self.l4.listen(upgrade_listeners).await
     }
 
     pub async fn accept(&mut self) -> Result<UninitializedStream> {
@@ -168,6 +178,14 @@ impl Listeners {
         self.stacks.push(TransportStackBuilder { l4, tls })
     }
 
+    /// The addresses of the endpoints, TLS or not.
+    pub fn addresses(&self) -> Vec<String> {
+        self.stacks
+            .iter()
+            .map(|s| s.l4.as_ref().to_string())
+            .collect()
+    }
+
     pub(crate) fn build(&mut self, upgrade_listeners: Option<ListenFds>) -> Vec<TransportStack> {
         self.stacks
             .iter_mut()
@@ -0,0 +1,354 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OCSP stapling of the TLS endpoints
+
+use async_trait::async_trait;
+use log::{error, info};
+use parking_lot::RwLock;
+use pingora_error::{Error, ErrorType, OkOrErr, OrErr, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::tls::TLS_CONF_ERR;
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+use crate::tls::asn1::{Asn1GeneralizedTimeRef, Asn1Time};
+use crate::tls::ext::asn1_generalized_time_as_time;
+use crate::tls::hash::MessageDigest;
+use crate::tls::ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspResponse, OcspResponseStatus};
+use crate::tls::ssl::SslRef;
+use crate::tls::stack::Stack;
+use crate::tls::x509::store::X509StoreBuilder;
+use crate::tls::x509::verify::X509VerifyFlags;
+use crate::tls::x509::{X509Ref, X509};
+
+/// The error type of the OCSP responses that cannot be stapled
+pub const OCSP_ERR: ErrorType = ErrorType::Custom("OCSPError");
+
+// the clock skew allowed when checking the thisUpdate and nextUpdate of the responses
+const MAX_CLOCK_SKEW: u32 = 300;
+
+/// The source of the OCSP responses to staple
+#[async_trait]
+pub trait OcspFetcher: Send + Sync {
+    /// Return the DER encoded OCSP response of `cert`, which is issued by `issuer`.
+    async fn fetch(&self, cert: &X509Ref, issuer: &X509Ref) -> Result<Vec<u8>>;
+}
+
+/// An [OcspFetcher] that reads the DER encoded response from a file, which is kept up to date
+/// by other means such as a cron job.
+pub struct OcspFile {
+    path: PathBuf,
+}
+
+impl OcspFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        OcspFile {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl OcspFetcher for OcspFile {
+    async fn fetch(&self, _cert: &X509Ref, _issuer: &X509Ref) -> Result<Vec<u8>> {
+        tokio::fs::read(&self.path)
+            .await
+            .or_err_with(TLS_CONF_ERR, || {
+                format!("fail to read OCSP response file {}", self.path.display())
+            })
+    }
+}
+
+struct StapledResponse {
+    der: Arc<[u8]>,
+    next_update: Option<SystemTime>,
+}
+
+/// The OCSP response stapled for one certificate
+///
+/// The response is only stapled after it is validated: it has to be signed by the issuer of the
+/// certificate or a responder the issuer delegates to, report the certificate as good, and be
+/// within its validity period. When running as a [BackgroundService], the response is refreshed
+/// before its `nextUpdate`. A response that fails the validation is discarded, and the previous
+/// one is stapled until it expires.
+pub struct OcspStapler {
+    cert: X509,
+    issuer: X509,
+    cert_digest: Vec<u8>,
+    fetcher: Box<dyn OcspFetcher>,
+    response: RwLock<Option<StapledResponse>>,
+    /// The longest time between two refreshes. Default 1 hour.
+    pub refresh_interval: Duration,
+    /// How soon to retry after a failed refresh. Default 1 minute.
+    pub retry_interval: Duration,
+}
+
+impl OcspStapler {
+    /// Create an [OcspStapler] for `cert` issued by `issuer`, of which the responses come from
+    /// the given [OcspFetcher].
+    pub fn new(cert: X509, issuer: X509, fetcher: Box<dyn OcspFetcher>) -> Result<Self> {
+        let cert_digest = cert_digest(&cert)?;
+        Ok(OcspStapler {
+            cert,
+            issuer,
+            cert_digest,
+            fetcher,
+            response: RwLock::new(None),
+            refresh_interval: Duration::from_secs(3600),
+            retry_interval: Duration::from_secs(60),
+        })
+    }
+
+    /// Create an [OcspStapler] from the PEM certificate chain file of a [super::TlsSettings],
+    /// which starts with the certificate followed by its issuer.
+    pub fn from_chain_file(cert_path: &str, fetcher: Box<dyn OcspFetcher>) -> Result<Self> {
+        let data = std::fs::read(cert_path).or_err_with(TLS_CONF_ERR, || {
+            format!("fail to read cert file {cert_path}")
+        })?;
+        let mut chain = X509::stack_from_pem(&data)
+            .or_err_with(TLS_CONF_ERR, || {
+                format!("fail to parse cert file {cert_path}")
+            })?
+            .into_iter();
+        let (Some(cert), Some(issuer)) = (chain.next(), chain.next()) else {
+            return Error::e_explain(
+                TLS_CONF_ERR,
+                format!("{cert_path} should contain the certificate and its issuer"),
+            );
+        };
+        Self::new(cert, issuer, fetcher)
+    }
+
+    /// The current response, if there is one that has not expired.
+    pub fn response(&self) -> Option<Arc<[u8]>> {
+        let response = self.response.read();
+        let response = response.as_ref()?;
+        match response.next_update {
+            Some(next_update) if next_update <= SystemTime::now() => None,
+            _ => Some(response.der.clone()),
+        }
+    }
+
+    /// Fetch, validate and update the response now.
+    ///
+    /// Return how long to wait before the next refresh.
+    pub async fn refresh(&self) -> Result<Duration> {
+        let der = self.fetcher.fetch(&self.cert, &self.issuer).await?;
+        let next_update = validate_response(&der, &self.cert, &self.issuer)?;
+        *self.response.write() = Some(StapledResponse {
+            der: der.into(),
+            next_update,
+        });
+        Ok(self.refresh_delay(next_update))
+    }
+
+    // refresh halfway to the nextUpdate so that there is time to retry
+    fn refresh_delay(&self, next_update: Option<SystemTime>) -> Duration {
+        let Some(next_update) = next_update else {
+            return self.refresh_interval;
+        };
+        let remaining = next_update
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        (remaining / 2)
+            .min(self.refresh_interval)
+            .max(self.retry_interval)
+    }
+
+    // whether this stapler is for the certificate the handshake is using
+    fn matches(&self, cert: &X509Ref) -> bool {
+        cert_digest(cert).map_or(false, |digest| digest == self.cert_digest)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for OcspStapler {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            let delay = match self.refresh().await {
+                Ok(delay) => {
+                    info!("OCSP response refreshed, next refresh in {delay:?}");
+                    delay
+                }
+                Err(e) => {
+                    error!("failed to refresh OCSP response: {e}");
+                    self.retry_interval
+                }
+            };
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+}
+
+// staple the response of the certificate in use, called by the status callback of the acceptor
+pub(crate) fn staple(ssl: &mut SslRef, staplers: &[Arc<OcspStapler>]) -> bool {
+    let response = ssl.certificate().and_then(|cert| {
+        staplers
+            .iter()
+            .find(|stapler| stapler.matches(cert))
+            .and_then(|stapler| stapler.response())
+    });
+    match response {
+        Some(response) => ssl.set_ocsp_status(&response).is_ok(),
+        None => false,
+    }
+}
+
+fn cert_digest(cert: &X509Ref) -> Result<Vec<u8>> {
+    cert.digest(MessageDigest::sha256())
+        .map(|digest| digest.to_vec())
+        .or_err(TLS_CONF_ERR, "fail to compute certificate digest")
+}
+
+// validate the response and return its nextUpdate
+fn validate_response(der: &[u8], cert: &X509Ref, issuer: &X509Ref) -> Result<Option<SystemTime>> {
+    let response = OcspResponse::from_der(der).or_err(OCSP_ERR, "invalid OCSP response")?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Error::e_explain(
+            OCSP_ERR,
+            format!("OCSP response status {}", response.status().as_raw()),
+        );
+    }
+    let basic = response
+        .basic()
+        .or_err(OCSP_ERR, "invalid basic OCSP response")?;
+
+    // the responder is either the issuer or delegated by the issuer, which is not a root CA
+    let mut store = X509StoreBuilder::new().or_err(OCSP_ERR, "fail to create X509 store")?;
+    store
+        .add_cert(issuer.to_owned())
+        .or_err(OCSP_ERR, "fail to add issuer to X509 store")?;
+    store
+        .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+        .or_err(OCSP_ERR, "fail to set X509 store flags")?;
+    let store = store.build();
+    let mut certs = Stack::new().or_err(OCSP_ERR, "fail to create cert stack")?;
+    certs
+        .push(issuer.to_owned())
+        .or_err(OCSP_ERR, "fail to add issuer to cert stack")?;
+    basic
+        .verify(&certs, &store, OcspFlag::TRUST_OTHER)
+        .or_err(OCSP_ERR, "fail to verify OCSP response signature")?;
+
+    let cert_id = OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)
+        .or_err(OCSP_ERR, "fail to create OCSP cert id")?;
+    let status = basic
+        .find_status(&cert_id)
+        .or_err(OCSP_ERR, "OCSP response is not for the certificate")?;
+    if status.status != OcspCertStatus::GOOD {
+        return Error::e_explain(
+            OCSP_ERR,
+            format!("OCSP cert status {}", status.status.as_raw()),
+        );
+    }
+    status
+        .check_validity(MAX_CLOCK_SKEW, None)
+        .or_err(OCSP_ERR, "OCSP response is not valid now")?;
+    status.next_update().map(to_system_time).transpose()
+}
+
+fn to_system_time(time: &Asn1GeneralizedTimeRef) -> Result<SystemTime> {
+    let diff = Asn1Time::from_unix(0)
+        .and_then(|epoch| epoch.diff(asn1_generalized_time_as_time(time)))
+        .or_err_with(OCSP_ERR, || format!("invalid OCSP time {time}"))?;
+    let secs = diff.days as i64 * 86400 + diff.secs as i64;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::asn1::Asn1GeneralizedTime;
+    use parking_lot::Mutex;
+
+    const KEYS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/keys");
+
+    // a responder that answers whatever it is told to
+    struct StubResponder(Arc<Mutex<Vec<u8>>>);
+
+    #[async_trait]
+    impl OcspFetcher for StubResponder {
+        async fn fetch(&self, _cert: &X509Ref, _issuer: &X509Ref) -> Result<Vec<u8>> {
+            Ok(self.0.lock().clone())
+        }
+    }
+
+    fn read(file: &str) -> Vec<u8> {
+        std::fs::read(format!("{KEYS}/{file}")).unwrap()
+    }
+
+    fn load_cert(file: &str) -> X509 {
+        X509::from_pem(&read(file)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let good = read("ocsp/resp.der");
+        let response = Arc::new(Mutex::new(good.clone()));
+        let chain = format!("{KEYS}/ocsp/chain.crt");
+        let stapler =
+            OcspStapler::from_chain_file(&chain, Box::new(StubResponder(response.clone())))
+                .unwrap();
+        assert!(stapler.response().is_none());
+
+        let delay = stapler.refresh().await.unwrap();
+        assert_eq!(delay, stapler.refresh_interval);
+        assert_eq!(&stapler.response().unwrap()[..], &good[..]);
+
+        // the revoked and the garbage responses are rejected, the good one stays
+        *response.lock() = read("ocsp/revoked.der");
+        assert!(stapler.refresh().await.is_err());
+        *response.lock() = b"not an OCSP response".to_vec();
+        assert!(stapler.refresh().await.is_err());
+        assert_eq!(&stapler.response().unwrap()[..], &good[..]);
+    }
+
+    #[test]
+    fn test_to_system_time() {
+        let time = Asn1GeneralizedTime::from_str("20240101000000Z").unwrap();
+        assert_eq!(
+            to_system_time(&time).unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1704067200)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_cert() {
+        // the response is for the certificate in ocsp/leaf.crt
+        let stapler = OcspStapler::new(
+            load_cert("server.crt"),
+            load_cert("ocsp/ca.crt"),
+            Box::new(OcspFile::new(format!("{KEYS}/ocsp/resp.der"))),
+        )
+        .unwrap();
+        assert!(stapler.refresh().await.is_err());
+        assert!(stapler.response().is_none());
+
+        let stapler = OcspStapler::new(
+            load_cert("ocsp/leaf.crt"),
+            load_cert("ocsp/ca.crt"),
+            Box::new(OcspFile::new(format!("{KEYS}/ocsp/resp.der"))),
+        )
+        .unwrap();
+        stapler.refresh().await.unwrap();
+        assert!(stapler.matches(&load_cert("ocsp/leaf.crt")));
+        assert!(!stapler.matches(&load_cert("server.crt")));
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session ticket keys of the TLS endpoints
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use parking_lot::{Mutex, RwLock};
+use pingora_error::{Error, OrErr, Result};
+use rand::RngCore;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::tls::TLS_CONF_ERR;
+use crate::server::transfer_fd::Fds;
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+
+pub use crate::tls::ext::TicketKey;
+
+// name + HMAC secret + AES key, the same as the 80 byte keys of nginx' `ssl_session_ticket_key`
+const KEY_SIZE: usize = 80;
+
+/// The session ticket keys shared by TLS endpoints
+///
+/// The first key encrypts new tickets and all of the keys decrypt. When running as a
+/// [BackgroundService], the keys are rotated every `rotation_interval`: they are reloaded from the
+/// key file if there is one, otherwise a new key is generated. The previous keys are retained for
+/// a while to decrypt the tickets issued before the rotation.
+///
+/// The keys are passed to the new process during graceful upgrade so that the tickets issued by
+/// the old process can still be resumed.
+pub struct TicketKeys {
+    keys: RwLock<Arc<[TicketKey]>>,
+    path: Option<PathBuf>,
+    /// How many previous keys to keep for decryption after a rotation. Default 2.
+    pub retained_keys: usize,
+    /// How often to rotate the keys. Default 1 hour.
+    pub rotation_interval: Duration,
+    // the file shared with the new process during graceful upgrade, updated on rotation
+    upgrade_file: Mutex<Option<File>>,
+}
+
+impl TicketKeys {
+    fn new(keys: Vec<TicketKey>, path: Option<PathBuf>) -> Self {
+        TicketKeys {
+            keys: RwLock::new(keys.into()),
+            path,
+            retained_keys: 2,
+            rotation_interval: Duration::from_secs(3600),
+            upgrade_file: Mutex::new(None),
+        }
+    }
+
+    /// Create [TicketKeys] with a random key.
+    pub fn generate() -> Self {
+        Self::new(vec![random_key()], None)
+    }
+
+    /// Load the keys from the given file.
+    ///
+    /// The file contains one or more 80 byte keys, each of which is the 16 byte name followed by
+    /// the 32 byte HMAC secret and the 32 byte AES key. The first key encrypts new tickets.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let keys = read_keys(path)?;
+        Ok(Self::new(keys, Some(path.to_path_buf())))
+    }
+
+    /// The current keys. The first one encrypts new tickets.
+    pub fn keys(&self) -> Arc<[TicketKey]> {
+        self.keys.read().clone()
+    }
+
+    /// Rotate the keys now.
+    ///
+    /// The keys are reloaded from the key file if there is one, otherwise a new key is generated.
+    /// Up to `retained_keys` of the previous keys are kept.
+    pub fn rotate(&self) -> Result<()> {
+        let new_keys = match self.path.as_ref() {
+            Some(path) => read_keys(path)?,
+            None => vec![random_key()],
+        };
+        let keys = {
+            let mut keys = self.keys.write();
+            let limit = new_keys.len() + self.retained_keys;
+            let mut rotated = new_keys;
+            for key in keys.iter() {
+                if rotated.len() >= limit {
+                    break;
+                }
+                if !rotated.iter().any(|k| k.name == key.name) {
+                    rotated.push(key.clone());
+                }
+            }
+            *keys = rotated.into();
+            keys.clone()
+        };
+        // not under the lock of the keys, see inherit()
+        self.update_upgrade_file(&keys);
+        Ok(())
+    }
+
+    fn update_upgrade_file(&self, keys: &[TicketKey]) {
+        if let Some(file) = self.upgrade_file.lock().as_ref() {
+            if let Err(e) = write_keys(file, keys) {
+                warn!("failed to update the ticket keys for graceful upgrade: {e}");
+            }
+        }
+    }
+
+    /// Take the keys of the old process from the fd table if it is upgrading, otherwise put the
+    /// keys in it so that they will be sent to the new process.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn inherit(&self, name: &str, table: &mut Fds) -> Result<()> {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let mut upgrade_file = self.upgrade_file.lock();
+        if upgrade_file.is_some() {
+            // already done by another endpoint sharing these keys
+            return Ok(());
+        }
+        let name = format!("{name}#ticket_keys");
+        if let Some(fd) = table.get(&name) {
+            // the fd stays in the table to be passed on to the next upgrade
+            let fd = nix::unistd::dup(*fd)
+                .or_err(TLS_CONF_ERR, "fail to dup the inherited ticket key file")?;
+            // SAFETY: the fd is just duplicated
+            let file = unsafe { File::from_raw_fd(fd) };
+            match read_keys_from(&file) {
+                Ok(keys) => {
+                    info!("inherited {} ticket keys of {name}", keys.len());
+                    *self.keys.write() = keys.into();
+                }
+                Err(e) => warn!("failed to inherit ticket keys of {name}: {e}"),
+            }
+            *upgrade_file = Some(file);
+            return Ok(());
+        }
+        // SAFETY: the name is nul terminated
+        let fd = unsafe {
+            libc::memfd_create(
+                b"pingora_ticket_keys\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Error::e_because(
+                TLS_CONF_ERR,
+                "fail to create the ticket key file for graceful upgrade",
+                std::io::Error::last_os_error(),
+            );
+        }
+        // SAFETY: the fd is just created
+        let file = unsafe { File::from_raw_fd(fd) };
+        write_keys(&file, &self.keys.read()).or_err(
+            TLS_CONF_ERR,
+            "fail to write the ticket keys for graceful upgrade",
+        )?;
+        table.add(name, file.as_raw_fd());
+        *upgrade_file = Some(file);
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn inherit(&self, _name: &str, _table: &mut Fds) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TicketKeys {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(self.rotation_interval);
+        // the first tick completes right away
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = interval.tick() => {
+                    if let Err(e) = self.rotate() {
+                        error!("failed to rotate the ticket keys: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn random_key() -> TicketKey {
+    let mut key = TicketKey {
+        name: [0; 16],
+        hmac_secret: [0; 32],
+        aes_key: [0; 32],
+    };
+    let mut rng = rand::thread_rng();
+    rng.fill_bytes(&mut key.name);
+    rng.fill_bytes(&mut key.hmac_secret);
+    rng.fill_bytes(&mut key.aes_key);
+    key
+}
+
+fn parse_keys(data: &[u8]) -> Result<Vec<TicketKey>> {
+    if data.is_empty() || data.len() % KEY_SIZE != 0 {
+        return Error::e_explain(
+            TLS_CONF_ERR,
+            format!(
+                "ticket keys should be {KEY_SIZE} bytes each, got {}",
+                data.len()
+            ),
+        );
+    }
+    Ok(data
+        .chunks(KEY_SIZE)
+        .map(|k| TicketKey {
+            name: k[..16].try_into().unwrap(),
+            hmac_secret: k[16..48].try_into().unwrap(),
+            aes_key: k[48..].try_into().unwrap(),
+        })
+        .collect())
+}
+
+fn serialize_keys(keys: &[TicketKey]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(keys.len() * KEY_SIZE);
+    for key in keys {
+        data.extend_from_slice(&key.name);
+        data.extend_from_slice(&key.hmac_secret);
+        data.extend_from_slice(&key.aes_key);
+    }
+    data
+}
+
+fn read_keys(path: &Path) -> Result<Vec<TicketKey>> {
+    let data = std::fs::read(path).or_err_with(TLS_CONF_ERR, || {
+        format!("fail to read ticket key file {}", path.display())
+    })?;
+    parse_keys(&data)
+}
+
+// the file is shared with the other process, so the positioned IOs are used
+fn read_keys_from(file: &File) -> Result<Vec<TicketKey>> {
+    use std::os::unix::fs::FileExt;
+
+    let len = file
+        .metadata()
+        .or_err(TLS_CONF_ERR, "fail to stat the ticket key file")?
+        .len();
+    let mut data = vec![0; len as usize];
+    file.read_exact_at(&mut data, 0)
+        .or_err(TLS_CONF_ERR, "fail to read the ticket key file")?;
+    parse_keys(&data)
+}
+
+fn write_keys(file: &File, keys: &[TicketKey]) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let data = serialize_keys(keys);
+    file.write_all_at(&data, 0)?;
+    file.set_len(data.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_file() {
+        let keys = vec![random_key(), random_key()];
+        let path = std::env::temp_dir().join("pingora_test_ticket_keys");
+        std::fs::write(&path, serialize_keys(&keys)).unwrap();
+        let ticket_keys = TicketKeys::from_file(&path).unwrap();
+        assert!(ticket_keys.keys()[..] == keys[..]);
+
+        std::fs::write(&path, [0; 79]).unwrap();
+        assert!(TicketKeys::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut ticket_keys = TicketKeys::generate();
+        ticket_keys.retained_keys = 1;
+        let first = ticket_keys.keys()[0].clone();
+        ticket_keys.rotate().unwrap();
+        let keys = ticket_keys.keys();
+        assert_eq!(keys.len(), 2);
+        assert!(keys[1] == first);
+
+        // only one previous key is kept
+        ticket_keys.rotate().unwrap();
+        let rotated = ticket_keys.keys();
+        assert_eq!(rotated.len(), 2);
+        assert!(rotated[1] == keys[0]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_inherit() {
+        let mut table = Fds::new();
+        let old = TicketKeys::generate();
+        old.inherit("0.0.0.0:443", &mut table).unwrap();
+        // the rotated keys are passed on
+        old.rotate().unwrap();
+
+        let new = TicketKeys::generate();
+        new.inherit("0.0.0.0:443", &mut table).unwrap();
+        assert!(new.keys()[..] == old.keys()[..]);
+    }
+}
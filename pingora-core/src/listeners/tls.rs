@@ -12,30 +12,87 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use log::debug;
+use ahash::AHasher;
+use log::{debug, warn};
 use pingora_error::{ErrorType, OrErr, Result};
+use std::hash::{Hash, Hasher};
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::protocols::ssl::{
     server::{handshake, handshake_with_callback, TlsAcceptCallbacks},
     SslStream,
 };
 use crate::protocols::IO;
-use crate::tls::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use crate::server::transfer_fd::Fds;
+use crate::tls::ext::{ssl_ctx_load_crl_file, ssl_ctx_set_session_cache, ssl_ctx_set_ticket_keys};
+use crate::tls::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use crate::tls::x509::{X509Name, X509Ref};
+use crate::utils::get_common_name;
+
+#[cfg(not(feature = "boringssl"))]
+use super::OcspStapler;
+use super::TicketKeys;
 
 pub use crate::protocols::ssl::ALPN;
 
 pub const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
 
+/// Whether the certificates of the clients are requested and verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientCertMode {
+    /// Client certificates are not requested.
+    None,
+    /// Client certificates are requested and verified if the clients send them.
+    Optional,
+    /// Client certificates are required and verified.
+    Required,
+}
+
+/// The settings to verify the certificates of the clients, a.k.a. mTLS
+///
+/// The verified certificate chain of a client is available in the
+/// [SslDigest](crate::protocols::ssl::digest::SslDigest) of its connection.
+#[derive(Debug, Clone)]
+pub struct ClientCertSettings {
+    /// Whether client certificates are required
+    pub mode: ClientCertMode,
+    /// The path to the CA bundle to verify the client certificates with. The subjects of these
+    /// CAs are sent to the clients as the acceptable ones.
+    pub ca_file: String,
+    /// The path to the CRLs in PEM to check the client certificate chains against
+    pub crl_file: Option<String>,
+    /// Only accept the client certificates that have a common name, or a DNS, email or URI
+    /// subject alternative name, matching one of these patterns, where `*` matches any
+    /// characters. Any verified certificate is accepted if empty.
+    pub allowed_subjects: Vec<String>,
+}
+
+impl ClientCertSettings {
+    /// Create a new [ClientCertSettings] verifying the client certificates with the CA bundle at
+    /// `ca_file`.
+    pub fn new(mode: ClientCertMode, ca_file: &str) -> Self {
+        ClientCertSettings {
+            mode,
+            ca_file: ca_file.to_string(),
+            crl_file: None,
+            allowed_subjects: vec![],
+        }
+    }
+}
+
 pub(crate) struct Acceptor {
     ssl_acceptor: SslAcceptor,
     callbacks: Option<TlsAcceptCallbacks>,
+    ticket_keys: Option<Arc<TicketKeys>>,
 }
 
 /// The TLS settings of a listening endpoint
 pub struct TlsSettings {
     accept_builder: SslAcceptorBuilder,
     callbacks: Option<TlsAcceptCallbacks>,
+    ticket_keys: Option<Arc<TicketKeys>>,
 }
 
 impl Deref for TlsSettings {
@@ -72,6 +129,7 @@ impl TlsSettings {
         Ok(TlsSettings {
             accept_builder,
             callbacks: None,
+            ticket_keys: None,
         })
     }
 
@@ -85,6 +143,7 @@ impl TlsSettings {
         Ok(TlsSettings {
             accept_builder,
             callbacks: Some(callbacks),
+            ticket_keys: None,
         })
     }
 
@@ -105,15 +164,107 @@ impl TlsSettings {
         }
     }
 
+    /// Encrypt and decrypt the session tickets of this endpoint with the given [TicketKeys]
+    /// instead of the keys generated by the SSL library, which are lost on graceful upgrade.
+    ///
+    /// The same [TicketKeys] can be shared by multiple endpoints. To rotate the keys, run it as a
+    /// background service.
+    pub fn set_ticket_keys(&mut self, keys: Arc<TicketKeys>) {
+        let keys_clone = keys.clone();
+        ssl_ctx_set_ticket_keys(&mut self.accept_builder, move || keys_clone.keys());
+        self.ticket_keys = Some(keys);
+    }
+
+    /// Set the size and the timeout of the session ID cache of this endpoint.
+    ///
+    /// The sessions of the cache are not shared with other processes, including the new process
+    /// of graceful upgrade. A `size` of 0 disables the cache.
+    pub fn set_session_cache(&mut self, size: usize, timeout: Duration) {
+        ssl_ctx_set_session_cache(&mut self.accept_builder, size, timeout);
+    }
+
+    /// Staple the OCSP responses of the given [OcspStapler]s to the handshakes of the clients
+    /// requesting them, each of which is for one of the certificates of this endpoint.
+    ///
+    /// The staplers should run as background services to keep the responses fresh. OCSP stapling
+    /// is not available with BoringSSL.
+    #[cfg(not(feature = "boringssl"))]
+    pub fn set_ocsp_staplers(&mut self, staplers: Vec<Arc<OcspStapler>>) -> Result<()> {
+        self.accept_builder
+            .set_status_callback(move |ssl| Ok(super::ocsp::staple(ssl, &staplers)))
+            .or_err(TLS_CONF_ERR, "fail to set OCSP status callback")
+    }
+
+    /// Set how the certificates of the clients are requested and verified.
+    pub fn set_client_cert_verification(&mut self, settings: ClientCertSettings) -> Result<()> {
+        let verify_mode = match settings.mode {
+            ClientCertMode::None => {
+                self.accept_builder.set_verify(SslVerifyMode::NONE);
+                return Ok(());
+            }
+            ClientCertMode::Optional => SslVerifyMode::PEER,
+            ClientCertMode::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        };
+        let ca_file = &settings.ca_file;
+        self.accept_builder
+            .set_ca_file(ca_file)
+            .or_err_with(TLS_CONF_ERR, || format!("fail to read CA file {ca_file}"))?;
+        let ca_list = X509Name::load_client_ca_file(ca_file).or_err_with(TLS_CONF_ERR, || {
+            format!("fail to read CA names of {ca_file}")
+        })?;
+        self.accept_builder.set_client_ca_list(ca_list);
+        if let Some(crl_file) = settings.crl_file.as_ref() {
+            ssl_ctx_load_crl_file(&mut self.accept_builder, crl_file)
+                .or_err_with(TLS_CONF_ERR, || format!("fail to read CRL file {crl_file}"))?;
+        }
+
+        // resumed sessions skip the verification, so only resume the ones verified the same way
+        let mut hasher = AHasher::default();
+        format!("{settings:?}").hash(&mut hasher);
+        self.accept_builder
+            .set_session_id_context(&hasher.finish().to_be_bytes())
+            .or_err(TLS_CONF_ERR, "fail to set session id context")?;
+
+        if settings.allowed_subjects.is_empty() {
+            self.accept_builder.set_verify(verify_mode);
+            return Ok(());
+        }
+        let allowed_subjects = settings.allowed_subjects;
+        self.accept_builder
+            .set_verify_callback(verify_mode, move |verified, ctx| {
+                // only check the client's own certificate, after it is verified
+                if !verified || ctx.error_depth() != 0 {
+                    return verified;
+                }
+                let allowed = ctx
+                    .current_cert()
+                    .map_or(false, |cert| subject_allowed(cert, &allowed_subjects));
+                if !allowed {
+                    warn!("client certificate subject is not allowed");
+                }
+                allowed
+            });
+        Ok(())
+    }
+
     pub(crate) fn build(self) -> Acceptor {
         Acceptor {
             ssl_acceptor: self.accept_builder.build(),
             callbacks: self.callbacks,
+            ticket_keys: self.ticket_keys,
         }
     }
 }
 
 impl Acceptor {
+    // pass the ticket keys on across graceful upgrade, see TicketKeys::inherit()
+    pub fn inherit_ticket_keys(&self, addr: &str, table: &mut Fds) -> Result<()> {
+        match self.ticket_keys.as_ref() {
+            Some(keys) => keys.inherit(addr, table),
+            None => Ok(()),
+        }
+    }
+
     pub async fn tls_handshake<S: IO>(&self, stream: S) -> Result<SslStream<S>> {
         debug!("new ssl session");
         // TODO: be able to offload this handshake in a thread pool
@@ -125,6 +276,50 @@ impl Acceptor {
     }
 }
 
+// Whether the common name or a DNS, email or URI SAN of the cert matches any of the patterns
+fn subject_allowed(cert: &X509Ref, patterns: &[String]) -> bool {
+    let mut names = vec![];
+    if let Some(cn) = get_common_name(&cert.to_owned()) {
+        names.push(cn);
+    }
+    if let Some(sans) = cert.subject_alt_names() {
+        for san in sans.iter() {
+            if let Some(name) = san.dnsname().or(san.email()).or(san.uri()) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+        .iter()
+        .any(|name| patterns.iter().any(|p| wildcard_match(p, name)))
+}
+
+// Case insensitive match where `*` in the pattern matches any characters
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+    let (mut p, mut n) = (0, 0);
+    // the position after the last `*` and the position in `name` it is matched up to
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if p < pattern.len() && pattern[p].eq_ignore_ascii_case(&name[n]) {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            // let the last `*` match one more character
+            p = star_p;
+            n = star_n + 1;
+            backtrack = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
 mod alpn {
     use super::*;
     use crate::tls::ssl::{select_next_proto, AlpnError, SslRef};
@@ -152,3 +347,150 @@ mod alpn {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::x509::X509;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("client.example.com", "Client.Example.com"));
+        assert!(wildcard_match("*.example.com", "a.example.com"));
+        assert!(wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(wildcard_match(
+            "spiffe://example.org/*/api",
+            "spiffe://example.org/ns/a/api"
+        ));
+        assert!(wildcard_match("*", "anything"));
+        assert!(!wildcard_match("a*c", "abcd"));
+        assert!(wildcard_match("a**d", "abcd"));
+    }
+
+    #[test]
+    fn test_subject_allowed() {
+        let cert_path = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let cert = X509::from_pem(&std::fs::read(cert_path).unwrap()).unwrap();
+        let cn = get_common_name(&cert).unwrap();
+        assert!(subject_allowed(&cert, &[cn]));
+        assert!(subject_allowed(&cert, &["*".to_string()]));
+        assert!(!subject_allowed(&cert, &["not.this.one".to_string()]));
+    }
+
+    // Handshake with a listener verifying the client certs with `settings`. Return the length of
+    // the client cert chain the listener sees.
+    async fn mtls_handshake(settings: ClientCertSettings, client_cert: bool) -> Result<usize> {
+        let cert = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let key = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        mtls_handshake_with(settings, client_cert.then_some((cert, key))).await
+    }
+
+    // The same as mtls_handshake() with the given client cert and key, if any
+    async fn mtls_handshake_with(
+        settings: ClientCertSettings,
+        client_cert: Option<(String, String)>,
+    ) -> Result<usize> {
+        use crate::protocols::ssl::client;
+        use crate::tls::ssl::SslConnector;
+        use tokio::io::AsyncReadExt;
+
+        let cert = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let key = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        let mut tls = TlsSettings::intermediate(&cert, &key).unwrap();
+        tls.set_client_cert_verification(settings).unwrap();
+        let acceptor = tls.build();
+
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            if let Some((cert, key)) = client_cert {
+                connector
+                    .set_certificate_file(&cert, SslFiletype::PEM)
+                    .unwrap();
+                connector
+                    .set_private_key_file(&key, SslFiletype::PEM)
+                    .unwrap();
+            }
+            let conn_config = connector.build().configure().unwrap();
+            // with TLS 1.3 the client may only learn about the rejection of its cert on read
+            if let Ok(mut stream) = client::handshake(conn_config, "openrusty.org", client).await {
+                let mut buf = [0; 1];
+                let _ = stream.read(&mut buf).await;
+            }
+        });
+
+        let stream = acceptor.tls_handshake(server).await?;
+        Ok(stream.ssl_digest().unwrap().peer_cert_chain.len())
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_mode() {
+        let ca = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+
+        let required = ClientCertSettings::new(ClientCertMode::Required, &ca);
+        assert_eq!(mtls_handshake(required.clone(), true).await.unwrap(), 1);
+        assert!(mtls_handshake(required, false).await.is_err());
+
+        let optional = ClientCertSettings::new(ClientCertMode::Optional, &ca);
+        assert_eq!(mtls_handshake(optional.clone(), true).await.unwrap(), 1);
+        assert_eq!(mtls_handshake(optional, false).await.unwrap(), 0);
+
+        let none = ClientCertSettings::new(ClientCertMode::None, &ca);
+        assert_eq!(mtls_handshake(none, true).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_allowed_subjects() {
+        let ca = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+
+        let mut settings = ClientCertSettings::new(ClientCertMode::Required, &ca);
+        settings.allowed_subjects = vec!["*.openrusty.org".to_string()];
+        assert_eq!(mtls_handshake(settings.clone(), true).await.unwrap(), 1);
+
+        settings.allowed_subjects = vec!["not.this.one".to_string()];
+        assert!(mtls_handshake(settings.clone(), true).await.is_err());
+
+        // the allow-list doesn't make the cert required in the optional mode
+        settings.mode = ClientCertMode::Optional;
+        assert_eq!(mtls_handshake(settings, false).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_crl() {
+        let dir = format!("{}/tests/keys/crl", env!("CARGO_MANIFEST_DIR"));
+        let ca = format!("{dir}/ca.crt");
+        let client_cert = Some((format!("{dir}/client.crt"), format!("{dir}/client.key")));
+
+        let mut settings = ClientCertSettings::new(ClientCertMode::Optional, &ca);
+        // the verified chain of the client cert includes the CA
+        assert_eq!(
+            mtls_handshake_with(settings.clone(), client_cert.clone())
+                .await
+                .unwrap(),
+            2
+        );
+        // the CRL of the CA revokes the client cert
+        settings.crl_file = Some(format!("{dir}/revoked.crl"));
+        assert!(mtls_handshake_with(settings.clone(), client_cert)
+            .await
+            .is_err());
+        assert_eq!(
+            mtls_handshake_with(settings.clone(), None).await.unwrap(),
+            0
+        );
+
+        let cert = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let key = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        let mut tls = TlsSettings::intermediate(&cert, &key).unwrap();
+        settings.crl_file = Some("/not/a/crl".to_string());
+        let e = tls
+            .set_client_cert_verification(settings.clone())
+            .unwrap_err();
+        assert_eq!(e.etype(), &TLS_CONF_ERR);
+        settings.crl_file = Some("bad\0path".to_string());
+        let e = tls.set_client_cert_verification(settings).unwrap_err();
+        assert_eq!(e.etype(), &TLS_CONF_ERR);
+    }
+}
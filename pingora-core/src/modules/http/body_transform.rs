@@ -0,0 +1,607 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP response body transformation filter
+//!
+//! [ResponseBodyTransform] takes care of the plumbing needed to rewrite response bodies:
+//! collecting chunks across boundaries, decoding and re-encoding compressed bodies and fixing
+//! the `Content-Length` and `Transfer-Encoding` headers. The actual rewriting is done by a
+//! [BodyTransform].
+
+use super::*;
+use crate::protocols::http::compression::{parse_content_encoding, Encode};
+use bytes::{Buf, BytesMut};
+use http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+use log::warn;
+use pingora_error::{Error, ErrorType};
+
+/// The error to return when a buffered body exceeds the configured size limit
+pub const BODY_TOO_LARGE: ErrorType = ErrorType::new("BodyTooLarge");
+
+/// The trait to rewrite a (decoded) response body
+pub trait BodyTransform {
+    /// Transform the given piece of the body. `end` signals the end of the body.
+    ///
+    /// The returned bytes are sent downstream. It is fine to return empty bytes when more input
+    /// is needed before anything can be emitted, as long as everything is flushed when `end` is
+    /// true.
+    fn transform(&mut self, input: Bytes, end: bool) -> Result<Bytes>;
+
+    /// Return the length of the output if it can be derived from the input length alone.
+    ///
+    /// When the output length is known, `Content-Length` is kept (and rewritten) instead of
+    /// switching the response to chunked encoding.
+    fn transformed_len(&self, _input_len: usize) -> Option<usize> {
+        None
+    }
+}
+
+/// A boxed [BodyTransform]
+pub type Transformer = Box<dyn BodyTransform + Send + Sync>;
+
+/// The trait to decide whether and how to transform a response
+pub trait BodyTransformFactory {
+    /// Return the [Transformer] to apply to the response, `None` to leave it untouched.
+    fn create(&self, resp: &ResponseHeader) -> Option<Transformer>;
+}
+
+/// How the body is fed into the [BodyTransform]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransformMode {
+    /// Feed each chunk to the transform as soon as it arrives.
+    Streaming,
+    /// Collect the whole (decoded) body, up to the given number of bytes, and feed it to the
+    /// transform at once.
+    ///
+    /// Uncompressed responses whose `Content-Length` is already above the limit are left
+    /// untouched. A decoded body that grows above the limit while streaming fails with
+    /// [BODY_TOO_LARGE].
+    Buffered(usize),
+}
+
+struct BodyState {
+    transform: Transformer,
+    decoder: Option<Box<dyn Encode + Send + Sync>>,
+    encoder: Option<Box<dyn Encode + Send + Sync>>,
+    buffer: Option<(BytesMut, usize)>, // the buffer and its size limit
+}
+
+impl BodyState {
+    fn filter(&mut self, data: Option<&Bytes>, end: bool) -> Result<Bytes> {
+        let data = data.map_or(&[][..], |d| d.as_ref());
+        let decoded = match self.decoder.as_mut() {
+            Some(d) => d.encode(data, end)?,
+            None => Bytes::copy_from_slice(data),
+        };
+
+        let transformed = match self.buffer.as_mut() {
+            Some((buf, limit)) => {
+                if buf.len() + decoded.len() > *limit {
+                    return Error::e_explain(
+                        BODY_TOO_LARGE,
+                        format!("response body is larger than {limit} bytes"),
+                    );
+                }
+                buf.extend_from_slice(&decoded);
+                if end {
+                    self.transform.transform(buf.split().freeze(), true)?
+                } else {
+                    Bytes::new()
+                }
+            }
+            None => self.transform.transform(decoded, end)?,
+        };
+
+        match self.encoder.as_mut() {
+            Some(e) => e.encode(&transformed, end),
+            None => Ok(transformed),
+        }
+    }
+}
+
+enum Phase {
+    Header,
+    Body(Option<BodyState>),
+}
+
+/// HTTP response body transformation module
+pub struct ResponseBodyTransform {
+    factory: Arc<dyn BodyTransformFactory + Send + Sync>,
+    mode: TransformMode,
+    recompress_level: u32,
+    head_request: bool,
+    phase: Phase,
+}
+
+impl ResponseBodyTransform {
+    /// Whether the response body is being transformed.
+    pub fn is_active(&self) -> bool {
+        matches!(self.phase, Phase::Body(Some(_)))
+    }
+
+    fn init_body_state(&self, resp: &mut ResponseHeader) -> Option<BodyState> {
+        if self.head_request {
+            return None;
+        }
+        let content_length = resp
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| std::str::from_utf8(v.as_bytes()).ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        // We can only look into the encodings we understand
+        let content_encoding = parse_content_encoding(resp.headers.get(CONTENT_ENCODING));
+
+        let buffer = match self.mode {
+            TransformMode::Streaming => None,
+            TransformMode::Buffered(limit) => {
+                // the limit applies to the decoded body, which the encoded length says nothing about
+                if content_encoding.is_none() && content_length.map_or(false, |cl| cl > limit) {
+                    return None;
+                }
+                Some((BytesMut::new(), limit))
+            }
+        };
+
+        let (decoder, encoder) = match content_encoding {
+            None => (None, None),
+            Some(algorithm) => {
+                let decoder = algorithm.decompressor(true)?;
+                (Some(decoder), algorithm.compressor(self.recompress_level))
+            }
+        };
+
+        let transform = self.factory.create(resp)?;
+
+        let new_length = match (&decoder, content_length) {
+            // the length of the encoded body says nothing about the decoded one
+            (None, Some(cl)) => transform.transformed_len(cl),
+            _ => None,
+        };
+        if decoder.is_some() && encoder.is_none() {
+            // the body will be sent decoded
+            resp.remove_header(&CONTENT_ENCODING);
+        }
+        if let Some(len) = new_length {
+            resp.insert_header(&CONTENT_LENGTH, len).unwrap();
+        } else {
+            // the length is not known until the whole body is transformed
+            resp.remove_header(&CONTENT_LENGTH);
+            // TODO: chunked is for h1 only, h2 removes it
+            resp.insert_header(&TRANSFER_ENCODING, HeaderValue::from_static("chunked"))
+                .unwrap();
+        }
+
+        Some(BodyState {
+            transform,
+            decoder,
+            encoder,
+            buffer,
+        })
+    }
+}
+
+#[async_trait]
+impl HttpModule for ResponseBodyTransform {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    async fn request_header_filter(&mut self, req: &mut RequestHeader) -> Result<()> {
+        self.head_request = req.method == http::Method::HEAD;
+        Ok(())
+    }
+
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        end_of_stream: bool,
+    ) -> Result<()> {
+        if !matches!(self.phase, Phase::Header) {
+            return Ok(());
+        }
+        if resp.status.is_informational() {
+            if resp.status == http::StatusCode::SWITCHING_PROTOCOLS {
+                // no transformation for upgraded connections
+                self.phase = Phase::Body(None);
+            }
+            // else, wait for the final response header for decision
+            return Ok(());
+        }
+        // do nothing if no body
+        if end_of_stream {
+            self.phase = Phase::Body(None);
+            return Ok(());
+        }
+        self.phase = Phase::Body(self.init_body_state(resp));
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> Result<()> {
+        let Phase::Body(Some(state)) = &mut self.phase else {
+            return Ok(());
+        };
+        match state.filter(body.as_ref(), end_of_stream) {
+            Ok(output) => {
+                *body = Some(output);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Failed to transform response body, {e}");
+                // the headers are already sent, no way to fall back to the original body
+                self.phase = Phase::Body(None);
+                Err(e)
+            }
+        }
+    }
+
+    fn response_body_filter_enabled(&self) -> bool {
+        // before the response header, the body may still be transformed
+        !matches!(self.phase, Phase::Body(None))
+    }
+}
+
+/// The builder for [ResponseBodyTransform]
+pub struct ResponseBodyTransformBuilder {
+    factory: Arc<dyn BodyTransformFactory + Send + Sync>,
+    mode: TransformMode,
+    recompress_level: u32,
+}
+
+impl ResponseBodyTransformBuilder {
+    /// Return a [ModuleBuilder] for [ResponseBodyTransform].
+    ///
+    /// Compressed responses are decoded before being transformed and compressed again with the
+    /// same algorithm at `recompress_level`. `0` leaves the transformed body uncompressed.
+    pub fn enable(
+        factory: impl BodyTransformFactory + Send + Sync + 'static,
+        mode: TransformMode,
+        recompress_level: u32,
+    ) -> ModuleBuilder {
+        Box::new(ResponseBodyTransformBuilder {
+            factory: Arc::new(factory),
+            mode,
+            recompress_level,
+        })
+    }
+}
+
+impl HttpModuleBuilder for ResponseBodyTransformBuilder {
+    fn init(&self) -> Module {
+        Box::new(ResponseBodyTransform {
+            factory: self.factory.clone(),
+            mode: self.mode,
+            recompress_level: self.recompress_level,
+            head_request: false,
+            phase: Phase::Header,
+        })
+    }
+}
+
+/// A streaming search and replace [BodyTransform]
+///
+/// Matches that span chunk boundaries are handled by holding back the tail of each chunk that
+/// could be the beginning of a match. When several patterns match at the same position, the one
+/// added first wins.
+pub struct SearchReplace {
+    rules: Vec<(Bytes, Bytes)>,
+    max_pattern_len: usize,
+    pending: BytesMut,
+}
+
+impl SearchReplace {
+    /// Create a new [SearchReplace] from the list of `(pattern, replacement)`.
+    ///
+    /// # Panic
+    /// Panic if any of the patterns is empty.
+    pub fn new<P: Into<Bytes>, R: Into<Bytes>>(rules: impl IntoIterator<Item = (P, R)>) -> Self {
+        let rules: Vec<(Bytes, Bytes)> = rules
+            .into_iter()
+            .map(|(p, r)| (p.into(), r.into()))
+            .collect();
+        assert!(
+            rules.iter().all(|(p, _)| !p.is_empty()),
+            "empty search pattern"
+        );
+        let max_pattern_len = rules.iter().map(|(p, _)| p.len()).max().unwrap_or(0);
+        SearchReplace {
+            rules,
+            max_pattern_len,
+            pending: BytesMut::new(),
+        }
+    }
+
+    // find the earliest match in data, return the position and the index of the rule
+    fn find(&self, data: &[u8]) -> Option<(usize, usize)> {
+        let mut found: Option<(usize, usize)> = None;
+        for (i, (pattern, _)) in self.rules.iter().enumerate() {
+            // only need to search before the current best match
+            let end = found.map_or(data.len(), |(pos, _)| {
+                std::cmp::min(data.len(), pos + pattern.len() - 1)
+            });
+            if let Some(pos) = data[..end]
+                .windows(pattern.len())
+                .position(|w| w == &pattern[..])
+            {
+                if found.map_or(true, |(best, _)| pos < best) {
+                    found = Some((pos, i));
+                }
+            }
+        }
+        found
+    }
+}
+
+impl BodyTransform for SearchReplace {
+    fn transform(&mut self, input: Bytes, end: bool) -> Result<Bytes> {
+        self.pending.extend_from_slice(&input);
+        let mut output = BytesMut::with_capacity(self.pending.len());
+        let mut pos = 0;
+        while let Some((offset, rule)) = self.find(&self.pending[pos..]) {
+            let (pattern, replacement) = &self.rules[rule];
+            output.extend_from_slice(&self.pending[pos..pos + offset]);
+            output.extend_from_slice(replacement);
+            pos += offset + pattern.len();
+        }
+        // the tail might be the beginning of a match that continues in the next chunk
+        let keep = if end {
+            0
+        } else {
+            std::cmp::min(
+                self.max_pattern_len.saturating_sub(1),
+                self.pending.len() - pos,
+            )
+        };
+        let emit_to = self.pending.len() - keep;
+        output.extend_from_slice(&self.pending[pos..emit_to]);
+        self.pending.advance(emit_to);
+        Ok(output.freeze())
+    }
+
+    fn transformed_len(&self, input_len: usize) -> Option<usize> {
+        // only when every replacement has the same length as its pattern
+        self.rules
+            .iter()
+            .all(|(p, r)| p.len() == r.len())
+            .then_some(input_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::http::compression::Algorithm;
+
+    fn replace_all(rules: &[(&'static str, &'static str)], chunks: &[&'static str]) -> String {
+        let mut sr = SearchReplace::new(rules.iter().copied());
+        let mut output = vec![];
+        for (i, c) in chunks.iter().enumerate() {
+            let end = i == chunks.len() - 1;
+            output.extend_from_slice(&sr.transform(Bytes::from_static(c.as_bytes()), end).unwrap());
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_search_replace() {
+        assert_eq!(replace_all(&[("foo", "bar")], &["a foo b"]), "a bar b");
+        assert_eq!(replace_all(&[("foo", "bar")], &["a fo", "o b"]), "a bar b");
+        assert_eq!(
+            replace_all(&[("foo", "bar")], &["a f", "o", "o f", "oo"]),
+            "a bar bar"
+        );
+        // partial match at the end is flushed
+        assert_eq!(replace_all(&[("foo", "bar")], &["a f", "o"]), "a fo");
+        // the earliest match wins
+        assert_eq!(replace_all(&[("bcd", "X"), ("ab", "Y")], &["abcd"]), "Ycd");
+        // replacement longer than the pattern
+        assert_eq!(
+            replace_all(
+                &[("<head>", "<head><script/>")],
+                &["<ht", "ml><he", "ad></html>"]
+            ),
+            "<html><head><script/></html>"
+        );
+    }
+
+    #[test]
+    fn test_search_replace_len() {
+        let sr = SearchReplace::new([("foo", "bar")]);
+        assert_eq!(sr.transformed_len(10), Some(10));
+        let sr = SearchReplace::new([("foo", "bar"), ("a", "bc")]);
+        assert_eq!(sr.transformed_len(10), None);
+    }
+
+    struct ReplaceFoo;
+    impl BodyTransformFactory for ReplaceFoo {
+        fn create(&self, resp: &ResponseHeader) -> Option<Transformer> {
+            if resp.status == 200 {
+                Some(Box::new(SearchReplace::new([("foo", "barbar")])))
+            } else {
+                None
+            }
+        }
+    }
+
+    fn build_ctx(mode: TransformMode, level: u32) -> HttpModuleCtx {
+        let mut modules = HttpModules::new();
+        modules.add_module(ResponseBodyTransformBuilder::enable(
+            ReplaceFoo, mode, level,
+        ));
+        modules.build_ctx()
+    }
+
+    #[tokio::test]
+    async fn test_transform_streaming() {
+        let mut ctx = build_ctx(TransformMode::Streaming, 0);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-length", "11").unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        assert!(ctx.get::<ResponseBodyTransform>().unwrap().is_active());
+        assert!(resp.headers.get("content-length").is_none());
+        assert_eq!(resp.headers.get("transfer-encoding").unwrap(), "chunked");
+
+        let mut body = Some(Bytes::from_static(b"1 fo"));
+        ctx.response_body_filter(&mut body, false).unwrap();
+        assert_eq!(body.unwrap(), "1 ");
+        let mut body = Some(Bytes::from_static(b"o 2 foo"));
+        ctx.response_body_filter(&mut body, true).unwrap();
+        assert_eq!(body.unwrap(), "barbar 2 barbar");
+    }
+
+    #[tokio::test]
+    async fn test_transform_skip() {
+        let mut ctx = build_ctx(TransformMode::Streaming, 0);
+        let mut resp = ResponseHeader::build(404, None).unwrap();
+        resp.insert_header("content-length", "3").unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        assert!(!ctx.get::<ResponseBodyTransform>().unwrap().is_active());
+        assert_eq!(resp.headers.get("content-length").unwrap(), "3");
+
+        let mut body = Some(Bytes::from_static(b"foo"));
+        ctx.response_body_filter(&mut body, true).unwrap();
+        assert_eq!(body.unwrap(), "foo");
+
+        // unknown encoding cannot be transformed
+        let mut ctx = build_ctx(TransformMode::Streaming, 0);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-encoding", "whatever").unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        assert!(!ctx.get::<ResponseBodyTransform>().unwrap().is_active());
+
+        // too large to buffer
+        let mut ctx = build_ctx(TransformMode::Buffered(10), 0);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-length", "11").unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        assert!(!ctx.get::<ResponseBodyTransform>().unwrap().is_active());
+    }
+
+    #[tokio::test]
+    async fn test_transform_buffered() {
+        let mut ctx = build_ctx(TransformMode::Buffered(10), 0);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+
+        let mut body = Some(Bytes::from_static(b"1 fo"));
+        ctx.response_body_filter(&mut body, false).unwrap();
+        assert!(body.unwrap().is_empty());
+        let mut body = Some(Bytes::from_static(b"o 2"));
+        ctx.response_body_filter(&mut body, false).unwrap();
+        assert!(body.unwrap().is_empty());
+        let mut body = None;
+        ctx.response_body_filter(&mut body, true).unwrap();
+        assert_eq!(body.unwrap(), "1 barbar 2");
+
+        // over the limit
+        let mut ctx = build_ctx(TransformMode::Buffered(10), 0);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        let mut body = Some(Bytes::from_static(b"0123456789a"));
+        let e = ctx.response_body_filter(&mut body, false).unwrap_err();
+        assert_eq!(e.etype(), &BODY_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_transform_compressed() {
+        let mut gzip = Algorithm::Gzip.compressor(6).unwrap();
+        let compressed = gzip.encode(b"1 foo 2", true).unwrap();
+
+        // decompress, transform and compress again
+        let mut ctx = build_ctx(TransformMode::Streaming, 6);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-encoding", "gzip").unwrap();
+        resp.insert_header("content-length", compressed.len())
+            .unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        assert_eq!(resp.headers.get("content-encoding").unwrap(), "gzip");
+        assert!(resp.headers.get("content-length").is_none());
+
+        let mut body = Some(compressed.clone());
+        ctx.response_body_filter(&mut body, true).unwrap();
+        let mut gunzip = Algorithm::Gzip.decompressor(true).unwrap();
+        let output = gunzip.encode(&body.unwrap(), true).unwrap();
+        assert_eq!(output, "1 barbar 2");
+
+        // decompress and transform only
+        let mut ctx = build_ctx(TransformMode::Streaming, 0);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-encoding", "gzip").unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        assert!(resp.headers.get("content-encoding").is_none());
+
+        let mut body = Some(compressed);
+        ctx.response_body_filter(&mut body, true).unwrap();
+        assert_eq!(body.unwrap(), "1 barbar 2");
+    }
+
+    #[tokio::test]
+    async fn test_transform_buffered_compressed() {
+        // the encoded length is above the limit but the decoded body is not
+        let mut gzip = Algorithm::Gzip.compressor(6).unwrap();
+        let compressed = gzip.encode(b"foo", true).unwrap();
+        assert!(compressed.len() > 10);
+
+        let mut ctx = build_ctx(TransformMode::Buffered(10), 0);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-encoding", "gzip").unwrap();
+        resp.insert_header("content-length", compressed.len())
+            .unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        assert!(ctx.get::<ResponseBodyTransform>().unwrap().is_active());
+
+        let mut body = Some(compressed);
+        ctx.response_body_filter(&mut body, true).unwrap();
+        assert_eq!(body.unwrap(), "barbar");
+
+        // the decoded body is over the limit
+        let mut gzip = Algorithm::Gzip.compressor(6).unwrap();
+        let compressed = gzip.encode(&[b'a'; 11], true).unwrap();
+        let mut ctx = build_ctx(TransformMode::Buffered(10), 0);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-encoding", "gzip").unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        let mut body = Some(compressed);
+        let e = ctx.response_body_filter(&mut body, true).unwrap_err();
+        assert_eq!(e.etype(), &BODY_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_transform_keep_content_length() {
+        struct SameLength;
+        impl BodyTransformFactory for SameLength {
+            fn create(&self, _resp: &ResponseHeader) -> Option<Transformer> {
+                Some(Box::new(SearchReplace::new([("foo", "bar")])))
+            }
+        }
+        let mut modules = HttpModules::new();
+        modules.add_module(ResponseBodyTransformBuilder::enable(
+            SameLength,
+            TransformMode::Streaming,
+            0,
+        ));
+        let mut ctx = modules.build_ctx();
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-length", "7").unwrap();
+        ctx.response_header_filter(&mut resp, false).await.unwrap();
+        assert_eq!(resp.headers.get("content-length").unwrap(), "7");
+        assert!(resp.headers.get("transfer-encoding").is_none());
+    }
+}
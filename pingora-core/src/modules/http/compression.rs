@@ -15,8 +15,11 @@
 //! HTTP compression filter
 
 use super::*;
-use crate::protocols::http::compression::ResponseCompressionCtx;
+use crate::protocols::http::compression::{
+    dictionary::DictionaryStore, Algorithm, RequestCompressionCtx, ResponseCompressionCtx,
+};
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
 /// HTTP response compression module
 pub struct ResponseCompression(ResponseCompressionCtx);
@@ -72,25 +75,47 @@ impl HttpModule for ResponseCompression {
         }
         Ok(())
     }
+
+    fn response_body_filter_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
 }
 
 /// The builder for HTTP response compression module
 pub struct ResponseCompressionBuilder {
     level: u32,
+    dictionaries: Option<Arc<DictionaryStore>>,
 }
 
 impl ResponseCompressionBuilder {
     /// Return a [ModuleBuilder] for [ResponseCompression] with the given compression level
     pub fn enable(level: u32) -> ModuleBuilder {
-        Box::new(ResponseCompressionBuilder { level })
+        Box::new(ResponseCompressionBuilder {
+            level,
+            dictionaries: None,
+        })
+    }
+
+    /// Return a [ModuleBuilder] for [ResponseCompression] with the given compression level
+    /// which also compresses with the shared dictionaries in `dictionaries`
+    pub fn enable_with_dictionaries(
+        level: u32,
+        dictionaries: Arc<DictionaryStore>,
+    ) -> ModuleBuilder {
+        Box::new(ResponseCompressionBuilder {
+            level,
+            dictionaries: Some(dictionaries),
+        })
     }
 }
 
 impl HttpModuleBuilder for ResponseCompressionBuilder {
     fn init(&self) -> Module {
-        Box::new(ResponseCompression(ResponseCompressionCtx::new(
-            self.level, false,
-        )))
+        let mut ctx = ResponseCompressionCtx::new(self.level, false);
+        if let Some(dictionaries) = self.dictionaries.as_ref() {
+            ctx.adjust_dictionaries(dictionaries.clone());
+        }
+        Box::new(ResponseCompression(ctx))
     }
 
     fn order(&self) -> i16 {
@@ -98,3 +123,106 @@ impl HttpModuleBuilder for ResponseCompressionBuilder {
         i16::MIN / 2
     }
 }
+
+/// HTTP request compression module
+pub struct RequestCompression(RequestCompressionCtx);
+
+impl Deref for RequestCompression {
+    type Target = RequestCompressionCtx;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for RequestCompression {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[async_trait]
+impl HttpModule for RequestCompression {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    async fn request_header_filter(&mut self, req: &mut RequestHeader) -> Result<()> {
+        self.0.request_header_filter(req);
+        Ok(())
+    }
+
+    async fn upstream_request_filter(&mut self, req: &mut RequestHeader) -> Result<()> {
+        self.0.upstream_request_filter(req);
+        Ok(())
+    }
+
+    async fn request_body_filter(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> Result<()> {
+        if !self.0.is_enabled() {
+            return Ok(());
+        }
+        let transcoded = self.0.request_body_filter(body.as_ref(), end_of_stream)?;
+        if transcoded.is_some() {
+            *body = transcoded;
+        }
+        Ok(())
+    }
+
+    fn response_body_filter_enabled(&self) -> bool {
+        false
+    }
+}
+
+/// The builder for HTTP request compression module
+pub struct RequestCompressionBuilder {
+    compression: Option<(Algorithm, u32)>,
+    max_decompressed_size: usize,
+}
+
+impl RequestCompressionBuilder {
+    /// Return a [ModuleBuilder] for [RequestCompression] that decompresses request bodies.
+    /// The decompressed body cannot be larger than `max_decompressed_size` bytes.
+    pub fn decompress(max_decompressed_size: usize) -> ModuleBuilder {
+        Box::new(RequestCompressionBuilder {
+            compression: None,
+            max_decompressed_size,
+        })
+    }
+
+    /// Return a [ModuleBuilder] for [RequestCompression] that compresses uncompressed request
+    /// bodies with the given algorithm and level.
+    pub fn compress(algorithm: Algorithm, level: u32) -> ModuleBuilder {
+        Box::new(RequestCompressionBuilder {
+            compression: Some((algorithm, level)),
+            max_decompressed_size: 0,
+        })
+    }
+}
+
+impl HttpModuleBuilder for RequestCompressionBuilder {
+    fn init(&self) -> Module {
+        let decompress = self.compression.is_none();
+        let mut ctx = RequestCompressionCtx::new(decompress, self.max_decompressed_size);
+        if let Some((algorithm, level)) = self.compression {
+            ctx.adjust_compression(algorithm, level);
+        }
+        Box::new(RequestCompression(ctx))
+    }
+
+    fn order(&self) -> i16 {
+        if self.compression.is_some() {
+            // compress the body after the other filters are done with it
+            i16::MIN / 2
+        } else {
+            // let the other filters see the decompressed body
+            i16::MAX / 2
+        }
+    }
+}
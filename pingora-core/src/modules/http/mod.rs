@@ -18,6 +18,7 @@
 //! application.
 //! See the [ResponseCompression] module for an example of how to implement a basic module.
 
+pub mod body_transform;
 pub mod compression;
 
 use async_trait::async_trait;
@@ -37,6 +38,14 @@ pub trait HttpModule {
         Ok(())
     }
 
+    /// Filter the request header sent to the upstream, which is a copy of the downstream request
+    /// header after all the other request filters.
+    ///
+    /// Only the proxy calls this filter.
+    async fn upstream_request_filter(&mut self, _req: &mut RequestHeader) -> Result<()> {
+        Ok(())
+    }
+
     async fn request_body_filter(
         &mut self,
         _body: &mut Option<Bytes>,
@@ -61,6 +70,13 @@ pub trait HttpModule {
         Ok(())
     }
 
+    /// Whether [Self::response_body_filter] may change the response body.
+    ///
+    /// When no module does, the response body can bypass the modules, e.g., to be spliced.
+    fn response_body_filter_enabled(&self) -> bool {
+        true
+    }
+
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
 }
@@ -191,6 +207,14 @@ impl HttpModuleCtx {
         Ok(())
     }
 
+    /// Run the `upstream_request_filter` for all the modules according to their orders.
+    pub async fn upstream_request_filter(&mut self, req: &mut RequestHeader) -> Result<()> {
+        for filter in self.module_ctx.iter_mut() {
+            filter.upstream_request_filter(req).await?;
+        }
+        Ok(())
+    }
+
     /// Run the `request_body_filter` for all the modules according to their orders.
     pub async fn request_body_filter(
         &mut self,
@@ -226,6 +250,13 @@ impl HttpModuleCtx {
         }
         Ok(())
     }
+
+    /// Whether any of the modules may change the response body.
+    pub fn response_body_filter_enabled(&self) -> bool {
+        self.module_ctx
+            .iter()
+            .any(|filter| filter.response_body_filter_enabled())
+    }
 }
 
 #[cfg(test)]
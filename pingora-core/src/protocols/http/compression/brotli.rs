@@ -12,27 +12,41 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::Encode;
+use super::dictionary::{CompressionDictionary, DCB_MAGIC};
 use super::COMPRESSION_ERROR;
+use super::{Encode, OutputBuf};
 
+use brotli::enc::encode::{
+    BrotliEncoderCompressStream, BrotliEncoderCreateInstance, BrotliEncoderDestroyInstance,
+    BrotliEncoderHasMoreOutput, BrotliEncoderIsFinished, BrotliEncoderOperation,
+    BrotliEncoderParameter, BrotliEncoderSetCustomDictionary, BrotliEncoderSetParameter,
+    BrotliEncoderStateStruct,
+};
+use brotli::enc::{interface, StandardAlloc};
 use brotli::{CompressorWriter, DecompressorWriter};
 use bytes::Bytes;
-use pingora_error::{OrErr, Result};
+use pingora_error::{Error, OrErr, Result};
 use std::io::Write;
 use std::time::{Duration, Instant};
 
 pub struct Decompressor {
-    decompress: DecompressorWriter<Vec<u8>>,
+    decompress: DecompressorWriter<OutputBuf>,
     total_in: usize,
     total_out: usize,
     duration: Duration,
 }
 
 impl Decompressor {
+    #[cfg(test)]
     pub fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// Create a decompressor which fails once its total output is larger than `limit` bytes.
+    pub fn with_limit(limit: usize) -> Self {
         Decompressor {
             // default buf is 4096 if 0 is used, TODO: figure out the significance of this value
-            decompress: DecompressorWriter::new(vec![], 0),
+            decompress: DecompressorWriter::new(OutputBuf::new(limit), 0),
             total_in: 0,
             total_out: 0,
             duration: Duration::new(0, 0),
@@ -56,19 +70,23 @@ impl Encode for Decompressor {
             input.len()
         };
         self.decompress.get_mut().reserve(reserve_size);
-        self.decompress
-            .write_all(input)
-            .or_err(COMPRESSION_ERROR, "while decompress Brotli")?;
-        // write to vec will never fail. The only possible error is that the input data
-        // is invalid (not brotli compressed)
-        if end {
+        self.decompress.write_all(input).map_err(|e| {
             self.decompress
-                .flush()
-                .or_err(COMPRESSION_ERROR, "while decompress Brotli")?;
+                .get_ref()
+                .error(e, "while decompress Brotli")
+        })?;
+        // the output buffer only fails when the output is over the limit, otherwise the input
+        // data is invalid (not brotli compressed)
+        if end {
+            self.decompress.flush().map_err(|e| {
+                self.decompress
+                    .get_ref()
+                    .error(e, "while decompress Brotli")
+            })?;
         }
         self.total_out += self.decompress.get_ref().len();
         self.duration += start.elapsed();
-        Ok(std::mem::take(self.decompress.get_mut()).into()) // into() Bytes will drop excess capacity
+        Ok(self.decompress.get_mut().take().into()) // into() Bytes will drop excess capacity
     }
 
     fn stat(&self) -> (&'static str, usize, usize, Duration) {
@@ -125,6 +143,115 @@ impl Encode for Compressor {
     }
 }
 
+/// The `dcb` compressor: brotli with the dictionary as the raw prefix of the content.
+///
+/// [CompressorWriter] doesn't expose custom dictionaries so the encoder state is driven directly.
+pub struct DictionaryCompressor {
+    state: BrotliEncoderStateStruct<StandardAlloc>,
+    output: Vec<u8>,
+    buf: Box<[u8]>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+}
+
+impl DictionaryCompressor {
+    pub fn new(level: u32, dictionary: &CompressionDictionary) -> Self {
+        // dcb allows windows up to 16MB
+        const LGWIN: u32 = 24;
+        let mut state = BrotliEncoderCreateInstance(StandardAlloc::default());
+        BrotliEncoderSetParameter(
+            &mut state,
+            BrotliEncoderParameter::BROTLI_PARAM_QUALITY,
+            level,
+        );
+        BrotliEncoderSetParameter(
+            &mut state,
+            BrotliEncoderParameter::BROTLI_PARAM_LGWIN,
+            LGWIN,
+        );
+        let dict = dictionary.data();
+        BrotliEncoderSetCustomDictionary(&mut state, dict.len(), dict);
+        DictionaryCompressor {
+            state,
+            // The dcb stream header goes out before the brotli stream.
+            output: dictionary.stream_header(&DCB_MAGIC),
+            buf: vec![0; 4096].into_boxed_slice(),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+        }
+    }
+
+    fn compress(&mut self, input: &[u8], op: BrotliEncoderOperation) -> Result<()> {
+        let mut nop_callback =
+            |_data: &mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
+             _cmds: &mut [interface::StaticCommand],
+             _mb: interface::InputPair,
+             _alloc: &mut StandardAlloc| ();
+        let mut avail_in = input.len();
+        let mut input_offset = 0;
+        loop {
+            let mut avail_out = self.buf.len();
+            let mut output_offset = 0;
+            let ret = BrotliEncoderCompressStream(
+                &mut self.state,
+                op,
+                &mut avail_in,
+                input,
+                &mut input_offset,
+                &mut avail_out,
+                &mut self.buf,
+                &mut output_offset,
+                &mut None,
+                &mut nop_callback,
+            );
+            self.output.extend_from_slice(&self.buf[..output_offset]);
+            if ret <= 0 {
+                return Error::e_explain(
+                    COMPRESSION_ERROR,
+                    "while compress Brotli with dictionary",
+                );
+            }
+            let done = match op {
+                BrotliEncoderOperation::BROTLI_OPERATION_FINISH => {
+                    BrotliEncoderIsFinished(&self.state) != 0
+                }
+                _ => avail_in == 0 && BrotliEncoderHasMoreOutput(&self.state) == 0,
+            };
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Encode for DictionaryCompressor {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        let start = Instant::now();
+        self.total_in += input.len();
+        if !input.is_empty() {
+            self.compress(input, BrotliEncoderOperation::BROTLI_OPERATION_PROCESS)?;
+        }
+        if end {
+            self.compress(&[], BrotliEncoderOperation::BROTLI_OPERATION_FINISH)?;
+        }
+        self.total_out += self.output.len();
+        self.duration += start.elapsed();
+        Ok(std::mem::take(&mut self.output).into()) // into() Bytes will drop excess capacity
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("dcb", self.total_in, self.total_out, self.duration)
+    }
+}
+
+impl Drop for DictionaryCompressor {
+    fn drop(&mut self) {
+        BrotliEncoderDestroyInstance(&mut self.state);
+    }
+}
+
 #[cfg(test)]
 mod tests_stream {
     use super::*;
@@ -158,4 +285,27 @@ mod tests_stream {
             ],
         );
     }
+
+    #[test]
+    fn compress_brotli_dictionary_data() {
+        let dict = CompressionDictionary::new(Bytes::from_static(b"adcdefgabcdefgh\n")).unwrap();
+        let mut compressor = DictionaryCompressor::new(11, &dict);
+        let input = b"adcdefgabcdefgh\nadcdefgabcdefgh\n";
+        let mut compressed = compressor.encode(&input[..10], false).unwrap().to_vec();
+        compressed.extend_from_slice(&compressor.encode(&input[10..], true).unwrap());
+
+        // the dcb header followed by the brotli stream
+        assert_eq!(&compressed[..4], &DCB_MAGIC);
+        assert_eq!(&compressed[4..36], dict.hash());
+
+        let mut decompressor = brotli::Decompressor::new_with_custom_dict(
+            &compressed[36..],
+            4096,
+            dict.data().to_vec().into(),
+        );
+        let mut output = vec![];
+        std::io::Read::read_to_end(&mut decompressor, &mut output).unwrap();
+        assert_eq!(&output[..], &input[..]);
+        assert_eq!(compressor.stat().1, input.len());
+    }
 }
@@ -0,0 +1,217 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compression dictionary transport
+//!
+//! See <https://datatracker.ietf.org/doc/rfc9842/>
+
+use super::COMPRESSION_ERROR;
+use crate::tls::hash::{hash, MessageDigest};
+
+use bytes::Bytes;
+use http::HeaderValue;
+use parking_lot::RwLock;
+use pingora_error::{OrErr, Result};
+use pingora_http::ResponseHeader;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The length of the SHA-256 hash that identifies a dictionary
+pub const DICTIONARY_HASH_LEN: usize = 32;
+
+/// The SHA-256 hash that identifies a dictionary
+pub type DictionaryHash = [u8; DICTIONARY_HASH_LEN];
+
+// the magic numbers of the dictionary compressed streams
+// https://datatracker.ietf.org/doc/html/rfc9842#name-dictionary-compressed-brotl
+pub(super) const DCB_MAGIC: [u8; 4] = [0xff, 0x44, 0x43, 0x42];
+// https://datatracker.ietf.org/doc/html/rfc9842#name-dictionary-compressed-zstan
+pub(super) const DCZ_MAGIC: [u8; 8] = [0x5e, 0x2a, 0x4d, 0x18, 0x20, 0x00, 0x00, 0x00];
+
+/// A shared compression dictionary
+///
+/// A dictionary is usually a previous version of the resource being compressed, e.g. the last
+/// release of a versioned JS bundle.
+pub struct CompressionDictionary {
+    data: Bytes,
+    hash: DictionaryHash,
+}
+
+impl CompressionDictionary {
+    /// Create a new dictionary from its raw content.
+    pub fn new(data: Bytes) -> Result<Self> {
+        let digest = hash(MessageDigest::sha256(), &data)
+            .or_err(COMPRESSION_ERROR, "while hashing dictionary")?;
+        let mut hash = [0; DICTIONARY_HASH_LEN];
+        hash.copy_from_slice(&digest);
+        Ok(CompressionDictionary { data, hash })
+    }
+
+    /// The raw content of this dictionary
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The SHA-256 hash of this dictionary, which clients send in `Available-Dictionary`
+    pub fn hash(&self) -> &DictionaryHash {
+        &self.hash
+    }
+
+    // the header of the dictionary compressed stream: the magic number followed by the hash
+    pub(super) fn stream_header(&self, magic: &[u8]) -> Vec<u8> {
+        let mut header = Vec::with_capacity(magic.len() + DICTIONARY_HASH_LEN);
+        header.extend_from_slice(magic);
+        header.extend_from_slice(&self.hash);
+        header
+    }
+}
+
+/// The collection of dictionaries available for compression, looked up by their hashes.
+#[derive(Default)]
+pub struct DictionaryStore {
+    dictionaries: RwLock<HashMap<DictionaryHash, Arc<CompressionDictionary>>>,
+}
+
+impl DictionaryStore {
+    /// Create an empty [DictionaryStore].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a dictionary. Return its hash.
+    pub fn insert(&self, dictionary: CompressionDictionary) -> DictionaryHash {
+        let hash = dictionary.hash;
+        self.dictionaries.write().insert(hash, Arc::new(dictionary));
+        hash
+    }
+
+    /// Remove the dictionary with the given hash, e.g. when a version is retired.
+    pub fn remove(&self, hash: &DictionaryHash) -> Option<Arc<CompressionDictionary>> {
+        self.dictionaries.write().remove(hash)
+    }
+
+    /// Find the dictionary with the given hash.
+    pub fn get(&self, hash: &DictionaryHash) -> Option<Arc<CompressionDictionary>> {
+        self.dictionaries.read().get(hash).cloned()
+    }
+
+    /// Find the dictionary referred to by the `Available-Dictionary` request header.
+    pub fn lookup(&self, available_dictionary: &HeaderValue) -> Option<Arc<CompressionDictionary>> {
+        parse_available_dictionary(available_dictionary).and_then(|h| self.get(&h))
+    }
+
+    /// Return the number of dictionaries in the store.
+    pub fn len(&self) -> usize {
+        self.dictionaries.read().len()
+    }
+
+    /// Whether the store has no dictionaries.
+    pub fn is_empty(&self) -> bool {
+        self.dictionaries.read().is_empty()
+    }
+}
+
+/// Parse the `Available-Dictionary` header, which is a structured field byte sequence of the
+/// SHA-256 hash of the dictionary.
+pub fn parse_available_dictionary(value: &HeaderValue) -> Option<DictionaryHash> {
+    let item = sfv::Parser::parse_item(value.as_bytes()).ok()?;
+    let bytes = item.bare_item.as_byte_seq()?;
+    bytes.as_slice().try_into().ok()
+}
+
+/// Mark the response as a dictionary for future requests whose URL matches `match_pattern`
+/// by setting the `Use-As-Dictionary` header.
+///
+/// `match_pattern` is a URL pattern such as `/js/app.*.js`.
+pub fn set_use_as_dictionary(resp: &mut ResponseHeader, match_pattern: &str) -> Result<()> {
+    // sf-string: escape '"' and '\'
+    let mut value = String::with_capacity(match_pattern.len() + 8);
+    value.push_str("match=\"");
+    for c in match_pattern.chars() {
+        if c == '"' || c == '\\' {
+            value.push('\\');
+        }
+        value.push(c);
+    }
+    value.push('"');
+    resp.insert_header("use-as-dictionary", value)
+}
+
+// the `Available-Dictionary` header value of the given hash, for tests
+#[cfg(test)]
+pub(super) fn available_dictionary_value(hash: &DictionaryHash) -> String {
+    // sf-binary: base64 between colons
+    format!(":{}:", tests::base64_encode(hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dictionary_store() {
+        let store = DictionaryStore::new();
+        let dict = CompressionDictionary::new(Bytes::from_static(b"hello dictionary")).unwrap();
+        let hash = store.insert(dict);
+        assert_eq!(store.len(), 1);
+        assert!(store.get(&hash).is_some());
+
+        let value = available_dictionary_value(&hash);
+        let header = HeaderValue::from_str(&value).unwrap();
+        assert_eq!(parse_available_dictionary(&header), Some(hash));
+        assert!(store.lookup(&header).is_some());
+
+        // wrong length
+        let header = HeaderValue::from_static(":aGVsbG8=:");
+        assert!(parse_available_dictionary(&header).is_none());
+        // not a byte sequence
+        let header = HeaderValue::from_static("\"hello\"");
+        assert!(parse_available_dictionary(&header).is_none());
+
+        assert!(store.remove(&hash).is_some());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_use_as_dictionary() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        set_use_as_dictionary(&mut resp, "/js/app.*.js").unwrap();
+        assert_eq!(
+            resp.headers.get("use-as-dictionary").unwrap(),
+            "match=\"/js/app.*.js\""
+        );
+    }
+
+    // minimal base64 encoder for the tests
+    pub(super) fn base64_encode(input: &[u8]) -> String {
+        const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in input.chunks(3) {
+            let b = [
+                chunk[0],
+                *chunk.get(1).unwrap_or(&0),
+                *chunk.get(2).unwrap_or(&0),
+            ];
+            let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(TABLE[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+}
@@ -12,15 +12,72 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::Encode;
+use super::{Encode, OutputBuf};
 
 use bytes::Bytes;
-use flate2::write::GzEncoder;
+use flate2::write::{GzDecoder, GzEncoder};
 use pingora_error::Result;
 use std::io::Write;
 use std::time::{Duration, Instant};
 
-// TODO: unzip
+pub struct Decompressor {
+    decompress: GzDecoder<OutputBuf>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+}
+
+impl Decompressor {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// Create a decompressor which fails once its total output is larger than `limit` bytes.
+    pub fn with_limit(limit: usize) -> Self {
+        Decompressor {
+            decompress: GzDecoder::new(OutputBuf::new(limit)),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+        }
+    }
+}
+
+impl Encode for Decompressor {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        // reserve at most 4k
+        const MAX_INIT_COMPRESSED_SIZE_CAP: usize = 4 * 1024;
+        // gzip compress ratio is usually between 2.5 and 4
+        const ESTIMATED_COMPRESSION_RATIO: usize = 3;
+        let start = Instant::now();
+        self.total_in += input.len();
+        // cap the buf size amplification, same reason as the brotli decompressor
+        let reserve_size = if input.len() < MAX_INIT_COMPRESSED_SIZE_CAP {
+            input.len() * ESTIMATED_COMPRESSION_RATIO
+        } else {
+            input.len()
+        };
+        self.decompress.get_mut().reserve(reserve_size);
+        self.decompress
+            .write_all(input)
+            .map_err(|e| self.decompress.get_ref().error(e, "while decompress Gzip"))?;
+        // the output buffer only fails when the output is over the limit, otherwise the input
+        // data is invalid (not gzip compressed)
+        if end {
+            self.decompress
+                .try_finish()
+                .map_err(|e| self.decompress.get_ref().error(e, "while decompress Gzip"))?;
+        }
+        self.total_out += self.decompress.get_ref().len();
+        self.duration += start.elapsed();
+        Ok(self.decompress.get_mut().take().into()) // into() Bytes will drop excess capacity
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("de-gzip", self.total_in, self.total_out, self.duration)
+    }
+}
 
 pub struct Compressor {
     // TODO: enum for other compression algorithms
@@ -100,4 +157,27 @@ mod tests_stream {
 
         assert!(compressor.get_ref().is_empty());
     }
+
+    #[test]
+    fn gunzip_data() {
+        let mut compressor = Compressor::new(6);
+        let compressed = compressor.encode(b"abcdefg", true).unwrap();
+
+        let mut decompressor = Decompressor::new();
+        // feed the data in two pieces to make sure the stream state is kept
+        let mut decompressed = decompressor
+            .encode(&compressed[..5], false)
+            .unwrap()
+            .to_vec();
+        decompressed.extend_from_slice(&decompressor.encode(&compressed[5..], true).unwrap());
+        assert_eq!(&decompressed[..], b"abcdefg");
+        assert_eq!(decompressor.total_in, compressed.len());
+        assert_eq!(decompressor.total_out, 7);
+    }
+
+    #[test]
+    fn gunzip_invalid_data() {
+        let mut decompressor = Decompressor::new();
+        assert!(decompressor.encode(b"not gzip at all", true).is_err());
+    }
 }
@@ -12,25 +12,94 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! HTTP response (de)compression libraries
+//! HTTP request and response (de)compression libraries
 //!
-//! Brotli and Gzip and partially supported.
+//! Brotli, Gzip and Zstd are supported, as well as shared dictionary compression (dcb and dcz).
 
 use super::HttpTask;
 
 use bytes::Bytes;
 use log::warn;
-use pingora_error::{ErrorType, Result};
+use pingora_error::{Error, ErrorType, Result};
 use pingora_http::{RequestHeader, ResponseHeader};
+use std::sync::Arc;
 use std::time::Duration;
 
 mod brotli;
+pub mod dictionary;
 mod gzip;
 mod zstd;
 
+use dictionary::{CompressionDictionary, DictionaryStore};
+
 /// The type of error to return when (de)compression fails
 pub const COMPRESSION_ERROR: ErrorType = ErrorType::new("CompressionError");
 
+// The output buffer of the decompressors. Writing more than `limit` bytes in total fails, so that
+// a decompression bomb is stopped as soon as its output passes the limit instead of after the
+// whole chunk is inflated.
+struct OutputBuf {
+    buf: Vec<u8>,
+    limit: usize,
+    remaining: usize,
+    exceeded: bool,
+}
+
+impl OutputBuf {
+    fn new(limit: usize) -> Self {
+        OutputBuf {
+            buf: vec![],
+            limit,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    // reserve the space for the output, but never more than what is allowed
+    fn reserve(&mut self, additional: usize) {
+        self.buf.reserve(additional.min(self.remaining));
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    // the error to return when decompressing into this buffer failed
+    fn error(&self, e: std::io::Error, context: &'static str) -> Box<Error> {
+        if self.exceeded {
+            Error::explain(
+                ErrorType::HTTPStatus(413),
+                format!("decompressed body is larger than {} bytes", self.limit),
+            )
+        } else {
+            Error::because(COMPRESSION_ERROR, context, e)
+        }
+    }
+}
+
+impl std::io::Write for OutputBuf {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if data.len() > self.remaining {
+            self.exceeded = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "decompressed size limit exceeded",
+            ));
+        }
+        self.remaining -= data.len();
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 /// The trait for both compress and decompress because the interface and syntax are the same:
 /// encode some bytes to other bytes
 pub trait Encode {
@@ -58,7 +127,7 @@ pub trait Encode {
 ///
 /// # Currently supported algorithms and actions
 /// - Brotli decompression: if the response is br compressed, this ctx can decompress it
-/// - Gzip compression: if the response is uncompressed, this ctx can compress it with gzip
+/// - Gzip, Brotli and Zstd compression: if the response is uncompressed, this ctx can compress it
 pub struct ResponseCompressionCtx(CtxInner);
 
 enum CtxInner {
@@ -67,6 +136,10 @@ enum CtxInner {
         decompress_enable: bool,
         // Store the preferred list to compare with content-encoding
         accept_encoding: Vec<Algorithm>,
+        // The dictionaries available for dcb and dcz
+        dictionaries: Option<Arc<DictionaryStore>>,
+        // The dictionary the request has and we have too
+        dictionary: Option<Arc<CompressionDictionary>>,
     },
     BodyPhase(Option<Box<dyn Encode + Send + Sync>>),
 }
@@ -80,6 +153,8 @@ impl ResponseCompressionCtx {
             compression_level,
             decompress_enable,
             accept_encoding: Vec::new(),
+            dictionaries: None,
+            dictionary: None,
         })
     }
 
@@ -91,6 +166,8 @@ impl ResponseCompressionCtx {
                 compression_level,
                 decompress_enable,
                 accept_encoding: _,
+                dictionaries: _,
+                dictionary: _,
             } => *compression_level != 0 || *decompress_enable,
             CtxInner::BodyPhase(c) => c.is_some(),
         }
@@ -104,6 +181,8 @@ impl ResponseCompressionCtx {
                 compression_level: _,
                 decompress_enable: _,
                 accept_encoding: _,
+                dictionaries: _,
+                dictionary: _,
             } => None,
             CtxInner::BodyPhase(c) => c.as_ref().map(|c| c.stat()),
         }
@@ -118,6 +197,8 @@ impl ResponseCompressionCtx {
                 compression_level,
                 decompress_enable: _,
                 accept_encoding: _,
+                dictionaries: _,
+                dictionary: _,
             } => {
                 *compression_level = new_level;
             }
@@ -134,6 +215,8 @@ impl ResponseCompressionCtx {
                 compression_level: _,
                 decompress_enable,
                 accept_encoding: _,
+                dictionaries: _,
+                dictionary: _,
             } => {
                 *decompress_enable = enabled;
             }
@@ -141,6 +224,27 @@ impl ResponseCompressionCtx {
         }
     }
 
+    /// Enable the shared dictionary compression (`dcb` and `dcz`) with the given dictionaries.
+    ///
+    /// When the request advertises a dictionary in the store via `Available-Dictionary` and
+    /// accepts `dcb` or `dcz`, the response will be compressed with that dictionary.
+    /// # Panic
+    /// This function will panic if it has already started encoding the response body.
+    pub fn adjust_dictionaries(&mut self, store: Arc<DictionaryStore>) {
+        match &mut self.0 {
+            CtxInner::HeaderPhase {
+                compression_level: _,
+                decompress_enable: _,
+                accept_encoding: _,
+                dictionaries,
+                dictionary: _,
+            } => {
+                *dictionaries = Some(store);
+            }
+            CtxInner::BodyPhase(_) => panic!("Wrong phase: BodyPhase"),
+        }
+    }
+
     /// Feed the request header into this ctx.
     pub fn request_filter(&mut self, req: &RequestHeader) {
         if !self.is_enabled() {
@@ -151,10 +255,9 @@ impl ResponseCompressionCtx {
                 compression_level: _,
                 decompress_enable: _,
                 accept_encoding,
-            } => parse_accept_encoding(
-                req.headers.get(http::header::ACCEPT_ENCODING),
-                accept_encoding,
-            ),
+                dictionaries,
+                dictionary,
+            } => *dictionary = negotiate_encoding(req, dictionaries.as_deref(), accept_encoding),
             CtxInner::BodyPhase(_) => panic!("Wrong phase: BodyPhase"),
         }
     }
@@ -169,6 +272,8 @@ impl ResponseCompressionCtx {
                 compression_level,
                 decompress_enable,
                 accept_encoding,
+                dictionaries: _,
+                dictionary,
             } => {
                 if resp.status.is_informational() {
                     if resp.status == http::status::StatusCode::SWITCHING_PROTOCOLS {
@@ -187,8 +292,15 @@ impl ResponseCompressionCtx {
                 let action = decide_action(resp, accept_encoding);
                 let encoder = match action {
                     Action::Noop => None,
+                    Action::Compress(algorithm) if algorithm.needs_dictionary() => dictionary
+                        .as_ref()
+                        .and_then(|d| algorithm.dictionary_compressor(*compression_level, d)),
                     Action::Compress(algorithm) => algorithm.compressor(*compression_level),
-                    Action::Decompress(algorithm) => algorithm.decompressor(*decompress_enable),
+                    // only brotli is decompressed for the downstream that doesn't accept it
+                    Action::Decompress(Algorithm::Brotli) => {
+                        Algorithm::Brotli.decompressor(*decompress_enable)
+                    }
+                    Action::Decompress(_) => None,
                 };
                 if encoder.is_some() {
                     adjust_response_header(resp, &action);
@@ -208,6 +320,8 @@ impl ResponseCompressionCtx {
                 compression_level: _,
                 decompress_enable: _,
                 accept_encoding: _,
+                dictionaries: _,
+                dictionary: _,
             } => panic!("Wrong phase: HeaderPhase"),
             CtxInner::BodyPhase(compressor) => {
                 let result = compressor
@@ -257,28 +371,260 @@ impl ResponseCompressionCtx {
     }
 }
 
+/// The request compression object. It (de)compresses the request body sent to the upstream.
+///
+/// To use it, the caller should create a [`RequestCompressionCtx`] per HTTP session and call the
+/// corresponding filters for the request header and request body.
+///
+/// If decompression is enabled and the incoming request body is encoded with one of the supported
+/// algorithms, the body will be decompressed and the `content-encoding` header removed. The size of
+/// the decompressed body is capped to protect the upstream from decompression bombs.
+///
+/// If compression is enabled and the incoming request body is not encoded, the body will be
+/// compressed with the configured algorithm.
+///
+/// Because the size of the transcoded body is not known ahead, `content-length` is replaced by
+/// chunked encoding in the request header sent to the upstream, see
+/// [`Self::upstream_request_filter()`]. The downstream request header is left untouched so that the
+/// request body is still read according to its original framing. Only requests that declare a
+/// body via `content-length` or `transfer-encoding` are compressed.
+pub struct RequestCompressionCtx(ReqCtxInner);
+
+enum ReqCtxInner {
+    HeaderPhase {
+        compression: Option<(Algorithm, u32)>,
+        decompress_enable: bool,
+        max_decompressed_size: usize,
+    },
+    BodyPhase {
+        encoder: Option<Box<dyn Encode + Send + Sync>>,
+        // how to adjust the upstream request header
+        action: Action,
+    },
+}
+
+impl RequestCompressionCtx {
+    /// Create a new [`RequestCompressionCtx`].
+    ///
+    /// The `decompress_enable` flag will tell the ctx to decompress if needed. The decompressed
+    /// request body cannot exceed `max_decompressed_size` bytes.
+    /// Compression is disabled by default, see [`Self::adjust_compression()`].
+    pub fn new(decompress_enable: bool, max_decompressed_size: usize) -> Self {
+        Self(ReqCtxInner::HeaderPhase {
+            compression: None,
+            decompress_enable,
+            max_decompressed_size,
+        })
+    }
+
+    /// Whether the encoder is enabled.
+    /// The enablement will change according to the request header filtered by this ctx.
+    pub fn is_enabled(&self) -> bool {
+        match &self.0 {
+            ReqCtxInner::HeaderPhase {
+                compression,
+                decompress_enable,
+                max_decompressed_size: _,
+            } => compression.is_some() || *decompress_enable,
+            ReqCtxInner::BodyPhase { encoder, .. } => encoder.is_some(),
+        }
+    }
+
+    /// Return the stat of this ctx:
+    /// algorithm name, in bytes, out bytes, time took for the (de)compression
+    pub fn get_info(&self) -> Option<(&'static str, usize, usize, Duration)> {
+        match &self.0 {
+            ReqCtxInner::HeaderPhase { .. } => None,
+            ReqCtxInner::BodyPhase { encoder, .. } => encoder.as_ref().map(|c| c.stat()),
+        }
+    }
+
+    /// Compress uncompressed request bodies with the given algorithm and level. `0` will disable
+    /// the compression.
+    /// # Panic
+    /// This function will panic if it has already started encoding the request body.
+    pub fn adjust_compression(&mut self, algorithm: Algorithm, level: u32) {
+        match &mut self.0 {
+            ReqCtxInner::HeaderPhase { compression, .. } => {
+                *compression = (level != 0).then_some((algorithm, level));
+            }
+            ReqCtxInner::BodyPhase { .. } => panic!("Wrong phase: BodyPhase"),
+        }
+    }
+
+    /// Adjust the decompression flag.
+    /// # Panic
+    /// This function will panic if it has already started encoding the request body.
+    pub fn adjust_decompression(&mut self, enabled: bool) {
+        match &mut self.0 {
+            ReqCtxInner::HeaderPhase {
+                decompress_enable, ..
+            } => {
+                *decompress_enable = enabled;
+            }
+            ReqCtxInner::BodyPhase { .. } => panic!("Wrong phase: BodyPhase"),
+        }
+    }
+
+    /// Adjust the maximum size of the decompressed request body.
+    /// # Panic
+    /// This function will panic if it has already started encoding the request body.
+    pub fn adjust_max_decompressed_size(&mut self, size: usize) {
+        match &mut self.0 {
+            ReqCtxInner::HeaderPhase {
+                max_decompressed_size,
+                ..
+            } => {
+                *max_decompressed_size = size;
+            }
+            ReqCtxInner::BodyPhase { .. } => panic!("Wrong phase: BodyPhase"),
+        }
+    }
+
+    /// Feed the request header into this ctx.
+    /// The downstream request header is not modified, see [`Self::upstream_request_filter()`].
+    pub fn request_header_filter(&mut self, req: &RequestHeader) {
+        if !self.is_enabled() {
+            self.0 = ReqCtxInner::BodyPhase {
+                encoder: None,
+                action: Action::Noop,
+            };
+            return;
+        }
+        match &self.0 {
+            ReqCtxInner::HeaderPhase {
+                compression,
+                decompress_enable,
+                max_decompressed_size,
+            } => {
+                use http::header::CONTENT_ENCODING;
+
+                let content_encoding = parse_content_encoding(req.headers.get(CONTENT_ENCODING));
+                let (encoder, action) = match (content_encoding, compression) {
+                    (Some(algorithm), _) => match algorithm
+                        .decompressor_with_limit(*decompress_enable, *max_decompressed_size)
+                    {
+                        Some(decoder) => (Some(decoder), Action::Decompress(algorithm)),
+                        None => (None, Action::Noop),
+                    },
+                    (None, Some((algorithm, level))) if request_has_body(req) => {
+                        match algorithm.compressor(*level) {
+                            Some(encoder) => (Some(encoder), Action::Compress(*algorithm)),
+                            None => (None, Action::Noop),
+                        }
+                    }
+                    _ => (None, Action::Noop),
+                };
+                self.0 = ReqCtxInner::BodyPhase { encoder, action };
+            }
+            ReqCtxInner::BodyPhase { .. } => panic!("Wrong phase: BodyPhase"),
+        }
+    }
+
+    /// Adjust the request header sent to the upstream according to the transcoding decided by
+    /// [`Self::request_header_filter()`]: `content-encoding` is updated and `content-length` is
+    /// replaced by chunked encoding.
+    /// # Panic
+    /// This function will panic if the request header is not filtered yet.
+    pub fn upstream_request_filter(&self, req: &mut RequestHeader) {
+        match &self.0 {
+            ReqCtxInner::HeaderPhase { .. } => panic!("Wrong phase: HeaderPhase"),
+            ReqCtxInner::BodyPhase { action, .. } => adjust_request_header(req, action),
+        }
+    }
+
+    /// Stream the request body chunks into this ctx. The return value will be the transcoded data.
+    ///
+    /// Return `Ok(None)` if the transcoding is not enabled. Unlike responses, a request body
+    /// cannot be passed through once the header is adjusted, so invalid input and oversized
+    /// output are returned as errors.
+    pub fn request_body_filter(
+        &mut self,
+        data: Option<&Bytes>,
+        end: bool,
+    ) -> Result<Option<Bytes>> {
+        match &mut self.0 {
+            ReqCtxInner::HeaderPhase { .. } => panic!("Wrong phase: HeaderPhase"),
+            ReqCtxInner::BodyPhase { encoder, .. } => {
+                let Some(encoder) = encoder.as_mut() else {
+                    return Ok(None);
+                };
+                // Feed even empty slice to encoder because it might yield data when `end` is true
+                let data = if let Some(b) = data { b.as_ref() } else { &[] };
+                // the decompressor fails as soon as its output passes the limit
+                let output = encoder.encode(data, end).map_err(|e| e.into_down())?;
+                Ok(Some(output))
+            }
+        }
+    }
+}
+
+// whether the request declares a body
+fn request_has_body(req: &RequestHeader) -> bool {
+    use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+
+    if req.headers.get(TRANSFER_ENCODING).is_some() {
+        return true;
+    }
+    req.headers
+        .get(CONTENT_LENGTH)
+        .and_then(|cl| std::str::from_utf8(cl.as_bytes()).ok())
+        .and_then(|cl| cl.parse::<usize>().ok())
+        .map_or(false, |cl| cl > 0)
+}
+
+fn adjust_request_header(req: &mut RequestHeader, action: &Action) {
+    use http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+
+    match action {
+        Action::Noop => return,
+        Action::Decompress(_) => {
+            req.remove_header(&CONTENT_ENCODING);
+        }
+        Action::Compress(a) => {
+            req.insert_header(&CONTENT_ENCODING, HeaderValue::from_static(a.as_str()))
+                .unwrap();
+        }
+    }
+    // because the transcoding is streamed, content length is not known ahead
+    req.remove_header(&CONTENT_LENGTH);
+    // h2 streams are delimited by frames, h1 needs chunked encoding
+    if req.version != http::Version::HTTP_2 {
+        req.insert_header(&TRANSFER_ENCODING, HeaderValue::from_static("chunked"))
+            .unwrap();
+    }
+}
+
+/// The content coding algorithms known to this module
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum Algorithm {
+pub enum Algorithm {
     Any, // the "*"
     Gzip,
     Brotli,
     Zstd,
+    DictionaryBrotli, // dcb
+    DictionaryZstd,   // dcz
     // TODO: Identity,
     // TODO: Deflate
     Other, // anything unknown
 }
 
 impl Algorithm {
+    /// The name of the algorithm as it appears in the `Content-Encoding` header
     pub fn as_str(&self) -> &'static str {
         match self {
             Algorithm::Gzip => "gzip",
             Algorithm::Brotli => "br",
             Algorithm::Zstd => "zstd",
+            Algorithm::DictionaryBrotli => "dcb",
+            Algorithm::DictionaryZstd => "dcz",
             Algorithm::Any => "*",
             Algorithm::Other => "other",
         }
     }
 
+    /// Return the compressor of this algorithm at the given level, `None` if the level is 0 or
+    /// the algorithm is not supported.
     pub fn compressor(&self, level: u32) -> Option<Box<dyn Encode + Send + Sync>> {
         if level == 0 {
             None
@@ -292,12 +638,52 @@ impl Algorithm {
         }
     }
 
+    /// Whether this algorithm compresses with a shared dictionary
+    pub fn needs_dictionary(&self) -> bool {
+        matches!(self, Self::DictionaryBrotli | Self::DictionaryZstd)
+    }
+
+    /// Return the compressor of this shared dictionary algorithm at the given level, `None` if
+    /// the level is 0 or the algorithm doesn't use a dictionary.
+    pub fn dictionary_compressor(
+        &self,
+        level: u32,
+        dictionary: &CompressionDictionary,
+    ) -> Option<Box<dyn Encode + Send + Sync>> {
+        if level == 0 {
+            None
+        } else {
+            match self {
+                Self::DictionaryBrotli => Some(Box::new(brotli::DictionaryCompressor::new(
+                    level, dictionary,
+                ))),
+                Self::DictionaryZstd => Some(Box::new(zstd::Compressor::with_dictionary(
+                    level, dictionary,
+                ))),
+                _ => None,
+            }
+        }
+    }
+
+    /// Return the decompressor of this algorithm, `None` if not `enabled` or the algorithm is
+    /// not supported.
     pub fn decompressor(&self, enabled: bool) -> Option<Box<dyn Encode + Send + Sync>> {
+        self.decompressor_with_limit(enabled, usize::MAX)
+    }
+
+    // the decompressor which fails once its total output is larger than `limit` bytes
+    fn decompressor_with_limit(
+        &self,
+        enabled: bool,
+        limit: usize,
+    ) -> Option<Box<dyn Encode + Send + Sync>> {
         if !enabled {
             None
         } else {
             match self {
-                Self::Brotli => Some(Box::new(brotli::Decompressor::new())),
+                Self::Gzip => Some(Box::new(gzip::Decompressor::with_limit(limit))),
+                Self::Brotli => Some(Box::new(brotli::Decompressor::with_limit(limit))),
+                Self::Zstd => Some(Box::new(zstd::Decompressor::with_limit(limit))),
                 _ => None, // not implemented
             }
         }
@@ -315,6 +701,10 @@ impl From<&str> for Algorithm {
             Algorithm::Brotli
         } else if coding == UniCase::ascii("zstd") {
             Algorithm::Zstd
+        } else if coding == UniCase::ascii("dcb") {
+            Algorithm::DictionaryBrotli
+        } else if coding == UniCase::ascii("dcz") {
+            Algorithm::DictionaryZstd
         } else if s.is_empty() {
             Algorithm::Any
         } else {
@@ -395,22 +785,69 @@ fn test_accept_encoding_req_header() {
     assert_eq!(ac_list[1], Algorithm::Gzip);
 }
 
-// filter response header to see if (de)compression is needed
-fn decide_action(resp: &ResponseHeader, accept_encoding: &[Algorithm]) -> Action {
-    use http::header::CONTENT_ENCODING;
+// parse Accept-Encoding and pick the dictionary to use, if any.
+// Dictionary compression is preferred when the request has a dictionary that we have as well,
+// otherwise dcb and dcz are removed from the list.
+fn negotiate_encoding(
+    req: &RequestHeader,
+    dictionaries: Option<&DictionaryStore>,
+    accept_encoding: &mut Vec<Algorithm>,
+) -> Option<Arc<CompressionDictionary>> {
+    parse_accept_encoding(
+        req.headers.get(http::header::ACCEPT_ENCODING),
+        accept_encoding,
+    );
+    let dictionary = dictionaries.and_then(|store| {
+        req.headers
+            .get("available-dictionary")
+            .and_then(|v| store.lookup(v))
+    });
+    if dictionary.is_some() {
+        // stable sort, the client's order is kept otherwise
+        accept_encoding.sort_by_key(|a| !a.needs_dictionary());
+    } else {
+        accept_encoding.retain(|a| !a.needs_dictionary());
+    }
+    dictionary
+}
 
-    let content_encoding = if let Some(ce) = resp.headers.get(CONTENT_ENCODING) {
-        // https://www.rfc-editor.org/rfc/rfc9110#name-content-encoding
+/// Return the algorithm that a [ResponseCompressionCtx] would use to compress the response to
+/// this request, `None` if it would not be compressed.
+///
+/// This is used as the cache variance so that each encoding of a response is stored as its own
+/// pre-compressed variant and served without compressing it again, see `add_encoding()` of the
+/// `VarianceBuilder` of `pingora-cache`.
+pub fn preferred_encoding(
+    req: &RequestHeader,
+    dictionaries: Option<&DictionaryStore>,
+) -> Option<Algorithm> {
+    let mut accept_encoding = Vec::new();
+    negotiate_encoding(req, dictionaries, &mut accept_encoding);
+    accept_encoding
+        .first()
+        .copied()
+        .filter(|a| *a != Algorithm::Any)
+}
+
+/// Parse the `Content-Encoding` header value.
+///
+/// Return `None` if there is no such header. Any coding that is not understood is returned as
+/// [Algorithm::Other].
+pub fn parse_content_encoding(content_encoding: Option<&http::HeaderValue>) -> Option<Algorithm> {
+    // https://www.rfc-editor.org/rfc/rfc9110#name-content-encoding
+    content_encoding.map(|ce| {
         if let Ok(ce_str) = std::str::from_utf8(ce.as_bytes()) {
-            Some(Algorithm::from(ce_str))
+            Algorithm::from(ce_str)
         } else {
             // not utf-8, treat it as unknown encoding to leave it untouched
-            Some(Algorithm::Other)
+            Algorithm::Other
         }
-    } else {
-        // no Accept-encoding
-        None
-    };
+    })
+}
+
+// filter response header to see if (de)compression is needed
+fn decide_action(resp: &ResponseHeader, accept_encoding: &[Algorithm]) -> Action {
+    let content_encoding = parse_content_encoding(resp.headers.get(http::header::CONTENT_ENCODING));
 
     if let Some(ce) = content_encoding {
         if accept_encoding.contains(&ce) {
@@ -548,7 +985,7 @@ fn compressible(resp: &ResponseHeader) -> bool {
 }
 
 fn adjust_response_header(resp: &mut ResponseHeader, action: &Action) {
-    use http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+    use http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING, VARY};
 
     fn set_stream_headers(resp: &mut ResponseHeader) {
         // because the transcoding is streamed, content length is not known ahead
@@ -567,6 +1004,13 @@ fn adjust_response_header(resp: &mut ResponseHeader, action: &Action) {
         Action::Compress(a) => {
             resp.insert_header(&CONTENT_ENCODING, HeaderValue::from_static(a.as_str()))
                 .unwrap();
+            if a.needs_dictionary() {
+                // https://datatracker.ietf.org/doc/html/rfc9842#name-vary
+                resp.append_header(&VARY, HeaderValue::from_static("accept-encoding"))
+                    .unwrap();
+                resp.append_header(&VARY, HeaderValue::from_static("available-dictionary"))
+                    .unwrap();
+            }
             set_stream_headers(resp)
         }
     }
@@ -618,3 +1062,225 @@ fn test_adjust_response_header() {
         b"chunked"
     );
 }
+
+#[test]
+fn test_request_compression_ctx() {
+    // decompress
+    let mut ctx = RequestCompressionCtx::new(true, 1024);
+    let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+    req.insert_header("content-encoding", "gzip").unwrap();
+    req.insert_header("content-length", "20").unwrap();
+    ctx.request_header_filter(&req);
+    assert!(ctx.is_enabled());
+    // the downstream request is read as is
+    assert_eq!(req.headers.get("content-length").unwrap().as_bytes(), b"20");
+    let mut upstream_req = req.clone();
+    ctx.upstream_request_filter(&mut upstream_req);
+    assert!(upstream_req.headers.get("content-encoding").is_none());
+    assert!(upstream_req.headers.get("content-length").is_none());
+    assert_eq!(
+        upstream_req
+            .headers
+            .get("transfer-encoding")
+            .unwrap()
+            .as_bytes(),
+        b"chunked"
+    );
+    let compressed = Algorithm::Gzip
+        .compressor(6)
+        .unwrap()
+        .encode(b"abcdefg", true)
+        .unwrap();
+    let body = ctx.request_body_filter(Some(&compressed), true).unwrap();
+    assert_eq!(body.unwrap(), &b"abcdefg"[..]);
+
+    // compress
+    let mut ctx = RequestCompressionCtx::new(false, 1024);
+    ctx.adjust_compression(Algorithm::Zstd, 3);
+    let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+    req.insert_header("content-length", "7").unwrap();
+    ctx.request_header_filter(&req);
+    assert!(req.headers.get("content-encoding").is_none());
+    ctx.upstream_request_filter(&mut req);
+    assert_eq!(
+        req.headers.get("content-encoding").unwrap().as_bytes(),
+        b"zstd"
+    );
+    let body = ctx
+        .request_body_filter(Some(&Bytes::from_static(b"abcdefg")), true)
+        .unwrap()
+        .unwrap();
+    assert_eq!(&body[..4], &[0x28, 0xB5, 0x2F, 0xFD]);
+
+    // no body to compress
+    let mut ctx = RequestCompressionCtx::new(false, 1024);
+    ctx.adjust_compression(Algorithm::Gzip, 6);
+    let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+    ctx.request_header_filter(&req);
+    ctx.upstream_request_filter(&mut req);
+    assert!(!ctx.is_enabled());
+    assert!(req.headers.get("content-encoding").is_none());
+    assert!(ctx.request_body_filter(None, true).unwrap().is_none());
+
+    // already compressed, decompression disabled
+    let mut ctx = RequestCompressionCtx::new(false, 1024);
+    ctx.adjust_compression(Algorithm::Gzip, 6);
+    let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+    req.insert_header("content-encoding", "br").unwrap();
+    req.insert_header("content-length", "20").unwrap();
+    ctx.request_header_filter(&req);
+    ctx.upstream_request_filter(&mut req);
+    assert!(!ctx.is_enabled());
+    assert_eq!(req.headers.get("content-length").unwrap().as_bytes(), b"20");
+    assert_eq!(
+        req.headers.get("content-encoding").unwrap().as_bytes(),
+        b"br"
+    );
+}
+
+#[test]
+fn test_request_decompression_limit() {
+    use std::io::Write;
+
+    let mut ctx = RequestCompressionCtx::new(true, 100);
+    let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+    req.insert_header("content-encoding", "gzip").unwrap();
+    req.insert_header("transfer-encoding", "chunked").unwrap();
+    ctx.request_header_filter(&req);
+
+    let compressed = Algorithm::Gzip
+        .compressor(6)
+        .unwrap()
+        .encode(&[b'a'; 1000], true)
+        .unwrap();
+    let e = ctx
+        .request_body_filter(Some(&compressed), true)
+        .unwrap_err();
+    assert_eq!(e.etype(), &ErrorType::HTTPStatus(413));
+
+    // a highly compressible payload is stopped as soon as the output passes the limit
+    let bomb = vec![0u8; 16 * 1024 * 1024];
+    for algorithm in [Algorithm::Gzip, Algorithm::Brotli, Algorithm::Zstd] {
+        let compressed = algorithm
+            .compressor(1)
+            .unwrap()
+            .encode(&bomb, true)
+            .unwrap();
+        assert!(compressed.len() < bomb.len() / 100);
+        let mut decompressor = algorithm.decompressor_with_limit(true, 64 * 1024).unwrap();
+        let e = decompressor.encode(&compressed, true).unwrap_err();
+        assert_eq!(e.etype(), &ErrorType::HTTPStatus(413));
+    }
+    let mut output = OutputBuf::new(10);
+    output.write_all(b"12345").unwrap();
+    output.write_all(b"12345").unwrap();
+    let e = output.write_all(b"1").unwrap_err();
+    assert_eq!(output.len(), 10);
+    assert_eq!(output.error(e, "").etype(), &ErrorType::HTTPStatus(413));
+
+    // invalid data
+    let mut ctx = RequestCompressionCtx::new(true, 100);
+    let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+    req.insert_header("content-encoding", "gzip").unwrap();
+    req.insert_header("content-length", "8").unwrap();
+    ctx.request_header_filter(&req);
+    let e = ctx
+        .request_body_filter(Some(&Bytes::from_static(b"not gzip")), true)
+        .unwrap_err();
+    assert_eq!(e.etype(), &COMPRESSION_ERROR);
+}
+
+#[test]
+fn test_preferred_encoding() {
+    let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+    assert_eq!(preferred_encoding(&req, None), None);
+    req.insert_header("accept-encoding", "*").unwrap();
+    assert_eq!(preferred_encoding(&req, None), None);
+    req.insert_header("accept-encoding", "br, gzip").unwrap();
+    assert_eq!(preferred_encoding(&req, None), Some(Algorithm::Brotli));
+
+    // no dictionary, dcb and dcz are ignored
+    req.insert_header("accept-encoding", "dcz, gzip, dcb")
+        .unwrap();
+    assert_eq!(preferred_encoding(&req, None), Some(Algorithm::Gzip));
+
+    let store = DictionaryStore::new();
+    let dict = CompressionDictionary::new(Bytes::from_static(b"dictionary")).unwrap();
+    let hash = store.insert(dict);
+    // unknown dictionary
+    let unknown = dictionary::available_dictionary_value(&[0; dictionary::DICTIONARY_HASH_LEN]);
+    req.insert_header("available-dictionary", unknown).unwrap();
+    assert_eq!(
+        preferred_encoding(&req, Some(&store)),
+        Some(Algorithm::Gzip)
+    );
+
+    // known dictionary, dictionary compression goes first
+    let value = dictionary::available_dictionary_value(&hash);
+    req.insert_header("available-dictionary", value).unwrap();
+    req.insert_header("accept-encoding", "gzip, dcb, dcz")
+        .unwrap();
+    assert_eq!(
+        preferred_encoding(&req, Some(&store)),
+        Some(Algorithm::DictionaryBrotli)
+    );
+}
+
+#[test]
+fn test_dictionary_compression_ctx() {
+    let store = Arc::new(DictionaryStore::new());
+    let dict = CompressionDictionary::new(Bytes::from_static(b"<html>hello</html>")).unwrap();
+    let hash = store.insert(dict);
+
+    let mut ctx = ResponseCompressionCtx::new(6, false);
+    ctx.adjust_dictionaries(store);
+    let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+    req.insert_header("accept-encoding", "gzip, br, zstd, dcb, dcz")
+        .unwrap();
+    let value = dictionary::available_dictionary_value(&hash);
+    req.insert_header("available-dictionary", value).unwrap();
+    ctx.request_filter(&req);
+
+    let mut resp = ResponseHeader::build(200, None).unwrap();
+    resp.insert_header("content-type", "text/html").unwrap();
+    ctx.response_header_filter(&mut resp, false);
+    assert_eq!(
+        resp.headers.get("content-encoding").unwrap().as_bytes(),
+        b"dcb"
+    );
+    let vary: Vec<_> = resp
+        .headers
+        .get_all("vary")
+        .iter()
+        .map(|v| v.as_bytes())
+        .collect();
+    assert_eq!(vary, [&b"accept-encoding"[..], b"available-dictionary"]);
+
+    let body = ctx
+        .response_body_filter(Some(&Bytes::from_static(b"<html>hello!</html>")), true)
+        .unwrap();
+    assert_eq!(&body[4..36], &hash);
+    assert_eq!(ctx.get_info().unwrap().0, "dcb");
+}
+
+#[test]
+fn test_response_decompression_brotli_only() {
+    for algorithm in [Algorithm::Gzip, Algorithm::Zstd] {
+        // the downstream doesn't accept the encoding, the response is left untouched
+        let mut ctx = ResponseCompressionCtx::new(0, true);
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        ctx.request_filter(&req);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-type", "text/html").unwrap();
+        resp.insert_header("content-encoding", algorithm.as_str())
+            .unwrap();
+        resp.insert_header("content-length", "10").unwrap();
+        ctx.response_header_filter(&mut resp, false);
+        assert!(!ctx.is_enabled());
+        assert_eq!(
+            resp.headers.get("content-encoding").unwrap().as_bytes(),
+            algorithm.as_str().as_bytes()
+        );
+        assert_eq!(resp.headers.get("content-length").unwrap(), "10");
+    }
+}
@@ -12,16 +12,82 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{Encode, COMPRESSION_ERROR};
+use super::dictionary::{CompressionDictionary, DCZ_MAGIC};
+use super::{Encode, OutputBuf, COMPRESSION_ERROR};
 use bytes::Bytes;
 use parking_lot::Mutex;
 use pingora_error::{OrErr, Result};
 use std::io::Write;
 use std::time::{Duration, Instant};
+use zstd::stream::raw::Decoder;
 use zstd::stream::write::Encoder;
+use zstd::stream::zio::Writer;
+
+pub struct Decompressor {
+    // the raw writer because the Decoder wrapper can't tell an incomplete frame at the end
+    decompress: Mutex<Writer<OutputBuf, Decoder<'static>>>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+}
+
+impl Decompressor {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// Create a decompressor which fails once its total output is larger than `limit` bytes.
+    pub fn with_limit(limit: usize) -> Self {
+        Decompressor {
+            // Mutex because Decoder is not Sync, same as the Encoder below
+            decompress: Mutex::new(Writer::new(OutputBuf::new(limit), Decoder::new().unwrap())),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+        }
+    }
+}
+
+impl Encode for Decompressor {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        // reserve at most 4k
+        const MAX_INIT_COMPRESSED_SIZE_CAP: usize = 4 * 1024;
+        // zstd compress ratio is usually between 3 and 4
+        const ESTIMATED_COMPRESSION_RATIO: usize = 3;
+        let start = Instant::now();
+        self.total_in += input.len();
+        let mut decompress = self.decompress.lock();
+        // cap the buf size amplification, same reason as the brotli decompressor
+        let reserve_size = if input.len() < MAX_INIT_COMPRESSED_SIZE_CAP {
+            input.len() * ESTIMATED_COMPRESSION_RATIO
+        } else {
+            input.len()
+        };
+        decompress.writer_mut().reserve(reserve_size);
+        decompress
+            .write_all(input)
+            .map_err(|e| decompress.writer().error(e, "while decompress zstd"))?;
+        // the output buffer only fails when the output is over the limit, otherwise the input
+        // data is invalid (not zstd compressed) or, at the end, truncated
+        if end {
+            decompress
+                .finish()
+                .map_err(|e| decompress.writer().error(e, "while decompress zstd"))?;
+        }
+        self.total_out += decompress.writer().len();
+        self.duration += start.elapsed();
+        Ok(decompress.writer_mut().take().into()) // into() Bytes will drop excess capacity
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("de-zstd", self.total_in, self.total_out, self.duration)
+    }
+}
 
 pub struct Compressor {
     compress: Mutex<Encoder<'static, Vec<u8>>>,
+    name: &'static str,
     total_in: usize,
     total_out: usize,
     duration: Duration,
@@ -33,6 +99,29 @@ impl Compressor {
             // Mutex because Encoder is not Sync
             // https://github.com/gyscos/zstd-rs/issues/186
             compress: Mutex::new(Encoder::new(vec![], level as i32).unwrap()),
+            name: "zstd",
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::new(0, 0),
+        }
+    }
+
+    /// Create a `dcz` compressor: zstd with the dictionary as the raw prefix of the content.
+    pub fn with_dictionary(level: u32, dictionary: &CompressionDictionary) -> Self {
+        // The dcz stream header goes out before the zstd frame.
+        let header = dictionary.stream_header(&DCZ_MAGIC);
+        let mut encoder =
+            Encoder::with_dictionary(header, level as i32, dictionary.data()).unwrap();
+        // The window needs to cover the dictionary. Decoders support at least 8MB and at most
+        // 128MB windows for dcz.
+        let window_log = (dictionary.data().len() * 5 / 4)
+            .next_power_of_two()
+            .trailing_zeros()
+            .clamp(23, 27);
+        encoder.window_log(window_log).unwrap();
+        Compressor {
+            compress: Mutex::new(encoder),
+            name: "dcz",
             total_in: 0,
             total_out: 0,
             duration: Duration::new(0, 0),
@@ -66,7 +155,7 @@ impl Encode for Compressor {
     }
 
     fn stat(&self) -> (&'static str, usize, usize, Duration) {
-        ("zstd", self.total_in, self.total_out, self.duration)
+        (self.name, self.total_in, self.total_out, self.duration)
     }
 }
 
@@ -88,4 +177,51 @@ mod tests_stream {
         assert_eq!(&compressed[..4], &[0x28, 0xB5, 0x2F, 0xFD]);
         assert!(compressed.len() < input.len());
     }
+
+    #[test]
+    fn compress_zstd_dictionary_data() {
+        let dict = CompressionDictionary::new(Bytes::from_static(
+            b"adcdefgabcdefghadcdefgabcdefghadcdefgabcdefghadcdefgabcdefgh\n",
+        ))
+        .unwrap();
+        let mut compressor = Compressor::with_dictionary(3, &dict);
+        let input = b"adcdefgabcdefghadcdefgabcdefghadcdefgabcdefghadcdefgabcdefgh\n";
+        let compressed = compressor.encode(&input[..], true).unwrap();
+
+        // the dcz header followed by the zstd frame
+        assert_eq!(&compressed[..8], &DCZ_MAGIC);
+        assert_eq!(&compressed[8..40], dict.hash());
+        assert_eq!(&compressed[40..44], &[0x28, 0xB5, 0x2F, 0xFD]);
+
+        let mut decoder =
+            zstd::stream::write::Decoder::with_dictionary(vec![], dict.data()).unwrap();
+        decoder.write_all(&compressed[40..]).unwrap();
+        decoder.flush().unwrap();
+        assert_eq!(&decoder.get_ref()[..], &input[..]);
+        assert_eq!(compressor.stat().0, "dcz");
+    }
+
+    #[test]
+    fn decompress_zstd_data() {
+        let mut compressor = Compressor::new(11);
+        let input = b"adcdefgabcdefghadcdefgabcdefghadcdefgabcdefghadcdefgabcdefgh\n";
+        let compressed = compressor.encode(&input[..], true).unwrap();
+
+        let mut decompressor = Decompressor::new();
+        let decompressed = decompressor.encode(&compressed[..], true).unwrap();
+        assert_eq!(&decompressed[..], &input[..]);
+    }
+
+    #[test]
+    fn decompress_zstd_truncated_data() {
+        let mut compressor = Compressor::new(11);
+        let input = b"adcdefgabcdefghadcdefgabcdefghadcdefgabcdefghadcdefgabcdefgh\n";
+        let compressed = compressor.encode(&input[..], true).unwrap();
+
+        let mut decompressor = Decompressor::new();
+        let truncated = &compressed[..compressed.len() - 4];
+        assert!(decompressor.encode(truncated, false).is_ok());
+        let e = decompressor.encode(b"", true).unwrap_err();
+        assert_eq!(e.etype(), &COMPRESSION_ERROR);
+    }
 }
@@ -19,14 +19,25 @@ use pingora_error::{
     ErrorType::{self, *},
     OrErr, Result,
 };
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::io::IoSlice;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::protocols::l4::stream::AsyncWriteVec;
+use crate::protocols::l4::stream::{AsyncWriteVec, Stream as L4Stream};
+use crate::protocols::Stream;
 use crate::utils::BufRef;
 
-// TODO: make this dynamically adjusted
+// The initial size of the body buffer, which is then adjusted to how fast the body arrives
 const BODY_BUFFER_SIZE: usize = 1024 * 64;
+// must stay above PARTIAL_CHUNK_HEAD_LIMIT to leave room to read the rest of a chunk head
+const MIN_BODY_BUFFER_SIZE: usize = 1024 * 16;
+const MAX_BODY_BUFFER_SIZE: usize = 1024 * 512;
+// shrink the body buffer after this many consecutive reads that fill less than a quarter of it
+const SHRINK_AFTER_SMALL_READS: u8 = 8;
+// below this size copying a body read out of the buffer is cheaper than allocating a new buffer
+const ZERO_COPY_MIN_SIZE: usize = 1024 * 16;
 // limit how much incomplete chunk-size and chunk-ext to buffer
 const PARTIAL_CHUNK_HEAD_LIMIT: usize = 1024 * 8;
 
@@ -103,6 +114,12 @@ pub struct BodyReader {
     pub body_buf: Option<BytesMut>,
     pub body_buf_size: usize,
     rewind_buf_len: usize,
+    // The preread body of non-chunked bodies, returned as is instead of copied into body_buf
+    rewind_buf: Bytes,
+    // Whether the body returned by the last read is in rewind_buf instead of body_buf
+    read_from_rewind: bool,
+    // How many consecutive reads filled less than a quarter of body_buf
+    small_reads: u8,
 }
 
 impl BodyReader {
@@ -112,6 +129,9 @@ impl BodyReader {
             body_buf: None,
             body_buf_size: BODY_BUFFER_SIZE,
             rewind_buf_len: 0,
+            rewind_buf: Bytes::new(),
+            read_from_rewind: false,
+            small_reads: 0,
         }
     }
 
@@ -123,12 +143,24 @@ impl BodyReader {
         self.body_state = PS::ToStart;
     }
 
-    fn prepare_buf(&mut self, buf_to_rewind: &[u8]) {
+    fn prepare_buf(&mut self, buf_to_rewind: Bytes) {
+        self.rewind_buf_len = 0;
+        self.rewind_buf = Bytes::new();
+        self.read_from_rewind = false;
+        self.small_reads = 0;
+        if !matches!(self.body_state, PS::Chunked(..)) {
+            // The body is returned in the same boundaries as it is read so the preread body can be
+            // returned without copying it. body_buf is allocated when reading from IO is needed.
+            self.rewind_buf = buf_to_rewind;
+            self.body_buf = None;
+            return;
+        }
+        // The chunked body parser needs the preread body in the same buffer as what follows it
+        // in order to parse chunk heads that are split between them, so it is copied.
         let mut body_buf = BytesMut::with_capacity(self.body_buf_size);
         if !buf_to_rewind.is_empty() {
             self.rewind_buf_len = buf_to_rewind.len();
-            // TODO: this is still 1 copy. Make it zero
-            body_buf.put_slice(buf_to_rewind);
+            body_buf.put_slice(&buf_to_rewind);
         }
         if self.body_buf_size > buf_to_rewind.len() {
             //body_buf.resize(self.body_buf_size, 0);
@@ -139,31 +171,57 @@ impl BodyReader {
         self.body_buf = Some(body_buf);
     }
 
-    pub fn init_chunked(&mut self, buf_to_rewind: &[u8]) {
+    pub fn init_chunked(&mut self, buf_to_rewind: Bytes) {
         self.body_state = PS::Chunked(0, 0, 0, 0);
+        self.body_buf_size = BODY_BUFFER_SIZE;
         self.prepare_buf(buf_to_rewind);
     }
 
-    pub fn init_content_length(&mut self, cl: usize, buf_to_rewind: &[u8]) {
+    pub fn init_content_length(&mut self, cl: usize, buf_to_rewind: Bytes) {
         match cl {
             0 => self.body_state = PS::Complete(0),
             _ => {
-                self.prepare_buf(buf_to_rewind);
                 self.body_state = PS::Partial(0, cl);
+                // no need to allocate more than the body itself
+                self.body_buf_size = cl.min(BODY_BUFFER_SIZE);
+                self.prepare_buf(buf_to_rewind);
             }
         }
     }
 
-    pub fn init_http10(&mut self, buf_to_rewind: &[u8]) {
-        self.prepare_buf(buf_to_rewind);
+    pub fn init_http10(&mut self, buf_to_rewind: Bytes) {
         self.body_state = PS::HTTP1_0(0);
+        self.body_buf_size = BODY_BUFFER_SIZE;
+        self.prepare_buf(buf_to_rewind);
     }
 
     pub fn get_body(&self, buf_ref: &BufRef) -> &[u8] {
+        if self.read_from_rewind {
+            return buf_ref.get(&self.rewind_buf);
+        }
         // TODO: these get_*() could panic. handle them better
         buf_ref.get(self.body_buf.as_ref().unwrap())
     }
 
+    /// Similar to [Self::get_body] but return [Bytes].
+    ///
+    /// The preread body and large reads of non-chunked bodies are returned without copying.
+    pub fn get_body_bytes(&mut self, buf_ref: &BufRef) -> Bytes {
+        if self.read_from_rewind {
+            return buf_ref.get_bytes(&self.rewind_buf);
+        }
+        // Chunked bodies may have more chunks to parse in the rest of the buffer.
+        // Otherwise, the buffer is no longer needed after this read so it can be handed over
+        // as long as allocating a new buffer for the next read is cheaper than copying.
+        if buf_ref.len() >= ZERO_COPY_MIN_SIZE && !matches!(self.body_state, PS::Chunked(..)) {
+            if let Some(mut body_buf) = self.body_buf.take() {
+                body_buf.truncate(buf_ref.1);
+                return body_buf.freeze().slice(buf_ref.0..);
+            }
+        }
+        Bytes::copy_from_slice(self.get_body(buf_ref))
+    }
+
     pub fn body_done(&self) -> bool {
         matches!(self.body_state, PS::Complete(_) | PS::Done(_))
     }
@@ -172,6 +230,88 @@ impl BodyReader {
         self.body_state == PS::Complete(0)
     }
 
+    /// Take the preread body that is not returned yet, if any.
+    ///
+    /// This is used when the rest of the body is transferred without going through this reader.
+    pub(super) fn take_rewind_buf(&mut self) -> Bytes {
+        if self.read_from_rewind {
+            self.read_from_rewind = false;
+            self.rewind_buf.clear();
+        }
+        std::mem::take(&mut self.rewind_buf)
+    }
+
+    // Return the length of the preread body if it is not returned yet.
+    fn rewind(&mut self) -> Option<usize> {
+        if self.read_from_rewind {
+            // returned by the last read already
+            self.read_from_rewind = false;
+            self.rewind_buf.clear();
+        } else if !self.rewind_buf.is_empty() {
+            self.read_from_rewind = true;
+            return Some(self.rewind_buf.len());
+        }
+        None
+    }
+
+    // Make sure body_buf is allocated with the current body_buf_size before reading into it.
+    fn buf_for_read(&mut self) -> &mut BytesMut {
+        let size = self.body_buf_size;
+        if self.body_buf.as_ref().map_or(true, |b| b.len() != size) {
+            let mut body_buf = BytesMut::with_capacity(size);
+            // the content will be overwritten by the read
+            unsafe {
+                body_buf.set_len(size);
+            }
+            self.body_buf = Some(body_buf);
+        }
+        self.body_buf.as_mut().unwrap()
+    }
+
+    // Read into body_buf. `to_read` is the rest of the body to read if known.
+    async fn read_from_io<S>(&mut self, stream: &mut S, to_read: Option<usize>) -> Result<usize>
+    where
+        S: AsyncRead + Unpin + Send,
+    {
+        let body_buf = self.buf_for_read();
+        let len = body_buf.len();
+        let n = stream
+            .read(body_buf)
+            .await
+            .or_err(ReadError, "when reading body")?;
+        self.adjust_buf_size(n, len, to_read.map(|l| l.saturating_sub(n)));
+        Ok(n)
+    }
+
+    // Size the buffer of the next read according to how much this read got: a full buffer means
+    // the peer is sending faster than we read so it grows, while a buffer that is mostly unused
+    // for several reads shrinks. `remaining` is the rest of the body to read if known.
+    fn adjust_buf_size(&mut self, read: usize, buf_len: usize, remaining: Option<usize>) {
+        if read == buf_len {
+            self.small_reads = 0;
+            let mut size = (self.body_buf_size * 2).min(MAX_BODY_BUFFER_SIZE);
+            if let Some(remaining) = remaining {
+                size = size.min(remaining);
+            }
+            if size > self.body_buf_size {
+                trace!("growing body buffer to {size}");
+                self.body_buf_size = size;
+            }
+        } else if read < buf_len / 4 {
+            self.small_reads += 1;
+            if self.small_reads >= SHRINK_AFTER_SMALL_READS {
+                self.small_reads = 0;
+                let size = (self.body_buf_size / 2).max(MIN_BODY_BUFFER_SIZE);
+                if size < self.body_buf_size {
+                    trace!("shrinking body buffer to {size}");
+                    self.body_buf_size = size;
+                }
+            }
+        } else {
+            self.small_reads = 0;
+        }
+    }
+
     pub async fn read_body<S>(&mut self, stream: &mut S) -> Result<Option<BufRef>>
     where
         S: AsyncRead + Unpin + Send,
@@ -190,16 +330,14 @@ impl BodyReader {
     where
         S: AsyncRead + Unpin + Send,
     {
-        let body_buf = self.body_buf.as_deref_mut().unwrap();
-        let mut n = self.rewind_buf_len;
-        self.rewind_buf_len = 0; // we only need to read rewind data once
-        if n == 0 {
+        let n = match self.rewind() {
+            Some(n) => n,
             /* Need to actually read */
-            n = stream
-                .read(body_buf)
-                .await
-                .or_err(ReadError, "when reading body")?;
-        }
+            None => match self.body_state {
+                PS::Partial(_, to_read) => self.read_from_io(stream, Some(to_read)).await?,
+                _ => panic!("wrong body state: {:?}", self.body_state),
+            },
+        };
         match self.body_state {
             PS::Partial(read, to_read) => {
                 debug!(
@@ -235,16 +373,11 @@ impl BodyReader {
     where
         S: AsyncRead + Unpin + Send,
     {
-        let body_buf = self.body_buf.as_deref_mut().unwrap();
-        let mut n = self.rewind_buf_len;
-        self.rewind_buf_len = 0; // we only need to read rewind data once
-        if n == 0 {
+        let n = match self.rewind() {
+            Some(n) => n,
             /* Need to actually read */
-            n = stream
-                .read(body_buf)
-                .await
-                .or_err(ReadError, "when reading body")?;
-        }
+            None => self.read_from_io(stream, None).await?,
+        };
         match self.body_state {
             PS::HTTP1_0(read) => {
                 if n == 0 {
@@ -272,17 +405,15 @@ impl BodyReader {
             ) => {
                 if existing_buf_start == 0 {
                     // read a new buf from IO
-                    let body_buf = self.body_buf.as_deref_mut().unwrap();
                     if existing_buf_end == 0 {
                         existing_buf_end = self.rewind_buf_len;
                         self.rewind_buf_len = 0; // we only need to read rewind data once
                         if existing_buf_end == 0 {
-                            existing_buf_end = stream
-                                .read(body_buf)
-                                .await
-                                .or_err(ReadError, "when reading body")?;
+                            // nothing left in the buffer, it can be resized for this read
+                            existing_buf_end = self.read_from_io(stream, None).await?;
                         }
                     } else {
+                        let body_buf = self.body_buf.as_deref_mut().unwrap();
                         /* existing_buf_end != 0 this is partial chunk head */
                         /* copy the #expecting_from_io bytes until index existing_buf_end
                          * to the front and read more to form a valid chunk head.
@@ -474,7 +605,21 @@ impl BodyWriter {
     where
         S: AsyncWrite + Unpin + Send,
     {
-        trace!("Writing Body, size: {}", buf.len());
+        self.write_body_vec(stream, &mut &buf[..]).await
+    }
+
+    /// Similar to [Self::write_body] but write any [Buf], such as a [BodyBufs] list, with
+    /// vectored writes so that its chunks are not copied into one buffer first.
+    pub async fn write_body_vec<S, B>(
+        &mut self,
+        stream: &mut S,
+        buf: &mut B,
+    ) -> Result<Option<usize>>
+    where
+        S: AsyncWrite + Unpin + Send,
+        B: Buf + Send,
+    {
+        trace!("Writing Body, size: {}", buf.remaining());
         match self.body_mode {
             BM::Complete(_) => Ok(None),
             BM::ContentLength(_, _) => self.do_write_body(stream, buf).await,
@@ -492,9 +637,10 @@ impl BodyWriter {
         }
     }
 
-    async fn do_write_body<S>(&mut self, stream: &mut S, buf: &[u8]) -> Result<Option<usize>>
+    async fn do_write_body<S, B>(&mut self, stream: &mut S, buf: &mut B) -> Result<Option<usize>>
     where
         S: AsyncWrite + Unpin + Send,
+        B: Buf + Send,
     {
         match self.body_mode {
             BM::ContentLength(total, written) => {
@@ -503,12 +649,12 @@ impl BodyWriter {
                     return Ok(None);
                 }
                 let mut to_write = total - written;
-                if to_write < buf.len() {
+                if to_write < buf.remaining() {
                     warn!("Trying to write data over content-length: {total}");
                 } else {
-                    to_write = buf.len();
+                    to_write = buf.remaining();
                 }
-                let res = stream.write_all(&buf[..to_write]).await;
+                let res = stream.write_vec_all(&mut buf.take(to_write)).await;
                 match res {
                     Ok(()) => {
                         self.body_mode = BM::ContentLength(total, written + to_write);
@@ -524,17 +670,18 @@ impl BodyWriter {
         }
     }
 
-    async fn do_write_chunked_body<S>(
+    async fn do_write_chunked_body<S, B>(
         &mut self,
         stream: &mut S,
-        buf: &[u8],
+        buf: &mut B,
     ) -> Result<Option<usize>>
     where
         S: AsyncWrite + Unpin + Send,
+        B: Buf + Send,
     {
         match self.body_mode {
             BM::ChunkedEncoding(written) => {
-                let chunk_size = buf.len();
+                let chunk_size = buf.remaining();
 
                 let chuck_size_buf = format!("{:X}\r\n", chunk_size);
                 let mut output_buf = Bytes::from(chuck_size_buf).chain(buf).chain(&b"\r\n"[..]);
@@ -550,22 +697,24 @@ impl BodyWriter {
         }
     }
 
-    async fn do_write_http1_0_body<S>(
+    async fn do_write_http1_0_body<S, B>(
         &mut self,
         stream: &mut S,
-        buf: &[u8],
+        buf: &mut B,
     ) -> Result<Option<usize>>
     where
         S: AsyncWrite + Unpin + Send,
+        B: Buf + Send,
     {
         match self.body_mode {
             BM::HTTP1_0(written) => {
-                let res = stream.write_all(buf).await;
+                let size = buf.remaining();
+                let res = stream.write_vec_all(buf).await;
                 match res {
                     Ok(()) => {
-                        self.body_mode = BM::HTTP1_0(written + buf.len());
+                        self.body_mode = BM::HTTP1_0(written + size);
                         stream.flush().await.or_err(WriteError, "flushing body")?;
-                        Ok(Some(buf.len()))
+                        Ok(Some(size))
                     }
                     Err(e) => Error::e_because(WriteError, "while writing body", e),
                 }
@@ -631,6 +780,173 @@ impl BodyWriter {
     }
 }
 
+/// Move the rest of the body read by `reader` from `src` to `dst`, written by `writer`, with
+/// `splice()` so that it is not copied through userspace.
+///
+/// This is only possible when both are plain TCP or UDS streams and the body needs no reframing,
+/// i.e., no chunked encoding on either side. Otherwise `Ok(None)` is returned without touching
+/// either stream so that the caller can fall back to reading and writing the body.
+pub(super) async fn splice_body(
+    reader: &mut BodyReader,
+    src: &mut Stream,
+    writer: &mut BodyWriter,
+    dst: &mut Stream,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> Result<Option<usize>> {
+    if !cfg!(target_os = "linux") {
+        return Ok(None);
+    }
+    let to_read = match reader.body_state {
+        PS::Partial(_, to_read) => Some(to_read),
+        PS::HTTP1_0(_) => None,
+        _ => return Ok(None),
+    };
+    let to_write = match writer.body_mode {
+        BM::ContentLength(total, written) => Some(total.saturating_sub(written)),
+        BM::HTTP1_0(_) => None,
+        _ => return Ok(None),
+    };
+    let len = match (to_read, to_write) {
+        (Some(r), Some(w)) if r == w => r,
+        // a body with known length can be sent as close delimited, but not the other way around
+        (Some(r), None) => r,
+        (None, None) => usize::MAX,
+        _ => return Ok(None),
+    };
+    if src.as_any().downcast_ref::<L4Stream>().is_none()
+        || dst.as_any().downcast_ref::<L4Stream>().is_none()
+    {
+        return Ok(None);
+    }
+
+    let rewind = reader.take_rewind_buf();
+    let mut moved = rewind.len().min(len);
+    if moved > 0 {
+        writer.write_body(dst, &rewind[..moved]).await?;
+    }
+    let spliced = splice_l4(src, dst, len - moved, read_timeout, write_timeout).await;
+    let spliced = match spliced {
+        Ok(n) => n,
+        Err(e) => {
+            reader.body_state = reader.body_state.done(moved);
+            return Err(e);
+        }
+    };
+    moved += spliced;
+    writer.body_mode = match writer.body_mode {
+        BM::ContentLength(total, written) => BM::ContentLength(total, written + spliced),
+        BM::HTTP1_0(written) => BM::HTTP1_0(written + spliced),
+        _ => panic!("wrong body mode: {:?}", writer.body_mode),
+    };
+    match reader.body_state {
+        PS::Partial(read, to_read) => {
+            if moved < to_read {
+                reader.body_state = PS::Done(read + moved);
+                return Error::e_explain(
+                    ConnectionClosed,
+                    format!(
+                        "Peer prematurely closed connection with {} bytes of body remaining to read",
+                        to_read - moved
+                    ),
+                );
+            }
+            reader.body_state = PS::Complete(read + to_read);
+        }
+        PS::HTTP1_0(read) => reader.body_state = PS::Complete(read + moved),
+        _ => panic!("wrong body state: {:?}", reader.body_state),
+    }
+    Ok(Some(moved))
+}
+
+#[cfg(target_os = "linux")]
+async fn splice_l4(
+    src: &mut Stream,
+    dst: &mut Stream,
+    len: usize,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> Result<usize> {
+    // both are checked to be L4Stream already
+    let src = src.as_any_mut().downcast_mut::<L4Stream>().unwrap();
+    let dst = dst.as_any_mut().downcast_mut::<L4Stream>().unwrap();
+    src.splice_to(dst, len, read_timeout, write_timeout).await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn splice_l4(
+    _src: &mut Stream,
+    _dst: &mut Stream,
+    _len: usize,
+    _read_timeout: Option<Duration>,
+    _write_timeout: Option<Duration>,
+) -> Result<usize> {
+    Error::e_explain(InternalError, "splice() is only supported on Linux")
+}
+
+/// A list of body [Bytes] to write together, without copying them into one buffer.
+#[derive(Debug, Default)]
+pub struct BodyBufs {
+    bufs: VecDeque<Bytes>,
+    remaining: usize,
+}
+
+impl BodyBufs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `buf` to the list.
+    pub fn push(&mut self, buf: Bytes) {
+        if !buf.is_empty() {
+            self.remaining += buf.len();
+            self.bufs.push_back(buf);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.bufs.clear();
+        self.remaining = 0;
+    }
+}
+
+impl Buf for BodyBufs {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.bufs.front().map_or(&[], |b| b.chunk())
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut n = 0;
+        for (buf, slice) in self.bufs.iter().zip(dst.iter_mut()) {
+            *slice = IoSlice::new(buf);
+            n += 1;
+        }
+        n
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(cnt <= self.remaining, "advance past the end of BodyBufs");
+        self.remaining -= cnt;
+        while cnt > 0 {
+            let front = self.bufs.front_mut().unwrap();
+            if cnt < front.len() {
+                front.advance(cnt);
+                return;
+            }
+            cnt -= front.len();
+            self.bufs.pop_front();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -646,7 +962,7 @@ mod tests {
         let input = b"abc";
         let mut mock_io = Builder::new().read(&input[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_content_length(3, b"");
+        body_reader.init_content_length(3, Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(0, 3));
         assert_eq!(body_reader.body_state, ParseState::Complete(3));
@@ -660,7 +976,7 @@ mod tests {
         let input2 = b"bc";
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_content_length(3, b"");
+        body_reader.init_content_length(3, Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(0, 1));
         assert_eq!(body_reader.body_state, ParseState::Partial(1, 2));
@@ -678,7 +994,7 @@ mod tests {
         let input2 = b""; // simulating close
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_content_length(3, b"");
+        body_reader.init_content_length(3, Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(0, 1));
         assert_eq!(body_reader.body_state, ParseState::Partial(1, 2));
@@ -695,7 +1011,7 @@ mod tests {
         let input2 = b"bcd";
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_content_length(3, b"");
+        body_reader.init_content_length(3, Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(0, 1));
         assert_eq!(body_reader.body_state, ParseState::Partial(1, 2));
@@ -713,7 +1029,7 @@ mod tests {
         let input = b"c";
         let mut mock_io = Builder::new().read(&input[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_content_length(3, rewind);
+        body_reader.init_content_length(3, Bytes::from_static(rewind));
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(0, 2));
         assert_eq!(body_reader.body_state, ParseState::Partial(2, 1));
@@ -731,7 +1047,7 @@ mod tests {
         let input2 = b""; // simulating close
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_http10(b"");
+        body_reader.init_http10(Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(0, 1));
         assert_eq!(body_reader.body_state, ParseState::HTTP1_0(1));
@@ -749,7 +1065,7 @@ mod tests {
         let input2 = b""; // simulating close
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_http10(rewind);
+        body_reader.init_http10(Bytes::from_static(rewind));
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(0, 2));
         assert_eq!(body_reader.body_state, ParseState::HTTP1_0(2));
@@ -769,7 +1085,7 @@ mod tests {
         let input = b"0\r\n\r\n";
         let mut mock_io = Builder::new().read(&input[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_chunked(b"");
+        body_reader.init_chunked(Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap();
         assert_eq!(res, None);
         assert_eq!(body_reader.body_state, ParseState::Complete(0));
@@ -781,7 +1097,7 @@ mod tests {
         let input = b"0;aaaa\r\n\r\n";
         let mut mock_io = Builder::new().read(&input[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_chunked(b"");
+        body_reader.init_chunked(Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap();
         assert_eq!(res, None);
         assert_eq!(body_reader.body_state, ParseState::Complete(0));
@@ -799,7 +1115,7 @@ mod tests {
             .read(&ext2[..])
             .build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_chunked(b"");
+        body_reader.init_chunked(Bytes::new());
         // read chunk-size, chunk incomplete
         let res = body_reader.read_body(&mut mock_io).await.unwrap();
         assert_eq!(res, Some(BufRef::new(0, 0)));
@@ -819,7 +1135,7 @@ mod tests {
         let input2 = b"0\r\n\r\n";
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_chunked(b"");
+        body_reader.init_chunked(Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(3, 1));
         assert_eq!(&input1[3..4], body_reader.get_body(&res));
@@ -837,7 +1153,7 @@ mod tests {
         let input2 = b"0\r\n\r\n";
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_chunked(rewind);
+        body_reader.init_chunked(Bytes::from_static(rewind));
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(3, 1));
         assert_eq!(&rewind[3..4], body_reader.get_body(&res));
@@ -858,7 +1174,7 @@ mod tests {
         let input2 = b"0\r\n\r\n";
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_chunked(b"");
+        body_reader.init_chunked(Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(3, 1));
         assert_eq!(&input1[3..4], body_reader.get_body(&res));
@@ -879,7 +1195,7 @@ mod tests {
         let input2 = b"bc\r\n0\r\n\r\n";
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_chunked(b"");
+        body_reader.init_chunked(Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(3, 1));
         assert_eq!(&input1[3..4], body_reader.get_body(&res));
@@ -900,7 +1216,7 @@ mod tests {
         let input2 = b"\na\r\n0\r\n\r\n";
         let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
         let mut body_reader = BodyReader::new();
-        body_reader.init_chunked(b"");
+        body_reader.init_chunked(Bytes::new());
         let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
         assert_eq!(res, BufRef::new(0, 0));
         assert_eq!(body_reader.body_state, ParseState::Chunked(0, 0, 2, 2));
@@ -1001,4 +1317,196 @@ mod tests {
         assert_eq!(res, 2);
         assert_eq!(body_writer.body_mode, BodyMode::Complete(2));
     }
+
+    #[tokio::test]
+    async fn read_with_body_buffer_adjusted() {
+        init_log();
+        let input = vec![b'a'; BODY_BUFFER_SIZE];
+        let mut mock_io = Builder::new().read(&input).read(&input).build();
+        let mut body_reader = BodyReader::new();
+        body_reader.init_content_length(BODY_BUFFER_SIZE * 3, Bytes::new());
+        assert_eq!(body_reader.body_buf_size, BODY_BUFFER_SIZE);
+        let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        assert_eq!(res, BufRef::new(0, BODY_BUFFER_SIZE));
+        // the buffer was filled, grow it
+        assert_eq!(body_reader.body_buf_size, BODY_BUFFER_SIZE * 2);
+        let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        assert_eq!(res, BufRef::new(0, BODY_BUFFER_SIZE));
+        // not filled
+        assert_eq!(body_reader.body_buf_size, BODY_BUFFER_SIZE * 2);
+
+        // small body, small buffer
+        body_reader.init_content_length(3, Bytes::new());
+        assert_eq!(body_reader.body_buf_size, 3);
+
+        // consistently small reads shrink the buffer
+        let mut builder = Builder::new();
+        for _ in 0..SHRINK_AFTER_SMALL_READS {
+            builder.read(b"a");
+        }
+        let mut mock_io = builder.build();
+        body_reader.init_http10(Bytes::new());
+        for _ in 0..SHRINK_AFTER_SMALL_READS {
+            body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        }
+        assert_eq!(body_reader.body_buf_size, BODY_BUFFER_SIZE / 2);
+    }
+
+    #[tokio::test]
+    async fn read_body_bytes_zero_copy() {
+        init_log();
+        let rewind = Bytes::from_static(b"ab");
+        let input = vec![b'c'; ZERO_COPY_MIN_SIZE];
+        let mut mock_io = Builder::new().read(&input).read(b"d").build();
+        let mut body_reader = BodyReader::new();
+        body_reader.init_content_length(ZERO_COPY_MIN_SIZE + 3, rewind.clone());
+        let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        let body = body_reader.get_body_bytes(&res);
+        assert_eq!(body, rewind);
+        // the same buffer
+        assert_eq!(body.as_ptr(), rewind.as_ptr());
+
+        let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        let body = body_reader.get_body_bytes(&res);
+        assert_eq!(body, input);
+        // the buffer is handed over
+        assert!(body_reader.body_buf.is_none());
+
+        let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        assert_eq!(body_reader.get_body_bytes(&res), b"d"[..]);
+        assert_eq!(
+            body_reader.body_state,
+            ParseState::Complete(ZERO_COPY_MIN_SIZE + 3)
+        );
+    }
+
+    #[tokio::test]
+    async fn write_body_vec_chunked() {
+        init_log();
+        let output = b"6\r\nabcdef\r\n";
+        let mut mock_io = Builder::new()
+            .write(&output[..])
+            .write(&LAST_CHUNK[..])
+            .build();
+        let mut body_writer = BodyWriter::new();
+        body_writer.init_chunked();
+        let mut bufs = BodyBufs::new();
+        bufs.push(Bytes::from_static(b"abc"));
+        bufs.push(Bytes::new());
+        bufs.push(Bytes::from_static(b"def"));
+        let res = body_writer
+            .write_body_vec(&mut mock_io, &mut bufs)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res, 6);
+        assert!(bufs.is_empty());
+        let res = body_writer.finish(&mut mock_io).await.unwrap().unwrap();
+        assert_eq!(res, 6);
+    }
+
+    #[tokio::test]
+    async fn write_body_vec_cl_over_limit() {
+        init_log();
+        let mut mock_io = Builder::new().write(b"abcd").build();
+        let mut body_writer = BodyWriter::new();
+        body_writer.init_content_length(4);
+        let mut bufs = BodyBufs::new();
+        bufs.push(Bytes::from_static(b"abc"));
+        bufs.push(Bytes::from_static(b"def"));
+        let res = body_writer
+            .write_body_vec(&mut mock_io, &mut bufs)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res, 4);
+        assert_eq!(body_writer.body_mode, BodyMode::ContentLength(4, 4));
+        assert_eq!(bufs.remaining(), 2);
+    }
+
+    #[tokio::test]
+    async fn splice_body_not_supported() {
+        init_log();
+        let mut src: Stream = Box::new(Builder::new().build());
+        let mut dst: Stream = Box::new(Builder::new().build());
+        let mut body_reader = BodyReader::new();
+        let mut body_writer = BodyWriter::new();
+
+        // chunked needs reframing
+        body_reader.init_chunked(Bytes::new());
+        body_writer.init_content_length(3);
+        let res = splice_body(
+            &mut body_reader,
+            &mut src,
+            &mut body_writer,
+            &mut dst,
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(res.unwrap(), None);
+
+        // not l4 streams
+        body_reader.init_content_length(3, Bytes::new());
+        let res = splice_body(
+            &mut body_reader,
+            &mut src,
+            &mut body_writer,
+            &mut dst,
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(body_reader.body_state, ParseState::Partial(0, 3));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn splice_body_content_length() {
+        use tokio::net::{TcpListener, TcpStream};
+
+        async fn tcp_pair() -> (TcpStream, TcpStream) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+            (client.unwrap(), server.unwrap().0)
+        }
+
+        init_log();
+        let (mut upstream, src) = tcp_pair().await;
+        let (dst, mut downstream) = tcp_pair().await;
+        let mut src: Stream = Box::new(L4Stream::from(src));
+        let mut dst: Stream = Box::new(L4Stream::from(dst));
+
+        let body = vec![b'x'; 256 * 1024];
+        let len = body.len() + 3;
+        let mut body_reader = BodyReader::new();
+        body_reader.init_content_length(len, Bytes::from_static(b"abc"));
+        let mut body_writer = BodyWriter::new();
+        body_writer.init_content_length(len);
+
+        let send = async {
+            upstream.write_all(&body).await.unwrap();
+        };
+        let receive = async {
+            let mut received = vec![0; len];
+            downstream.read_exact(&mut received).await.unwrap();
+            received
+        };
+        let splice = splice_body(
+            &mut body_reader,
+            &mut src,
+            &mut body_writer,
+            &mut dst,
+            None,
+            None,
+        );
+        let (res, _, received) = tokio::join!(splice, send, receive);
+        assert_eq!(res.unwrap(), Some(len));
+        assert_eq!(&received[..3], b"abc");
+        assert_eq!(&received[3..], &body[..]);
+        assert_eq!(body_reader.body_state, ParseState::Complete(len));
+        assert_eq!(body_writer.body_mode, BodyMode::ContentLength(len, len));
+    }
 }
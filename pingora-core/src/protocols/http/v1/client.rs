@@ -149,7 +149,7 @@ impl HttpSession {
     fn maybe_force_close_body_reader(&mut self) {
         if self.upgraded && !self.body_reader.body_done() {
             // request is done, reset the response body to close
-            self.body_reader.init_content_length(0, b"");
+            self.body_reader.init_content_length(0, Bytes::new());
         }
     }
 
@@ -344,21 +344,30 @@ impl HttpSession {
     /// Return `Ok(Some(ref)) after a successful read.
     /// Return `Ok(None)` if there is no more body to read.
     pub async fn read_body_ref(&mut self) -> Result<Option<&[u8]>> {
-        let result = match self.read_timeout {
+        let result = self.read_body().await;
+        result.map(|maybe_body| maybe_body.map(|body_ref| self.body_reader.get_body(&body_ref)))
+    }
+
+    async fn read_body(&mut self) -> Result<Option<BufRef>> {
+        match self.read_timeout {
             Some(t) => match timeout(t, self.do_read_body()).await {
                 Ok(res) => res,
                 Err(_) => Error::e_explain(ReadTimedout, format!("reading body, timeout: {t:?}")),
             },
             None => self.do_read_body().await,
-        };
-
-        result.map(|maybe_body| maybe_body.map(|body_ref| self.body_reader.get_body(&body_ref)))
+        }
     }
 
     /// Similar to [`Self::read_body_ref`] but return `Bytes` instead of a slice reference.
     pub async fn read_body_bytes(&mut self) -> Result<Option<Bytes>> {
-        let read = self.read_body_ref().await?;
-        Ok(read.map(Bytes::copy_from_slice))
+        let read = self.read_body().await?;
+        Ok(read.map(|b| self.body_reader.get_body_bytes(&b)))
+    }
+
+    // The reader and the stream of the response body, for splicing it to the downstream
+    pub(super) fn response_body_parts(&mut self) -> (&mut BodyReader, &mut Stream) {
+        self.init_body_reader();
+        (&mut self.body_reader, &mut self.underlying_stream)
     }
 
     /// Whether there is no more body to read.
@@ -494,7 +503,7 @@ impl HttpSession {
     fn init_body_reader(&mut self) {
         if self.body_reader.need_init() {
             /* follow https://tools.ietf.org/html/rfc7230#section-3.3.3 */
-            let preread_body = self.preread_body.as_ref().unwrap().get(&self.buf[..]);
+            let preread_body = self.preread_body.as_ref().unwrap().get_bytes(&self.buf);
 
             if let Some(req) = self.request_written.as_ref() {
                 if req.method == http::method::Method::HEAD {
@@ -655,6 +664,12 @@ impl HttpSession {
     pub fn stream(&self) -> &Stream {
         &self.underlying_stream
     }
+
+    // Consume `self` to get the underlying stream regardless of the keepalive status, for
+    // connections that were never used.
+    pub(crate) fn into_stream(self) -> Stream {
+        self.underlying_stream
+    }
 }
 
 #[inline]
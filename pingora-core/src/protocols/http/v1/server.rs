@@ -28,7 +28,8 @@ use regex::bytes::Regex;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use super::body::{BodyReader, BodyWriter};
+use super::body::{splice_body, BodyBufs, BodyReader, BodyWriter};
+use super::client::HttpSession as ClientSession;
 use super::common::*;
 use crate::protocols::http::{body_buffer::FixedBuffer, date, error_resp, HttpTask};
 use crate::protocols::{Digest, SocketAddr, Stream};
@@ -49,8 +50,8 @@ pub struct HttpSession {
     body_reader: BodyReader,
     /// A state machine to track how to write the response body
     body_writer: BodyWriter,
-    /// An internal buffer to buf multiple body writes to reduce the underlying syscalls
-    body_write_buf: BytesMut,
+    /// The body chunks to write together, with one vectored write, to reduce the underlying syscalls
+    body_write_buf: BodyBufs,
     /// Track how many application (not on the wire) body bytes already sent
     body_bytes_sent: usize,
     /// Track how many application (not on the wire) body bytes already read
@@ -94,7 +95,7 @@ impl HttpSession {
             preread_body: None,
             body_reader: BodyReader::new(),
             body_writer: BodyWriter::new(),
-            body_write_buf: BytesMut::new(),
+            body_write_buf: BodyBufs::new(),
             keepalive_timeout: KeepaliveStatus::Off,
             update_resp_headers: true,
             response_written: None,
@@ -340,7 +341,7 @@ impl HttpSession {
     pub async fn read_body_bytes(&mut self) -> Result<Option<Bytes>> {
         let read = self.read_body().await?;
         Ok(read.map(|b| {
-            let bytes = Bytes::copy_from_slice(self.get_body(&b));
+            let bytes = self.body_reader.get_body_bytes(&b);
             self.body_bytes_read += bytes.len();
             if let Some(buffer) = self.retry_buffer.as_mut() {
                 buffer.write_to_buffer(&bytes);
@@ -431,7 +432,7 @@ impl HttpSession {
                     debug!("bad upgrade handshake!");
                     // reset request body buf and mark as done
                     // safe to reset an upgrade because it doesn't have body
-                    self.body_reader.init_content_length(0, b"");
+                    self.body_reader.init_content_length(0, Bytes::new());
                 }
             }
             self.init_body_writer(&header);
@@ -588,6 +589,36 @@ impl HttpSession {
         }
     }
 
+    /// Write the rest of the response body read from `upstream` with `splice()` so that it is
+    /// not copied through userspace.
+    ///
+    /// Return `Ok(None)` when this is not possible, e.g., either side is TLS or uses chunked
+    /// encoding. Nothing is read or written in that case and the body should be proxied as usual.
+    pub async fn splice_response_body_from(
+        &mut self,
+        upstream: &mut ClientSession,
+    ) -> Result<Option<usize>> {
+        let read_timeout = upstream.read_timeout;
+        let (reader, src) = upstream.response_body_parts();
+        let spliced = splice_body(
+            reader,
+            src,
+            &mut self.body_writer,
+            &mut self.underlying_stream,
+            read_timeout,
+            self.write_timeout,
+        )
+        .await
+        .map_err(|e| match e.etype() {
+            WriteError | WriteTimedout => e.into_down(),
+            _ => e.into_up(),
+        })?;
+        if let Some(n) = spliced {
+            self.body_bytes_sent += n;
+        }
+        Ok(spliced)
+    }
+
     async fn write_body_buf(&mut self) -> Result<Option<usize>> {
         // Don't flush empty chunks, they are considered end of body for chunks
         if self.body_write_buf.is_empty() {
@@ -596,7 +627,7 @@ impl HttpSession {
 
         let written = self
             .body_writer
-            .write_body(&mut self.underlying_stream, &self.body_write_buf)
+            .write_body_vec(&mut self.underlying_stream, &mut self.body_write_buf)
             .await;
 
         if let Ok(Some(num_bytes)) = written {
@@ -612,7 +643,7 @@ impl HttpSession {
     fn maybe_force_close_body_reader(&mut self) {
         if self.upgraded && !self.body_reader.body_done() {
             // response is done, reset the request body to close
-            self.body_reader.init_content_length(0, b"");
+            self.body_reader.init_content_length(0, Bytes::new());
         }
     }
 
@@ -660,7 +691,7 @@ impl HttpSession {
             }
 
             /* follow https://tools.ietf.org/html/rfc7230#section-3.3.3 */
-            let preread_body = self.preread_body.as_ref().unwrap().get(&self.buf[..]);
+            let preread_body = self.preread_body.as_ref().unwrap().get_bytes(&self.buf);
 
             if self.req_header().version == Version::HTTP_11 && self.is_upgrade_req() {
                 self.body_reader.init_http10(preread_body);
@@ -714,6 +745,7 @@ impl HttpSession {
         })
     }
 
+    #[cfg(test)]
     fn get_body(&self, buf_ref: &BufRef) -> &[u8] {
         // TODO: these get_*() could panic. handle them better
         self.body_reader.get_body(buf_ref)
@@ -881,7 +913,6 @@ impl HttpSession {
         Ok(end_stream)
     }
 
-    // TODO: use vectored write to avoid copying
     pub async fn response_duplex_vec(&mut self, mut tasks: Vec<HttpTask>) -> Result<bool> {
         let n_tasks = tasks.len();
         if n_tasks == 1 {
@@ -900,7 +931,7 @@ impl HttpSession {
                 HttpTask::Body(data, end_stream) => match data {
                     Some(d) => {
                         if !d.is_empty() && !self.body_writer.finished() {
-                            self.body_write_buf.put_slice(&d);
+                            self.body_write_buf.push(d);
                         }
                         end_stream
                     }
@@ -18,12 +18,15 @@ use bytes::Bytes;
 use futures::Future;
 use h2::server;
 use h2::server::SendResponse;
-use h2::{RecvStream, SendStream};
+use h2::{Ping, PingPong, RecvStream, SendStream};
 use http::header::HeaderName;
 use http::{header, HeaderMap, Response};
 use log::{debug, warn};
 use pingora_http::{RequestHeader, ResponseHeader};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep_until, timeout_at, Instant};
 
 use crate::protocols::http::body_buffer::FixedBuffer;
 use crate::protocols::http::date::get_cached_date;
@@ -58,6 +61,122 @@ pub async fn handshake(io: Stream, options: Option<H2Options>) -> Result<H2Conne
     }
 }
 
+const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Server side HTTP/2 settings
+///
+/// Unlike [`H2Options`], these settings can be written in the configuration file
+/// (see [`ServerConf`](crate::server::configuration::ServerConf)).
+/// `None` means to use the default of the h2 library.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct H2ServerSettings {
+    /// The initial flow control window size of the connection, in bytes.
+    pub initial_connection_window_size: Option<u32>,
+    /// The initial flow control window size of each stream, in bytes.
+    pub initial_stream_window_size: Option<u32>,
+    /// How many streams a client can have open at the same time on one connection.
+    pub max_concurrent_streams: Option<u32>,
+    /// The max size of the request header list the server accepts, in bytes.
+    pub max_header_list_size: Option<u32>,
+    /// When set, a PING is sent to the client every this many seconds to detect dead connections.
+    pub keepalive_interval_seconds: Option<u64>,
+    /// How long to wait for the PING to be acknowledged before closing the connection.
+    /// Default 20 seconds.
+    pub keepalive_timeout_seconds: Option<u64>,
+}
+
+impl H2ServerSettings {
+    /// Apply these settings to the given [`H2Options`].
+    pub fn apply(&self, options: &mut H2Options) {
+        if let Some(size) = self.initial_connection_window_size {
+            options.initial_connection_window_size(size);
+        }
+        if let Some(size) = self.initial_stream_window_size {
+            options.initial_window_size(size);
+        }
+        if let Some(max) = self.max_concurrent_streams {
+            options.max_concurrent_streams(max);
+        }
+        if let Some(size) = self.max_header_list_size {
+            options.max_header_list_size(size);
+        }
+    }
+
+    /// Create the [`H2Keepalive`] of the given connection if keepalive PING is enabled.
+    pub fn keepalive(&self, conn: &mut H2Connection<Stream>) -> Option<H2Keepalive> {
+        let interval = Duration::from_secs(self.keepalive_interval_seconds?);
+        let timeout = self
+            .keepalive_timeout_seconds
+            .map_or(DEFAULT_KEEPALIVE_TIMEOUT, Duration::from_secs);
+        // ping_pong() can only be taken once per connection
+        let ping_pong = conn.ping_pong()?;
+        Some(H2Keepalive {
+            ping_pong,
+            interval,
+            timeout,
+            next_ping: Instant::now() + interval,
+            pong_deadline: None,
+        })
+    }
+}
+
+/// The keepalive PING sender of an HTTP/2 server connection
+pub struct H2Keepalive {
+    ping_pong: PingPong,
+    interval: Duration,
+    timeout: Duration,
+    next_ping: Instant,
+    pong_deadline: Option<Instant>,
+}
+
+impl H2Keepalive {
+    /// Keep sending PINGs to the client.
+    ///
+    /// This function only returns when a PING is not acknowledged in time or the connection
+    /// fails. The connection should be closed then.
+    ///
+    /// The connection itself must be driven (by [`HttpSession::from_h2_conn()`]) concurrently in
+    /// order to receive the acknowledgements. This function is cancellation safe.
+    pub async fn keepalive(&mut self) -> Result<()> {
+        loop {
+            if let Some(deadline) = self.pong_deadline {
+                let ping_pong = &mut self.ping_pong;
+                let pong = timeout_at(
+                    deadline,
+                    futures::future::poll_fn(|cx| ping_pong.poll_pong(cx)),
+                )
+                .await;
+                match pong {
+                    Ok(Ok(_)) => {
+                        self.pong_deadline = None;
+                        self.next_ping = Instant::now() + self.interval;
+                    }
+                    Ok(Err(e)) => {
+                        return Error::e_because(
+                            ErrorType::H2Error,
+                            "while waiting for keepalive PONG",
+                            e,
+                        )
+                    }
+                    Err(_) => {
+                        return Error::e_explain(
+                            ErrorType::ReadTimedout,
+                            format!("keepalive PING not acknowledged in {:?}", self.timeout),
+                        )
+                    }
+                }
+            } else {
+                sleep_until(self.next_ping).await;
+                self.ping_pong
+                    .send_ping(Ping::opaque())
+                    .or_err(ErrorType::H2Error, "while sending keepalive PING")?;
+                self.pong_deadline = Some(Instant::now() + self.timeout);
+            }
+        }
+    }
+}
+
 use futures::task::Context;
 use futures::task::Poll;
 use std::pin::Pin;
@@ -557,4 +676,101 @@ mod test {
             });
         }
     }
+
+    #[tokio::test]
+    async fn test_server_settings() {
+        let (client, server) = duplex(65536);
+        let settings = H2ServerSettings {
+            max_concurrent_streams: Some(5),
+            initial_stream_window_size: Some(1024),
+            ..Default::default()
+        };
+
+        let client = tokio::spawn(async move {
+            let (h2, connection) = h2::client::handshake(client).await.unwrap();
+            tokio::spawn(async move {
+                connection.await.unwrap();
+            });
+            let mut h2 = h2.ready().await.unwrap();
+            let request = Request::builder()
+                .method(Method::GET)
+                .uri("https://www.example.com/")
+                .body(())
+                .unwrap();
+            let (response, _) = h2.send_request(request, true).unwrap();
+            let (head, mut body) = response.await.unwrap().into_parts();
+            assert_eq!(head.status, 200);
+            assert!(body.data().await.is_none());
+            // the server SETTINGS are received before the response
+            assert_eq!(h2.current_max_send_streams(), 5);
+        });
+
+        let mut options = H2Options::new();
+        settings.apply(&mut options);
+        let mut connection = handshake(Box::new(server), Some(options)).await.unwrap();
+        let digest = Arc::new(Digest::default());
+        while let Some(mut http) = HttpSession::from_h2_conn(&mut connection, digest.clone())
+            .await
+            .unwrap()
+        {
+            let response_header = Box::new(ResponseHeader::build(200, None).unwrap());
+            http.write_response_header(response_header, true).unwrap();
+        }
+        client.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_keepalive() {
+        let (client, server) = duplex(65536);
+        let settings = H2ServerSettings {
+            keepalive_interval_seconds: Some(10),
+            keepalive_timeout_seconds: Some(5),
+            ..Default::default()
+        };
+
+        tokio::spawn(async move {
+            let (_h2, connection) = h2::client::handshake(client).await.unwrap();
+            // the client answers PINGs as long as its connection is driven
+            let _ = connection.await;
+        });
+
+        let mut connection = handshake(Box::new(server), None).await.unwrap();
+        let mut keepalive = settings.keepalive(&mut connection).unwrap();
+        let digest = Arc::new(Digest::default());
+        tokio::select! {
+            _ = HttpSession::from_h2_conn(&mut connection, digest) => panic!("no new stream expected"),
+            res = keepalive.keepalive() => panic!("keepalive should not fail: {res:?}"),
+            // the clock jumps ahead when the runtime is idle, several PINGs are answered meanwhile
+            _ = tokio::time::sleep(Duration::from_secs(60)) => {}
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_keepalive_timeout() {
+        let (client, server) = duplex(65536);
+        let settings = H2ServerSettings {
+            keepalive_interval_seconds: Some(10),
+            keepalive_timeout_seconds: Some(5),
+            ..Default::default()
+        };
+
+        let (_h2, client_connection) = h2::client::handshake(client).await.unwrap();
+        // the client connection is never driven so no PONG is sent back
+
+        let mut connection = handshake(Box::new(server), None).await.unwrap();
+        let mut keepalive = settings.keepalive(&mut connection).unwrap();
+        // ping_pong() can only be taken once
+        assert!(settings.keepalive(&mut connection).is_none());
+        let digest = Arc::new(Digest::default());
+        let start = Instant::now();
+        let res = tokio::select! {
+            _ = HttpSession::from_h2_conn(&mut connection, digest) => panic!("no new stream expected"),
+            res = keepalive.keepalive() => res,
+            _ = tokio::time::sleep(Duration::from_secs(60)) => panic!("keepalive should time out"),
+        };
+        // the first PING is sent after the interval, then it times out
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+        assert_eq!(res.unwrap_err().etype(), &ErrorType::ReadTimedout);
+        drop(client_connection);
+    }
 }
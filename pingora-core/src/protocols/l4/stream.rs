@@ -26,6 +26,7 @@ use std::time::{Duration, Instant, SystemTime};
 use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream, ReadBuf};
 use tokio::net::{TcpStream, UnixStream};
 
+use crate::connectors::limit::ConnectionPermit;
 use crate::protocols::l4::ext::{set_tcp_keepalive, TcpKeepalive};
 use crate::protocols::raw_connect::ProxyDigest;
 use crate::protocols::{
@@ -143,6 +144,8 @@ pub struct Stream {
     pub tracer: Option<Tracer>,
     read_pending_time: AccumulatedDuration,
     write_pending_time: AccumulatedDuration,
+    // the permit of the connector to the peer, returned when this connection is closed
+    connection_permit: Option<ConnectionPermit>,
 }
 
 impl Stream {
@@ -163,6 +166,177 @@ impl Stream {
         }
         Ok(())
     }
+
+    /// Hold the connection permit of the connector until this connection is closed
+    pub(crate) fn set_connection_permit(&mut self, permit: ConnectionPermit) {
+        self.connection_permit = Some(permit);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Stream {
+    /// Move up to `len` bytes from this stream to `dst` with `splice()` so that they are not
+    /// copied through userspace. Return the number of bytes moved, which is less than `len` only
+    /// when this stream is closed.
+    ///
+    /// `read_timeout` and `write_timeout` limit how long to wait for each stream to be ready.
+    pub async fn splice_to(
+        &mut self,
+        dst: &mut Stream,
+        len: usize,
+        read_timeout: Option<Duration>,
+        write_timeout: Option<Duration>,
+    ) -> Result<usize> {
+        use tokio::io::{AsyncBufRead, Interest};
+
+        // what is already read into the buffer of this stream has to go through userspace
+        let buffered =
+            futures::future::poll_fn(|cx| match Pin::new(&mut self.stream).poll_fill_buf(cx) {
+                Poll::Ready(Ok(buf)) => Poll::Ready(Ok(buf[..buf.len().min(len)].to_vec())),
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Ready(Ok(vec![])),
+            })
+            .await
+            .or_err(ReadError, "while reading buffered data to splice")?;
+        Pin::new(&mut self.stream).consume(buffered.len());
+        if !buffered.is_empty() {
+            dst.write_all(&buffered)
+                .await
+                .or_err(WriteError, "while writing buffered data to splice")?;
+        }
+        dst.flush()
+            .await
+            .or_err(WriteError, "while flushing before splice")?;
+
+        let mut moved = buffered.len();
+        let src = self.stream.get_ref();
+        let dst = dst.stream.get_ref();
+        let pipe = splice::Pipe::new().or_err(InternalError, "while creating pipe to splice")?;
+        while moved < len {
+            let to_move = (len - moved).min(splice::PIPE_SIZE);
+            let read = splice::splice_ready(
+                src,
+                Interest::READABLE,
+                src.as_raw_fd(),
+                pipe.write_fd(),
+                to_move,
+            );
+            let n = match read_timeout {
+                Some(t) => pingora_timeout::timeout(t, read)
+                    .await
+                    .or_err(ReadTimedout, "while waiting to splice")?,
+                None => read.await,
+            }
+            .or_err(ReadError, "while splicing from socket")?;
+            if n == 0 {
+                // closed
+                break;
+            }
+            let mut in_pipe = n;
+            while in_pipe > 0 {
+                let write = splice::splice_ready(
+                    dst,
+                    Interest::WRITABLE,
+                    pipe.read_fd(),
+                    dst.as_raw_fd(),
+                    in_pipe,
+                );
+                in_pipe -= match write_timeout {
+                    Some(t) => pingora_timeout::timeout(t, write)
+                        .await
+                        .or_err(WriteTimedout, "while waiting to splice")?,
+                    None => write.await,
+                }
+                .or_err(WriteError, "while splicing to socket")?;
+            }
+            moved += n;
+        }
+        Ok(moved)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    use super::RawStream;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use tokio::io::{self, Interest};
+
+    // the default capacity of a pipe
+    pub const PIPE_SIZE: usize = 64 * 1024;
+
+    pub struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        pub fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safety: the fds are just created and owned by nothing else
+            unsafe {
+                Ok(Pipe {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                })
+            }
+        }
+
+        pub fn read_fd(&self) -> RawFd {
+            self.read.as_raw_fd()
+        }
+
+        pub fn write_fd(&self) -> RawFd {
+            self.write.as_raw_fd()
+        }
+    }
+
+    // splice() from `fd_in` to `fd_out` once `stream`, which is one of them, is ready.
+    // The pipe on the other side is always ready: it is either drained or filled before this.
+    pub async fn splice_ready(
+        stream: &RawStream,
+        interest: Interest,
+        fd_in: RawFd,
+        fd_out: RawFd,
+        len: usize,
+    ) -> io::Result<usize> {
+        loop {
+            let res = match stream {
+                RawStream::Tcp(s) => {
+                    s.ready(interest).await?;
+                    s.try_io(interest, || splice(fd_in, fd_out, len))
+                }
+                RawStream::Unix(s) => {
+                    s.ready(interest).await?;
+                    s.try_io(interest, || splice(fd_in, fd_out, len))
+                }
+            };
+            match res {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+
+    fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+        let n = unsafe {
+            libc::splice(
+                fd_in,
+                std::ptr::null_mut(),
+                fd_out,
+                std::ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
 }
 
 impl From<TcpStream> for Stream {
@@ -176,6 +350,7 @@ impl From<TcpStream> for Stream {
             tracer: None,
             read_pending_time: AccumulatedDuration::new(),
             write_pending_time: AccumulatedDuration::new(),
+            connection_permit: None,
         }
     }
 }
@@ -191,6 +366,7 @@ impl From<UnixStream> for Stream {
             tracer: None,
             read_pending_time: AccumulatedDuration::new(),
             write_pending_time: AccumulatedDuration::new(),
+            connection_permit: None,
         }
     }
 }
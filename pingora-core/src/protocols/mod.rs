@@ -83,6 +83,8 @@ pub trait IO:
 {
     /// helper to cast as the reference of the concrete type
     fn as_any(&self) -> &dyn Any;
+    /// helper to cast as the mutable reference of the concrete type
+    fn as_any_mut(&mut self) -> &mut dyn Any;
     /// helper to cast back of the concrete type
     fn into_any(self: Box<Self>) -> Box<dyn Any>;
 }
@@ -107,6 +109,9 @@ where
     fn as_any(&self) -> &dyn Any {
         self
     }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
     fn into_any(self: Box<Self>) -> Box<dyn Any> {
         self
     }
@@ -272,3 +277,24 @@ impl ConnFdReusable for InetSocketAddr {
         }
     }
 }
+
+impl ConnFdReusable for [InetSocketAddr] {
+    fn check_fd_match<V: AsRawFd>(&self, fd: V) -> bool {
+        let fd = fd.as_raw_fd();
+        match getpeername::<SockaddrStorage>(fd) {
+            Ok(peer) => {
+                if self.iter().any(|addr| SockaddrStorage::from(*addr) == peer) {
+                    debug!("Inet FD to: {peer:?} is reusable");
+                    true
+                } else {
+                    error!("Crit: FD mismatch: fd: {fd:?}, addrs: {self:?}, peer: {peer:?}",);
+                    false
+                }
+            }
+            Err(e) => {
+                debug!("Idle connection is broken: {e:?}");
+                false
+            }
+        }
+    }
+}
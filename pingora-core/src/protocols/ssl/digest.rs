@@ -14,7 +14,9 @@
 
 //! TLS information from the TLS connection
 
-use crate::tls::{hash::MessageDigest, ssl::SslRef};
+#[cfg(not(feature = "boringssl"))]
+use crate::tls::ssl::SslVerifyMode;
+use crate::tls::{hash::MessageDigest, ssl::SslRef, x509::X509};
 use crate::utils;
 
 /// The TLS connection information
@@ -30,6 +32,13 @@ pub struct SslDigest {
     pub serial_number: Option<String>,
     /// The digest of the peer's certificate
     pub cert_digest: Vec<u8>,
+    /// The verified certificate chain of the client, starting with the client's certificate
+    ///
+    /// It is only collected on the server side when client certificates are verified, otherwise
+    /// it is empty.
+    pub peer_cert_chain: Vec<X509>,
+    /// Whether this connection resumed a previous TLS session
+    pub session_reused: bool,
 }
 
 impl SslDigest {
@@ -39,19 +48,17 @@ impl SslDigest {
             None => "",
         };
 
-        let (cert_digest, org, sn) = match ssl.peer_certificate() {
+        let (cert_digest, org, sn, peer_cert_chain) = match ssl.peer_certificate() {
             Some(cert) => {
                 let cert_digest = match cert.digest(MessageDigest::sha256()) {
                     Ok(c) => c.as_ref().to_vec(),
                     Err(_) => Vec::new(),
                 };
-                (
-                    cert_digest,
-                    utils::get_organization(&cert),
-                    utils::get_serial(&cert).ok(),
-                )
+                let org = utils::get_organization(&cert);
+                let sn = utils::get_serial(&cert).ok();
+                (cert_digest, org, sn, client_cert_chain(ssl))
             }
-            None => (Vec::new(), None, None),
+            None => (Vec::new(), None, None, Vec::new()),
         };
 
         SslDigest {
@@ -60,6 +67,32 @@ impl SslDigest {
             organization: org,
             serial_number: sn,
             cert_digest,
+            peer_cert_chain,
+            session_reused: ssl.session_reused(),
         }
     }
+
+    /// The hex encoded SHA-256 fingerprint of the peer's certificate, empty if there is none
+    pub fn cert_fingerprint(&self) -> String {
+        self.cert_digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+// Only the chain of a client is collected, and only when it is verified.
+#[cfg(not(feature = "boringssl"))]
+fn client_cert_chain(ssl: &SslRef) -> Vec<X509> {
+    if !ssl.is_server() || !ssl.verify_mode().contains(SslVerifyMode::PEER) {
+        return Vec::new();
+    }
+    ssl.verified_chain().map_or_else(Vec::new, |chain| {
+        chain.iter().map(|c| c.to_owned()).collect()
+    })
+}
+
+#[cfg(feature = "boringssl")]
+fn client_cert_chain(_ssl: &SslRef) -> Vec<X509> {
+    Vec::new()
 }
@@ -0,0 +1,478 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Layered configuration loading
+//!
+//! A [LayeredConf] merges the layers of a configuration in order, the later ones overriding the
+//! values set by the earlier ones:
+//! 1. the defaults of the configuration type
+//! 2. the configuration file
+//! 3. the `*.yaml` and `*.yml` files in a drop-in directory, in the order of their names
+//! 4. the environment variables with a prefix such as `PINGORA_`
+//! 5. the command line options
+//!
+//! Mappings are merged key by key, while any other value, including lists, replaces the value of
+//! the previous layers.
+//!
+//! The name of an environment variable after the prefix is the path of the key, lowercased, with
+//! `__` separating the nested keys. For example `PINGORA_THREADS=4` sets `threads`, and
+//! `PINGORA_H2_SERVER__MAX_CONCURRENT_STREAMS=100` sets `max_concurrent_streams` of `h2_server`.
+//! The values are parsed as YAML, so `PINGORA_CLIENT_BIND_TO_IPV4='[1.2.3.4]'` sets a list.
+//!
+//! The layers also record where each value comes from, see [LayeredConf::describe()].
+
+use log::debug;
+use pingora_error::{ErrorType::*, OrErr, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The prefix of the environment variables of [ServerConf](super::ServerConf)
+pub const ENV_PREFIX: &str = "PINGORA_";
+
+/// Where a configuration value comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfSource {
+    /// The default of the configuration type
+    Default,
+    /// A configuration file
+    File(PathBuf),
+    /// An environment variable
+    Env(String),
+    /// The command line options
+    Cli,
+}
+
+impl Display for ConfSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ConfSource::Default => write!(f, "default"),
+            ConfSource::File(path) => write!(f, "file {}", path.display()),
+            ConfSource::Env(name) => write!(f, "env {name}"),
+            ConfSource::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// The merged layers of a configuration
+#[derive(Debug, Clone)]
+pub struct LayeredConf {
+    value: Value,
+    // the source of each leaf value, keyed by the dotted path of the key
+    sources: BTreeMap<String, ConfSource>,
+}
+
+impl Default for LayeredConf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LayeredConf {
+    /// Create an empty [LayeredConf].
+    pub fn new() -> Self {
+        LayeredConf {
+            value: Value::Mapping(Mapping::new()),
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// Create a [LayeredConf] whose first layer is the default of `T`.
+    pub fn from_default<T: Default + Serialize>() -> Result<Self> {
+        let value = serde_yaml::to_value(T::default())
+            .or_err(InternalError, "fail to serialize the default configuration")?;
+        let mut conf = Self::new();
+        conf.merge(value, &ConfSource::Default);
+        Ok(conf)
+    }
+
+    /// Merge the given value as a layer.
+    pub fn merge(&mut self, layer: Value, source: &ConfSource) -> &mut Self {
+        merge_value(
+            &mut self.value,
+            layer,
+            &mut vec![],
+            source,
+            &mut self.sources,
+        );
+        self
+    }
+
+    /// Merge the YAML file at `path` as a layer.
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        let path = path.as_ref();
+        let conf_str = fs::read_to_string(path).or_err_with(ReadError, || {
+            format!("Unable to read conf file from {}", path.display())
+        })?;
+        let value: Value = serde_yaml::from_str(&conf_str).or_err_with(ReadError, || {
+            format!("Unable to parse yaml conf {}", path.display())
+        })?;
+        debug!("Conf file read from {}", path.display());
+        // an empty file
+        if value.is_null() {
+            return Ok(self);
+        }
+        Ok(self.merge(value, &ConfSource::File(path.to_path_buf())))
+    }
+
+    /// Merge the `*.yaml` and `*.yml` files in the directory as layers, in the order of their
+    /// names.
+    pub fn add_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<&mut Self> {
+        let dir = dir.as_ref();
+        let entries = fs::read_dir(dir).or_err_with(ReadError, || {
+            format!("Unable to read conf dir {}", dir.display())
+        })?;
+        let mut files = vec![];
+        for entry in entries {
+            let path = entry
+                .or_err_with(ReadError, || {
+                    format!("Unable to read conf dir {}", dir.display())
+                })?
+                .path();
+            let is_yaml = path
+                .extension()
+                .map_or(false, |ext| ext == "yaml" || ext == "yml");
+            if is_yaml && path.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        for file in files {
+            self.add_file(file)?;
+        }
+        Ok(self)
+    }
+
+    /// Merge the environment variables starting with `prefix` as a layer.
+    pub fn add_env(&mut self, prefix: &str) -> Result<&mut Self> {
+        self.add_env_vars(prefix, std::env::vars())
+    }
+
+    /// Merge the given environment variables starting with `prefix` as a layer. The other ones are
+    /// ignored.
+    pub fn add_env_vars<I>(&mut self, prefix: &str, vars: I) -> Result<&mut Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (name, value) in vars {
+            let Some(path) = env_key_path(prefix, &name) else {
+                continue;
+            };
+            let path: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+            let value = parse_env_value(&value);
+            self.set(&path, value, ConfSource::Env(name));
+        }
+        Ok(self)
+    }
+
+    /// Merge the values of `after` which differ from `before` as a layer.
+    ///
+    /// This records the changes made in code, such as applying the command line options to the
+    /// deserialized configuration, with their source.
+    pub fn merge_changes(
+        &mut self,
+        before: &Value,
+        after: Value,
+        source: &ConfSource,
+    ) -> &mut Self {
+        match changes(before, after) {
+            Some(layer) => self.merge(layer, source),
+            None => self,
+        }
+    }
+
+    /// Set the value of the key at `path`, e.g. `["h2_server", "max_concurrent_streams"]`.
+    pub fn set(&mut self, path: &[&str], value: Value, source: ConfSource) -> &mut Self {
+        let layer = path.iter().rev().fold(value, |value, key| {
+            let mut map = Mapping::new();
+            map.insert(Value::from(*key), value);
+            Value::Mapping(map)
+        });
+        self.merge(layer, &source)
+    }
+
+    /// The merged value
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// Where the value of the key at the dotted path, e.g. `h2_server.max_concurrent_streams`,
+    /// comes from. `None` if the key is not set by any layer.
+    pub fn source(&self, path: &str) -> Option<&ConfSource> {
+        self.sources.get(path)
+    }
+
+    /// Deserialize the merged value into `T`.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_yaml::from_value(self.value.clone())
+            .or_err(ReadError, "Unable to parse the merged conf")
+    }
+
+    /// The effective configuration, one value per line with its source, e.g.
+    /// `threads: 4  # env PINGORA_THREADS`
+    pub fn describe(&self) -> String {
+        let mut lines = vec![];
+        describe_value(&self.value, &mut vec![], &self.sources, &mut lines);
+        lines.join("\n")
+    }
+}
+
+fn merge_value(
+    base: &mut Value,
+    layer: Value,
+    path: &mut Vec<String>,
+    source: &ConfSource,
+    sources: &mut BTreeMap<String, ConfSource>,
+) {
+    match (base, layer) {
+        (Value::Mapping(base), Value::Mapping(layer)) => {
+            for (key, value) in layer {
+                let name = key_name(&key);
+                path.push(name);
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_value(base_value, value, path, source, sources),
+                    None => {
+                        record_sources(&value, path, source, sources);
+                        base.insert(key, value);
+                    }
+                }
+                path.pop();
+            }
+        }
+        (base, layer) => {
+            // the old sources under this key no longer apply
+            let key = path.join(".");
+            let prefix = format!("{key}.");
+            sources.retain(|k, _| *k != key && !k.starts_with(&prefix));
+            record_sources(&layer, path, source, sources);
+            *base = layer;
+        }
+    }
+}
+
+// the values of `after` that differ from `before`, keeping the mappings to them
+fn changes(before: &Value, after: Value) -> Option<Value> {
+    match (before, after) {
+        (Value::Mapping(before), Value::Mapping(after)) => {
+            let mut changed = Mapping::new();
+            for (key, value) in after {
+                let value = match before.get(&key) {
+                    Some(old) => changes(old, value),
+                    None => Some(value),
+                };
+                if let Some(value) = value {
+                    changed.insert(key, value);
+                }
+            }
+            (!changed.is_empty()).then_some(Value::Mapping(changed))
+        }
+        (before, after) => (*before != after).then_some(after),
+    }
+}
+
+fn record_sources(
+    value: &Value,
+    path: &mut Vec<String>,
+    source: &ConfSource,
+    sources: &mut BTreeMap<String, ConfSource>,
+) {
+    match value {
+        Value::Mapping(map) if !map.is_empty() => {
+            for (key, value) in map.iter() {
+                path.push(key_name(key));
+                record_sources(value, path, source, sources);
+                path.pop();
+            }
+        }
+        _ => {
+            sources.insert(path.join("."), source.clone());
+        }
+    }
+}
+
+fn describe_value(
+    value: &Value,
+    path: &mut Vec<String>,
+    sources: &BTreeMap<String, ConfSource>,
+    lines: &mut Vec<String>,
+) {
+    match value {
+        Value::Mapping(map) if !map.is_empty() => {
+            for (key, value) in map.iter() {
+                path.push(key_name(key));
+                describe_value(value, path, sources, lines);
+                path.pop();
+            }
+        }
+        _ => {
+            let key = path.join(".");
+            // JSON is the inline form of YAML
+            let value = serde_json::to_string(value).unwrap_or_default();
+            let source = sources.get(&key).unwrap_or(&ConfSource::Default);
+            lines.push(format!("{key}: {value}  # {source}"));
+        }
+    }
+}
+
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        other => serde_json::to_string(other).unwrap_or_default(),
+    }
+}
+
+/// The path of the key that the environment variable sets, if it has the prefix.
+pub(crate) fn env_key_path(prefix: &str, name: &str) -> Option<Vec<String>> {
+    let key = name.strip_prefix(prefix)?;
+    if key.is_empty() {
+        return None;
+    }
+    Some(key.split("__").map(|s| s.to_ascii_lowercase()).collect())
+}
+
+fn parse_env_value(value: &str) -> Value {
+    if value.is_empty() {
+        return Value::String(String::new());
+    }
+    serde_yaml::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+/// Load a configuration of type `T` from the layers in order: the default of `T`, the optional
+/// file and drop-in directory, then the environment variables starting with `env_prefix`.
+///
+/// The returned [LayeredConf] can be used to add more layers, such as command line options,
+/// before deserializing it again.
+pub fn load_layered<T>(
+    file: Option<&str>,
+    dir: Option<&str>,
+    env_prefix: &str,
+) -> Result<(T, LayeredConf)>
+where
+    T: Default + Serialize + DeserializeOwned,
+{
+    let mut layers = LayeredConf::from_default::<T>()?;
+    if let Some(file) = file {
+        layers.add_file(file)?;
+    }
+    if let Some(dir) = dir {
+        layers.add_dir(dir)?;
+    }
+    layers.add_env(env_prefix)?;
+    let conf = layers.deserialize()?;
+    Ok((conf, layers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::configuration::ServerConf;
+
+    #[test]
+    fn test_layers() {
+        let dir = std::env::temp_dir().join("pingora_test_layered_conf");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let file = dir.join("conf.yaml");
+        fs::write(
+            &file,
+            "version: 1\nthreads: 2\nh2_server:\n  max_concurrent_streams: 10\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/10-a.yaml"), "threads: 3\nuser: a\n").unwrap();
+        fs::write(dir.join("conf.d/20-b.yml"), "user: b\n").unwrap();
+        fs::write(dir.join("conf.d/30-c.txt"), "user: c\n").unwrap();
+
+        let mut layers = LayeredConf::from_default::<ServerConf>().unwrap();
+        layers
+            .add_file(&file)
+            .unwrap()
+            .add_dir(dir.join("conf.d"))
+            .unwrap()
+            .add_env_vars(
+                ENV_PREFIX,
+                vec![
+                    (
+                        "PINGORA_H2_SERVER__MAX_CONCURRENT_STREAMS".into(),
+                        "100".into(),
+                    ),
+                    ("PINGORA_CLIENT_BIND_TO_IPV4".into(), "[1.2.3.4]".into()),
+                    ("OTHER_THREADS".into(), "8".into()),
+                ],
+            )
+            .unwrap();
+        layers.set(&["daemon"], Value::Bool(true), ConfSource::Cli);
+
+        let conf: ServerConf = layers.deserialize().unwrap();
+        assert_eq!(conf.threads, 3);
+        assert_eq!(conf.user.as_deref(), Some("b"));
+        assert!(conf.daemon);
+        assert_eq!(conf.client_bind_to_ipv4, vec!["1.2.3.4"]);
+        assert_eq!(conf.h2_server.unwrap().max_concurrent_streams, Some(100));
+
+        assert_eq!(
+            layers.source("threads"),
+            Some(&ConfSource::File(dir.join("conf.d/10-a.yaml")))
+        );
+        assert_eq!(
+            layers.source("user"),
+            Some(&ConfSource::File(dir.join("conf.d/20-b.yml")))
+        );
+        assert_eq!(layers.source("daemon"), Some(&ConfSource::Cli));
+        assert_eq!(
+            layers.source("h2_server.max_concurrent_streams"),
+            Some(&ConfSource::Env(
+                "PINGORA_H2_SERVER__MAX_CONCURRENT_STREAMS".to_string()
+            ))
+        );
+        assert_eq!(layers.source("work_stealing"), Some(&ConfSource::Default));
+
+        let description = layers.describe();
+        assert!(description.contains("threads: 3  # file "));
+        assert!(description.contains(
+            "h2_server.max_concurrent_streams: 100  # env PINGORA_H2_SERVER__MAX_CONCURRENT_STREAMS"
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_changes() {
+        let mut layers = LayeredConf::from_default::<ServerConf>().unwrap();
+        let before = layers.value().clone();
+        let mut after = before.clone();
+        after["daemon"] = Value::Bool(true);
+        layers.merge_changes(&before, after, &ConfSource::Cli);
+        assert_eq!(layers.value()["daemon"], Value::Bool(true));
+        assert_eq!(layers.source("daemon"), Some(&ConfSource::Cli));
+        assert_eq!(layers.source("threads"), Some(&ConfSource::Default));
+
+        // nothing changed
+        let before = layers.value().clone();
+        layers.merge_changes(&before, before.clone(), &ConfSource::Cli);
+        assert_eq!(layers.source("threads"), Some(&ConfSource::Default));
+    }
+
+    #[test]
+    fn test_env_key_path() {
+        assert_eq!(
+            env_key_path("PINGORA_", "PINGORA_H2_SERVER__KEEPALIVE_INTERVAL_SECONDS").unwrap(),
+            vec!["h2_server", "keepalive_interval_seconds"]
+        );
+        assert!(env_key_path("PINGORA_", "PINGORA_").is_none());
+        assert!(env_key_path("PINGORA_", "HOME").is_none());
+    }
+}
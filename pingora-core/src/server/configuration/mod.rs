@@ -16,28 +16,56 @@
 //!
 //! Server configurations define startup settings such as:
 //! * User and group to run as after daemonization
-//! * Number of threads per service
+//! * Resource limits of the process
+//! * Number of threads per service, and their CPU affinity
 //! * Error log file path
 
+use crate::protocols::http::v2::server::H2ServerSettings;
 use clap::Parser;
-use log::{debug, trace};
-use pingora_error::{Error, ErrorType::*, OrErr, Result};
+use log::{debug, trace, warn};
+use pingora_error::{Error, ErrorType, ErrorType::*, OrErr, Result};
+use pingora_runtime::affinity::{numa_node_cpus, parse_cpu_list};
+use pingora_runtime::ThreadOptions;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// The error type of invalid configurations
+pub const INVALID_CONF_ERR: ErrorType = ErrorType::new("InvalidConfError");
+
+/// The prefix of the keys that are ignored by [`ServerConf`], for the users' own settings
+pub const EXTENSION_KEY_PREFIX: &str = "x_";
+
+mod layered;
+
+pub use layered::{load_layered, ConfSource, LayeredConf, ENV_PREFIX};
+
+// the largest flow control window of HTTP/2
+const H2_MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
 
 /// The configuration file
 ///
 /// Pingora configuration files are by default YAML files, but any key value format can potentially
 /// be used.
 ///
+/// # Validation
+/// Unknown keys are logged as warnings, with a suggestion of the likely intended key if there is
+/// one. They are rejected instead when `reject_unknown_keys` is set. See [`ServerConf::check()`]
+/// for the other checks.
+///
 /// # Extension
-/// New keys can be added to the configuration files which this configuration object will ignore.
-/// Then, users can parse these key-values to pass to their code to use.
+/// New keys prefixed with `x_` can be added to the configuration files which this configuration
+/// object will ignore. Then, users can parse these key-values to pass to their code to use.
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ServerConf {
     /// Version
     pub version: usize,
+    /// Fail to load the configuration if it has unknown keys instead of ignoring them with a
+    /// warning. Default `false`.
+    pub reject_unknown_keys: bool,
     /// Whether to run this process in the background.
     pub daemon: bool,
     /// When configured, error log will be written to the given file. Otherwise StdErr will be used.
@@ -54,10 +82,26 @@ pub struct ServerConf {
     pub user: Option<String>,
     /// Similar to `user`, the group this process should switch to.
     pub group: Option<String>,
+    /// The working directory of the daemonized process. Default `/`.
+    pub working_directory: Option<String>,
+    /// If configured, the limit of open file descriptors (`RLIMIT_NOFILE`) is set to this value.
+    pub rlimit_nofile: Option<u64>,
+    /// If configured, the limit of the core dump size in bytes (`RLIMIT_CORE`) is set to this
+    /// value. When it is not 0, core dumps are allowed after switching to `user` as well.
+    pub rlimit_core: Option<u64>,
+    /// Keep the `CAP_NET_BIND_SERVICE` capability after switching to `user`, so that ports below
+    /// 1024 can still be bound, e.g. by a new listener. Linux only.
+    pub keep_net_bind_service: bool,
     /// How many threads **each** service should get. The threads are not shared across services.
     pub threads: usize,
     /// Allow work stealing between threads of the same service. Default `true`.
     pub work_stealing: bool,
+    /// The CPU affinity, priority and name of the threads of each service. See
+    /// [`ThreadSettings`].
+    pub thread_settings: Option<ThreadSettings>,
+    /// The [`ThreadSettings`] of the services by their names. They take precedence over the
+    /// settings of the services themselves and `thread_settings`.
+    pub service_thread_settings: BTreeMap<String, ThreadSettings>,
     /// The path to CA file the SSL library should use. If empty, the default trust store location
     /// defined by the SSL library will be used.
     pub ca_file: Option<String>,
@@ -88,12 +132,26 @@ pub struct ServerConf {
     /// for debugging purposes.
     /// Note: this is an _unstable_ field that may be renamed or removed in the future.
     pub upstream_debug_ssl_keylog: bool,
+    /// The maximum number of open connections to each upstream peer. See [`ConnectorOptions`].
+    /// Note: this is an _unstable_ field that may be renamed or removed in the future.
+    pub upstream_max_connections_per_peer: Option<usize>,
+    /// How many connection attempts can wait for a peer that reached
+    /// `upstream_max_connections_per_peer`. See [`ConnectorOptions`].
+    /// Note: this is an _unstable_ field that may be renamed or removed in the future.
+    pub upstream_connection_queue_size: usize,
+    /// Timeout in seconds of waiting in the connection queue. See [`ConnectorOptions`].
+    /// Note: this is an _unstable_ field that may be renamed or removed in the future.
+    pub upstream_connection_queue_timeout_seconds: Option<u64>,
+    /// The HTTP/2 settings of the downstream connections, such as flow control windows,
+    /// `max_concurrent_streams` and keepalive PING. See [`H2ServerSettings`].
+    pub h2_server: Option<H2ServerSettings>,
 }
 
 impl Default for ServerConf {
     fn default() -> Self {
         ServerConf {
             version: 0,
+            reject_unknown_keys: false,
             client_bind_to_ipv4: vec![],
             client_bind_to_ipv6: vec![],
             ca_file: None,
@@ -104,14 +162,78 @@ impl Default for ServerConf {
             upgrade_sock: "/tmp/pingora_upgrade.sock".to_string(),
             user: None,
             group: None,
+            working_directory: None,
+            rlimit_nofile: None,
+            rlimit_core: None,
+            keep_net_bind_service: false,
             threads: 1,
             work_stealing: true,
+            thread_settings: None,
+            service_thread_settings: BTreeMap::new(),
             upstream_keepalive_pool_size: 128,
             upstream_connect_offload_threadpools: None,
             upstream_connect_offload_thread_per_pool: None,
+            upstream_max_connections_per_peer: None,
+            upstream_connection_queue_size: 0,
+            upstream_connection_queue_timeout_seconds: None,
             grace_period_seconds: None,
             graceful_shutdown_timeout_seconds: None,
+            h2_server: None,
+        }
+    }
+}
+
+/// The settings of the threads of a service, see [`ThreadOptions`]
+///
+/// The CPU affinity and the priority are only supported on Linux.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThreadSettings {
+    /// The name of the threads. The name of the service is used if not set.
+    pub name: Option<String>,
+    /// The CPUs the threads are allowed to run on, in the format of the Linux kernel, e.g.
+    /// `0-3,8`. Any CPU if neither this nor `numa_nodes` is set.
+    pub cpus: Option<String>,
+    /// The NUMA nodes whose CPUs the threads are allowed to run on, in addition to `cpus`.
+    pub numa_nodes: Vec<usize>,
+    /// Pin each thread to a single one of the CPUs above, in turn.
+    pub pin_threads: bool,
+    /// The nice value of the threads, from -20 to 19. A higher value means a lower priority,
+    /// e.g. for background services.
+    pub nice: Option<i32>,
+}
+
+impl ThreadSettings {
+    /// Resolve these settings into [`ThreadOptions`].
+    pub fn thread_options(&self) -> Result<ThreadOptions> {
+        self.resolve()
+            .map_err(|problem| Error::explain(INVALID_CONF_ERR, problem))
+    }
+
+    fn resolve(&self) -> std::result::Result<ThreadOptions, String> {
+        let mut cpus = match self.cpus.as_ref() {
+            Some(list) => parse_cpu_list(list).map_err(|e| format!("cpus: {e}"))?,
+            None => vec![],
+        };
+        for node in self.numa_nodes.iter() {
+            cpus.extend(numa_node_cpus(*node).map_err(|e| format!("numa_nodes: {e}"))?);
         }
+        cpus.sort_unstable();
+        cpus.dedup();
+        if self.pin_threads && cpus.is_empty() {
+            return Err("pin_threads: requires cpus or numa_nodes".to_string());
+        }
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                return Err(format!("nice: {nice} should be between -20 and 19"));
+            }
+        }
+        Ok(ThreadOptions {
+            name: self.name.clone(),
+            cpus,
+            pin_threads: self.pin_threads,
+            nice: self.nice,
+        })
     }
 }
 
@@ -142,6 +264,8 @@ pub struct Opt {
     /// Test the configuration and exit
     ///
     /// When this flag is set, calling `server.bootstrap()` will exit the process without errors
+    /// if the configuration is valid. Otherwise all the problems found are logged and the process
+    /// exits with status 1.
     ///
     /// This flag is useful for upgrading service where the user wants to make sure the new
     /// service can start before shutting down the old server process.
@@ -160,6 +284,17 @@ pub struct Opt {
     /// See [`ServerConf`] for more details of the configuration file.
     #[clap(short, long, help = "The path to the configuration file.", long_help = None)]
     pub conf: Option<String>,
+
+    /// The path to a drop-in directory of configuration files.
+    ///
+    /// The `*.yaml` and `*.yml` files in it override the configuration file, in the order of
+    /// their names. See [`LayeredConf`] for all the layers of the configuration.
+    #[clap(long, help = "The path to a drop-in directory of configuration files.", long_help = None)]
+    pub conf_dir: Option<String>,
+
+    /// Print the effective configuration and where each value comes from, then exit
+    #[clap(long, help = "Print the effective configuration and exit.", long_help = None)]
+    pub print_conf: bool,
 }
 
 /// Create the default instance of Opt based on the current command-line args.
@@ -194,6 +329,51 @@ impl ServerConf {
         }
     }
 
+    /// Build the layers of the configuration: the defaults, the file and the drop-in directory
+    /// of `opt`, the environment variables prefixed with [`ENV_PREFIX`], then the other command
+    /// line options as applied by [`ServerConf::merge_with_opt()`].
+    ///
+    /// Without a file the configuration is generated with `version: 1`, like [`ServerConf::new()`].
+    ///
+    /// Only the environment variables of known keys are used, because other variables with the
+    /// prefix could be meant for something else.
+    pub fn layers(opt: &Opt) -> Result<LayeredConf> {
+        let mut layers = LayeredConf::from_default::<Self>()?;
+        match opt.conf.as_ref() {
+            Some(path) => {
+                layers.add_file(path)?;
+            }
+            None => {
+                layers.set(
+                    &["version"],
+                    serde_yaml::Value::from(1),
+                    ConfSource::Default,
+                );
+            }
+        }
+        if let Some(dir) = opt.conf_dir.as_ref() {
+            layers.add_dir(dir)?;
+        }
+        let known = known_keys::<Self>();
+        let vars = std::env::vars().filter(|(name, _)| {
+            layered::env_key_path(ENV_PREFIX, name).map_or(false, |path| known.contains(&path[0]))
+        });
+        layers.add_env_vars(ENV_PREFIX, vars)?;
+
+        // the command line layer is whatever merge_with_opt() changes
+        let mut conf: Self = layers.deserialize()?;
+        let before = serde_yaml::to_value(&conf).or_err(InternalError, "fail to serialize conf")?;
+        conf.merge_with_opt(opt);
+        let after = serde_yaml::to_value(&conf).or_err(InternalError, "fail to serialize conf")?;
+        layers.merge_changes(&before, after, &ConfSource::Cli);
+        Ok(layers)
+    }
+
+    /// Parse and validate the merged layers of the configuration.
+    pub fn from_layers(layers: &LayeredConf) -> Result<Self> {
+        Self::from_yaml_value(layers.value().clone())
+    }
+
     pub fn new() -> Option<Self> {
         Self::from_yaml("---\nversion: 1").ok()
     }
@@ -211,21 +391,189 @@ impl ServerConf {
 
     pub fn from_yaml(conf_str: &str) -> Result<Self> {
         trace!("Read conf file: {conf_str}");
-        let conf: ServerConf = serde_yaml::from_str(conf_str).or_err_with(ReadError, || {
-            format!("Unable to parse yaml conf {conf_str}")
-        })?;
+        let value: serde_yaml::Value = serde_yaml::from_str(conf_str)
+            .or_err_with(ReadError, || {
+                format!("Unable to parse yaml conf {conf_str}")
+            })?;
+        Self::from_yaml_value(value)
+    }
 
+    // parse and validate, reporting the unknown keys together with the other problems if they
+    // are rejected
+    fn from_yaml_value(value: serde_yaml::Value) -> Result<Self> {
+        let unknown = unknown_keys(&value);
+        let conf: ServerConf = match serde_yaml::from_value(value) {
+            Ok(conf) => conf,
+            Err(e) => {
+                // the unknown keys may explain the error, e.g., a misspelled key of a mapping
+                let mut problems = unknown;
+                problems.push(e.to_string());
+                return Error::e_explain(ReadError, problems_to_string(&problems));
+            }
+        };
         trace!("Loaded conf: {conf:?}");
-        conf.validate()
+        let mut problems = if conf.reject_unknown_keys {
+            unknown
+        } else {
+            for problem in unknown {
+                warn!("Ignored in the configuration: {problem}");
+            }
+            vec![]
+        };
+        problems.extend(conf.check());
+        if problems.is_empty() {
+            Ok(conf)
+        } else {
+            Error::e_explain(INVALID_CONF_ERR, problems_to_string(&problems))
+        }
     }
 
     pub fn to_yaml(&self) -> String {
         serde_yaml::to_string(self).unwrap()
     }
 
+    /// Validate the configuration, returning all the problems found in one error.
     pub fn validate(self) -> Result<Self> {
-        // TODO: do the validation
-        Ok(self)
+        let problems = self.check();
+        if problems.is_empty() {
+            Ok(self)
+        } else {
+            Error::e_explain(INVALID_CONF_ERR, problems_to_string(&problems))
+        }
+    }
+
+    /// Check the configuration and return the problems found, if any.
+    ///
+    /// Besides the value of each field, this checks that the fields agree with each other, and
+    /// that the files and directories in use exist and can be written to if they need to be.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.threads == 0 {
+            problems.push("threads: should be at least 1".to_string());
+        }
+        match (
+            self.upstream_connect_offload_threadpools,
+            self.upstream_connect_offload_thread_per_pool,
+        ) {
+            (Some(pools), Some(threads)) => {
+                if pools == 0 || threads == 0 {
+                    problems.push(
+                        "upstream_connect_offload_threadpools, \
+                         upstream_connect_offload_thread_per_pool: should be at least 1"
+                            .to_string(),
+                    );
+                }
+            }
+            (None, None) => {}
+            _ => problems.push(
+                "upstream_connect_offload_threadpools, upstream_connect_offload_thread_per_pool: \
+                 should be set together"
+                    .to_string(),
+            ),
+        }
+        for addr in self.client_bind_to_ipv4.iter() {
+            if addr.parse::<Ipv4Addr>().is_err() {
+                problems.push(format!(
+                    "client_bind_to_ipv4: {addr} is not an IPv4 address"
+                ));
+            }
+        }
+        for addr in self.client_bind_to_ipv6.iter() {
+            if addr.parse::<Ipv6Addr>().is_err() {
+                problems.push(format!(
+                    "client_bind_to_ipv6: {addr} is not an IPv6 address"
+                ));
+            }
+        }
+        if self.upstream_max_connections_per_peer == Some(0) {
+            problems.push("upstream_max_connections_per_peer: should be at least 1".to_string());
+        }
+        if self.rlimit_nofile == Some(0) {
+            problems.push("rlimit_nofile: should be at least 1".to_string());
+        }
+        if self.keep_net_bind_service && self.user.is_none() {
+            problems.push("keep_net_bind_service: requires user to be set".to_string());
+        }
+        if let Some(Err(problem)) = self.thread_settings.as_ref().map(|t| t.resolve()) {
+            problems.push(format!("thread_settings.{problem}"));
+        }
+        for (service, settings) in self.service_thread_settings.iter() {
+            if let Err(problem) = settings.resolve() {
+                problems.push(format!("service_thread_settings.{service}.{problem}"));
+            }
+        }
+        if let Some(h2) = self.h2_server.as_ref() {
+            for (key, size) in [
+                (
+                    "initial_connection_window_size",
+                    h2.initial_connection_window_size,
+                ),
+                ("initial_stream_window_size", h2.initial_stream_window_size),
+            ] {
+                if size.map_or(false, |s| s > H2_MAX_WINDOW_SIZE) {
+                    problems.push(format!(
+                        "h2_server.{key}: should be at most {H2_MAX_WINDOW_SIZE}"
+                    ));
+                }
+            }
+            if h2.keepalive_interval_seconds == Some(0) {
+                problems
+                    .push("h2_server.keepalive_interval_seconds: should be at least 1".to_string());
+            }
+            if h2.keepalive_timeout_seconds.is_some() && h2.keepalive_interval_seconds.is_none() {
+                problems.push(
+                    "h2_server.keepalive_timeout_seconds: requires keepalive_interval_seconds"
+                        .to_string(),
+                );
+            }
+        }
+
+        // paths
+        check_parent_dir("upgrade_sock", &self.upgrade_sock, &mut problems);
+        if self.daemon {
+            check_parent_dir("pid_file", &self.pid_file, &mut problems);
+            if let Some(dir) = self.working_directory.as_ref() {
+                if !Path::new(dir).is_dir() {
+                    problems.push(format!("working_directory: {dir} is not a directory"));
+                }
+            }
+        }
+        if let Some(error_log) = self.error_log.as_ref() {
+            if Path::new(error_log).exists() {
+                if !writable(error_log) {
+                    problems.push(format!("error_log: {error_log} is not writable"));
+                }
+            } else {
+                check_parent_dir("error_log", error_log, &mut problems);
+            }
+        }
+        if let Some(ca_file) = self.ca_file.as_ref() {
+            if !Path::new(ca_file).is_file() {
+                problems.push(format!("ca_file: {ca_file} does not exist"));
+            }
+        }
+        problems
+    }
+
+    /// The [`ThreadOptions`] of the service of the given name: its entry in
+    /// `service_thread_settings` if any, otherwise the given options of the service itself if
+    /// any, otherwise `thread_settings`.
+    pub fn service_thread_options(
+        &self,
+        service: &str,
+        own: Option<ThreadOptions>,
+    ) -> Result<ThreadOptions> {
+        if let Some(settings) = self.service_thread_settings.get(service) {
+            return settings.thread_options();
+        }
+        if let Some(options) = own {
+            return Ok(options);
+        }
+        match self.thread_settings.as_ref() {
+            Some(settings) => settings.thread_options(),
+            None => Ok(ThreadOptions::default()),
+        }
     }
 
     pub fn merge_with_opt(&mut self, opt: &Opt) {
@@ -235,6 +583,131 @@ impl ServerConf {
     }
 }
 
+fn problems_to_string(problems: &[String]) -> String {
+    let mut s = format!("{} problem(s) found in the configuration", problems.len());
+    for problem in problems {
+        s.push_str("\n  ");
+        s.push_str(problem);
+    }
+    s
+}
+
+fn writable(path: &str) -> bool {
+    use nix::unistd::{access, AccessFlags};
+    access(path, AccessFlags::W_OK).is_ok()
+}
+
+// the file at `path` will be created, so its directory should exist and be writable
+fn check_parent_dir(key: &str, path: &str, problems: &mut Vec<String>) {
+    if path.is_empty() {
+        problems.push(format!("{key}: should not be empty"));
+        return;
+    }
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        problems.push(format!(
+            "{key}: the directory {} of {path} does not exist",
+            dir.display()
+        ));
+    } else if !writable(dir.to_str().unwrap_or_default()) {
+        problems.push(format!(
+            "{key}: the directory {} of {path} is not writable",
+            dir.display()
+        ));
+    }
+}
+
+// the keys of the mapping that the default of T serializes to
+fn known_keys<T: Default + Serialize>() -> Vec<String> {
+    match serde_yaml::to_value(T::default()) {
+        Ok(serde_yaml::Value::Mapping(map)) => map
+            .into_iter()
+            .filter_map(|(k, _)| k.as_str().map(str::to_string))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn unknown_keys(value: &serde_yaml::Value) -> Vec<String> {
+    fn check(
+        map: &serde_yaml::Mapping,
+        known: &[String],
+        prefix: &str,
+        problems: &mut Vec<String>,
+    ) {
+        for (key, _) in map.iter() {
+            let Some(key) = key.as_str() else {
+                continue;
+            };
+            if key.starts_with(EXTENSION_KEY_PREFIX) || known.iter().any(|k| k == key) {
+                continue;
+            }
+            let mut problem = format!("{prefix}{key}: unknown key");
+            if let Some(suggestion) = suggest(key, known) {
+                problem.push_str(&format!(", did you mean `{prefix}{suggestion}`?"));
+            }
+            problems.push(problem);
+        }
+    }
+
+    let mut problems = vec![];
+    let Some(map) = value.as_mapping() else {
+        return problems;
+    };
+    check(map, &known_keys::<ServerConf>(), "", &mut problems);
+    let h2_server = map.get(&serde_yaml::Value::from("h2_server"));
+    if let Some(h2_map) = h2_server.and_then(|v| v.as_mapping()) {
+        let known = known_keys::<H2ServerSettings>();
+        check(h2_map, &known, "h2_server.", &mut problems);
+    }
+    let known = known_keys::<ThreadSettings>();
+    let thread_settings = map.get(&serde_yaml::Value::from("thread_settings"));
+    if let Some(thread_map) = thread_settings.and_then(|v| v.as_mapping()) {
+        check(thread_map, &known, "thread_settings.", &mut problems);
+    }
+    let services = map.get(&serde_yaml::Value::from("service_thread_settings"));
+    if let Some(services) = services.and_then(|v| v.as_mapping()) {
+        for (service, settings) in services.iter() {
+            let (Some(service), Some(thread_map)) = (service.as_str(), settings.as_mapping())
+            else {
+                continue;
+            };
+            let prefix = format!("service_thread_settings.{service}.");
+            check(thread_map, &known, &prefix, &mut problems);
+        }
+    }
+    problems
+}
+
+// the known key closest to the given one, if it is close enough to be a typo
+fn suggest<'a>(key: &str, known: &'a [String]) -> Option<&'a str> {
+    let max_distance = (key.len() / 3).max(2);
+    known
+        .iter()
+        .map(|k| (edit_distance(key, k), k))
+        .filter(|(d, _)| *d <= max_distance)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| k.as_str())
+}
+
+// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -248,6 +721,7 @@ mod tests {
         init_log();
         let conf = ServerConf {
             version: 1,
+            reject_unknown_keys: false,
             client_bind_to_ipv4: vec!["1.2.3.4".to_string(), "5.6.7.8".to_string()],
             client_bind_to_ipv6: vec![],
             ca_file: None,
@@ -258,13 +732,23 @@ mod tests {
             upgrade_sock: "".to_string(),
             user: None,
             group: None,
+            working_directory: None,
+            rlimit_nofile: None,
+            rlimit_core: None,
+            keep_net_bind_service: false,
             threads: 1,
             work_stealing: true,
+            thread_settings: None,
+            service_thread_settings: BTreeMap::new(),
             upstream_keepalive_pool_size: 4,
             upstream_connect_offload_threadpools: None,
             upstream_connect_offload_thread_per_pool: None,
+            upstream_max_connections_per_peer: None,
+            upstream_connection_queue_size: 0,
+            upstream_connection_queue_timeout_seconds: None,
             grace_period_seconds: None,
             graceful_shutdown_timeout_seconds: None,
+            h2_server: None,
         };
         // cargo test -- --nocapture not_a_test_i_cannot_write_yaml_by_hand
         println!("{}", conf.to_yaml());
@@ -302,4 +786,171 @@ version: 1
         assert_eq!(1, conf.version);
         assert_eq!("/tmp/pingora.pid", conf.pid_file);
     }
+
+    #[test]
+    fn test_layers_with_opt() {
+        init_log();
+        // no conf file
+        let opt = Opt::parse_from(["pingora", "-d"]);
+        let layers = ServerConf::layers(&opt).unwrap();
+        let conf = ServerConf::from_layers(&layers).unwrap();
+        assert_eq!(1, conf.version);
+        assert!(conf.daemon);
+        assert_eq!(layers.source("daemon"), Some(&ConfSource::Cli));
+
+        let opt = Opt::parse_from(["pingora"]);
+        let layers = ServerConf::layers(&opt).unwrap();
+        let conf = ServerConf::from_layers(&layers).unwrap();
+        assert_eq!(ServerConf::new_with_opt_override(&opt), Some(conf));
+        assert_eq!(layers.source("daemon"), Some(&ConfSource::Default));
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        init_log();
+        let conf_str = r#"
+---
+version: 1
+thread: 2
+x_my_setting: 1
+h2_server:
+    max_concurent_streams: 100
+        "#;
+        // ignored by default
+        let conf = ServerConf::from_yaml(conf_str).unwrap();
+        assert_eq!(conf.threads, 1);
+        assert_eq!(conf.h2_server.unwrap().max_concurrent_streams, None);
+
+        let conf_str = format!("{conf_str}\nreject_unknown_keys: true\n");
+        let e = ServerConf::from_yaml(&conf_str).unwrap_err();
+        assert_eq!(e.etype(), &INVALID_CONF_ERR);
+        let msg = e.to_string();
+        assert!(msg.contains("thread: unknown key, did you mean `threads`?"));
+        assert!(msg.contains(
+            "h2_server.max_concurent_streams: unknown key, did you mean \
+             `h2_server.max_concurrent_streams`?"
+        ));
+        assert!(!msg.contains("x_my_setting"));
+    }
+
+    #[test]
+    fn test_check() {
+        init_log();
+        let conf_str = r#"
+---
+version: 1
+threads: 0
+upstream_connect_offload_threadpools: 2
+client_bind_to_ipv4:
+    - ::1
+upgrade_sock: /nonexistent/pingora_upgrade.sock
+upstream_max_connections_per_peer: 0
+h2_server:
+    keepalive_timeout_seconds: 10
+        "#;
+        let e = ServerConf::from_yaml(conf_str).unwrap_err();
+        // all the problems are reported at once
+        let msg = e.to_string();
+        assert!(msg.contains("6 problem(s)"), "{msg}");
+        assert!(msg.contains("upstream_max_connections_per_peer: should be at least 1"));
+        assert!(msg.contains("threads: should be at least 1"));
+        assert!(msg.contains("should be set together"));
+        assert!(msg.contains("::1 is not an IPv4 address"));
+        assert!(msg.contains(
+            "the directory /nonexistent of /nonexistent/pingora_upgrade.sock does not exist"
+        ));
+        assert!(msg.contains("requires keepalive_interval_seconds"));
+
+        assert!(ServerConf::new().unwrap().check().is_empty());
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("threads", "threads"), 0);
+        assert_eq!(edit_distance("thread", "threads"), 1);
+        assert_eq!(edit_distance("daemon", "deamon"), 2);
+        let known = known_keys::<ServerConf>();
+        assert_eq!(suggest("deamon", &known), Some("daemon"));
+        assert_eq!(suggest("listeners", &known), None);
+    }
+
+    #[test]
+    fn test_h2_server_settings() {
+        init_log();
+        let conf_str = r#"
+---
+version: 1
+h2_server:
+    max_concurrent_streams: 100
+    initial_connection_window_size: 1048576
+    keepalive_interval_seconds: 30
+        "#
+        .to_string();
+        let conf = ServerConf::from_yaml(&conf_str).unwrap();
+        let h2 = conf.h2_server.unwrap();
+        assert_eq!(h2.max_concurrent_streams, Some(100));
+        assert_eq!(h2.initial_connection_window_size, Some(1048576));
+        assert_eq!(h2.initial_stream_window_size, None);
+        assert_eq!(h2.keepalive_interval_seconds, Some(30));
+    }
+
+    #[test]
+    fn test_thread_settings() {
+        init_log();
+        let conf_str = r#"
+---
+version: 1
+thread_settings:
+    nice: 5
+service_thread_settings:
+    tls:
+        name: tls
+        cpus: 0-1,3
+        pin_threads: true
+        "#;
+        let conf = ServerConf::from_yaml(conf_str).unwrap();
+        let tls = conf.service_thread_options("tls", None).unwrap();
+        assert_eq!(tls.name.as_deref(), Some("tls"));
+        assert_eq!(tls.cpus, [0, 1, 3]);
+        assert!(tls.pin_threads);
+        assert_eq!(tls.nice, None);
+
+        // the settings of the service itself are used over the global ones
+        let own = ThreadOptions {
+            nice: Some(10),
+            ..Default::default()
+        };
+        let background = conf
+            .service_thread_options("background", Some(own.clone()))
+            .unwrap();
+        assert_eq!(background, own);
+        let other = conf.service_thread_options("other", None).unwrap();
+        assert_eq!(other.nice, Some(5));
+
+        let conf_str = r#"
+---
+version: 1
+reject_unknown_keys: true
+service_thread_settings:
+    tls:
+        cpus: 3-1
+        pin_thread: true
+    background:
+        nice: 20
+        "#;
+        let msg = ServerConf::from_yaml(conf_str).unwrap_err().to_string();
+        assert!(
+            msg.contains("service_thread_settings.tls.pin_thread: unknown key"),
+            "{msg}"
+        );
+        // serde itself ignores the unknown keys
+        let conf: ServerConf = serde_yaml::from_str(conf_str).unwrap();
+        let problems = conf.check();
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("service_thread_settings.tls.cpus: invalid CPU list")));
+        assert!(problems.iter().any(
+            |p| p == "service_thread_settings.background.nice: 20 should be between -20 and 19"
+        ));
+    }
 }
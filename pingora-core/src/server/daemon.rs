@@ -13,9 +13,11 @@
 // limitations under the License.
 
 use daemonize::Daemonize;
-use log::{debug, error};
+use log::{debug, error, warn};
+use pingora_error::{Error, ErrorType::*, Result};
 use std::ffi::CString;
 use std::fs::{self, OpenOptions};
+use std::io;
 use std::os::unix::prelude::OpenOptionsExt;
 use std::path::Path;
 
@@ -24,8 +26,8 @@ use crate::server::configuration::ServerConf;
 // Utilities to daemonize a pingora server, i.e. run the process in the background, possibly
 // under a different running user and/or group.
 
-// XXX: this operation should have been done when the old service is exiting.
-// Now the new pid file just kick the old one out of the way
+// The new pid file just kicks the old one out of the way, the old process removes it when
+// exiting, see remove_pid_file()
 fn move_old_pid(path: &str) {
     if !Path::new(path).exists() {
         debug!("Old pid file does not exist");
@@ -45,6 +47,118 @@ fn move_old_pid(path: &str) {
     }
 }
 
+/// Remove the pid file when exiting, if it is still the one of this process.
+pub(crate) fn remove_pid_file(path: &str) {
+    let pid = std::process::id().to_string();
+    // the file is renamed if a new process took over during graceful upgrade
+    for path in [path.to_string(), format!("{path}.old")] {
+        match fs::read_to_string(&path) {
+            Ok(content) if content.trim() == pid => match fs::remove_file(&path) {
+                Ok(()) => debug!("pid file {path} removed"),
+                Err(e) => warn!("failed to remove pid file {path}: {e}"),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Apply the `rlimit_*` settings to the current process.
+///
+/// Only the soft limits are set to the configured values. The hard limits are raised when they
+/// are lower, which requires privileges.
+pub(crate) fn set_rlimits(conf: &ServerConf) -> Result<()> {
+    let limits = [
+        (conf.rlimit_nofile, libc::RLIMIT_NOFILE, "RLIMIT_NOFILE"),
+        (conf.rlimit_core, libc::RLIMIT_CORE, "RLIMIT_CORE"),
+    ];
+    for (limit, resource, name) in limits {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let limit = limit as libc::rlim_t;
+        let mut rlim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: rlim is a valid rlimit to write to
+        if unsafe { libc::getrlimit(resource, &mut rlim) } != 0 {
+            return Error::e_because(
+                InternalError,
+                format!("fail to get {name}"),
+                io::Error::last_os_error(),
+            );
+        }
+        rlim.rlim_cur = limit;
+        rlim.rlim_max = rlim.rlim_max.max(limit);
+        // SAFETY: rlim is a valid rlimit
+        if unsafe { libc::setrlimit(resource, &rlim) } != 0 {
+            return Error::e_because(
+                InternalError,
+                format!("fail to set {name} to {limit}"),
+                io::Error::last_os_error(),
+            );
+        }
+        debug!("{name} set to {limit}");
+    }
+    Ok(())
+}
+
+// The capabilities are dropped when switching from root to another user. To retain
+// CAP_NET_BIND_SERVICE, the permitted capabilities are kept across setuid() via PR_SET_KEEPCAPS,
+// then all but CAP_NET_BIND_SERVICE are dropped after it.
+#[cfg(target_os = "linux")]
+mod caps {
+    use std::io;
+
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+    const CAP_NET_BIND_SERVICE: u32 = 10;
+
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    fn set_keep_caps(keep: bool) -> io::Result<()> {
+        // SAFETY: PR_SET_KEEPCAPS only takes an integer argument
+        if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, keep as libc::c_ulong, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Called before switching the user
+    pub fn keep_caps() -> io::Result<()> {
+        set_keep_caps(true)
+    }
+
+    /// Called after switching the user
+    pub fn retain_net_bind_service() -> io::Result<()> {
+        let header = CapHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0, // this thread
+        };
+        let mut data = [CapData::default(); 2];
+        data[0].effective = 1 << CAP_NET_BIND_SERVICE;
+        data[0].permitted = 1 << CAP_NET_BIND_SERVICE;
+        // SAFETY: the header and the 2 data structs of version 3 are valid to read
+        if unsafe { libc::syscall(libc::SYS_capset, &header as *const CapHeader, data.as_ptr()) }
+            != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        set_keep_caps(false)
+    }
+}
+
 unsafe fn gid_for_username(name: &CString) -> Option<libc::gid_t> {
     let passwd = libc::getpwnam(name.as_ptr() as *const libc::c_char);
     if !passwd.is_null() {
@@ -55,12 +169,15 @@ unsafe fn gid_for_username(name: &CString) -> Option<libc::gid_t> {
 
 /// Start a server instance as a daemon.
 pub fn daemonize(conf: &ServerConf) {
-    // TODO: customize working dir
-
     let daemonize = Daemonize::new()
         .umask(0o007) // allow same group to access files but not everyone else
         .pid_file(&conf.pid_file);
 
+    let daemonize = match conf.working_directory.as_ref() {
+        Some(dir) => daemonize.working_directory(dir),
+        None => daemonize,
+    };
+
     let daemonize = if let Some(error_log) = conf.error_log.as_ref() {
         let err = OpenOptions::new()
             .append(true)
@@ -87,9 +204,17 @@ pub fn daemonize(conf: &ServerConf) {
             let group_id = unsafe { gid_for_username(&user_cstr).map(|gid| gid as u32) };
             #[cfg(target_os = "linux")]
             let group_id = unsafe { gid_for_username(&user_cstr) };
+            #[cfg(target_os = "linux")]
+            let keep_net_bind_service = conf.keep_net_bind_service;
 
             daemonize
                 .privileged_action(move || {
+                    #[cfg(target_os = "linux")]
+                    if keep_net_bind_service {
+                        if let Err(e) = caps::keep_caps() {
+                            error!("failed to keep capabilities: {e}");
+                        }
+                    }
                     if let Some(gid) = group_id {
                         // Set the supplemental group privileges for the child process.
                         unsafe {
@@ -111,4 +236,76 @@ pub fn daemonize(conf: &ServerConf) {
     move_old_pid(&conf.pid_file);
 
     daemonize.start().unwrap(); // hard crash when fail
+
+    if conf.user.is_some() {
+        after_switching_user(conf);
+    } else if conf.keep_net_bind_service {
+        warn!("keep_net_bind_service has no effect without user");
+    }
+}
+
+// restore what is lost when switching the user
+#[cfg(target_os = "linux")]
+fn after_switching_user(conf: &ServerConf) {
+    if conf.keep_net_bind_service {
+        match caps::retain_net_bind_service() {
+            Ok(()) => debug!("CAP_NET_BIND_SERVICE retained"),
+            Err(e) => error!("failed to retain CAP_NET_BIND_SERVICE: {e}"),
+        }
+    }
+    // switching the user disables core dumps
+    if conf.rlimit_core.map_or(false, |core| core > 0) {
+        // SAFETY: PR_SET_DUMPABLE only takes an integer argument
+        if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 1, 0, 0, 0) } != 0 {
+            error!("failed to allow core dumps: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn after_switching_user(conf: &ServerConf) {
+    if conf.keep_net_bind_service {
+        warn!("keep_net_bind_service is only supported on Linux");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_rlimits() {
+        let mut rlim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) };
+        // lowering the soft limit never requires privileges
+        let conf = ServerConf {
+            rlimit_nofile: Some(rlim.rlim_cur as u64 - 1),
+            ..Default::default()
+        };
+        set_rlimits(&conf).unwrap();
+        let mut new_rlim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut new_rlim) };
+        assert_eq!(new_rlim.rlim_cur, rlim.rlim_cur - 1);
+        assert_eq!(new_rlim.rlim_max, rlim.rlim_max);
+        unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) };
+    }
+
+    #[test]
+    fn test_remove_pid_file() {
+        let path = std::env::temp_dir().join("pingora_test_remove_pid_file.pid");
+        let path = path.to_str().unwrap();
+        fs::write(path, "1").unwrap();
+        fs::write(format!("{path}.old"), std::process::id().to_string()).unwrap();
+        remove_pid_file(path);
+        // only the one of this process is removed
+        assert!(Path::new(path).exists());
+        assert!(!Path::new(&format!("{path}.old")).exists());
+        fs::remove_file(path).unwrap();
+    }
 }
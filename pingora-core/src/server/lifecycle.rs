@@ -0,0 +1,325 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Signal handling and the lifecycle of the server
+//!
+//! The [LifecycleController] maps the signals the server receives to [SignalAction]s. By default
+//! - SIGINT: [SignalAction::FastShutdown]
+//! - SIGTERM: [SignalAction::Drain]
+//! - SIGQUIT: [SignalAction::Upgrade]
+//!
+//! A graceful shutdown runs in the phases of [ShutdownPhase]. Any signal mapped to a shutdown
+//! action received while shutting down escalates the shutdown: during the grace period, the
+//! server moves on to force close the services right away; while force closing, the server exits
+//! without waiting for the services.
+//!
+//! The same actions can also be triggered without a signal, such as by the
+//! [AdminHttpApp](crate::apps::admin_http_app::AdminHttpApp), regardless of the signal mapping.
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{error, info};
+pub use nix::sys::signal::Signal;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+
+/// What to do when a signal is received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalAction {
+    /// Exit immediately, interrupting all the unfinished requests
+    FastShutdown,
+    /// Stop accepting new connections, give the existing ones the grace period to finish,
+    /// then exit
+    Drain,
+    /// Send the listening sockets to the new server process, then [SignalAction::Drain]
+    Upgrade,
+    /// Call [LifecycleHandler::reload()]
+    Reload,
+    /// Call [LifecycleHandler::reopen_logs()]
+    ReopenLogs,
+    /// Log the services of the server and call [LifecycleHandler::dump_state()]
+    DumpState,
+    /// Do nothing
+    Ignore,
+}
+
+/// Where a [SignalAction] comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ActionSource {
+    /// A signal mapped to the action
+    Signal(Signal),
+    /// A request to the [AdminHttpApp](crate::apps::admin_http_app::AdminHttpApp)
+    Admin,
+}
+
+impl fmt::Display for ActionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Signal(signal) => write!(f, "{signal}"),
+            Self::Admin => write!(f, "admin request"),
+        }
+    }
+}
+
+pub(crate) type ActionSender = mpsc::UnboundedSender<(ActionSource, SignalAction)>;
+pub(crate) type ActionReceiver = mpsc::UnboundedReceiver<(ActionSource, SignalAction)>;
+
+impl SignalAction {
+    /// Whether this action shuts down the server
+    pub fn is_shutdown(&self) -> bool {
+        matches!(self, Self::FastShutdown | Self::Drain | Self::Upgrade)
+    }
+}
+
+/// The phases of the server shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownPhase {
+    /// The server is running
+    Running,
+    /// The listeners stop accepting new connections, see [ShutdownWatch](super::ShutdownWatch)
+    StopAccepting,
+    /// The existing connections are given `grace_period_seconds` to finish
+    Draining,
+    /// The services are told to wrap up what is left
+    Notifying,
+    /// The runtimes of the services are shut down. Tasks still running after
+    /// `graceful_shutdown_timeout_seconds` are dropped.
+    ForceClosing,
+}
+
+/// The application logic to run on lifecycle events
+///
+/// All the methods do nothing by default.
+#[cfg_attr(not(doc_async_trait), async_trait)]
+pub trait LifecycleHandler: Send + Sync {
+    /// Called when a signal mapped to [SignalAction::Reload] is received
+    async fn reload(&self) {}
+
+    /// Called when a signal mapped to [SignalAction::ReopenLogs] is received
+    async fn reopen_logs(&self) {}
+
+    /// Called when a signal mapped to [SignalAction::DumpState] is received
+    async fn dump_state(&self) {}
+
+    /// Called when the server enters a phase of its shutdown
+    ///
+    /// The server waits up to 5 seconds for the handlers of each phase, less if a shutdown signal
+    /// escalates the shutdown.
+    async fn shutdown_phase(&self, _phase: ShutdownPhase) {}
+}
+
+/// The signal handling and the shutdown phases of a [Server](super::Server)
+pub struct LifecycleController {
+    signals: Vec<(Signal, SignalAction)>,
+    handlers: Vec<Arc<dyn LifecycleHandler>>,
+    phase: watch::Sender<ShutdownPhase>,
+    actions: ActionSender,
+    // taken by listen()
+    action_receiver: Mutex<Option<ActionReceiver>>,
+}
+
+impl Default for LifecycleController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LifecycleController {
+    /// Create a [LifecycleController] with the default signal mapping.
+    pub fn new() -> Self {
+        let (phase, _) = watch::channel(ShutdownPhase::Running);
+        let (actions, action_receiver) = mpsc::unbounded_channel();
+        LifecycleController {
+            signals: vec![
+                (Signal::SIGINT, SignalAction::FastShutdown),
+                (Signal::SIGTERM, SignalAction::Drain),
+                (Signal::SIGQUIT, SignalAction::Upgrade),
+            ],
+            handlers: vec![],
+            phase,
+            actions,
+            action_receiver: Mutex::new(Some(action_receiver)),
+        }
+    }
+
+    /// Map the signal to the action, replacing its previous action if any.
+    pub fn map_signal(&mut self, signal: Signal, action: SignalAction) -> &mut Self {
+        match self.signals.iter_mut().find(|(s, _)| *s == signal) {
+            Some(mapping) => mapping.1 = action,
+            None => self.signals.push((signal, action)),
+        }
+        self
+    }
+
+    /// The action the signal is mapped to
+    pub fn action(&self, signal: Signal) -> Option<SignalAction> {
+        self.signals
+            .iter()
+            .find(|(s, _)| *s == signal)
+            .map(|(_, action)| *action)
+    }
+
+    /// Add a handler of the lifecycle events.
+    pub fn add_handler(&mut self, handler: impl LifecycleHandler + 'static) -> &mut Self {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// Add a handler that is shared with the rest of the application, e.g., the task of a
+    /// background service.
+    pub fn add_shared_handler(&mut self, handler: Arc<dyn LifecycleHandler>) -> &mut Self {
+        self.handlers.push(handler);
+        self
+    }
+
+    /// Subscribe to the shutdown phases of the server.
+    pub fn phase(&self) -> watch::Receiver<ShutdownPhase> {
+        self.phase.subscribe()
+    }
+
+    // The sender of the actions triggered without a signal
+    pub(crate) fn action_sender(&self) -> ActionSender {
+        self.actions.clone()
+    }
+
+    // Start listening to the mapped signals, together with the actions of action_sender(). Must
+    // be called once within a tokio runtime.
+    pub(crate) fn listen(&self) -> ActionReceiver {
+        let rx = self
+            .action_receiver
+            .lock()
+            .unwrap()
+            .take()
+            .expect("listen() should be called only once");
+        for (sig, action) in self.signals.iter().copied() {
+            if action == SignalAction::Ignore {
+                continue;
+            }
+            let mut stream = match signal(SignalKind::from_raw(sig as i32)) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to listen to {sig}: {e}");
+                    continue;
+                }
+            };
+            let tx = self.actions.clone();
+            tokio::spawn(async move {
+                while stream.recv().await.is_some() {
+                    if tx.send((ActionSource::Signal(sig), action)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        rx
+    }
+
+    // Run the handlers of the action which doesn't shut down the server, in the background
+    pub(crate) fn dispatch(&self, action: SignalAction) {
+        for handler in self.handlers.iter() {
+            let handler = handler.clone();
+            match action {
+                SignalAction::Reload => tokio::spawn(async move { handler.reload().await }),
+                SignalAction::ReopenLogs => {
+                    tokio::spawn(async move { handler.reopen_logs().await })
+                }
+                SignalAction::DumpState => tokio::spawn(async move { handler.dump_state().await }),
+                _ => continue,
+            };
+        }
+    }
+
+    // Enter the phase right away. The returned future resolves when the handlers finish with it.
+    pub(crate) fn enter(&self, phase: ShutdownPhase) -> impl Future<Output = ()> + '_ {
+        info!("Shutdown phase: {phase:?}");
+        self.phase.send_replace(phase);
+        let handlers = join_all(self.handlers.iter().map(|h| h.shutdown_phase(phase)));
+        async move {
+            handlers.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counter {
+        reloads: AtomicUsize,
+        phases: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl LifecycleHandler for Arc<Counter> {
+        async fn reload(&self) {
+            self.reloads.fetch_add(1, Ordering::SeqCst);
+        }
+
+        async fn shutdown_phase(&self, _phase: ShutdownPhase) {
+            self.phases.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_map_signal() {
+        let mut controller = LifecycleController::new();
+        assert_eq!(
+            controller.action(Signal::SIGTERM),
+            Some(SignalAction::Drain)
+        );
+        controller
+            .map_signal(Signal::SIGTERM, SignalAction::FastShutdown)
+            .map_signal(Signal::SIGHUP, SignalAction::Reload);
+        assert_eq!(
+            controller.action(Signal::SIGTERM),
+            Some(SignalAction::FastShutdown)
+        );
+        assert_eq!(
+            controller.action(Signal::SIGHUP),
+            Some(SignalAction::Reload)
+        );
+        assert_eq!(controller.action(Signal::SIGUSR1), None);
+        assert!(SignalAction::Upgrade.is_shutdown());
+        assert!(!SignalAction::DumpState.is_shutdown());
+    }
+
+    #[tokio::test]
+    async fn test_signal_dispatch() {
+        let counter = Arc::new(Counter::default());
+        let mut controller = LifecycleController::new();
+        controller
+            .map_signal(Signal::SIGUSR2, SignalAction::Reload)
+            .add_handler(counter.clone());
+        let mut phase = controller.phase();
+
+        let mut signals = controller.listen();
+        nix::sys::signal::raise(Signal::SIGUSR2).unwrap();
+        let (source, action) = signals.recv().await.unwrap();
+        assert_eq!(source, ActionSource::Signal(Signal::SIGUSR2));
+        assert_eq!(action, SignalAction::Reload);
+        controller.dispatch(action);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(counter.reloads.load(Ordering::SeqCst), 1);
+
+        controller.enter(ShutdownPhase::Draining).await;
+        assert_eq!(counter.phases.load(Ordering::SeqCst), 1);
+        assert!(phase.has_changed().unwrap());
+        assert_eq!(*phase.borrow_and_update(), ShutdownPhase::Draining);
+    }
+}
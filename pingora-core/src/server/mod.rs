@@ -16,20 +16,24 @@
 
 pub mod configuration;
 mod daemon;
+pub mod lifecycle;
 pub(crate) mod transfer_fd;
 
-use daemon::daemonize;
+use daemon::{daemonize, remove_pid_file, set_rlimits};
 use log::{debug, error, info, warn};
-use pingora_runtime::Runtime;
-use pingora_timeout::fast_timeout;
+use pingora_runtime::{Runtime, ThreadOptions};
+use pingora_timeout::{fast_timeout, tokio_timeout};
+use std::future::{pending, Future};
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::thread;
-use tokio::signal::unix;
-use tokio::sync::{watch, Mutex};
-use tokio::time::{sleep, Duration};
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::time::Duration;
 
-use crate::services::Service;
+use crate::apps::admin_http_app::ServiceInfo;
+use crate::services::{ReadyNotifier, Service};
 use configuration::{Opt, ServerConf};
+use lifecycle::{ActionReceiver, ActionSource, LifecycleController, ShutdownPhase, SignalAction};
 pub use transfer_fd::Fds;
 
 use pingora_error::{Error, ErrorType, Result};
@@ -40,6 +44,9 @@ const EXIT_TIMEOUT: u64 = 60 * 5;
 /* Time to wait before shutting down listening sockets.
 This is the graceful period for the new service to get ready */
 const CLOSE_TIMEOUT: u64 = 5;
+/* Time to wait for the handlers of a shutdown phase.
+Handlers which take longer are left behind */
+const PHASE_TIMEOUT: u64 = 5;
 
 enum ShutdownType {
     Graceful,
@@ -70,74 +77,213 @@ pub struct Server {
     ///
     /// Panics and other events sentry captures will send to this DSN **only in release mode**
     pub sentry: Option<String>,
+    /// the signal handling and the shutdown phases, see [lifecycle]
+    pub lifecycle: LifecycleController,
+    // what the services are, filled when the server starts, see AdminHttpApp
+    pub(crate) service_info: Arc<parking_lot::RwLock<Vec<ServiceInfo>>>,
+    // how many services have started, see HealthHttpApp
+    pub(crate) started_services: Arc<AtomicUsize>,
 }
 
-// TODO: delete the pid when exit
-
 impl Server {
-    async fn main_loop(&self) -> ShutdownType {
-        // waiting for exit signal
-        // TODO: there should be a signal handling function
-        let mut graceful_upgrade_signal = unix::signal(unix::SignalKind::quit()).unwrap();
-        let mut graceful_terminate_signal = unix::signal(unix::SignalKind::terminate()).unwrap();
-        let mut fast_shutdown_signal = unix::signal(unix::SignalKind::interrupt()).unwrap();
-        tokio::select! {
-            _ = fast_shutdown_signal.recv() => {
-                info!("SIGINT received, exiting");
-                ShutdownType::Quick
-            },
-            _ = graceful_terminate_signal.recv() => {
-                // we receive a graceful terminate, all instances are instructed to stop
-                info!("SIGTERM received, gracefully exiting");
-                // graceful shutdown if there are listening sockets
-                info!("Broadcasting graceful shutdown");
-                match self.shutdown_watch.send(true) {
-                    Ok(_) => { info!("Graceful shutdown started!"); }
-                    Err(e) => {
-                        error!("Graceful shutdown broadcast failed: {e}");
+    async fn main_loop(&self, runtimes: Vec<Runtime>) {
+        // waiting for exit signal, or the same action triggered otherwise
+        let mut signals = self.lifecycle.listen();
+        let shutdown_type = loop {
+            let Some((signal, action)) = signals.recv().await else {
+                error!("No signal to wait for, exiting");
+                break ShutdownType::Quick;
+            };
+            match action {
+                SignalAction::FastShutdown => {
+                    info!("{signal} received, exiting");
+                    break ShutdownType::Quick;
+                }
+                SignalAction::Drain => {
+                    // we receive a graceful terminate, all instances are instructed to stop
+                    info!("{signal} received, gracefully exiting");
+                    break ShutdownType::Graceful;
+                }
+                SignalAction::Upgrade => {
+                    info!("{signal} received, sending socks and gracefully exiting");
+                    if self.send_fds().await
+                        && self
+                            .wait_or_escalate(
+                                &mut signals,
+                                pending(),
+                                Duration::from_secs(CLOSE_TIMEOUT),
+                            )
+                            .await
+                    {
+                        break ShutdownType::Quick;
                     }
+                    break ShutdownType::Graceful;
                 }
-                info!("Broadcast graceful shutdown complete");
-                ShutdownType::Graceful
+                action => self.handle_action(signal, action),
             }
-            _ = graceful_upgrade_signal.recv() => {
-                // TODO: still need to select! on signals in case a fast shutdown is needed
-                // aka: move below to another task and only kick it off here
-                info!("SIGQUIT received, sending socks and gracefully exiting");
-                if let Some(fds) = &self.listen_fds {
-                    let fds = fds.lock().await;
-                    info!("Trying to send socks");
-                    // XXX: this is blocking IO
-                    match fds.send_to_sock(
-                        self.configuration.as_ref().upgrade_sock.as_str())
-                    {
-                        Ok(_) => {info!("listener sockets sent");},
-                        Err(e) => {
-                            error!("Unable to send listener sockets to new process: {e}");
-                            // sentry log error on fd send failure
-                            #[cfg(not(debug_assertions))]
-                            sentry::capture_error(&e);
-                        }
+        };
+
+        let mut shutdown_timeout = Duration::from_secs(0);
+        if matches!(shutdown_type, ShutdownType::Graceful) && !self.drain(&mut signals).await {
+            // Give tokio runtimes time to exit
+            shutdown_timeout = Duration::from_secs(
+                self.configuration
+                    .as_ref()
+                    .graceful_shutdown_timeout_seconds
+                    .unwrap_or(5),
+            );
+        }
+
+        let escalated = self
+            .enter_phase(&mut signals, ShutdownPhase::ForceClosing)
+            .await;
+        let mut done = shutdown_runtimes(runtimes, shutdown_timeout);
+        if escalated {
+            info!("Exiting without waiting for runtimes");
+            return;
+        }
+        loop {
+            tokio::select! {
+                _ = &mut done => {
+                    info!("All runtimes exited");
+                    return;
+                }
+                Some((signal, action)) = signals.recv() => {
+                    if action.is_shutdown() {
+                        info!("{signal} received, exiting without waiting for runtimes");
+                        return;
                     }
-                    sleep(Duration::from_secs(CLOSE_TIMEOUT)).await;
-                    info!("Broadcasting graceful shutdown");
-                    // gracefully exiting
-                    match self.shutdown_watch.send(true) {
-                        Ok(_) => { info!("Graceful shutdown started!"); }
-                        Err(e) => {
-                            error!("Graceful shutdown broadcast failed: {e}");
-                            // switch to fast shutdown
-                            return ShutdownType::Graceful;
-                        }
+                    self.handle_action(signal, action);
+                }
+            }
+        }
+    }
+
+    // Run the graceful shutdown phases until the runtimes are to be shut down. Return true if a
+    // shutdown signal escalates the shutdown in the middle.
+    async fn drain(&self, signals: &mut ActionReceiver) -> bool {
+        if self
+            .enter_phase(signals, ShutdownPhase::StopAccepting)
+            .await
+        {
+            return true;
+        }
+        // graceful shutdown if there are listening sockets
+        info!("Broadcasting graceful shutdown");
+        match self.shutdown_watch.send(true) {
+            Ok(_) => {
+                info!("Graceful shutdown started!");
+            }
+            Err(e) => {
+                error!("Graceful shutdown broadcast failed: {e}");
+            }
+        }
+        info!("Broadcast graceful shutdown complete");
+
+        if self.enter_phase(signals, ShutdownPhase::Draining).await {
+            return true;
+        }
+        let exit_timeout = self
+            .configuration
+            .as_ref()
+            .grace_period_seconds
+            .unwrap_or(EXIT_TIMEOUT);
+        info!("Graceful shutdown: grace period {}s starts", exit_timeout);
+        if self
+            .wait_or_escalate(signals, pending(), Duration::from_secs(exit_timeout))
+            .await
+        {
+            return true;
+        }
+        info!("Graceful shutdown: grace period ends");
+
+        self.enter_phase(signals, ShutdownPhase::Notifying).await
+    }
+
+    // Enter the shutdown phase and give its handlers up to PHASE_TIMEOUT to finish with it. Return
+    // true if a shutdown signal escalates the shutdown before that.
+    async fn enter_phase(&self, signals: &mut ActionReceiver, phase: ShutdownPhase) -> bool {
+        let handlers = self.lifecycle.enter(phase);
+        let mut finished = false;
+        let task = async {
+            handlers.await;
+            finished = true;
+        };
+        let escalated = self
+            .wait_or_escalate(signals, task, Duration::from_secs(PHASE_TIMEOUT))
+            .await;
+        if !escalated && !finished {
+            warn!("Shutdown phase {phase:?} handlers didn't finish in {PHASE_TIMEOUT}s, moving on");
+        }
+        escalated
+    }
+
+    // Wait for the task to finish, for at most the given duration. Return true if a shutdown
+    // signal escalates the shutdown before either.
+    async fn wait_or_escalate(
+        &self,
+        signals: &mut ActionReceiver,
+        task: impl Future<Output = ()>,
+        duration: Duration,
+    ) -> bool {
+        let wait = tokio_timeout(duration, task);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => return false,
+                Some((signal, action)) = signals.recv() => {
+                    if action.is_shutdown() {
+                        info!("{signal} received, escalating the shutdown");
+                        return true;
                     }
-                    info!("Broadcast graceful shutdown complete");
-                    ShutdownType::Graceful
-                } else {
-                    info!("No socks to send, shutting down.");
-                    ShutdownType::Graceful
+                    self.handle_action(signal, action);
+                }
+            }
+        }
+    }
+
+    // Handle the actions which don't shut down the server
+    fn handle_action(&self, signal: ActionSource, action: SignalAction) {
+        match action {
+            SignalAction::Ignore => return,
+            SignalAction::Reload => info!("{signal} received, reloading"),
+            SignalAction::ReopenLogs => info!("{signal} received, reopening logs"),
+            SignalAction::DumpState => {
+                info!("{signal} received, dumping state");
+                info!("Shutdown phase: {:?}", *self.lifecycle.phase().borrow());
+                for service in self.service_info.read().iter() {
+                    info!(
+                        "Service {}: threads {}, listeners {:?}",
+                        service.name, service.threads, service.listeners
+                    );
                 }
-            },
+            }
+            _ => {}
+        }
+        self.lifecycle.dispatch(action);
+    }
+
+    // Send the listening sockets to the new process. Return whether there are sockets to send.
+    async fn send_fds(&self) -> bool {
+        let Some(fds) = &self.listen_fds else {
+            info!("No socks to send, shutting down.");
+            return false;
+        };
+        let fds = fds.lock().await;
+        info!("Trying to send socks");
+        // XXX: this is blocking IO
+        match fds.send_to_sock(self.configuration.as_ref().upgrade_sock.as_str()) {
+            Ok(_) => {
+                info!("listener sockets sent");
+            }
+            Err(e) => {
+                error!("Unable to send listener sockets to new process: {e}");
+                // sentry log error on fd send failure
+                #[cfg(not(debug_assertions))]
+                sentry::capture_error(&e);
+            }
         }
+        true
     }
 
     fn run_service(
@@ -146,11 +292,13 @@ impl Server {
         shutdown: ShutdownWatch,
         threads: usize,
         work_stealing: bool,
+        thread_options: &ThreadOptions,
     ) -> Runtime
 // NOTE: we need to keep the runtime outside async since
         // otherwise the runtime will be dropped.
     {
-        let service_runtime = Server::create_runtime(service.name(), threads, work_stealing);
+        let service_runtime =
+            Server::create_runtime(service.name(), threads, work_stealing, thread_options);
         service_runtime.get_handle().spawn(async move {
             service.start_service(fds, shutdown).await;
             info!("service exited.")
@@ -191,6 +339,9 @@ impl Server {
             configuration: Arc::new(conf),
             options: Some(opt),
             sentry: None,
+            lifecycle: LifecycleController::new(),
+            service_info: Default::default(),
+            started_services: Default::default(),
         }
     }
 
@@ -206,22 +357,38 @@ impl Server {
         let (tx, rx) = watch::channel(false);
 
         let conf = if let Some(opt) = opt.as_ref() {
-            opt.conf.as_ref().map_or_else(
-                || {
-                    // options, no conf, generated
-                    ServerConf::new_with_opt_override(opt).ok_or_else(|| {
-                        Error::explain(ErrorType::ReadError, "Conf generation failed")
-                    })
-                },
-                |_| {
-                    // options and conf loaded
-                    ServerConf::load_yaml_with_opt_override(opt)
-                },
-            )
+            // the conf file, drop-in dir, env vars and command line options
+            let layers = ServerConf::layers(opt);
+            if opt.print_conf {
+                if let Ok(layers) = layers.as_ref() {
+                    println!("{}", layers.describe());
+                }
+            }
+            let conf = layers.and_then(|layers| ServerConf::from_layers(&layers));
+            if opt.print_conf {
+                match conf {
+                    Ok(_) => std::process::exit(0),
+                    Err(e) => {
+                        error!("{e}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            conf
         } else {
             ServerConf::new()
                 .ok_or_else(|| Error::explain(ErrorType::ReadError, "Conf generation failed"))
-        }?;
+        };
+        let conf = match conf {
+            Ok(conf) => conf,
+            Err(e) if opt.as_ref().map_or(false, |o| o.test) => {
+                // report all the problems of the configuration file
+                error!("{e}");
+                error!("Server Test failed, exiting");
+                std::process::exit(1);
+            }
+            Err(e) => return Err(e),
+        };
 
         Ok(Server {
             services: vec![],
@@ -231,6 +398,9 @@ impl Server {
             configuration: Arc::new(conf),
             options: opt,
             sentry: None,
+            lifecycle: LifecycleController::new(),
+            service_info: Default::default(),
+            started_services: Default::default(),
         })
     }
 
@@ -262,6 +432,15 @@ impl Server {
         };
 
         if self.options.as_ref().map_or(false, |o| o.test) {
+            // the configuration may not be loaded from a file, see new_with_opt_and_conf()
+            let problems = self.configuration.check();
+            if !problems.is_empty() {
+                for problem in problems.iter() {
+                    error!("Invalid configuration: {problem}");
+                }
+                error!("Server Test failed, exiting");
+                std::process::exit(1);
+            }
             info!("Server Test passed, exiting");
             std::process::exit(0);
         }
@@ -294,6 +473,11 @@ impl Server {
 
         let conf = self.configuration.as_ref();
 
+        if let Err(e) = set_rlimits(conf) {
+            error!("Failed to set resource limits: {e}, exiting.");
+            std::process::exit(1);
+        }
+
         if conf.daemon {
             info!("Daemonizing the server");
             fast_timeout::pause_for_fork();
@@ -310,51 +494,80 @@ impl Server {
 
         let mut runtimes: Vec<Runtime> = Vec::new();
 
-        while let Some(service) = self.services.pop() {
+        *self.service_info.write() = self
+            .services
+            .iter()
+            .map(|service| ServiceInfo {
+                name: service.name().to_string(),
+                listeners: service.listener_addresses(),
+                threads: service.threads().unwrap_or(conf.threads),
+            })
+            .collect();
+
+        while let Some(mut service) = self.services.pop() {
             let threads = service.threads().unwrap_or(conf.threads);
+            let thread_options =
+                match conf.service_thread_options(service.name(), service.thread_options()) {
+                    Ok(options) => options,
+                    Err(e) => {
+                        error!(
+                            "Invalid thread settings of {}: {e}, exiting.",
+                            service.name()
+                        );
+                        std::process::exit(1);
+                    }
+                };
+            service.set_ready_notifier(ReadyNotifier::new(self.started_services.clone()));
             let runtime = Server::run_service(
                 service,
                 self.listen_fds.clone(),
                 self.shutdown_recv.clone(),
                 threads,
                 conf.work_stealing,
+                &thread_options,
             );
             runtimes.push(runtime);
         }
 
         // blocked on main loop so that it runs forever
         // Only work steal runtime can use block_on()
-        let server_runtime = Server::create_runtime("Server", 1, true);
-        let shutdown_type = server_runtime.get_handle().block_on(self.main_loop());
-
-        if matches!(shutdown_type, ShutdownType::Graceful) {
-            let exit_timeout = self
-                .configuration
-                .as_ref()
-                .grace_period_seconds
-                .unwrap_or(EXIT_TIMEOUT);
-            info!("Graceful shutdown: grace period {}s starts", exit_timeout);
-            thread::sleep(Duration::from_secs(exit_timeout));
-            info!("Graceful shutdown: grace period ends");
+        let server_runtime = Server::create_runtime("Server", 1, true, &ThreadOptions::default());
+        server_runtime
+            .get_handle()
+            .block_on(self.main_loop(runtimes));
+
+        if self.configuration.daemon {
+            remove_pid_file(&self.configuration.pid_file);
         }
+        info!("Exiting now");
+        std::process::exit(0)
+    }
 
-        // Give tokio runtimes time to exit
-        let shutdown_timeout = match shutdown_type {
-            ShutdownType::Quick => Duration::from_secs(0),
-            ShutdownType::Graceful => Duration::from_secs(
-                self.configuration
-                    .as_ref()
-                    .graceful_shutdown_timeout_seconds
-                    .unwrap_or(5),
-            ),
-        };
+    fn create_runtime(
+        name: &str,
+        threads: usize,
+        work_steal: bool,
+        options: &ThreadOptions,
+    ) -> Runtime {
+        if work_steal {
+            Runtime::new_steal_with_options(threads, name, options)
+        } else {
+            Runtime::new_no_steal_with_options(threads, name, options)
+        }
+    }
+}
+
+// Shut down the runtimes in the background, the receiver resolves once they all exit
+fn shutdown_runtimes(runtimes: Vec<Runtime>, timeout: Duration) -> oneshot::Receiver<()> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
         let shutdowns: Vec<_> = runtimes
             .into_iter()
             .map(|rt| {
                 info!("Waiting for runtimes to exit!");
                 thread::spawn(move || {
-                    rt.shutdown_timeout(shutdown_timeout);
-                    thread::sleep(shutdown_timeout)
+                    rt.shutdown_timeout(timeout);
+                    thread::sleep(timeout)
                 })
             })
             .collect();
@@ -363,15 +576,56 @@ impl Server {
                 error!("Failed to shutdown runtime: {:?}", e);
             }
         }
-        info!("All runtimes exited, exiting now");
-        std::process::exit(0)
-    }
+        let _ = tx.send(());
+    });
+    rx
+}
 
-    fn create_runtime(name: &str, threads: usize, work_steal: bool) -> Runtime {
-        if work_steal {
-            Runtime::new_steal(threads, name)
-        } else {
-            Runtime::new_no_steal(threads, name)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use lifecycle::LifecycleHandler;
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+
+    struct Stuck;
+
+    #[async_trait]
+    impl LifecycleHandler for Stuck {
+        async fn shutdown_phase(&self, _phase: ShutdownPhase) {
+            pending::<()>().await
         }
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stuck_phase_handler() {
+        let mut server = Server::new(None).unwrap();
+        server.lifecycle.add_handler(Stuck);
+        let (tx, mut signals) = mpsc::unbounded_channel();
+
+        // the handler is left behind after the timeout
+        let start = Instant::now();
+        assert!(
+            !server
+                .enter_phase(&mut signals, ShutdownPhase::Draining)
+                .await
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(PHASE_TIMEOUT));
+        assert_eq!(*server.lifecycle.phase().borrow(), ShutdownPhase::Draining);
+
+        // a shutdown signal doesn't wait for the handler
+        tx.send((
+            ActionSource::Signal(lifecycle::Signal::SIGINT),
+            SignalAction::FastShutdown,
+        ))
+        .unwrap();
+        let start = Instant::now();
+        assert!(
+            server
+                .enter_phase(&mut signals, ShutdownPhase::ForceClosing)
+                .await
+        );
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
 }
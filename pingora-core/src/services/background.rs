@@ -20,6 +20,7 @@
 //! push-style metrics.
 
 use async_trait::async_trait;
+use pingora_runtime::ThreadOptions;
 use std::sync::Arc;
 
 use super::Service;
@@ -42,6 +43,8 @@ pub struct GenBackgroundService<A> {
     task: Arc<A>,
     /// The number of threads. Default is 1
     pub threads: Option<usize>,
+    /// The CPU affinity, priority and name of the threads. `None` to follow global setting.
+    pub thread_options: Option<ThreadOptions>,
 }
 
 impl<A> GenBackgroundService<A> {
@@ -51,6 +54,7 @@ impl<A> GenBackgroundService<A> {
             name,
             task,
             threads: Some(1),
+            thread_options: None,
         }
     }
 
@@ -76,6 +80,10 @@ where
     fn threads(&self) -> Option<usize> {
         self.threads
     }
+
+    fn thread_options(&self) -> Option<ThreadOptions> {
+        self.thread_options.clone()
+    }
 }
 
 // Helper function to create a background service with a human readable name
@@ -22,14 +22,15 @@ use crate::apps::ServerApp;
 use crate::listeners::{Listeners, ServerAddress, TcpSocketOptions, TlsSettings, TransportStack};
 use crate::protocols::Stream;
 use crate::server::{ListenFds, ShutdownWatch};
-use crate::services::Service as ServiceTrait;
+use crate::services::{ReadyNotifier, Service as ServiceTrait};
 
 use async_trait::async_trait;
 use log::{debug, error, info};
 use pingora_error::Result;
-use pingora_runtime::current_handle;
+use pingora_runtime::{current_handle, ThreadOptions};
 use std::fs::Permissions;
 use std::sync::Arc;
+use tokio::sync::oneshot;
 
 /// The type of service that is associated with a list of listening endpoints and a particular application
 pub struct Service<A> {
@@ -38,6 +39,9 @@ pub struct Service<A> {
     app_logic: Option<A>,
     /// The number of preferred threads. `None` to follow global setting.
     pub threads: Option<usize>,
+    /// The CPU affinity, priority and name of the threads. `None` to follow global setting.
+    pub thread_options: Option<ThreadOptions>,
+    ready: Option<ReadyNotifier>,
 }
 
 impl<A> Service<A> {
@@ -48,6 +52,8 @@ impl<A> Service<A> {
             listeners: Listeners::new(),
             app_logic: Some(app_logic),
             threads: None,
+            thread_options: None,
+            ready: None,
         }
     }
 
@@ -59,6 +65,8 @@ impl<A> Service<A> {
             listeners,
             app_logic: Some(app_logic),
             threads: None,
+            thread_options: None,
+            ready: None,
         }
     }
 
@@ -135,11 +143,13 @@ impl<A: ServerApp + Send + Sync + 'static> Service<A> {
         app_logic: Arc<A>,
         mut stack: TransportStack,
         mut shutdown: ShutdownWatch,
+        listening: oneshot::Sender<()>,
     ) {
         if let Err(e) = stack.listen().await {
             error!("Listen() failed: {e}");
             return;
         }
+        let _ = listening.send(());
 
         // the accept loop, until the system is shutting down
         loop {
@@ -210,13 +220,29 @@ impl<A: ServerApp + Send + Sync + 'static> ServiceTrait for Service<A> {
             .expect("can only start_service() once");
         let app_logic = Arc::new(app_logic);
 
-        let handlers = endpoints.into_iter().map(|endpoint| {
-            let shutdown = shutdown.clone();
-            let my_app_logic = app_logic.clone();
-            runtime.spawn(async move {
-                Self::run_endpoint(my_app_logic, endpoint, shutdown).await;
+        let mut listening = vec![];
+        let handlers: Vec<_> = endpoints
+            .into_iter()
+            .map(|endpoint| {
+                let shutdown = shutdown.clone();
+                let my_app_logic = app_logic.clone();
+                let (tx, rx) = oneshot::channel();
+                listening.push(rx);
+                runtime.spawn(async move {
+                    Self::run_endpoint(my_app_logic, endpoint, shutdown, tx).await;
+                })
             })
-        });
+            .collect();
+
+        if let Some(ready) = self.ready.take() {
+            runtime.spawn(async move {
+                // started once all the endpoints are listening
+                let listened = futures::future::join_all(listening).await;
+                if listened.iter().all(|l| l.is_ok()) {
+                    ready.ready();
+                }
+            });
+        }
 
         futures::future::join_all(handlers).await;
         self.listeners.cleanup();
@@ -230,8 +256,22 @@ impl<A: ServerApp + Send + Sync + 'static> ServiceTrait for Service<A> {
     fn threads(&self) -> Option<usize> {
         self.threads
     }
+
+    fn thread_options(&self) -> Option<ThreadOptions> {
+        self.thread_options.clone()
+    }
+
+    fn set_ready_notifier(&mut self, notifier: ReadyNotifier) {
+        self.ready = Some(notifier);
+    }
+
+    fn listener_addresses(&self) -> Vec<String> {
+        self.listeners.addresses()
+    }
 }
 
+use crate::apps::admin_http_app::AdminHttpApp;
+use crate::apps::health_http_app::HealthHttpApp;
 use crate::apps::prometheus_http_app::PrometheusServer;
 
 impl Service<PrometheusServer> {
@@ -245,3 +285,45 @@ impl Service<PrometheusServer> {
         )
     }
 }
+
+impl Service<HealthHttpApp> {
+    /// The readiness and liveness HTTP server listening to the given TCP address
+    pub fn health_http_service(addr: &str, app: HealthHttpApp) -> Self {
+        let mut service = Service::new("Health HTTP".to_string(), app);
+        service.add_tcp(addr);
+        service
+    }
+}
+
+impl Service<AdminHttpApp> {
+    /// The admin HTTP server listening to the Unix domain socket of the given path
+    ///
+    /// The socket is only accessible by the owner of the process.
+    pub fn admin_http_service_uds(path: &str, app: AdminHttpApp) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut service = Service::new("Admin HTTP".to_string(), app);
+        service.add_uds(path, Some(Permissions::from_mode(0o600)));
+        service
+    }
+
+    /// The admin HTTP server listening to the given loopback TCP address
+    ///
+    /// The token of the [AdminHttpApp] has to be set.
+    pub fn admin_http_service_tcp(addr: &str, app: AdminHttpApp) -> Result<Self> {
+        use pingora_error::{Error, ErrorType::BindError, OrErr};
+
+        let sock_addr: std::net::SocketAddr = addr
+            .parse()
+            .or_err_with(BindError, || format!("invalid admin address {addr}"))?;
+        if !sock_addr.ip().is_loopback() {
+            return Error::e_explain(BindError, "admin address has to be a loopback address");
+        }
+        if !app.has_token() {
+            return Error::e_explain(BindError, "admin token has to be set to listen to TCP");
+        }
+        let mut service = Service::new("Admin HTTP".to_string(), app);
+        service.add_tcp(addr);
+        Ok(service)
+    }
+}
@@ -22,12 +22,31 @@
 //! - services that are just running in the background.
 
 use async_trait::async_trait;
+use pingora_runtime::ThreadOptions;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use crate::server::{ListenFds, ShutdownWatch};
 
 pub mod background;
 pub mod listening;
 
+/// Reports to the server that a service has started, see [`Service::set_ready_notifier()`]
+pub struct ReadyNotifier {
+    started: Arc<AtomicUsize>,
+}
+
+impl ReadyNotifier {
+    pub(crate) fn new(started: Arc<AtomicUsize>) -> Self {
+        ReadyNotifier { started }
+    }
+
+    /// Report that the service has started.
+    pub fn ready(self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 /// The service interface
 #[async_trait]
 pub trait Service: Sync + Send {
@@ -41,6 +60,17 @@ pub trait Service: Sync + Send {
     /// - `shutdown`: the shutdown signal this server would receive.
     async fn start_service(&mut self, fds: Option<ListenFds>, mut shutdown: ShutdownWatch);
 
+    /// This function will be called right before [`Service::start_service()`] with the notifier
+    /// to report that the service has started, see
+    /// [`HealthHttpApp`](crate::apps::health_http_app::HealthHttpApp).
+    ///
+    /// By default the service is considered started right away. Services which take time to
+    /// start, such as to bind their listeners, can keep the notifier and call
+    /// [`ReadyNotifier::ready()`] once they have started.
+    fn set_ready_notifier(&mut self, notifier: ReadyNotifier) {
+        notifier.ready();
+    }
+
     /// The name of the service, just for logging and naming the threads assigned to this service
     ///
     /// Note that due to the limit of the underlying system, only the first 16 chars will be used
@@ -52,4 +82,17 @@ pub trait Service: Sync + Send {
     fn threads(&self) -> Option<usize> {
         None
     }
+
+    /// The preferred CPU affinity, priority and name of the threads of this service
+    ///
+    /// If `None`, the global setting will be used. The setting of this service in the
+    /// configuration file, if any, takes precedence over this.
+    fn thread_options(&self) -> Option<ThreadOptions> {
+        None
+    }
+
+    /// The addresses this service listens to, if any
+    fn listener_addresses(&self) -> Vec<String> {
+        vec![]
+    }
 }
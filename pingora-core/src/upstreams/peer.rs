@@ -15,14 +15,18 @@
 //! Defines where to connect to and how to connect to a remote server
 
 use ahash::AHasher;
+use once_cell::sync::OnceCell;
 use pingora_error::{
     ErrorType::{InternalError, SocketError},
-    OrErr, Result,
+    OkOrErr, OrErr, Result,
 };
+use pingora_pool::GroupKey;
 use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::hash::{Hash, Hasher};
-use std::net::{IpAddr, SocketAddr as InetSocketAddr, ToSocketAddrs as ToInetSocketAddrs};
+use std::net::{
+    IpAddr, Ipv4Addr, SocketAddr as InetSocketAddr, ToSocketAddrs as ToInetSocketAddrs,
+};
 use std::os::unix::net::SocketAddr as UnixSocketAddr;
 use std::os::unix::prelude::AsRawFd;
 use std::path::{Path, PathBuf};
@@ -71,6 +75,13 @@ pub trait Peer: Display + Clone {
     /// The connections to two peers are considered reusable to each other if their reuse hashes are
     /// the same
     fn reuse_hash(&self) -> u64;
+    /// The key of the connection pool group of this [`Peer`], made of [`Self::reuse_hash()`]
+    /// and the full identity of the peer, which rules out collisions of the hash.
+    ///
+    /// By default the identity is the reuse hash itself.
+    fn group_key(&self) -> GroupKey {
+        self.reuse_hash().into()
+    }
     /// Get the proxy setting to connect to the remote server
     fn get_proxy(&self) -> Option<&Proxy> {
         None
@@ -109,6 +120,26 @@ pub trait Peer: Display + Clone {
             None => None,
         }
     }
+    /// The host name of the remote server, if it is defined by name.
+    ///
+    /// The name is resolved when connecting to the server, and [`Self::address()`] only provides
+    /// the port to connect to in this case.
+    fn hostname(&self) -> Option<&str> {
+        None
+    }
+    /// Other addresses of the same remote server, for example, the rest of the IPv4 and IPv6
+    /// addresses its name resolves to.
+    ///
+    /// They are only used when Happy Eyeballs is enabled, see [`Self::happy_eyeballs_delay()`].
+    fn additional_addresses(&self) -> &[InetSocketAddr] {
+        &[]
+    }
+    /// When set, connect to [`Self::address()`] and [`Self::additional_addresses()`] following
+    /// Happy Eyeballs (RFC 8305): connection attempts to IPv6 and IPv4 addresses are raced, each
+    /// one started after this delay if the previous one is not established yet.
+    fn happy_eyeballs_delay(&self) -> Option<Duration> {
+        self.get_peer_options().and_then(|o| o.happy_eyeballs_delay)
+    }
     /// Which local source address this connection should be bind to.
     fn bind_to(&self) -> Option<&InetSocketAddr> {
         match self.get_peer_options() {
@@ -179,6 +210,10 @@ pub trait Peer: Display + Clone {
             .unwrap_or_default()
     }
 
+    /// Whether the given fd is connected to this peer.
+    ///
+    /// Return false if the address of the peer is not known, e.g., it is only defined by
+    /// [`Self::hostname()`].
     fn matches_fd<V: AsRawFd>(&self, fd: V) -> bool {
         self.address().check_fd_match(fd)
     }
@@ -251,6 +286,10 @@ impl Peer for BasicPeer {
         hasher.finish()
     }
 
+    fn group_key(&self) -> GroupKey {
+        GroupKey::new(self.reuse_hash(), &self._address)
+    }
+
     fn get_peer_options(&self) -> Option<&PeerOptions> {
         Some(&self.options)
     }
@@ -315,6 +354,11 @@ pub struct PeerOptions {
     pub tcp_fast_open: bool,
     // use Arc because Clone is required but not allowed in trait object
     pub tracer: Option<Tracer>,
+    // the delay between Happy Eyeballs connection attempts, `None` to disable Happy Eyeballs.
+    // RFC 8305 recommends 250ms
+    pub happy_eyeballs_delay: Option<Duration>,
+    // whether to resume the TLS sessions of the connector's session cache, default true
+    pub tls_session_reuse: bool,
 }
 
 impl PeerOptions {
@@ -342,6 +386,8 @@ impl PeerOptions {
             second_keyshare: true, // default true and noop when not using PQ curves
             tcp_fast_open: false,
             tracer: None,
+            happy_eyeballs_delay: None,
+            tls_session_reuse: true,
         }
     }
 
@@ -391,19 +437,39 @@ impl Display for PeerOptions {
         if let Some(h2_ping_interval) = self.h2_ping_interval {
             write!(f, "h2_ping_interval: {:?},", h2_ping_interval)?;
         }
+        if let Some(delay) = self.happy_eyeballs_delay {
+            write!(f, "happy_eyeballs_delay: {:?},", delay)?;
+        }
+        if !self.tls_session_reuse {
+            write!(f, "tls_session_reuse: false,")?;
+        }
         Ok(())
     }
 }
 
+// The group key of a peer, computed the first time it is needed. It is not carried over to the
+// clones because they are usually modified to make other peers.
+#[derive(Debug, Default)]
+struct GroupKeyCache(OnceCell<GroupKey>);
+
+impl Clone for GroupKeyCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
 /// A peer representing the remote HTTP server to connect to
 #[derive(Debug, Clone)]
 pub struct HttpPeer {
     pub _address: SocketAddr,
+    pub additional_addresses: Vec<InetSocketAddr>,
+    pub hostname: Option<String>,
     pub scheme: Scheme,
     pub sni: String,
     pub proxy: Option<Proxy>,
     pub client_cert_key: Option<Arc<CertKey>>,
     pub options: PeerOptions,
+    group_key: GroupKeyCache,
 }
 
 impl HttpPeer {
@@ -418,19 +484,63 @@ impl HttpPeer {
     fn new_from_sockaddr(address: SocketAddr, tls: bool, sni: String) -> Self {
         HttpPeer {
             _address: address,
+            additional_addresses: vec![],
+            hostname: None,
             scheme: Scheme::from_tls_bool(tls),
             sni,
             proxy: None,
             client_cert_key: None,
             options: PeerOptions::new(),
+            group_key: GroupKeyCache::default(),
         }
     }
 
     /// Create a new [`HttpPeer`] with the given socket address and TLS settings.
+    ///
+    /// # Panics
+    /// If the address cannot be resolved. Use [`Self::try_new()`] to handle the error instead.
     pub fn new<A: ToInetSocketAddrs>(address: A, tls: bool, sni: String) -> Self {
-        let mut addrs_iter = address.to_socket_addrs().unwrap(); //TODO: handle error
-        let addr = addrs_iter.next().unwrap();
-        Self::new_from_sockaddr(SocketAddr::Inet(addr), tls, sni)
+        Self::try_new(address, tls, sni).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Create a new [`HttpPeer`] with the given socket address and TLS settings.
+    ///
+    /// If the address resolves to multiple addresses, the first one is the primary address and the
+    /// rest are kept as the [`Peer::additional_addresses()`].
+    pub fn try_new<A: ToInetSocketAddrs>(address: A, tls: bool, sni: String) -> Result<Self> {
+        let addrs: Vec<_> = address
+            .to_socket_addrs()
+            .or_err(SocketError, "failed to resolve address")?
+            .collect();
+        Self::new_with_addresses(&addrs, tls, sni)
+    }
+
+    /// Create a new [`HttpPeer`] with all the given addresses of the same server.
+    ///
+    /// The first address is the primary address. The rest are kept as the
+    /// [`Peer::additional_addresses()`] to connect to with Happy Eyeballs.
+    pub fn new_with_addresses(
+        addresses: &[InetSocketAddr],
+        tls: bool,
+        sni: String,
+    ) -> Result<Self> {
+        let (primary, additional) = addresses
+            .split_first()
+            .or_err(SocketError, "no address to create HttpPeer")?;
+        let mut peer = Self::new_from_sockaddr(SocketAddr::Inet(*primary), tls, sni);
+        peer.additional_addresses = additional.to_vec();
+        Ok(peer)
+    }
+
+    /// Create a new [`HttpPeer`] with the given host name, port and TLS settings.
+    ///
+    /// Unlike [`Self::new()`], the name is not resolved here but asynchronously by the connector
+    /// when connecting to this peer. See [`crate::connectors::resolver`].
+    pub fn new_from_hostname(host: &str, port: u16, tls: bool, sni: String) -> Self {
+        let placeholder = InetSocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+        let mut peer = Self::new_from_sockaddr(SocketAddr::Inet(placeholder), tls, sni);
+        peer.hostname = Some(host.to_string());
+        peer
     }
 
     /// Create a new [`HttpPeer`] with the given path to Unix domain socket and TLS settings.
@@ -453,6 +563,8 @@ impl HttpPeer {
     ) -> Self {
         HttpPeer {
             _address: SocketAddr::Inet(InetSocketAddr::new(ip_addr, port)),
+            additional_addresses: vec![],
+            hostname: None,
             scheme: Scheme::from_tls_bool(tls),
             sni: sni.to_string(),
             proxy: Some(Proxy {
@@ -463,6 +575,7 @@ impl HttpPeer {
             }),
             client_cert_key: None,
             options: PeerOptions::new(),
+            group_key: GroupKeyCache::default(),
         }
     }
 
@@ -476,6 +589,7 @@ impl HttpPeer {
 impl Hash for HttpPeer {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self._address.hash(state);
+        self.hostname.hash(state);
         self.scheme.hash(state);
         self.proxy.hash(state);
         self.sni.hash(state);
@@ -490,6 +604,9 @@ impl Hash for HttpPeer {
 
 impl Display for HttpPeer {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if let Some(host) = self.hostname.as_ref() {
+            write!(f, "host: {host}, ")?;
+        }
         write!(f, "addr: {}, scheme: {},", self._address, self.scheme)?;
         if !self.sni.is_empty() {
             write!(f, "sni: {},", self.sni)?;
@@ -522,6 +639,15 @@ impl Peer for HttpPeer {
         self.peer_hash()
     }
 
+    /// The key is computed once and cached, so the peer should not be modified after it is used
+    /// to connect. Modify a clone of it instead.
+    fn group_key(&self) -> GroupKey {
+        self.group_key
+            .0
+            .get_or_init(|| GroupKey::new(self.peer_hash(), self))
+            .clone()
+    }
+
     fn get_peer_options(&self) -> Option<&PeerOptions> {
         Some(&self.options)
     }
@@ -534,9 +660,30 @@ impl Peer for HttpPeer {
         self.proxy.as_ref()
     }
 
+    fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    fn additional_addresses(&self) -> &[InetSocketAddr] {
+        &self.additional_addresses
+    }
+
     fn matches_fd<V: AsRawFd>(&self, fd: V) -> bool {
         if let Some(proxy) = self.get_proxy() {
             proxy.next_hop.check_fd_match(fd)
+        } else if self.hostname.is_some() {
+            // The peer doesn't know the addresses of the name. The connector checks the connection
+            // against what the name resolves to instead.
+            false
+        } else if let (SocketAddr::Inet(primary), true) = (
+            self.address(),
+            self.happy_eyeballs_delay().is_some() && !self.additional_addresses.is_empty(),
+        ) {
+            // the connection could be made to any of the addresses
+            let mut addrs = Vec::with_capacity(self.additional_addresses.len() + 1);
+            addrs.push(*primary);
+            addrs.extend_from_slice(&self.additional_addresses);
+            addrs[..].check_fd_match(fd)
         } else {
             self.address().check_fd_match(fd)
         }
@@ -571,3 +718,44 @@ impl Display for Proxy {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_matches_fd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        let fd = stream.as_raw_fd();
+
+        assert!(HttpPeer::new(addr, false, "".to_string()).matches_fd(fd));
+        assert!(BasicPeer::new(&addr.to_string()).matches_fd(fd));
+
+        // a different server
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = other.local_addr().unwrap();
+        assert!(!HttpPeer::new(other, false, "".to_string()).matches_fd(fd));
+        assert!(!BasicPeer::new(&other.to_string()).matches_fd(fd));
+
+        // the peer doesn't know what the name resolves to
+        let peer = HttpPeer::new_from_hostname("localhost", addr.port(), false, "".to_string());
+        assert!(!peer.matches_fd(fd));
+    }
+
+    #[test]
+    fn test_group_key() {
+        let peer = HttpPeer::new("127.0.0.1:443", true, "one.com".to_string());
+        let key = peer.group_key();
+        assert_eq!(key.hash(), peer.reuse_hash());
+        assert_eq!(peer.group_key(), key);
+
+        // the cached key is not carried over to the modified clone
+        let mut other = peer.clone();
+        other.sni = "two.com".to_string();
+        assert_ne!(other.group_key(), key);
+        assert_eq!(peer.clone().group_key(), key);
+    }
+}
@@ -275,6 +275,14 @@ impl Health {
         h.healthy && h.enabled
     }
 
+    pub fn healthy(&self) -> bool {
+        self.0.load().healthy
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.load().enabled
+    }
+
     pub fn enable(&self, enabled: bool) {
         let h = self.0.load();
         if h.enabled != enabled {
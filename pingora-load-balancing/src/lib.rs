@@ -17,7 +17,10 @@
 //! algorithms for proxies to use.
 
 use arc_swap::ArcSwap;
+use async_trait::async_trait;
 use futures::FutureExt;
+use pingora_core::apps::admin_http_app::{AdminBackends, BackendInfo};
+use pingora_core::apps::health_http_app::ReadinessCheck;
 use pingora_core::protocols::l4::socket::SocketAddr;
 use pingora_error::{ErrorType, OrErr, Result};
 use std::collections::hash_map::DefaultHasher;
@@ -260,6 +263,33 @@ impl Backends {
     }
 }
 
+impl AdminBackends for Backends {
+    fn backends(&self) -> Vec<BackendInfo> {
+        let health = self.health.load();
+        self.get_backend()
+            .iter()
+            .map(|backend| {
+                let h = health.get(&backend.hash_key());
+                BackendInfo {
+                    addr: backend.addr.to_string(),
+                    weight: backend.weight,
+                    healthy: h.map_or(self.health_check.is_none(), |h| h.healthy()),
+                    enabled: h.map_or(true, |h| h.enabled()),
+                }
+            })
+            .collect()
+    }
+
+    fn set_enable(&self, addr: &str, enabled: bool) -> bool {
+        let backends = self.get_backend();
+        let Some(backend) = backends.iter().find(|b| b.addr.to_string() == addr) else {
+            return false;
+        };
+        Backends::set_enable(self, backend, enabled);
+        true
+    }
+}
+
 /// A [LoadBalancer] instance contains the service discovery, health check and backend selection
 /// all together.
 ///
@@ -376,10 +406,38 @@ where
     }
 }
 
+impl<S> AdminBackends for LoadBalancer<S>
+where
+    S: BackendSelection + Send + Sync,
+{
+    fn backends(&self) -> Vec<BackendInfo> {
+        AdminBackends::backends(&self.backends)
+    }
+
+    fn set_enable(&self, addr: &str, enabled: bool) -> bool {
+        AdminBackends::set_enable(&self.backends, addr, enabled)
+    }
+}
+
+/// The [LoadBalancer] is ready when at least one of its backends is ready.
+#[async_trait]
+impl<S> ReadinessCheck for LoadBalancer<S>
+where
+    S: BackendSelection + Send + Sync,
+{
+    async fn check(&self) -> std::result::Result<(), String> {
+        let backends = self.backends.get_backend();
+        if backends.iter().any(|backend| self.backends.ready(backend)) {
+            Ok(())
+        } else {
+            Err("no healthy backend".to_string())
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use async_trait::async_trait;
 
     #[tokio::test]
     async fn test_static_backends() {
@@ -425,6 +483,27 @@ mod test {
         assert!(!backends.ready(&bad));
     }
 
+    #[tokio::test]
+    async fn test_admin_backends() {
+        let lb: LoadBalancer<selection::RoundRobin> =
+            LoadBalancer::try_from_iter(["1.1.1.1:80"]).unwrap();
+        let backends = AdminBackends::backends(&lb);
+        assert_eq!(backends.len(), 1);
+        assert_eq!(backends[0].addr, "1.1.1.1:80");
+        assert!(backends[0].healthy && backends[0].enabled);
+
+        assert!(AdminBackends::set_enable(&lb, "1.1.1.1:80", false));
+        assert!(!AdminBackends::set_enable(&lb, "1.0.0.1:80", false));
+        assert!(!AdminBackends::backends(&lb)[0].enabled);
+        assert!(!lb.backends().ready(&Backend::new("1.1.1.1:80").unwrap()));
+        assert_eq!(
+            ReadinessCheck::check(&lb).await,
+            Err("no healthy backend".to_string())
+        );
+        assert!(AdminBackends::set_enable(&lb, "1.1.1.1:80", true));
+        assert!(ReadinessCheck::check(&lb).await.is_ok());
+    }
+
     #[tokio::test]
     async fn test_discovery_readiness() {
         use discovery::Static;
@@ -14,18 +14,22 @@
 
 use foreign_types::ForeignTypeRef;
 use libc::*;
+use openssl::asn1::{Asn1GeneralizedTimeRef, Asn1TimeRef};
 use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
 use openssl::pkey::{HasPrivate, PKeyRef};
-use openssl::ssl::{Ssl, SslAcceptor, SslRef};
+use openssl::ssl::{Ssl, SslAcceptor, SslContext, SslContextBuilder, SslRef, SslSessionCacheMode};
 use openssl::x509::store::X509StoreRef;
 use openssl::x509::verify::X509VerifyParamRef;
 use openssl::x509::X509Ref;
 use openssl_sys::{
-    SSL_ctrl, EVP_PKEY, SSL, SSL_CTRL_SET_GROUPS_LIST, SSL_CTRL_SET_VERIFY_CERT_STORE, X509,
-    X509_VERIFY_PARAM,
+    SSL_CTX_ctrl, SSL_ctrl, EVP_PKEY, SSL, SSL_CTRL_SET_GROUPS_LIST,
+    SSL_CTRL_SET_VERIFY_CERT_STORE, SSL_CTX, X509, X509_VERIFY_PARAM,
 };
 use std::ffi::CString;
 use std::os::raw;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 fn cvt(r: c_int) -> Result<c_int, ErrorStack> {
     if r != 1 {
@@ -45,6 +49,14 @@ extern "C" {
     pub fn SSL_use_certificate(ssl: *const SSL, cert: *mut X509) -> c_int;
     pub fn SSL_use_PrivateKey(ctx: *const SSL, key: *mut EVP_PKEY) -> c_int;
 
+    pub fn SSL_CTX_set_timeout(ctx: *mut SSL_CTX, t: c_long) -> c_long;
+
+    pub fn SSL_CTX_callback_ctrl(
+        ctx: *mut SSL_CTX,
+        cmd: c_int,
+        fp: Option<extern "C" fn()>,
+    ) -> c_long;
+
     pub fn SSL_set_cert_cb(
         ssl: *mut SSL,
         cb: ::std::option::Option<
@@ -200,6 +212,191 @@ pub fn is_suspended_for_cert(error: &openssl::ssl::Error) -> bool {
     error.code().as_raw() == openssl_sys::SSL_ERROR_WANT_X509_LOOKUP
 }
 
+/// The secrets of a session ticket key
+#[derive(Clone, PartialEq, Eq)]
+pub struct TicketKey {
+    /// The name that identifies the key in the tickets it encrypts
+    pub name: [u8; 16],
+    /// The HMAC-SHA256 secret
+    pub hmac_secret: [u8; 32],
+    /// The AES-256-CBC key
+    pub aes_key: [u8; 32],
+}
+
+type TicketKeyCallback = unsafe extern "C" fn(
+    *mut openssl_sys::SSL,
+    *mut c_uchar,
+    *mut c_uchar,
+    *mut openssl_sys::EVP_CIPHER_CTX,
+    *mut openssl_sys::HMAC_CTX,
+    c_int,
+) -> c_int;
+
+// not exported by openssl_sys
+const SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB: c_int = 72;
+const SSL_CTRL_SET_SESS_CACHE_SIZE: c_int = 42;
+
+type TicketKeysFn = Box<dyn Fn() -> Arc<[TicketKey]> + Send + Sync>;
+
+fn ticket_keys_index() -> Index<SslContext, TicketKeysFn> {
+    static INDEX: OnceLock<Index<SslContext, TicketKeysFn>> = OnceLock::new();
+    *INDEX.get_or_init(|| SslContext::new_ex_index().unwrap())
+}
+
+/// Encrypt and decrypt the session tickets of `ctx` with the keys returned by `keys`
+///
+/// The first key encrypts new tickets and all of them decrypt. The tickets decrypted with a key
+/// other than the first one are renewed. `keys` is called for every ticket so it should be cheap.
+///
+/// See [SSL_CTX_set_tlsext_ticket_key_cb](https://www.openssl.org/docs/man1.1.1/man3/SSL_CTX_set_tlsext_ticket_key_cb.html).
+pub fn ssl_ctx_set_ticket_keys<F>(ctx: &mut SslContextBuilder, keys: F)
+where
+    F: Fn() -> Arc<[TicketKey]> + Send + Sync + 'static,
+{
+    let keys: TicketKeysFn = Box::new(keys);
+    ctx.set_ex_data(ticket_keys_index(), keys);
+    unsafe {
+        let cb: TicketKeyCallback = raw_ticket_key_cb;
+        // the callback is passed as a generic function pointer by SSL_CTX_callback_ctrl()
+        SSL_CTX_callback_ctrl(
+            ctx.as_ptr(),
+            SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB,
+            Some(std::mem::transmute::<TicketKeyCallback, extern "C" fn()>(
+                cb,
+            )),
+        );
+    }
+}
+
+unsafe extern "C" fn raw_ticket_key_cb(
+    ssl: *mut openssl_sys::SSL,
+    key_name: *mut c_uchar,
+    iv: *mut c_uchar,
+    cipher_ctx: *mut openssl_sys::EVP_CIPHER_CTX,
+    hmac_ctx: *mut openssl_sys::HMAC_CTX,
+    enc: c_int,
+) -> c_int {
+    let ssl = SslRef::from_ptr(ssl);
+    let Some(keys_fn) = ssl.ssl_context().ex_data(ticket_keys_index()) else {
+        return -1;
+    };
+    let keys = keys_fn();
+    let key_name = std::slice::from_raw_parts_mut(key_name, 16);
+    let (key, ret) = if enc == 1 {
+        // 0: no ticket is issued
+        let Some(key) = keys.first() else {
+            return 0;
+        };
+        if openssl_sys::RAND_bytes(iv, 16) != 1 {
+            return -1;
+        }
+        key_name.copy_from_slice(&key.name);
+        let init = openssl_sys::EVP_EncryptInit_ex(
+            cipher_ctx,
+            openssl_sys::EVP_aes_256_cbc(),
+            std::ptr::null_mut(),
+            key.aes_key.as_ptr(),
+            iv,
+        );
+        if init != 1 {
+            return -1;
+        }
+        (key, 1)
+    } else {
+        // 0: the ticket cannot be decrypted, fall back to a full handshake
+        let Some(index) = keys.iter().position(|k| k.name[..] == *key_name) else {
+            return 0;
+        };
+        let key = &keys[index];
+        let init = openssl_sys::EVP_DecryptInit_ex(
+            cipher_ctx,
+            openssl_sys::EVP_aes_256_cbc(),
+            std::ptr::null_mut(),
+            key.aes_key.as_ptr(),
+            iv,
+        );
+        if init != 1 {
+            return -1;
+        }
+        // 2: the ticket is valid but should be renewed with the current key
+        (key, if index == 0 { 1 } else { 2 })
+    };
+    let init = openssl_sys::HMAC_Init_ex(
+        hmac_ctx,
+        key.hmac_secret.as_ptr() as *const c_void,
+        key.hmac_secret.len() as _,
+        openssl_sys::EVP_sha256(),
+        std::ptr::null_mut(),
+    );
+    if init != 1 {
+        return -1;
+    }
+    ret
+}
+
+/// Set the size limit and the timeout of the server side session ID cache of `ctx`
+///
+/// A `size` of 0 disables the cache.
+///
+/// See [SSL_CTX_sess_set_cache_size](https://www.openssl.org/docs/man1.1.1/man3/SSL_CTX_sess_set_cache_size.html).
+pub fn ssl_ctx_set_session_cache(ctx: &mut SslContextBuilder, size: usize, timeout: Duration) {
+    if size == 0 {
+        ctx.set_session_cache_mode(SslSessionCacheMode::OFF);
+        return;
+    }
+    ctx.set_session_cache_mode(SslSessionCacheMode::SERVER);
+    unsafe {
+        SSL_CTX_ctrl(
+            ctx.as_ptr(),
+            SSL_CTRL_SET_SESS_CACHE_SIZE,
+            size as c_long,
+            std::ptr::null_mut(),
+        );
+        SSL_CTX_set_timeout(ctx.as_ptr(), timeout.as_secs() as c_long);
+    }
+}
+
+/// Load the CRLs in the PEM file into the cert store of `ctx` and check the certificates of the
+/// whole chain against them.
+///
+/// See [X509_load_crl_file](https://www.openssl.org/docs/man1.1.1/man3/X509_load_crl_file.html).
+/// A `path` containing a NUL byte is an error too.
+pub fn ssl_ctx_load_crl_file(
+    ctx: &mut SslContextBuilder,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = CString::new(path)?;
+    unsafe {
+        let store = openssl_sys::SSL_CTX_get_cert_store(ctx.as_ptr());
+        let lookup = openssl_sys::X509_STORE_add_lookup(store, openssl_sys::X509_LOOKUP_file());
+        if lookup.is_null() {
+            return Err(ErrorStack::get().into());
+        }
+        // the number of the CRLs loaded
+        if openssl_sys::X509_load_crl_file(
+            lookup,
+            path.as_ptr(),
+            openssl_sys::X509_FILETYPE_PEM as _,
+        ) <= 0
+        {
+            return Err(ErrorStack::get().into());
+        }
+        cvt(openssl_sys::X509_STORE_set_flags(
+            store,
+            (openssl_sys::X509_V_FLAG_CRL_CHECK | openssl_sys::X509_V_FLAG_CRL_CHECK_ALL) as _,
+        ))?;
+    }
+    Ok(())
+}
+
+/// View the generalized time as an [Asn1TimeRef], e.g., to compare it via [Asn1TimeRef::diff()]
+///
+/// An ASN1_TIME is either a UTCTime or a GeneralizedTime.
+pub fn asn1_generalized_time_as_time(time: &Asn1GeneralizedTimeRef) -> &Asn1TimeRef {
+    // both are ASN1_STRING underneath
+    unsafe { Asn1TimeRef::from_ptr(time.as_ptr() as *mut _) }
+}
+
 #[allow(clippy::mut_from_ref)]
 /// Get a mutable SslRef ouf of SslRef, which is a missing functionality even when holding &mut SslStream
 /// # Safety
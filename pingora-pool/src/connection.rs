@@ -17,8 +17,12 @@
 use log::{debug, warn};
 use parking_lot::{Mutex, RwLock};
 use pingora_timeout::{sleep, timeout};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt};
@@ -26,9 +30,73 @@ use tokio::sync::{oneshot, watch, Notify, OwnedMutexGuard};
 
 use super::lru::Lru;
 
-type GroupKey = u64;
 type ID = i32;
 
+/// The key of a connection group. All connections under the same key are considered the same for
+/// connection reuse.
+///
+/// The pool looks up the group with the hash, then compares the full identity of the group so that
+/// two groups whose hashes collide never share their connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupKey {
+    hash: u64,
+    identity: Arc<[u8]>,
+}
+
+impl GroupKey {
+    /// Create the key of the group identified by `value`, e.g. a peer, with its `hash`.
+    ///
+    /// All the data `value` feeds to [Hasher] is kept as the identity of the group.
+    pub fn new<T: Hash + ?Sized>(hash: u64, value: &T) -> Self {
+        let mut identity = IdentityWriter(vec![]);
+        value.hash(&mut identity);
+        GroupKey {
+            hash,
+            identity: identity.0.into(),
+        }
+    }
+
+    /// The hash of this key
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+// Only the hash is hashed, the identity is compared when the hashes are equal
+impl Hash for GroupKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash.hash(state);
+    }
+}
+
+/// The key identified by the hash alone
+impl From<u64> for GroupKey {
+    fn from(hash: u64) -> Self {
+        Self::new(hash, &hash)
+    }
+}
+
+impl fmt::Display for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.hash)
+    }
+}
+
+// A Hasher that records everything written to it
+struct IdentityWriter(Vec<u8>);
+
+impl Hasher for IdentityWriter {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&self.0);
+        hasher.finish()
+    }
+}
+
 /// the metadata of a connection
 #[derive(Clone, Debug)]
 pub struct ConnectionMeta {
@@ -75,7 +143,8 @@ pub struct PoolNode<T> {
     hot_queue: ArrayQueue<(ID, T)>,
     // to avoid race between 2 evictions on the queue
     hot_queue_remove_lock: Mutex<()>,
-    // TODO: store the GroupKey to avoid hash collision?
+    // the number of items in both the hot queue and the table
+    len: AtomicUsize,
 }
 
 // Keep the queue size small because eviction is O(n) in the queue
@@ -88,11 +157,30 @@ impl<T> PoolNode<T> {
             connections: Mutex::new(HashMap::new()),
             hot_queue: ArrayQueue::new(HOT_QUEUE_SIZE),
             hot_queue_remove_lock: Mutex::new(()),
+            len: AtomicUsize::new(0),
         }
     }
 
+    /// The number of items in the pool
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Whether the pool has no item
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Get any item from the pool
     pub fn get_any(&self) -> Option<(ID, T)> {
+        let item = self.pop_any();
+        if item.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        item
+    }
+
+    fn pop_any(&self) -> Option<(ID, T)> {
         let hot_conn = self.hot_queue.pop();
         if hot_conn.is_some() {
             return hot_conn;
@@ -114,6 +202,23 @@ impl<T> PoolNode<T> {
 
     /// Insert an item with the given unique ID into the pool
     pub fn insert(&self, id: ID, conn: T) {
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.push(id, conn);
+    }
+
+    /// Insert an item with the given unique ID into the pool only if the pool has fewer than
+    /// `max` items. Otherwise the item is given back.
+    pub fn try_insert(&self, id: ID, conn: T, max: usize) -> Result<(), T> {
+        // reserve the slot first so that concurrent inserts cannot go over the limit
+        if self.len.fetch_add(1, Ordering::Relaxed) >= max {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return Err(conn);
+        }
+        self.push(id, conn);
+        Ok(())
+    }
+
+    fn push(&self, id: ID, conn: T) {
         if let Err(node) = self.hot_queue.push((id, conn)) {
             // hot queue is full
             let mut connections = self.connections.lock();
@@ -126,6 +231,14 @@ impl<T> PoolNode<T> {
     /// Remove the item associated with the id from the pool. The item is returned
     /// if it is found and removed.
     pub fn remove(&self, id: ID) -> Option<T> {
+        let removed = self.remove_item(id);
+        if removed.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    fn remove_item(&self, id: ID) -> Option<T> {
         // check the table first as least recent used ones are likely there
         let removed = self.connections.lock().remove(&id);
         if removed.is_some() {
@@ -142,7 +255,7 @@ impl<T> PoolNode<T> {
                     return Some(conn);
                 } else {
                     // not this item, put back to hot queue, but it could also be full
-                    self.insert(conn_id, conn);
+                    self.push(conn_id, conn);
                 }
             } else {
                 // other threads grab all the connections
@@ -154,14 +267,32 @@ impl<T> PoolNode<T> {
     }
 }
 
+/// The options of a [ConnectionPool]
+#[derive(Debug, Clone, Default)]
+pub struct PoolOptions {
+    /// The maximum number of idle connections of each group. When a group is full, the
+    /// connections released to it are closed right away.
+    pub max_per_group: Option<usize>,
+    /// The maximum time a connection can stay idle in the pool, regardless of the timeout given
+    /// to [ConnectionPool::idle_poll()] and [ConnectionPool::idle_timeout()].
+    pub max_idle_time: Option<Duration>,
+}
+
+// The number of shards of the pool table. Each shard has its own lock.
+const POOL_SHARDS: usize = 16;
+
+// the groups whose hashes collide are chained under the same bucket and told apart by their keys
+type PoolShard<S> = RwLock<HashMap<GroupKey, Arc<PoolNode<PoolConnection<S>>>>>;
+
 /// Connection pool
 ///
 /// [ConnectionPool] holds reusable connections. A reusable connection is released to this pool to
 /// be picked up by another user/request.
 pub struct ConnectionPool<S> {
-    // TODO: n-way pools to reduce lock contention
-    pool: RwLock<HashMap<GroupKey, Arc<PoolNode<PoolConnection<S>>>>>,
+    // n-way pools to reduce lock contention
+    shards: Box<[PoolShard<S>]>,
     lru: Lru<ID, ConnectionMeta>,
+    options: PoolOptions,
 }
 
 impl<S> ConnectionPool<S> {
@@ -169,47 +300,72 @@ impl<S> ConnectionPool<S> {
     ///
     /// When a connection is released to this pool, the least recently used connection will be dropped.
     pub fn new(size: usize) -> Self {
+        Self::new_with_options(size, PoolOptions::default())
+    }
+
+    /// Create a new [ConnectionPool] with a size limit and the given [PoolOptions].
+    pub fn new_with_options(size: usize, options: PoolOptions) -> Self {
+        // this is oversized since some connections will have the same key
+        let shard_capacity = size / POOL_SHARDS + 1;
         ConnectionPool {
-            pool: RwLock::new(HashMap::with_capacity(size)), // this is oversized since some connections will have the same key
+            shards: (0..POOL_SHARDS)
+                .map(|_| RwLock::new(HashMap::with_capacity(shard_capacity)))
+                .collect(),
             lru: Lru::new(size),
+            options,
         }
     }
 
-    /* get or create and insert a pool node for the hash key */
-    fn get_pool_node(&self, key: GroupKey) -> Arc<PoolNode<PoolConnection<S>>> {
-        {
-            let pool = self.pool.read();
-            if let Some(v) = pool.get(&key) {
-                return (*v).clone();
-            }
-        } // read lock released here
+    /// The maximum time a connection can stay idle in this pool, if any
+    pub fn max_idle_time(&self) -> Option<Duration> {
+        self.options.max_idle_time
+    }
+
+    /// The number of idle connections under the given group key
+    pub fn idle_count(&self, key: &GroupKey) -> usize {
+        self.find_pool_node(key).map_or(0, |node| node.len())
+    }
+
+    /// The number of idle connections of all the groups
+    pub fn total_idle_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().values().map(|node| node.len()).sum::<usize>())
+            .sum()
+    }
+
+    fn shard(&self, key: &GroupKey) -> &PoolShard<S> {
+        &self.shards[(key.hash % POOL_SHARDS as u64) as usize]
+    }
+
+    fn find_pool_node(&self, key: &GroupKey) -> Option<Arc<PoolNode<PoolConnection<S>>>> {
+        let pool = self.shard(key).read();
+        pool.get(key).cloned()
+        // read lock released here
+    }
+
+    /* get or create and insert a pool node for the key */
+    fn get_pool_node(&self, key: &GroupKey) -> Arc<PoolNode<PoolConnection<S>>> {
+        if let Some(node) = self.find_pool_node(key) {
+            return node;
+        }
 
         {
             // write lock section
-            let mut pool = self.pool.write();
+            let mut pool = self.shard(key).write();
             // check again since another task might have already added it
-            if let Some(v) = pool.get(&key) {
-                return (*v).clone();
-            }
-            let node = Arc::new(PoolNode::new());
-            let node_ret = node.clone();
-            pool.insert(key, node); // TODO: check dup
-            node_ret
+            pool.entry(key.clone())
+                .or_insert_with(|| Arc::new(PoolNode::new()))
+                .clone()
         }
     }
 
     // only remove from the pool because lru already removed it
     fn pop_evicted(&self, meta: &ConnectionMeta) {
-        let pool_node = {
-            let pool = self.pool.read();
-            match pool.get(&meta.key) {
-                Some(v) => (*v).clone(),
-                None => {
-                    warn!("Fail to get pool node for {:?}", meta);
-                    return;
-                } // nothing to pop, should return error?
-            }
-        }; // read lock released here
+        let Some(pool_node) = self.find_pool_node(&meta.key) else {
+            warn!("Fail to get pool node for {:?}", meta);
+            return; // nothing to pop, should return error?
+        };
 
         pool_node.remove(meta.id);
         debug!("evict fd: {} from key {}", meta.id, meta.key);
@@ -223,13 +379,7 @@ impl<S> ConnectionPool<S> {
 
     /// Get a connection from this pool under the same group key
     pub fn get(&self, key: &GroupKey) -> Option<S> {
-        let pool_node = {
-            let pool = self.pool.read();
-            match pool.get(key) {
-                Some(v) => (*v).clone(),
-                None => return None,
-            }
-        }; // read lock released here
+        let pool_node = self.find_pool_node(key)?;
 
         if let Some((id, connection)) = pool_node.get_any() {
             self.lru.pop(&id); // the notified is not needed
@@ -243,26 +393,53 @@ impl<S> ConnectionPool<S> {
     ///
     /// - The returned [`Arc<Notify>`] will notify any listen when the connection is evicted from the pool.
     /// - The returned [`oneshot::Receiver<bool>`] will notify when the connection is being picked up by [Self::get()].
+    ///
+    /// If the group of the connection is already full according to [PoolOptions::max_per_group],
+    /// the connection is dropped and the returned [`Arc<Notify>`] is already notified.
     pub fn put(
         &self,
         meta: &ConnectionMeta,
         connection: S,
     ) -> (Arc<Notify>, oneshot::Receiver<bool>) {
+        let pool_node = self.get_pool_node(&meta.key);
+        let max_per_group = self.options.max_per_group.unwrap_or(usize::MAX);
+        let (notify_use, watch_use) = oneshot::channel();
+        if pool_node.len() >= max_per_group {
+            // don't bother the lru, which could evict another connection for nothing
+            debug!("pool of key {} is full, drop fd: {}", meta.key, meta.id);
+            let notify_close = Arc::new(Notify::new());
+            notify_close.notify_one();
+            return (notify_close, watch_use);
+        }
         let (notify_close, replaced) = self.lru.add(meta.id, meta.clone());
         if let Some(meta) = replaced {
             self.pop_evicted(&meta);
         };
-        let pool_node = self.get_pool_node(meta.key);
-        let (notify_use, watch_use) = oneshot::channel();
         let connection = PoolConnection::new(notify_use, connection);
-        pool_node.insert(meta.id, connection);
+        // check the limit again, other connections might have been added in the meantime
+        if pool_node
+            .try_insert(meta.id, connection, max_per_group)
+            .is_err()
+        {
+            debug!("pool of key {} is full, drop fd: {}", meta.key, meta.id);
+            self.lru.pop(&meta.id);
+            notify_close.notify_one();
+        }
         (notify_close, watch_use)
     }
 
+    // the idle timeout capped by the max idle time
+    fn effective_idle_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        match (timeout, self.options.max_idle_time) {
+            (Some(t), Some(max)) => Some(t.min(max)),
+            (t, max) => t.or(max),
+        }
+    }
+
     /// Actively monitor the health of a connection that is already released to this pool
     ///
-    /// When the connection breaks, or the optional `timeout` is reached this function will
-    /// remove it from the pool and drop the connection.
+    /// When the connection breaks, or the optional `timeout` (capped by [PoolOptions::max_idle_time])
+    /// is reached this function will remove it from the pool and drop the connection.
     ///
     /// If the connection is reused via [Self::get()] or being evicted, this function will just exit.
     pub async fn idle_poll<Stream>(
@@ -277,8 +454,12 @@ impl<S> ConnectionPool<S> {
     {
         let read_result = tokio::select! {
             biased;
-            _ = watch_use => {
-                debug!("idle connection is being picked up");
+            used = watch_use => {
+                if used.is_ok() {
+                    debug!("idle connection is being picked up");
+                } else {
+                    debug!("idle connection is dropped by the pool");
+                }
                 return
             },
             _ = notify_evicted.notified() => {
@@ -286,7 +467,7 @@ impl<S> ConnectionPool<S> {
                 // TODO: gracefully close the connection?
                 return
             }
-            read_result = read_with_timeout(connection , timeout) => read_result
+            read_result = read_with_timeout(connection, self.effective_idle_timeout(timeout)) => read_result
         };
 
         match read_result {
@@ -308,8 +489,9 @@ impl<S> ConnectionPool<S> {
 
     /// Passively wait to close the connection after the timeout
     ///
-    /// If this connection is not being picked up or evicted before the timeout is reach, this
-    /// function will remove it from the pool and close the connection.
+    /// If this connection is not being picked up or evicted before the timeout (capped by
+    /// [PoolOptions::max_idle_time]) is reach, this function will remove it from the pool and close
+    /// the connection.
     pub async fn idle_timeout(
         &self,
         meta: &ConnectionMeta,
@@ -318,10 +500,17 @@ impl<S> ConnectionPool<S> {
         mut notify_closed: watch::Receiver<bool>,
         watch_use: oneshot::Receiver<bool>,
     ) {
+        let timeout = self
+            .effective_idle_timeout(Some(timeout))
+            .unwrap_or(timeout);
         tokio::select! {
             biased;
-            _ = watch_use => {
-                debug!("idle connection is being picked up");
+            used = watch_use => {
+                if used.is_ok() {
+                    debug!("idle connection is being picked up");
+                } else {
+                    debug!("idle connection is dropped by the pool");
+                }
             },
             _ = notify_evicted.notified() => {
                 debug!("idle connection is being evicted");
@@ -370,11 +559,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_lookup() {
-        let meta1 = ConnectionMeta::new(101, 1);
+        let meta1 = ConnectionMeta::new(101.into(), 1);
         let value1 = "v1".to_string();
-        let meta2 = ConnectionMeta::new(102, 2);
+        let meta2 = ConnectionMeta::new(102.into(), 2);
         let value2 = "v2".to_string();
-        let meta3 = ConnectionMeta::new(101, 3);
+        let meta3 = ConnectionMeta::new(101.into(), 3);
         let value3 = "v3".to_string();
         let cp: ConnectionPool<String> = ConnectionPool::new(3); //#CP3
         cp.put(&meta1, value1.clone());
@@ -394,11 +583,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_pop() {
-        let meta1 = ConnectionMeta::new(101, 1);
+        let meta1 = ConnectionMeta::new(101.into(), 1);
         let value1 = "v1".to_string();
-        let meta2 = ConnectionMeta::new(102, 2);
+        let meta2 = ConnectionMeta::new(102.into(), 2);
         let value2 = "v2".to_string();
-        let meta3 = ConnectionMeta::new(101, 3);
+        let meta3 = ConnectionMeta::new(101.into(), 3);
         let value3 = "v3".to_string();
         let cp: ConnectionPool<String> = ConnectionPool::new(3); //#CP3
         cp.put(&meta1, value1);
@@ -416,11 +605,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_eviction() {
-        let meta1 = ConnectionMeta::new(101, 1);
+        let meta1 = ConnectionMeta::new(101.into(), 1);
         let value1 = "v1".to_string();
-        let meta2 = ConnectionMeta::new(102, 2);
+        let meta2 = ConnectionMeta::new(102.into(), 2);
         let value2 = "v2".to_string();
-        let meta3 = ConnectionMeta::new(101, 3);
+        let meta3 = ConnectionMeta::new(101.into(), 3);
         let value3 = "v3".to_string();
         let cp: ConnectionPool<String> = ConnectionPool::new(2);
         let (notify_close1, _) = cp.put(&meta1, value1.clone());
@@ -442,13 +631,13 @@ mod tests {
     #[tokio::test]
     #[should_panic(expected = "There is still data left to read.")]
     async fn test_read_close() {
-        let meta1 = ConnectionMeta::new(101, 1);
+        let meta1 = ConnectionMeta::new(101.into(), 1);
         let mock_io1 = Arc::new(AsyncMutex::new(Builder::new().read(b"garbage").build()));
-        let meta2 = ConnectionMeta::new(102, 2);
+        let meta2 = ConnectionMeta::new(102.into(), 2);
         let mock_io2 = Arc::new(AsyncMutex::new(
             Builder::new().wait(Duration::from_secs(99)).build(),
         ));
-        let meta3 = ConnectionMeta::new(101, 3);
+        let meta3 = ConnectionMeta::new(101.into(), 3);
         let mock_io3 = Arc::new(AsyncMutex::new(
             Builder::new().wait(Duration::from_secs(99)).build(),
         ));
@@ -470,15 +659,15 @@ mod tests {
 
     #[tokio::test]
     async fn test_read_timeout() {
-        let meta1 = ConnectionMeta::new(101, 1);
+        let meta1 = ConnectionMeta::new(101.into(), 1);
         let mock_io1 = Arc::new(AsyncMutex::new(
             Builder::new().wait(Duration::from_secs(99)).build(),
         ));
-        let meta2 = ConnectionMeta::new(102, 2);
+        let meta2 = ConnectionMeta::new(102.into(), 2);
         let mock_io2 = Arc::new(AsyncMutex::new(
             Builder::new().wait(Duration::from_secs(99)).build(),
         ));
-        let meta3 = ConnectionMeta::new(101, 3);
+        let meta3 = ConnectionMeta::new(101.into(), 3);
         let mock_io3 = Arc::new(AsyncMutex::new(
             Builder::new().wait(Duration::from_secs(99)).build(),
         ));
@@ -500,15 +689,15 @@ mod tests {
 
     #[tokio::test]
     async fn test_evict_poll() {
-        let meta1 = ConnectionMeta::new(101, 1);
+        let meta1 = ConnectionMeta::new(101.into(), 1);
         let mock_io1 = Arc::new(AsyncMutex::new(
             Builder::new().wait(Duration::from_secs(99)).build(),
         ));
-        let meta2 = ConnectionMeta::new(102, 2);
+        let meta2 = ConnectionMeta::new(102.into(), 2);
         let mock_io2 = Arc::new(AsyncMutex::new(
             Builder::new().wait(Duration::from_secs(99)).build(),
         ));
-        let meta3 = ConnectionMeta::new(101, 3);
+        let meta3 = ConnectionMeta::new(101.into(), 3);
         let mock_io3 = Arc::new(AsyncMutex::new(
             Builder::new().wait(Duration::from_secs(99)).build(),
         ));
@@ -527,4 +716,97 @@ mod tests {
         let _ = cp.get(&meta1.key).unwrap(); // mock_io3 should be selected
         assert!(cp.get(&meta1.key).is_none()) // mock_io1 should already be removed by idle_poll
     }
+
+    #[tokio::test]
+    async fn test_max_per_group() {
+        let options = PoolOptions {
+            max_per_group: Some(1),
+            ..Default::default()
+        };
+        let cp: ConnectionPool<String> = ConnectionPool::new_with_options(3, options);
+        let meta1 = ConnectionMeta::new(101.into(), 1);
+        let meta2 = ConnectionMeta::new(101.into(), 2);
+        let meta3 = ConnectionMeta::new(102.into(), 3);
+        cp.put(&meta1, "v1".to_string());
+        let (notify_close2, watch_use2) = cp.put(&meta2, "v2".to_string());
+        cp.put(&meta3, "v3".to_string());
+        // the group of 101 is full so v2 is dropped right away
+        notify_close2.notified().await;
+        assert!(watch_use2.await.is_err());
+        assert_eq!(cp.idle_count(&101.into()), 1);
+        assert_eq!(cp.idle_count(&102.into()), 1);
+
+        assert_eq!(cp.get(&101.into()).unwrap(), "v1");
+        assert!(cp.get(&101.into()).is_none());
+        assert_eq!(cp.idle_count(&101.into()), 0);
+        // there is room again
+        cp.put(&meta2, "v2".to_string());
+        assert_eq!(cp.get(&101.into()).unwrap(), "v2");
+        assert_eq!(cp.get(&102.into()).unwrap(), "v3");
+    }
+
+    #[tokio::test]
+    async fn test_max_idle_time() {
+        let meta1 = ConnectionMeta::new(101.into(), 1);
+        let mock_io1 = Arc::new(AsyncMutex::new(
+            Builder::new().wait(Duration::from_secs(99)).build(),
+        ));
+        let options = PoolOptions {
+            max_idle_time: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let cp: ConnectionPool<Arc<AsyncMutex<Mock>>> =
+            ConnectionPool::new_with_options(3, options);
+        let (c1, u1) = cp.put(&meta1, mock_io1.clone());
+        assert_eq!(cp.idle_count(&101.into()), 1);
+
+        // the max idle time takes precedence over the longer timeout
+        let poll = cp.idle_poll(
+            mock_io1.try_lock_owned().unwrap(),
+            &meta1,
+            Some(Duration::from_secs(99)),
+            c1,
+            u1,
+        );
+        timeout(Duration::from_secs(1), poll).await.unwrap();
+        assert_eq!(cp.idle_count(&101.into()), 0);
+        assert!(cp.get(&meta1.key).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sharded_keys() {
+        let cp: ConnectionPool<u64> = ConnectionPool::new(64);
+        // keys that land in the same shard
+        let keys: Vec<u64> = (0..4).map(|i| 7 + i * POOL_SHARDS as u64).collect();
+        for (id, key) in keys.iter().enumerate() {
+            cp.put(&ConnectionMeta::new((*key).into(), id as ID), *key);
+        }
+        for key in keys.iter() {
+            let key = GroupKey::from(*key);
+            assert_eq!(cp.get(&key), Some(key.hash()));
+            assert!(cp.get(&key).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_collision() {
+        let cp: ConnectionPool<&str> = ConnectionPool::new(64);
+        // two groups with the same hash
+        let key_a = GroupKey::new(101, "a");
+        let key_b = GroupKey::new(101, "b");
+        assert_ne!(key_a, key_b);
+        assert_eq!(key_a, GroupKey::new(101, "a"));
+
+        cp.put(&ConnectionMeta::new(key_a.clone(), 1), "a");
+        assert!(cp.get(&key_b).is_none());
+        assert_eq!(cp.idle_count(&key_b), 0);
+
+        // both groups are pooled under the same hash without sharing their connections
+        cp.put(&ConnectionMeta::new(key_b.clone(), 2), "b");
+        assert_eq!(cp.idle_count(&key_a), 1);
+        assert_eq!(cp.idle_count(&key_b), 1);
+        assert_eq!(cp.get(&key_a), Some("a"));
+        assert_eq!(cp.get(&key_b), Some("b"));
+        assert!(cp.get(&key_a).is_none());
+    }
 }
@@ -16,7 +16,8 @@
 //!
 //! The pool is optimized for high concurrency, high RPS use cases. Each connection group has a
 //! lock free hot pool to reduce the lock contention when some connections are reused and released
-//! very frequently.
+//! very frequently. The groups are spread over several shards, each with its own lock, so that
+//! looking up different groups rarely contends either.
 
 #![warn(clippy::all)]
 #![allow(clippy::new_without_default)]
@@ -25,4 +26,4 @@
 mod connection;
 mod lru;
 
-pub use connection::{ConnectionMeta, ConnectionPool, PoolNode};
+pub use connection::{ConnectionMeta, ConnectionPool, GroupKey, PoolNode, PoolOptions};
@@ -0,0 +1,785 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access logging
+//!
+//! An [AccessLogger] renders one line per request from an nginx style template such as
+//! `$remote_addr "$request" $status $bytes_sent $request_time`, either as text or as a JSON
+//! object keyed by the variable names. The lines are handed to a channel on the request path and
+//! written to an [AccessLogSink] by the logger running as a
+//! [BackgroundService](pingora_core::services::background::BackgroundService).
+//!
+//! To use it, keep an [AccessLogCtx] in the `CTX` of the [ProxyHttp](crate::ProxyHttp), record the
+//! upstream connection in `connected_to_upstream()` with [AccessLogCtx::upstream_connected()] and
+//! call [AccessLogger::log()] in `logging()`.
+//!
+//! The logger reopens its sink on [SignalAction::ReopenLogs] once it is added to the
+//! [LifecycleController](pingora_core::server::lifecycle::LifecycleController) of the server,
+//! e.g., `server.lifecycle.add_shared_handler(logger_service.task())` together with
+//! `server.lifecycle.map_signal(Signal::SIGUSR1, SignalAction::ReopenLogs)`.
+//!
+//! The supported variables:
+//! - `$remote_addr`: the client IP, or the path of the Unix socket
+//! - `$request`: the full request line, e.g. `GET /index.html HTTP/1.1`
+//! - `$request_method`, `$request_uri`, `$server_protocol`, `$host`
+//! - `$status`: the response status sent to the client
+//! - `$bytes_sent`, `$bytes_received`: the response and request body bytes
+//! - `$request_time`: seconds since the [AccessLogCtx] was created
+//! - `$connection_time`: seconds since the downstream connection was established
+//! - `$upstream_addr`, `$upstream_reused`
+//! - `$upstream_connect_time`: seconds from the start of the request to the upstream connection
+//! - `$upstream_connection_time`: seconds since the upstream connection was established
+//! - `$cache_status`: the cache phase, e.g. `hit` or `miss`
+//! - `$cache_hit_status`: the freshness of the asset found in cache, e.g. `fresh` or `expired`
+//! - `$ssl_protocol`, `$ssl_cipher`: of the downstream TLS connection
+//! - `$msec`: the time of logging in seconds since the epoch, `$time_iso8601`
+//! - `$error`: the type of the error that failed the request
+//! - `$http_<name>`: the request header `<name>`, with `_` in it matching `-`
+//! - `$sent_http_<name>`: the response header `<name>`
+//!
+//! Variables which are unavailable are logged as `-` in text, and `null` in JSON.
+
+use async_trait::async_trait;
+use http::header::{HeaderName, HOST};
+use log::{error, info};
+use pingora_core::protocols::Digest;
+use pingora_core::server::lifecycle::LifecycleHandler;
+#[cfg(doc)]
+use pingora_core::server::lifecycle::SignalAction;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use pingora_core::upstreams::peer::{HttpPeer, Peer};
+use pingora_error::{Error, ErrorType, OkOrErr, OrErr, Result};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use crate::Session;
+
+/// The error type of invalid access log formats
+pub const ACCESS_LOG_FORMAT_ERR: ErrorType = ErrorType::new("AccessLogFormatError");
+
+/// A format close to the nginx `combined` log format
+pub const DEFAULT_FORMAT: &str = r#"$remote_addr [$time_iso8601] "$request" $status $bytes_sent "$http_referer" "$http_user_agent" $request_time"#;
+
+/// The default number of lines buffered before they are dropped
+pub const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+// how many lines to write to the sink at once
+const BATCH_SIZE: usize = 128;
+
+/// The per request state of access logging, to be kept in the `CTX` of the proxy
+#[derive(Debug, Clone)]
+pub struct AccessLogCtx {
+    start: Instant,
+    upstream: Option<UpstreamInfo>,
+}
+
+#[derive(Debug, Clone)]
+struct UpstreamInfo {
+    addr: String,
+    reused: bool,
+    connected: Duration,
+    established: Option<SystemTime>,
+}
+
+impl AccessLogCtx {
+    /// Create a new [AccessLogCtx]. `$request_time` is measured from now.
+    pub fn new() -> Self {
+        AccessLogCtx {
+            start: Instant::now(),
+            upstream: None,
+        }
+    }
+
+    /// Record the connection to the upstream.
+    ///
+    /// This should be called in `connected_to_upstream()`. If the request is retried, the last
+    /// connection is logged.
+    pub fn upstream_connected(&mut self, reused: bool, peer: &HttpPeer, digest: Option<&Digest>) {
+        let established = digest.and_then(connection_established);
+        self.upstream = Some(UpstreamInfo {
+            addr: peer.address().to_string(),
+            reused,
+            connected: self.start.elapsed(),
+            established,
+        });
+    }
+}
+
+impl Default for AccessLogCtx {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the time the connection was established, according to its lowest layer that knows about it
+fn connection_established(digest: &Digest) -> Option<SystemTime> {
+    digest
+        .timing_digest
+        .iter()
+        .find_map(|t| t.as_ref().map(|t| t.established_ts))
+}
+
+#[derive(Debug, Clone)]
+enum Var {
+    RemoteAddr,
+    Request,
+    RequestMethod,
+    RequestUri,
+    ServerProtocol,
+    Host,
+    Status,
+    BytesSent,
+    BytesReceived,
+    RequestTime,
+    ConnectionTime,
+    UpstreamAddr,
+    UpstreamReused,
+    UpstreamConnectTime,
+    UpstreamConnectionTime,
+    CacheStatus,
+    CacheHitStatus,
+    SslProtocol,
+    SslCipher,
+    Msec,
+    TimeIso8601,
+    Error,
+    RequestHeader(HeaderName),
+    ResponseHeader(HeaderName),
+}
+
+impl Var {
+    fn parse(name: &str) -> Result<Self> {
+        let var = match name {
+            "remote_addr" => Var::RemoteAddr,
+            "request" => Var::Request,
+            "request_method" => Var::RequestMethod,
+            "request_uri" => Var::RequestUri,
+            "server_protocol" => Var::ServerProtocol,
+            "host" => Var::Host,
+            "status" => Var::Status,
+            "bytes_sent" => Var::BytesSent,
+            "bytes_received" => Var::BytesReceived,
+            "request_time" => Var::RequestTime,
+            "connection_time" => Var::ConnectionTime,
+            "upstream_addr" => Var::UpstreamAddr,
+            "upstream_reused" => Var::UpstreamReused,
+            "upstream_connect_time" => Var::UpstreamConnectTime,
+            "upstream_connection_time" => Var::UpstreamConnectionTime,
+            "cache_status" => Var::CacheStatus,
+            "cache_hit_status" => Var::CacheHitStatus,
+            "ssl_protocol" => Var::SslProtocol,
+            "ssl_cipher" => Var::SslCipher,
+            "msec" => Var::Msec,
+            "time_iso8601" => Var::TimeIso8601,
+            "error" => Var::Error,
+            _ => {
+                if let Some(header) = name.strip_prefix("sent_http_") {
+                    Var::ResponseHeader(header_name(header)?)
+                } else if let Some(header) = name.strip_prefix("http_") {
+                    Var::RequestHeader(header_name(header)?)
+                } else {
+                    return Error::e_explain(
+                        ACCESS_LOG_FORMAT_ERR,
+                        format!("unknown variable ${name}"),
+                    );
+                }
+            }
+        };
+        Ok(var)
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName> {
+    HeaderName::from_bytes(name.replace('_', "-").as_bytes())
+        .or_err_with(ACCESS_LOG_FORMAT_ERR, || {
+            format!("invalid header name {name}")
+        })
+}
+
+enum Value {
+    Str(String),
+    Int(u64),
+    Float(f64),
+    Bool(bool),
+    None,
+}
+
+impl Value {
+    fn seconds(d: Duration) -> Self {
+        Value::Float(d.as_secs_f64())
+    }
+
+    fn write_text(&self, out: &mut String) {
+        let _ = match self {
+            Value::Str(s) => write!(out, "{s}"),
+            Value::Int(i) => write!(out, "{i}"),
+            Value::Float(f) => write!(out, "{f:.3}"),
+            Value::Bool(b) => write!(out, "{b}"),
+            Value::None => write!(out, "-"),
+        };
+    }
+
+    fn into_json(self) -> serde_json::Value {
+        match self {
+            Value::Str(s) => s.into(),
+            Value::Int(i) => i.into(),
+            // keep the millisecond precision of the text output
+            Value::Float(f) => ((f * 1000.0).round() / 1000.0).into(),
+            Value::Bool(b) => b.into(),
+            Value::None => serde_json::Value::Null,
+        }
+    }
+}
+
+enum Part {
+    Literal(String),
+    Var(String, Var),
+}
+
+/// How the lines are rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogOutput {
+    /// The template with the variables substituted
+    Text,
+    /// A JSON object of the variables of the template, the literals are ignored
+    Json,
+}
+
+/// A parsed access log template
+pub struct AccessLogFormat {
+    parts: Vec<Part>,
+    output: AccessLogOutput,
+}
+
+impl AccessLogFormat {
+    /// Parse the given template.
+    ///
+    /// A variable is a `$` followed by the name, or the name enclosed by `${` and `}` when it is
+    /// followed by characters which could be part of it.
+    pub fn parse(template: &str, output: AccessLogOutput) -> Result<Self> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(pos) = rest.find('$') {
+            literal.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            let name = if let Some(braced) = rest.strip_prefix('{') {
+                let end = braced.find('}').or_err_with(ACCESS_LOG_FORMAT_ERR, || {
+                    format!("unclosed variable in {template}")
+                })?;
+                rest = &braced[end + 1..];
+                &braced[..end]
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let name = &rest[..end];
+                rest = &rest[end..];
+                name
+            };
+            if name.is_empty() {
+                return Error::e_explain(
+                    ACCESS_LOG_FORMAT_ERR,
+                    format!("empty variable name in {template}"),
+                );
+            }
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Var(name.to_string(), Var::parse(name)?));
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(AccessLogFormat { parts, output })
+    }
+
+    /// Render the access log line of the request.
+    pub fn render(&self, session: &Session, error: Option<&Error>, ctx: &AccessLogCtx) -> String {
+        match self.output {
+            AccessLogOutput::Text => {
+                let mut line = String::new();
+                for part in self.parts.iter() {
+                    match part {
+                        Part::Literal(s) => line.push_str(s),
+                        Part::Var(_, var) => value(var, session, error, ctx).write_text(&mut line),
+                    }
+                }
+                line
+            }
+            AccessLogOutput::Json => {
+                let mut obj = serde_json::Map::new();
+                for part in self.parts.iter() {
+                    if let Part::Var(name, var) = part {
+                        obj.insert(name.clone(), value(var, session, error, ctx).into_json());
+                    }
+                }
+                serde_json::Value::Object(obj).to_string()
+            }
+        }
+    }
+}
+
+fn header_value(value: Option<&http::HeaderValue>) -> Value {
+    value.map_or(Value::None, |v| {
+        Value::Str(String::from_utf8_lossy(v.as_bytes()).into_owned())
+    })
+}
+
+fn value(var: &Var, session: &Session, error: Option<&Error>, ctx: &AccessLogCtx) -> Value {
+    let downstream = session.as_downstream();
+    let req = downstream.req_header();
+    let ssl = downstream.digest().and_then(|d| d.ssl_digest.as_ref());
+    match var {
+        Var::RemoteAddr => downstream.client_addr().map_or(Value::None, |addr| {
+            Value::Str(match addr.as_inet() {
+                Some(inet) => inet.ip().to_string(),
+                None => addr.to_string(),
+            })
+        }),
+        Var::Request => Value::Str(format!("{} {} {:?}", req.method, req.uri, req.version)),
+        Var::RequestMethod => Value::Str(req.method.to_string()),
+        Var::RequestUri => Value::Str(req.uri.to_string()),
+        Var::ServerProtocol => Value::Str(format!("{:?}", req.version)),
+        Var::Host => match req.headers.get(HOST) {
+            Some(host) => header_value(Some(host)),
+            // HTTP/2
+            None => req
+                .uri
+                .host()
+                .map_or(Value::None, |h| Value::Str(h.to_string())),
+        },
+        Var::Status => downstream
+            .response_written()
+            .map_or(Value::None, |resp| Value::Int(resp.status.as_u16() as u64)),
+        Var::BytesSent => Value::Int(downstream.body_bytes_sent() as u64),
+        Var::BytesReceived => Value::Int(downstream.body_bytes_read() as u64),
+        Var::RequestTime => Value::seconds(ctx.start.elapsed()),
+        Var::ConnectionTime => downstream
+            .digest()
+            .and_then(connection_established)
+            .and_then(|t| t.elapsed().ok())
+            .map_or(Value::None, Value::seconds),
+        Var::UpstreamAddr => ctx
+            .upstream
+            .as_ref()
+            .map_or(Value::None, |u| Value::Str(u.addr.clone())),
+        Var::UpstreamReused => ctx
+            .upstream
+            .as_ref()
+            .map_or(Value::None, |u| Value::Bool(u.reused)),
+        Var::UpstreamConnectTime => ctx
+            .upstream
+            .as_ref()
+            .map_or(Value::None, |u| Value::seconds(u.connected)),
+        Var::UpstreamConnectionTime => ctx
+            .upstream
+            .as_ref()
+            .and_then(|u| u.established)
+            .and_then(|t| t.elapsed().ok())
+            .map_or(Value::None, Value::seconds),
+        Var::CacheStatus => Value::Str(session.cache.phase().as_str().to_string()),
+        Var::CacheHitStatus => session
+            .cache
+            .hit_status()
+            .map_or(Value::None, |s| Value::Str(s.as_str().to_string())),
+        Var::SslProtocol => ssl.map_or(Value::None, |s| Value::Str(s.version.to_string())),
+        Var::SslCipher => ssl.map_or(Value::None, |s| Value::Str(s.cipher.to_string())),
+        Var::Msec => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            Value::Float(now.as_secs_f64())
+        }
+        Var::TimeIso8601 => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            chrono::DateTime::from_timestamp(now.as_secs() as i64, now.subsec_nanos())
+                .map_or(Value::None, |t| {
+                    Value::Str(t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+                })
+        }
+        Var::Error => error.map_or(Value::None, |e| Value::Str(e.etype().as_str().to_string())),
+        Var::RequestHeader(name) => header_value(req.headers.get(name)),
+        Var::ResponseHeader(name) => header_value(
+            downstream
+                .response_written()
+                .and_then(|r| r.headers.get(name)),
+        ),
+    }
+}
+
+/// Where the access log lines are written to
+///
+/// The sink is called by the [AccessLogger] in its background task, so blocking IO is fine.
+pub trait AccessLogSink: Send {
+    /// Write the lines, which don't end with a newline.
+    fn write(&mut self, lines: &[String]) -> io::Result<()>;
+
+    /// Reopen the underlying destination, e.g. after the log file is rotated.
+    fn reopen(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Append the lines to a file
+///
+/// The file is reopened on [SignalAction::ReopenLogs], so that it works with `logrotate` the same
+/// way as nginx.
+pub struct FileSink {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl FileSink {
+    /// Open the file at `path` for appending, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path).or_err_with(ErrorType::FileOpenError, || {
+            format!("fail to open access log {}", path.display())
+        })?;
+        Ok(FileSink {
+            path,
+            file: BufWriter::new(file),
+        })
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl AccessLogSink for FileSink {
+    fn write(&mut self, lines: &[String]) -> io::Result<()> {
+        for line in lines {
+            self.file.write_all(line.as_bytes())?;
+            self.file.write_all(b"\n")?;
+        }
+        self.file.flush()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file = BufWriter::new(open_append(&self.path)?);
+        Ok(())
+    }
+}
+
+// facility local0, severity info
+const SYSLOG_PRIORITY: u8 = 16 * 8 + 6;
+
+/// Send each line as a datagram to a Unix socket, such as `/dev/log` of syslog
+pub struct UdsSink {
+    path: PathBuf,
+    socket: Option<UnixDatagram>,
+    syslog_tag: Option<String>,
+}
+
+impl UdsSink {
+    /// Send the lines as they are to the datagram socket at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        UdsSink {
+            path: path.as_ref().to_path_buf(),
+            socket: None,
+            syslog_tag: None,
+        }
+    }
+
+    /// Send the lines as syslog messages of facility `local0` and severity `info` with the given
+    /// tag to the syslog socket at `path`, e.g. `/dev/log`.
+    pub fn syslog<P: AsRef<Path>>(path: P, tag: &str) -> Self {
+        UdsSink {
+            syslog_tag: Some(tag.to_string()),
+            ..Self::new(path)
+        }
+    }
+
+    fn socket(&mut self) -> io::Result<&UnixDatagram> {
+        if self.socket.is_none() {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(&self.path)?;
+            self.socket = Some(socket);
+        }
+        Ok(self.socket.as_ref().unwrap())
+    }
+}
+
+impl AccessLogSink for UdsSink {
+    fn write(&mut self, lines: &[String]) -> io::Result<()> {
+        for line in lines {
+            let msg = match self.syslog_tag.as_ref() {
+                Some(tag) => format!("<{SYSLOG_PRIORITY}>{tag}: {line}"),
+                None => line.clone(),
+            };
+            if let Err(e) = self.socket().and_then(|s| s.send(msg.as_bytes())) {
+                // connect again next time, in case the receiver restarted
+                self.socket = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.socket = None;
+        Ok(())
+    }
+}
+
+/// Keep the lines in memory, mostly for testing
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl MemorySink {
+    /// Create an empty [MemorySink]. Its clones share the same lines.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The lines written so far
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+}
+
+impl AccessLogSink for MemorySink {
+    fn write(&mut self, lines: &[String]) -> io::Result<()> {
+        self.lines.lock().unwrap().extend_from_slice(lines);
+        Ok(())
+    }
+}
+
+/// Render and write the access logs
+///
+/// [AccessLogger::log()] only renders the line and queues it, the writes happen when the logger
+/// runs as a [BackgroundService]. Lines are dropped instead of slowing down the requests when the
+/// queue is full.
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    sender: mpsc::Sender<String>,
+    receiver: Mutex<Option<mpsc::Receiver<String>>>,
+    sink: Mutex<Box<dyn AccessLogSink>>,
+    dropped: AtomicU64,
+}
+
+impl AccessLogger {
+    /// Create an [AccessLogger] which buffers up to [DEFAULT_BUFFER_SIZE] lines.
+    pub fn new(format: AccessLogFormat, sink: Box<dyn AccessLogSink>) -> Self {
+        Self::with_buffer_size(format, sink, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Create an [AccessLogger] which buffers up to `buffer_size` lines.
+    pub fn with_buffer_size(
+        format: AccessLogFormat,
+        sink: Box<dyn AccessLogSink>,
+        buffer_size: usize,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(buffer_size);
+        AccessLogger {
+            format,
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            sink: Mutex::new(sink),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Log the request. This function doesn't block.
+    pub fn log(&self, session: &Session, error: Option<&Error>, ctx: &AccessLogCtx) {
+        let line = self.format.render(session, error, ctx);
+        if self.sender.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The number of lines dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn write(&self, lines: &mut Vec<String>) {
+        if lines.is_empty() {
+            return;
+        }
+        if let Err(e) = self.sink.lock().unwrap().write(lines) {
+            error!("failed to write {} access logs: {e}", lines.len());
+        }
+        lines.clear();
+    }
+
+    fn reopen(&self) {
+        if let Err(e) = self.sink.lock().unwrap().reopen() {
+            error!("failed to reopen the access log: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl LifecycleHandler for AccessLogger {
+    async fn reopen_logs(&self) {
+        info!("reopening the access log");
+        self.reopen();
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AccessLogger {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(mut receiver) = self.receiver.lock().unwrap().take() else {
+            error!("the access logger is already running");
+            return;
+        };
+        let mut lines = Vec::with_capacity(BATCH_SIZE);
+        loop {
+            tokio::select! {
+                // never closed since the logger itself holds a sender
+                Some(line) = receiver.recv() => {
+                    lines.push(line);
+                    while lines.len() < BATCH_SIZE {
+                        match receiver.try_recv() {
+                            Ok(line) => lines.push(line),
+                            Err(_) => break,
+                        }
+                    }
+                    self.write(&mut lines);
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+        // flush what has been logged so far
+        receiver.close();
+        while let Some(line) = receiver.recv().await {
+            lines.push(line);
+            if lines.len() >= BATCH_SIZE {
+                self.write(&mut lines);
+            }
+        }
+        self.write(&mut lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_test::io::Builder;
+
+    async fn session(request: &[u8]) -> Session {
+        let mock_io = Builder::new().read(request).build();
+        let mut session = Session::new_h1(Box::new(mock_io));
+        session.read_request().await.unwrap();
+        session
+    }
+
+    #[test]
+    fn test_parse() {
+        let format = AccessLogFormat::parse(DEFAULT_FORMAT, AccessLogOutput::Text).unwrap();
+        assert_eq!(format.parts.len(), 15);
+        let format = AccessLogFormat::parse("${status}ms $$", AccessLogOutput::Text);
+        assert!(format.is_err());
+        assert!(AccessLogFormat::parse("$unknown", AccessLogOutput::Text).is_err());
+        assert!(AccessLogFormat::parse("${status", AccessLogOutput::Text).is_err());
+        let format = AccessLogFormat::parse("${status}ms", AccessLogOutput::Text).unwrap();
+        assert!(matches!(format.parts[0], Part::Var(_, Var::Status)));
+        assert!(matches!(&format.parts[1], Part::Literal(s) if s == "ms"));
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let session =
+            session(b"GET /a?b=1 HTTP/1.1\r\nHost: pingora.org\r\nUser-Agent: test\r\n\r\n").await;
+        let ctx = AccessLogCtx::new();
+
+        let template = r#""$request" $host $status $bytes_sent "$http_user_agent" $upstream_addr"#;
+        let format = AccessLogFormat::parse(template, AccessLogOutput::Text).unwrap();
+        assert_eq!(
+            format.render(&session, None, &ctx),
+            r#""GET /a?b=1 HTTP/1.1" pingora.org - 0 "test" -"#
+        );
+
+        let template = "$request_method $request_uri $status $cache_status $error";
+        let format = AccessLogFormat::parse(template, AccessLogOutput::Json).unwrap();
+        let error = Error::new(ErrorType::ConnectTimedout);
+        let line = format.render(&session, Some(error.as_ref()), &ctx);
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "request_method": "GET",
+                "request_uri": "/a?b=1",
+                "status": null,
+                "cache_status": "disabled",
+                "error": "ConnectTimedout",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_logger() {
+        let sink = MemorySink::new();
+        let format = AccessLogFormat::parse("$request_uri", AccessLogOutput::Text).unwrap();
+        let logger = Arc::new(AccessLogger::with_buffer_size(
+            format,
+            Box::new(sink.clone()),
+            2,
+        ));
+        let session = session(b"GET /1 HTTP/1.1\r\n\r\n").await;
+        let ctx = AccessLogCtx::new();
+        for _ in 0..3 {
+            logger.log(&session, None, &ctx);
+        }
+        // the third line doesn't fit in the buffer
+        assert_eq!(logger.dropped(), 1);
+
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn({
+            let logger = logger.clone();
+            async move { logger.start(rx).await }
+        });
+        tx.send(true).unwrap();
+        task.await.unwrap();
+        assert_eq!(sink.lines(), vec!["/1", "/1"]);
+    }
+
+    #[tokio::test]
+    async fn test_reopen_logs() {
+        struct ReopenCounter(Arc<AtomicU64>);
+        impl AccessLogSink for ReopenCounter {
+            fn write(&mut self, _lines: &[String]) -> io::Result<()> {
+                Ok(())
+            }
+            fn reopen(&mut self) -> io::Result<()> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        let reopened = Arc::new(AtomicU64::new(0));
+        let format = AccessLogFormat::parse("$request_uri", AccessLogOutput::Text).unwrap();
+        let logger = AccessLogger::new(format, Box::new(ReopenCounter(reopened.clone())));
+        logger.reopen_logs().await;
+        assert_eq!(reopened.load(Ordering::Relaxed), 1);
+    }
+}
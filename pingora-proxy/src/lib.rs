@@ -48,6 +48,7 @@ use pingora_http::{RequestHeader, ResponseHeader};
 use std::fmt::Debug;
 use std::str;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::sync::{mpsc, Notify};
 use tokio::time;
 
@@ -58,6 +59,7 @@ use pingora_core::modules::http::compression::ResponseCompressionBuilder;
 use pingora_core::modules::http::{HttpModuleCtx, HttpModules};
 use pingora_core::protocols::http::client::HttpSession as ClientSession;
 use pingora_core::protocols::http::v1::client::HttpSession as HttpSessionV1;
+use pingora_core::protocols::http::v2::server::H2ServerSettings;
 use pingora_core::protocols::http::HttpTask;
 use pingora_core::protocols::http::ServerSession as HttpSession;
 use pingora_core::protocols::http::SERVER_NAME;
@@ -65,12 +67,13 @@ use pingora_core::protocols::Stream;
 use pingora_core::protocols::{Digest, UniqueID};
 use pingora_core::server::configuration::ServerConf;
 use pingora_core::server::ShutdownWatch;
-use pingora_core::upstreams::peer::{HttpPeer, Peer};
+use pingora_core::upstreams::peer::{HttpPeer, Peer, Tracer};
 use pingora_error::{Error, ErrorSource, ErrorType::*, OrErr, Result};
 
 const MAX_RETRIES: usize = 16;
 const TASK_BUFFER_SIZE: usize = 4;
 
+pub mod access_log;
 mod proxy_cache;
 mod proxy_common;
 mod proxy_h1;
@@ -78,8 +81,10 @@ mod proxy_h2;
 mod proxy_purge;
 mod proxy_trait;
 mod subrequest;
+pub mod tracing;
 
 use subrequest::Ctx as SubReqCtx;
+use tracing::{RequestTrace, RequestTracer};
 
 pub use proxy_purge::PurgeStatus;
 pub use proxy_trait::ProxyHttp;
@@ -93,21 +98,36 @@ pub mod prelude {
 /// Users don't need to interact with this object directly.
 pub struct HttpProxy<SV> {
     inner: SV, // TODO: name it better than inner
-    client_upstream: Connector,
+    client_upstream: Arc<Connector>,
     shutdown: Notify,
+    h2_server_settings: Option<H2ServerSettings>,
     pub downstream_modules: HttpModules,
+    /// Trace the requests when set, see [tracing]
+    pub request_tracer: Option<RequestTracer>,
 }
 
 impl<SV> HttpProxy<SV> {
     fn new(inner: SV, conf: Arc<ServerConf>) -> Self {
         HttpProxy {
             inner,
-            client_upstream: Connector::new(Some(ConnectorOptions::from_server_conf(&conf))),
+            client_upstream: Arc::new(Connector::new(Some(ConnectorOptions::from_server_conf(
+                &conf,
+            )))),
             shutdown: Notify::new(),
+            h2_server_settings: conf.h2_server.clone(),
             downstream_modules: HttpModules::new(),
+            request_tracer: None,
         }
     }
 
+    /// The [Connector] that connects to the upstream servers.
+    ///
+    /// It can be shared with background tasks that manage the upstream connections, such as the
+    /// [ConnectionPrewarmer](pingora_core::connectors::http::prewarm::ConnectionPrewarmer).
+    pub fn upstream_connector(&self) -> Arc<Connector> {
+        self.client_upstream.clone()
+    }
+
     async fn handle_new_request(
         &self,
         mut downstream_session: Box<HttpSession>,
@@ -161,14 +181,27 @@ impl<SV> HttpProxy<SV> {
         SV: ProxyHttp + Send + Sync,
         SV::CTX: Send + Sync,
     {
-        let peer = match self.inner.upstream_peer(session, ctx).await {
+        let mut peer = match self.inner.upstream_peer(session, ctx).await {
             Ok(p) => p,
             Err(e) => return (false, Some(e)),
         };
 
+        let connect_tracer = session.trace.connect_tracer(&mut peer.options.tracer);
+        if let Some(tracer) = connect_tracer.as_ref() {
+            peer.options.tracer = Some(Tracer(Box::new(tracer.clone())));
+        }
+        let connect_start = SystemTime::now();
         let client_session = self.client_upstream.get_http_session(&*peer).await;
+        session.trace.record_connect(
+            connect_start,
+            connect_tracer.as_ref(),
+            peer.tls(),
+            client_session.as_ref().ok().map(|(_, reused)| *reused),
+            client_session.as_ref().err().map(|e| e.as_ref()),
+        );
         match client_session {
             Ok((client_session, client_reused)) => {
+                session.trace.start_upstream();
                 let (server_reused, error) = match client_session {
                     ClientSession::H1(mut h1) => {
                         let (server_reused, client_reuse, error) = self
@@ -212,6 +245,7 @@ impl<SV> HttpProxy<SV> {
                         (server_reused, error)
                     }
                 };
+                session.trace.finish_upstream(error.as_deref());
                 (
                     server_reused,
                     error.map(|e| {
@@ -265,6 +299,7 @@ impl<SV> HttpProxy<SV> {
         SV::CTX: Send + Sync,
     {
         self.inner.logging(&mut session, error, ctx).await;
+        finish_trace(&mut session, error);
 
         if reuse {
             // TODO: log error
@@ -295,6 +330,8 @@ pub struct Session {
     subrequest_ctx: Option<Box<SubReqCtx>>,
     // Downstream filter modules
     pub downstream_modules_ctx: HttpModuleCtx,
+    /// The distributed trace of this request
+    pub trace: RequestTrace,
 }
 
 impl Session {
@@ -309,6 +346,7 @@ impl Session {
             ignore_downstream_range: false,
             subrequest_ctx: None,
             downstream_modules_ctx: downstream_modules.build_ctx(),
+            trace: RequestTrace::disabled(),
         }
     }
 
@@ -486,6 +524,7 @@ impl<SV> HttpProxy<SV> {
                 if response_sent {
                     // TODO: log error
                     self.inner.logging(&mut session, None, &mut ctx).await;
+                    finish_trace(&mut session, None);
                     return session.downstream_session.finish().await.ok().flatten();
                 }
                 /* else continue */
@@ -637,9 +676,19 @@ impl<SV> HttpProxy<SV> {
         }
         self.inner.fail_to_proxy(session, &e, ctx).await;
         self.inner.logging(session, Some(&e), ctx).await;
+        finish_trace(session, Some(&e));
     }
 }
 
+// finish the `request` span with the final status of the request
+fn finish_trace(session: &mut Session, error: Option<&Error>) {
+    let status = session
+        .downstream_session
+        .response_written()
+        .map(|resp| resp.status.as_u16());
+    session.trace.finish(status, error);
+}
+
 /* Make process_subrequest() a trait to workaround https://github.com/rust-lang/rust/issues/78649
    if process_subrequest() is implemented as a member of HttpProxy, rust complains
 
@@ -700,11 +749,18 @@ where
     ) -> Option<Stream> {
         let session = Box::new(session);
 
+        let read_start = SystemTime::now();
         // TODO: keepalive pool, use stack
         let mut session = match self.handle_new_request(session).await {
             Some(downstream_session) => Session::new(downstream_session, &self.downstream_modules),
             None => return None, // bad request
         };
+        if let Some(tracer) = self.request_tracer.as_ref() {
+            session.trace = tracer.start(session.req_header(), read_start);
+            session
+                .trace
+                .record("downstream_read", read_start, SystemTime::now(), vec![]);
+        }
 
         if *shutdown.borrow() {
             // stop downstream from reusing if this service is shutting down soon
@@ -725,7 +781,9 @@ where
         // TODO: impl shutting down flag so that we don't need to read stack.is_shutting_down()
     }
 
-    // TODO implement h2_options
+    fn h2_server_settings(&self) -> Option<&H2ServerSettings> {
+        self.h2_server_settings.as_ref()
+    }
 }
 
 use pingora_core::services::listening::Service;
@@ -22,6 +22,19 @@ use pingora_core::protocols::http::conditional_filter::to_304;
 use pingora_core::protocols::http::v1::common::header_value_content_length;
 use pingora_core::ErrorType;
 
+// wait for the cache lock, recording the wait in the trace
+async fn cache_lock_wait(session: &mut Session) -> LockStatus {
+    let start = SystemTime::now();
+    let lock_status = session.cache.cache_lock_wait().await;
+    session.trace.record(
+        "cache_lock_wait",
+        start,
+        SystemTime::now(),
+        vec![("status", format!("{lock_status:?}"))],
+    );
+    lock_status
+}
+
 impl<SV> HttpProxy<SV> {
     // return bool: server_session can be reused, and error if any
     pub(crate) async fn proxy_cache(
@@ -77,7 +90,15 @@ impl<SV> HttpProxy<SV> {
         // cache lookup logic
         loop {
             // for cache lock, TODO: cap the max number of loops
-            match session.cache.cache_lookup().await {
+            let lookup_start = SystemTime::now();
+            let lookup = session.cache.cache_lookup().await;
+            session.trace.record(
+                "cache_lookup",
+                lookup_start,
+                SystemTime::now(),
+                vec![("phase", session.cache.phase().as_str().to_string())],
+            );
+            match lookup {
                 Ok(res) => {
                     if let Some((mut meta, handler)) = res {
                         // Vary logic
@@ -159,7 +180,7 @@ impl<SV> HttpProxy<SV> {
                                 let will_serve_stale = session.cache.can_serve_stale_updating()
                                     && self.inner.should_serve_stale(session, ctx, None);
                                 if !will_serve_stale {
-                                    let lock_status = session.cache.cache_lock_wait().await;
+                                    let lock_status = cache_lock_wait(session).await;
                                     if self.handle_lock_status(session, ctx, lock_status) {
                                         continue;
                                     } else {
@@ -204,7 +225,7 @@ impl<SV> HttpProxy<SV> {
                         // cache miss
                         if session.cache.is_cache_locked() {
                             // Another request is filling the cache; try waiting til that's done and retry.
-                            let lock_status = session.cache.cache_lock_wait().await;
+                            let lock_status = cache_lock_wait(session).await;
                             if self.handle_lock_status(session, ctx, lock_status) {
                                 continue;
                             } else {
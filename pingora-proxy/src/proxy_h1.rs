@@ -64,6 +64,16 @@ impl<SV> HttpProxy<SV> {
             }
         }
 
+        if let Err(e) = session
+            .downstream_modules_ctx
+            .upstream_request_filter(&mut req)
+            .await
+        {
+            return (false, true, Some(e));
+        }
+
+        session.trace.inject(&mut req);
+
         match self
             .inner
             .upstream_request_filter(session, &mut req, ctx)
@@ -77,6 +87,17 @@ impl<SV> HttpProxy<SV> {
 
         session.upstream_compression.request_filter(&req);
 
+        // the response body can skip the body filters only when none of them changes it
+        let splice_response_body = !session.is_http2()
+            && !session.is_upgrade_req()
+            && !session.cache.enabled()
+            && session.is_body_empty()
+            && !session.upstream_compression.is_enabled()
+            && !session
+                .downstream_modules_ctx
+                .response_body_filter_enabled()
+            && self.inner.allow_response_body_splice(session, ctx);
+
         debug!("Sending header to upstream {:?}", req);
 
         match client_session.write_request_header(Box::new(req)).await {
@@ -93,12 +114,28 @@ impl<SV> HttpProxy<SV> {
 
         // start bi-directional streaming
         let ret = tokio::try_join!(
-            self.proxy_handle_downstream(session, tx_downstream, rx_upstream, ctx),
-            self.proxy_handle_upstream(client_session, tx_upstream, rx_downstream),
+            self.proxy_handle_downstream(
+                session,
+                tx_downstream,
+                rx_upstream,
+                ctx,
+                splice_response_body
+            ),
+            self.proxy_handle_upstream(
+                client_session,
+                tx_upstream,
+                rx_downstream,
+                splice_response_body
+            ),
         );
 
         match ret {
             Ok((_first, _second)) => {
+                if splice_response_body {
+                    if let Err(e) = self.splice_response_body(session, client_session).await {
+                        return (false, false, Some(e));
+                    }
+                }
                 client_session.respect_keepalive();
                 (true, true, None)
             }
@@ -145,6 +182,7 @@ impl<SV> HttpProxy<SV> {
         client_session: &mut HttpSessionV1,
         tx: mpsc::Sender<HttpTask>,
         mut rx: mpsc::Receiver<HttpTask>,
+        splice_response_body: bool,
     ) -> Result<()>
     where
         SV: ProxyHttp + Send + Sync,
@@ -160,6 +198,12 @@ impl<SV> HttpProxy<SV> {
                     match res {
                         Ok(task) => {
                             response_done = task.is_end();
+                            if splice_response_body {
+                                // stop at the final header, the body is sent after it is written
+                                if let HttpTask::Header(resp, _) = &task {
+                                    response_done |= !resp.status.is_informational();
+                                }
+                            }
                             let result = tx.send(task)
                                 .await.or_err(
                                         InternalError,
@@ -208,6 +252,7 @@ impl<SV> HttpProxy<SV> {
         tx: mpsc::Sender<HttpTask>,
         mut rx: mpsc::Receiver<HttpTask>,
         ctx: &mut SV::CTX,
+        splice_response_body: bool,
     ) -> Result<()>
     where
         SV: ProxyHttp + Send + Sync,
@@ -414,6 +459,11 @@ impl<SV> HttpProxy<SV> {
             }
         }
 
+        if splice_response_body {
+            // the body is sent by splice_response_body() after this
+            return Ok(());
+        }
+
         match session.as_mut().finish_body().await {
             Ok(_) => {
                 debug!("finished sending body to downstream");
@@ -426,6 +476,47 @@ impl<SV> HttpProxy<SV> {
         Ok(())
     }
 
+    // Send the response body after its header is sent, skipping the body filters as allowed by
+    // allow_response_body_splice(): splice() it if possible, otherwise read and write it.
+    async fn splice_response_body(
+        &self,
+        session: &mut Session,
+        client_session: &mut HttpSessionV1,
+    ) -> Result<()> {
+        // the modules can still decide to change the body after seeing the response header
+        let filtering = session.upstream_compression.is_enabled()
+            || session
+                .downstream_modules_ctx
+                .response_body_filter_enabled();
+        if !filtering && !client_session.is_body_done() {
+            if let HttpSession::H1(downstream) = &mut *session.downstream_session {
+                if let Some(n) = downstream.splice_response_body_from(client_session).await? {
+                    debug!("spliced {n} bytes of response body to downstream");
+                }
+            }
+        }
+
+        // not spliced
+        while !client_session.is_body_done() {
+            let mut task = client_session
+                .read_response_task()
+                .await
+                .map_err(|e| e.into_up())?;
+            session.upstream_compression.response_filter(&mut task);
+            session.write_response_tasks(vec![task]).await?;
+        }
+
+        match session.as_mut().finish_body().await {
+            Ok(_) => {
+                debug!("finished sending body to downstream");
+            }
+            Err(e) => {
+                error!("Error finish sending body to downstream: {}", e);
+            }
+        }
+        Ok(())
+    }
+
     async fn h1_response_filter(
         &self,
         session: &mut Session,
@@ -549,6 +640,11 @@ impl<SV> HttpProxy<SV> {
         // affected by the request_body_filter
         let end_of_body = end_of_body || data.is_none();
 
+        session
+            .downstream_modules_ctx
+            .request_body_filter(&mut data, end_of_body)
+            .await?;
+
         self.inner
             .request_body_filter(session, &mut data, end_of_body, ctx)
             .await?;
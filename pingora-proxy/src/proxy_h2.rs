@@ -98,6 +98,16 @@ impl<SV> HttpProxy<SV> {
             }
         }
 
+        if let Err(e) = session
+            .downstream_modules_ctx
+            .upstream_request_filter(&mut req)
+            .await
+        {
+            return (false, Some(e));
+        }
+
+        session.trace.inject(&mut req);
+
         match self
             .inner
             .upstream_request_filter(session, &mut req, ctx)
@@ -520,6 +530,11 @@ impl<SV> HttpProxy<SV> {
         SV: ProxyHttp + Send + Sync,
         SV::CTX: Send + Sync,
     {
+        session
+            .downstream_modules_ctx
+            .request_body_filter(&mut data, end_of_body)
+            .await?;
+
         self.inner
             .request_body_filter(session, &mut data, end_of_body, ctx)
             .await?;
@@ -424,6 +424,18 @@ pub trait ProxyHttp {
         Ok(())
     }
 
+    /// Whether the response body can be moved from the upstream connection to the downstream one
+    /// with `splice()`, without copying it through userspace.
+    ///
+    /// This is only asked when both sides are plain HTTP/1 connections and the request has no
+    /// body, is not cacheable and has no HTTP modules or compression that change the response
+    /// body. When the body is spliced, [Self::upstream_response_body_filter()] and
+    /// [Self::response_body_filter()] are **not** called, so only return `true` when they do
+    /// nothing to this response.
+    fn allow_response_body_splice(&self, _session: &Session, _ctx: &Self::CTX) -> bool {
+        false
+    }
+
     /// This callback is invoked every time request related error log needs to be generated
     ///
     /// Users can define what is important to be written about this request via the returned string.
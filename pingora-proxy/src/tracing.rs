@@ -0,0 +1,793 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Distributed tracing of the requests
+//!
+//! When a [RequestTracer] is set on the [HttpProxy](crate::HttpProxy), the trace context of each
+//! request is extracted from its W3C `traceparent`/`tracestate` headers, or its B3 headers, and a
+//! new trace is started if there is none. The context is propagated to the upstream in the same
+//! format it came in, plus `traceparent`.
+//!
+//! The spans of a sampled request are sent to the [SpanExporter] of the tracer as they finish:
+//! - `request`: the whole request, the parent of all the spans below
+//! - `downstream_read`: reading the request header. For a reused downstream connection this
+//!   includes the time waiting for the request to arrive.
+//! - `cache_lookup` and `cache_lock_wait`
+//! - `upstream_connect`: from asking for a connection until the TCP connection is established,
+//!   which is reported by [Tracing::on_connected()]
+//! - `tls_handshake`: after the TCP connection is established until the connection is ready
+//! - `upstream_response`: proxying the request to the upstream and its response back. Its ID is the
+//!   parent ID sent to the upstream.
+//!
+//! More spans can be added with [RequestTrace::record()] via [Session::trace](crate::Session).
+
+use pingora_core::upstreams::peer::{Tracer, Tracing};
+use pingora_error::Error;
+use pingora_http::RequestHeader;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The ID of a trace
+pub type TraceId = [u8; 16];
+/// The ID of a span
+pub type SpanId = [u8; 8];
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+const B3: &str = "b3";
+const B3_TRACE_ID: &str = "x-b3-traceid";
+const B3_SPAN_ID: &str = "x-b3-spanid";
+const B3_PARENT_SPAN_ID: &str = "x-b3-parentspanid";
+const B3_SAMPLED: &str = "x-b3-sampled";
+const B3_FLAGS: &str = "x-b3-flags";
+
+/// The headers the trace context came in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// `traceparent` and `tracestate`
+    W3C,
+    /// The single `b3` header
+    B3Single,
+    /// The `X-B3-*` headers
+    B3Multi,
+}
+
+/// The trace context carried by the request headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: TraceId,
+    /// The ID of the span of the sender
+    pub span_id: SpanId,
+    /// The sampling decision of the sender, `None` when it is deferred to the receiver
+    pub sampled: Option<bool>,
+    /// The vendor specific `tracestate` of W3C
+    pub trace_state: Option<String>,
+    pub propagation: Propagation,
+}
+
+impl TraceContext {
+    /// Extract the trace context from the request headers.
+    ///
+    /// `traceparent` is preferred over the B3 headers when there are both. Invalid headers are
+    /// ignored.
+    pub fn extract(req: &RequestHeader) -> Option<Self> {
+        let header = |name: &str| req.headers.get(name).and_then(|v| v.to_str().ok());
+
+        if let Some(ctx) = header(TRACEPARENT).and_then(parse_traceparent) {
+            let trace_state = join_values(req, TRACESTATE);
+            return Some(TraceContext { trace_state, ..ctx });
+        }
+        if let Some(ctx) = header(B3).and_then(parse_b3) {
+            return Some(ctx);
+        }
+        let trace_id = parse_trace_id(header(B3_TRACE_ID)?)?;
+        let span_id = parse_hex(header(B3_SPAN_ID)?)?;
+        // the sampling decision is deferred to us when absent
+        let sampled = if header(B3_FLAGS) == Some("1") {
+            Some(true)
+        } else {
+            header(B3_SAMPLED).map(|s| s == "1" || s == "true")
+        };
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled,
+            trace_state: None,
+            propagation: Propagation::B3Multi,
+        })
+    }
+
+    /// The `traceparent` header value of this context. A deferred sampling decision is sent as
+    /// not sampled.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{}",
+            to_hex(&self.trace_id),
+            to_hex(&self.span_id),
+            if self.sampled == Some(true) {
+                "01"
+            } else {
+                "00"
+            }
+        )
+    }
+
+    /// Write this context to the request headers.
+    ///
+    /// `traceparent` is always written, the B3 headers are written in the format the context
+    /// came in.
+    pub fn inject(&self, req: &mut RequestHeader) -> pingora_error::Result<()> {
+        req.insert_header(TRACEPARENT, self.traceparent())?;
+        match self.trace_state.as_ref() {
+            Some(state) => req.insert_header(TRACESTATE, state.as_str())?,
+            None => {
+                req.remove_header(TRACESTATE);
+            }
+        }
+        let sampled = self.sampled.map(|s| if s { "1" } else { "0" });
+        match self.propagation {
+            Propagation::W3C => {}
+            Propagation::B3Single => {
+                let mut b3 = format!("{}-{}", to_hex(&self.trace_id), to_hex(&self.span_id));
+                if let Some(sampled) = sampled {
+                    b3.push('-');
+                    b3.push_str(sampled);
+                }
+                req.insert_header(B3, b3)?;
+            }
+            Propagation::B3Multi => {
+                req.insert_header(B3_TRACE_ID, to_hex(&self.trace_id))?;
+                req.insert_header(B3_SPAN_ID, to_hex(&self.span_id))?;
+                match sampled {
+                    Some(sampled) => req.insert_header(B3_SAMPLED, sampled)?,
+                    None => {
+                        req.remove_header(B3_SAMPLED);
+                    }
+                }
+                // the parent of the sender is not ours
+                req.remove_header(B3_PARENT_SPAN_ID);
+                req.remove_header(B3_FLAGS);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn join_values(req: &RequestHeader, name: &str) -> Option<String> {
+    let values: Vec<&str> = req
+        .headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(","))
+    }
+}
+
+fn parse_traceparent(value: &str) -> Option<TraceContext> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    if version.len() != 2 || version == "ff" {
+        return None;
+    }
+    let trace_id = parse_hex::<16>(parts.next()?)?;
+    let span_id = parse_hex::<8>(parts.next()?)?;
+    let flags = parse_hex::<1>(parts.next()?)?;
+    // future versions may append more fields
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+    Some(TraceContext {
+        trace_id,
+        span_id,
+        sampled: Some(flags[0] & 1 == 1),
+        trace_state: None,
+        propagation: Propagation::W3C,
+    })
+}
+
+// {TraceId}-{SpanId}-{SamplingState}-{ParentSpanId}, the last two are optional
+fn parse_b3(value: &str) -> Option<TraceContext> {
+    let mut parts = value.trim().split('-');
+    let trace_id = parse_trace_id(parts.next()?)?;
+    let span_id = parse_hex(parts.next()?)?;
+    let sampled = match parts.next() {
+        None => None,
+        Some("1") | Some("d") => Some(true),
+        Some("0") => Some(false),
+        Some(_) => return None,
+    };
+    Some(TraceContext {
+        trace_id,
+        span_id,
+        sampled,
+        trace_state: None,
+        propagation: Propagation::B3Single,
+    })
+}
+
+// B3 trace IDs can be 64 bit
+fn parse_trace_id(value: &str) -> Option<TraceId> {
+    if value.len() == 16 {
+        let low = parse_hex::<8>(value)?;
+        let mut id = [0; 16];
+        id[8..].copy_from_slice(&low);
+        Some(id)
+    } else {
+        parse_hex(value)
+    }
+}
+
+fn parse_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        let hex = value.get(i * 2..i * 2 + 2)?;
+        // uppercase is not allowed by W3C but harmless
+        if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        *byte = u8::from_str_radix(hex, 16).ok()?;
+    }
+    Some(out)
+}
+
+/// Lowercase hex encoding of the IDs
+pub fn to_hex(id: &[u8]) -> String {
+    let mut s = String::with_capacity(id.len() * 2);
+    for b in id {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+/// A finished span
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub trace_id: TraceId,
+    pub span_id: SpanId,
+    /// The parent span, which can be the span of the downstream for the `request` span
+    pub parent_id: Option<SpanId>,
+    pub name: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+impl SpanData {
+    /// Look up the value of the attribute
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// The interface to send the finished spans to a tracing backend
+///
+/// This method is called on the request path, so implementations should hand the spans off to be
+/// sent in the background.
+pub trait SpanExporter: Send + Sync {
+    fn export(&self, span: SpanData);
+}
+
+/// Keep the spans in memory, mostly for testing
+#[derive(Clone, Default)]
+pub struct InMemoryExporter {
+    spans: Arc<Mutex<Vec<SpanData>>>,
+}
+
+impl InMemoryExporter {
+    /// Create an empty [InMemoryExporter]. Its clones share the same spans.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The spans exported so far
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.spans.lock().unwrap().clone()
+    }
+}
+
+impl SpanExporter for InMemoryExporter {
+    fn export(&self, span: SpanData) {
+        self.spans.lock().unwrap().push(span);
+    }
+}
+
+/// Start the traces of the requests
+pub struct RequestTracer {
+    exporter: Arc<dyn SpanExporter>,
+    /// The ratio of the new traces to be sampled, between 0.0 and 1.0. Default 1.0.
+    ///
+    /// Requests with an incoming trace context follow the sampling decision of the context, unless
+    /// the decision is deferred to us.
+    pub sample_ratio: f64,
+}
+
+impl RequestTracer {
+    /// Create a [RequestTracer] which sends the spans to the given exporter
+    pub fn new(exporter: Arc<dyn SpanExporter>) -> Self {
+        RequestTracer {
+            exporter,
+            sample_ratio: 1.0,
+        }
+    }
+
+    /// Start the trace of the request which started at `start`.
+    pub fn start(&self, req: &RequestHeader, start: SystemTime) -> RequestTrace {
+        let parent = TraceContext::extract(req);
+        let trace_id = parent.as_ref().map_or_else(rand::random, |p| p.trace_id);
+        let sampled = parent
+            .as_ref()
+            .and_then(|p| p.sampled)
+            .unwrap_or_else(|| rand::random::<f64>() < self.sample_ratio);
+        let mut root = SpanData {
+            trace_id,
+            span_id: rand::random(),
+            parent_id: parent.as_ref().map(|p| p.span_id),
+            name: "request".to_string(),
+            start,
+            end: start,
+            attributes: vec![
+                ("http.method", req.method.to_string()),
+                ("http.target", req.uri.to_string()),
+            ],
+        };
+        if let Some(host) = req.headers.get(http::header::HOST) {
+            let host = String::from_utf8_lossy(host.as_bytes()).into_owned();
+            root.attributes.push(("http.host", host));
+        }
+        RequestTrace {
+            inner: Some(Box::new(TraceInner {
+                exporter: self.exporter.clone(),
+                sampled,
+                trace_state: parent.as_ref().and_then(|p| p.trace_state.clone()),
+                propagation: parent.map_or(Propagation::W3C, |p| p.propagation),
+                root,
+                upstream: None,
+                finished: false,
+            })),
+        }
+    }
+}
+
+/// The trace of a request
+///
+/// The `request` span is exported when this object is dropped if it is not finished already.
+#[derive(Default)]
+pub struct RequestTrace {
+    inner: Option<Box<TraceInner>>,
+}
+
+struct TraceInner {
+    exporter: Arc<dyn SpanExporter>,
+    sampled: bool,
+    trace_state: Option<String>,
+    propagation: Propagation,
+    root: SpanData,
+    // the span of the current upstream attempt
+    upstream: Option<(SpanId, SystemTime)>,
+    finished: bool,
+}
+
+impl RequestTrace {
+    /// A trace that does nothing
+    pub fn disabled() -> Self {
+        RequestTrace { inner: None }
+    }
+
+    /// Whether the spans of this request are exported
+    pub fn is_sampled(&self) -> bool {
+        self.inner.as_ref().map_or(false, |i| i.sampled)
+    }
+
+    /// The context to propagate to the upstream. The span ID is the one of the current
+    /// `upstream_response` span, or the `request` span outside of it.
+    pub fn context(&self) -> Option<TraceContext> {
+        let inner = self.inner.as_ref()?;
+        let span_id = inner.upstream.map_or(inner.root.span_id, |(id, _)| id);
+        Some(TraceContext {
+            trace_id: inner.root.trace_id,
+            span_id,
+            sampled: Some(inner.sampled),
+            trace_state: inner.trace_state.clone(),
+            propagation: inner.propagation,
+        })
+    }
+
+    /// Add an attribute to the `request` span.
+    pub fn set_attribute(&mut self, key: &'static str, value: String) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.root.attributes.push((key, value));
+        }
+    }
+
+    /// Export a span which is a child of the `request` span.
+    pub fn record(
+        &self,
+        name: &str,
+        start: SystemTime,
+        end: SystemTime,
+        attributes: Vec<(&'static str, String)>,
+    ) {
+        self.export(None, name, start, end, attributes);
+    }
+
+    fn export(
+        &self,
+        span_id: Option<SpanId>,
+        name: &str,
+        start: SystemTime,
+        end: SystemTime,
+        attributes: Vec<(&'static str, String)>,
+    ) {
+        let Some(inner) = self.inner.as_ref() else {
+            return;
+        };
+        if !inner.sampled {
+            return;
+        }
+        inner.exporter.export(SpanData {
+            trace_id: inner.root.trace_id,
+            span_id: span_id.unwrap_or_else(rand::random),
+            parent_id: Some(inner.root.span_id),
+            name: name.to_string(),
+            start,
+            end,
+            attributes,
+        });
+    }
+
+    pub(crate) fn inject(&self, req: &mut RequestHeader) {
+        if let Some(ctx) = self.context() {
+            // the header values are hex so this never fails
+            let _ = ctx.inject(req);
+        }
+    }
+
+    // a tracer for the peer to learn when the connection is established, which wraps the tracer
+    // of the peer. The tracer of the peer is left in place if this request is not sampled.
+    pub(crate) fn connect_tracer(&self, inner: &mut Option<Tracer>) -> Option<ConnectTracer> {
+        if !self.is_sampled() {
+            return None;
+        }
+        Some(ConnectTracer {
+            inner: inner.take(),
+            connected: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    // record the spans of connecting to the upstream that started at `start`
+    pub(crate) fn record_connect(
+        &self,
+        start: SystemTime,
+        connect_tracer: Option<&ConnectTracer>,
+        tls: bool,
+        reused: Option<bool>,
+        error: Option<&Error>,
+    ) {
+        if !self.is_sampled() {
+            return;
+        }
+        let now = SystemTime::now();
+        let connected = connect_tracer.and_then(|t| *t.connected.lock().unwrap());
+        let mut attributes = vec![];
+        if let Some(reused) = reused {
+            attributes.push(("reused", reused.to_string()));
+        }
+        if let Some(e) = error {
+            attributes.push(("error", e.etype().as_str().to_string()));
+        }
+        match connected {
+            // a new connection
+            Some(connected) => {
+                self.record("upstream_connect", start, connected, attributes);
+                if tls {
+                    self.record("tls_handshake", connected, now, vec![]);
+                }
+            }
+            None => self.record("upstream_connect", start, now, attributes),
+        }
+    }
+
+    pub(crate) fn start_upstream(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.upstream = Some((rand::random(), SystemTime::now()));
+        }
+    }
+
+    pub(crate) fn finish_upstream(&mut self, error: Option<&Error>) {
+        let Some((span_id, start)) = self.inner.as_mut().and_then(|i| i.upstream.take()) else {
+            return;
+        };
+        let mut attributes = vec![];
+        if let Some(e) = error {
+            attributes.push(("error", e.etype().as_str().to_string()));
+        }
+        self.export(
+            Some(span_id),
+            "upstream_response",
+            start,
+            SystemTime::now(),
+            attributes,
+        );
+    }
+
+    /// Finish and export the `request` span.
+    pub(crate) fn finish(&mut self, status: Option<u16>, error: Option<&Error>) {
+        if let Some(status) = status {
+            self.set_attribute("http.status_code", status.to_string());
+        }
+        if let Some(e) = error {
+            self.set_attribute("error", e.etype().as_str().to_string());
+        }
+        let Some(inner) = self.inner.as_mut() else {
+            return;
+        };
+        if inner.finished {
+            return;
+        }
+        inner.finished = true;
+        if inner.sampled {
+            let mut root = inner.root.clone();
+            root.end = SystemTime::now();
+            inner.exporter.export(root);
+        }
+    }
+}
+
+impl Drop for RequestTrace {
+    fn drop(&mut self) {
+        self.finish(None, None);
+    }
+}
+
+// records when the connection is established, and passes the events on to the tracer of the peer
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectTracer {
+    inner: Option<Tracer>,
+    connected: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl Tracing for ConnectTracer {
+    fn on_connected(&self) {
+        *self.connected.lock().unwrap() = Some(SystemTime::now());
+        if let Some(t) = self.inner.as_ref() {
+            t.0.on_connected();
+        }
+    }
+
+    fn on_disconnected(&self) {
+        if let Some(t) = self.inner.as_ref() {
+            t.0.on_disconnected();
+        }
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Tracing> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&'static str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(*name, *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_traceparent() {
+        let req = request(&[
+            (
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            ),
+            ("tracestate", "a=1"),
+            ("tracestate", "b=2"),
+        ]);
+        let ctx = TraceContext::extract(&req).unwrap();
+        assert_eq!(to_hex(&ctx.trace_id), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(to_hex(&ctx.span_id), "b7ad6b7169203331");
+        assert_eq!(ctx.sampled, Some(true));
+        assert_eq!(ctx.trace_state.as_deref(), Some("a=1,b=2"));
+        assert_eq!(ctx.propagation, Propagation::W3C);
+        assert_eq!(
+            ctx.traceparent(),
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+        );
+
+        for invalid in [
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b716920333-01",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+        ] {
+            assert!(TraceContext::extract(&request(&[("traceparent", invalid)])).is_none());
+        }
+    }
+
+    #[test]
+    fn test_b3() {
+        let req = request(&[("b3", "80f198ee56343ba8-e457b5a2e4d86bd1-0")]);
+        let ctx = TraceContext::extract(&req).unwrap();
+        assert_eq!(to_hex(&ctx.trace_id), "000000000000000080f198ee56343ba8");
+        assert_eq!(ctx.sampled, Some(false));
+        assert_eq!(ctx.propagation, Propagation::B3Single);
+
+        // the sampling decision is deferred
+        let req = request(&[("b3", "80f198ee56343ba8-e457b5a2e4d86bd1")]);
+        let ctx = TraceContext::extract(&req).unwrap();
+        assert_eq!(ctx.sampled, None);
+        let mut upstream = req.clone();
+        ctx.inject(&mut upstream).unwrap();
+        assert_eq!(
+            upstream.headers["b3"],
+            "000000000000000080f198ee56343ba8-e457b5a2e4d86bd1"
+        );
+
+        let req = request(&[
+            ("X-B3-TraceId", "463ac35c9f6413ad48485a3953bb6124"),
+            ("X-B3-SpanId", "a2fb4a1d1a96d312"),
+            ("X-B3-ParentSpanId", "0020000000000001"),
+            ("X-B3-Sampled", "1"),
+        ]);
+        let ctx = TraceContext::extract(&req).unwrap();
+        assert_eq!(ctx.sampled, Some(true));
+        assert_eq!(ctx.propagation, Propagation::B3Multi);
+
+        let no_sampled = request(&[
+            ("X-B3-TraceId", "463ac35c9f6413ad48485a3953bb6124"),
+            ("X-B3-SpanId", "a2fb4a1d1a96d312"),
+        ]);
+        assert_eq!(TraceContext::extract(&no_sampled).unwrap().sampled, None);
+
+        let mut upstream = req.clone();
+        let ctx = TraceContext {
+            span_id: [1; 8],
+            ..ctx
+        };
+        ctx.inject(&mut upstream).unwrap();
+        assert_eq!(upstream.headers["x-b3-spanid"], "0101010101010101");
+        assert!(upstream.headers.get("x-b3-parentspanid").is_none());
+        assert_eq!(
+            upstream.headers["traceparent"],
+            "00-463ac35c9f6413ad48485a3953bb6124-0101010101010101-01"
+        );
+    }
+
+    #[test]
+    fn test_request_trace() {
+        let exporter = InMemoryExporter::new();
+        let tracer = RequestTracer::new(Arc::new(exporter.clone()));
+        let req = request(&[(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        )]);
+        let mut trace = tracer.start(&req, SystemTime::now());
+        trace.record(
+            "downstream_read",
+            SystemTime::now(),
+            SystemTime::now(),
+            vec![],
+        );
+        trace.start_upstream();
+        let mut upstream = req.clone();
+        trace.inject(&mut upstream);
+        trace.finish_upstream(None);
+        trace.finish(Some(200), None);
+        drop(trace);
+
+        let spans = exporter.spans();
+        assert_eq!(spans.len(), 3);
+        let root = &spans[2];
+        assert_eq!(root.name, "request");
+        assert_eq!(to_hex(&root.parent_id.unwrap()), "b7ad6b7169203331");
+        assert_eq!(root.attribute("http.status_code"), Some("200"));
+        for span in &spans[..2] {
+            assert_eq!(span.trace_id, root.trace_id);
+            assert_eq!(span.parent_id, Some(root.span_id));
+        }
+        // the upstream sees the upstream_response span as its parent
+        let parent = TraceContext::extract(&upstream).unwrap();
+        assert_eq!(spans[1].name, "upstream_response");
+        assert_eq!(parent.span_id, spans[1].span_id);
+
+        // not sampled
+        let req = request(&[(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00",
+        )]);
+        let mut trace = tracer.start(&req, SystemTime::now());
+        trace.record(
+            "downstream_read",
+            SystemTime::now(),
+            SystemTime::now(),
+            vec![],
+        );
+        // the tracer of the peer is kept
+        let mut peer_tracer = Some(Tracer(Box::new(NoopTracer)));
+        assert!(trace.connect_tracer(&mut peer_tracer).is_none());
+        assert!(peer_tracer.is_some());
+        trace.finish(None, None);
+        assert_eq!(exporter.spans().len(), 3);
+
+        assert!(RequestTrace::disabled()
+            .connect_tracer(&mut peer_tracer)
+            .is_none());
+        assert!(peer_tracer.is_some());
+
+        // the sampled trace wraps the tracer of the peer
+        let trace = tracer.start(&request(&[]), SystemTime::now());
+        let connect_tracer = trace.connect_tracer(&mut peer_tracer).unwrap();
+        assert!(peer_tracer.is_none());
+        assert!(connect_tracer.inner.is_some());
+    }
+
+    #[test]
+    fn test_deferred_sampling() {
+        let exporter = InMemoryExporter::new();
+        let mut tracer = RequestTracer::new(Arc::new(exporter.clone()));
+        tracer.sample_ratio = 0.0;
+        let deferred = request(&[
+            ("X-B3-TraceId", "463ac35c9f6413ad48485a3953bb6124"),
+            ("X-B3-SpanId", "a2fb4a1d1a96d312"),
+        ]);
+
+        // the sample ratio decides
+        let trace = tracer.start(&deferred, SystemTime::now());
+        assert!(!trace.is_sampled());
+        let ctx = trace.context().unwrap();
+        assert_eq!(to_hex(&ctx.trace_id), "463ac35c9f6413ad48485a3953bb6124");
+        assert_eq!(ctx.sampled, Some(false));
+        let mut upstream = deferred.clone();
+        trace.inject(&mut upstream);
+        assert_eq!(upstream.headers["x-b3-sampled"], "0");
+
+        tracer.sample_ratio = 1.0;
+        assert!(tracer.start(&deferred, SystemTime::now()).is_sampled());
+
+        // an explicit decision is followed regardless of the sample ratio
+        let mut sampled = deferred.clone();
+        sampled.insert_header("X-B3-Sampled", "1").unwrap();
+        tracer.sample_ratio = 0.0;
+        assert!(tracer.start(&sampled, SystemTime::now()).is_sampled());
+    }
+
+    #[derive(Debug)]
+    struct NoopTracer;
+
+    impl Tracing for NoopTracer {
+        fn on_connected(&self) {}
+        fn on_disconnected(&self) {}
+        fn boxed_clone(&self) -> Box<dyn Tracing> {
+            Box::new(NoopTracer)
+        }
+    }
+}
@@ -211,6 +211,35 @@ async fn test_h2_to_h1_upload() {
     assert_eq!(body, payload);
 }
 
+#[tokio::test]
+async fn test_request_decompression() {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    init();
+    let payload = "test upload ".repeat(100);
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(payload.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let client = reqwest::Client::new();
+    // the gzip body is framed by content-length downstream and sent chunked upstream
+    for _ in 0..2 {
+        let res = client
+            .post("http://127.0.0.1:6147/echo")
+            .header("x-decompress-request", "1")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::CONTENT_LENGTH, compressed.len())
+            .body(compressed.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.text().await.unwrap();
+        assert_eq!(body, payload);
+    }
+}
+
 #[tokio::test]
 async fn test_simple_proxy_uds() {
     init();
@@ -1659,6 +1659,36 @@ mod test_cache {
         assert!(cache_a_miss_epoch != cache_b_miss_epoch);
     }
 
+    #[tokio::test]
+    async fn test_precompressed_variants() {
+        init();
+        let url = "http://127.0.0.1:6148/unique/test_precompressed_variants/no_compression";
+        // disable reqwest gzip support to check the encoding of the variants
+        let client = reqwest::ClientBuilder::new().gzip(false).build().unwrap();
+
+        for (encoding, cache_status) in [
+            ("gzip", "miss"),
+            ("gzip", "hit"),
+            ("br", "miss"),
+            ("br", "hit"),
+            ("gzip", "hit"),
+        ] {
+            let res = client
+                .get(url)
+                .header("x-precompress", "1")
+                .header("accept-encoding", encoding)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let headers = res.headers();
+            assert_eq!(headers["x-cache-status"], cache_status);
+            assert_eq!(headers["content-encoding"], encoding);
+            let body = res.bytes().await.unwrap();
+            assert!(body.len() < 32);
+        }
+    }
+
     #[tokio::test]
     async fn test_vary_caching_ignored_vary_header() {
         init();
@@ -26,7 +26,9 @@ use pingora_cache::{
     set_compression_dict_path, CacheMeta, CacheMetaDefaults, CachePhase, MemCache, NoCacheReason,
     RespCacheable,
 };
-use pingora_core::modules::http::compression::ResponseCompression;
+use pingora_core::modules::http::compression::{
+    RequestCompression, RequestCompressionBuilder, ResponseCompression,
+};
 use pingora_core::protocols::{l4::socket::SocketAddr, Digest};
 use pingora_core::server::configuration::Opt;
 use pingora_core::services::Service;
@@ -214,6 +216,7 @@ impl ProxyHttp for ExampleProxyHttp {
     ) -> Result<()> {
         let req = session.req_header();
         let downstream_compression = req.headers.get("x-downstream-compression").is_some();
+        let decompress_request = req.headers.get("x-decompress-request").is_some();
         if downstream_compression {
             session
                 .downstream_modules_ctx
@@ -224,6 +227,11 @@ impl ProxyHttp for ExampleProxyHttp {
             // enable upstream compression for all requests by default
             session.upstream_compression.adjust_level(6);
         }
+        session
+            .downstream_modules_ctx
+            .get_mut::<RequestCompression>()
+            .unwrap()
+            .adjust_decompression(decompress_request);
         Ok(())
     }
 
@@ -355,6 +363,11 @@ impl ProxyHttp for ExampleProxyCache {
             .cache
             .enable(&*CACHE_BACKEND, eviction, Some(&*CACHE_PREDICTOR), lock);
 
+        if session.get_header("x-precompress").is_some() {
+            // compress before caching so that each encoding is stored pre-compressed
+            session.upstream_compression.adjust_level(6);
+        }
+
         if let Some(max_file_size_hdr) = session
             .req_header()
             .headers
@@ -408,6 +421,10 @@ impl ProxyHttp for ExampleProxyCache {
             );
         });
 
+        if req.headers.contains_key("x-precompress") {
+            key.add_encoding(req, None);
+        }
+
         key.finalize()
     }
 
@@ -499,6 +516,11 @@ fn test_main() {
 
     let mut proxy_service_http =
         pingora_proxy::http_proxy_service(&my_server.configuration, ExampleProxyHttp {});
+    proxy_service_http
+        .app_logic_mut()
+        .unwrap()
+        .downstream_modules
+        .add_module(RequestCompressionBuilder::decompress(1024 * 1024));
     proxy_service_http.add_tcp("0.0.0.0:6147");
     proxy_service_http.add_uds("/tmp/pingora_proxy.sock", None);
 
@@ -0,0 +1,198 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CPU affinity, priority and naming of the runtime threads
+//!
+//! The CPU affinity and the priority are only supported on Linux.
+
+use std::io::{Error, ErrorKind, Result};
+
+/// How the threads of a [Runtime](crate::Runtime) are set up
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadOptions {
+    /// The name of the threads. The name of the runtime is used if not set.
+    ///
+    /// Note that due to the limit of the underlying system, only the first 15 chars will be used
+    pub name: Option<String>,
+    /// The CPUs the threads are allowed to run on. Any CPU if empty.
+    pub cpus: Vec<usize>,
+    /// Pin each thread to a single CPU of `cpus`, in turn, instead of letting all the threads run
+    /// on any of them.
+    ///
+    /// The threads of the work stealing flavor, including its blocking threads, take the CPUs in
+    /// the order they start.
+    pub pin_threads: bool,
+    /// The nice value of the threads, from -20 to 19. A higher value means a lower priority.
+    pub nice: Option<i32>,
+}
+
+impl ThreadOptions {
+    /// Whether these options leave the threads as they are, apart from their names
+    pub fn is_noop(&self) -> bool {
+        self.cpus.is_empty() && self.nice.is_none()
+    }
+
+    /// Apply the CPU affinity and the priority to the current thread, which is the `index`-th
+    /// thread of its runtime.
+    pub fn apply(&self, index: usize) -> Result<()> {
+        if !self.cpus.is_empty() {
+            if self.pin_threads {
+                set_affinity(&[self.cpus[index % self.cpus.len()]])?;
+            } else {
+                set_affinity(&self.cpus)?;
+            }
+        }
+        if let Some(nice) = self.nice {
+            set_nice(nice)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn thread_name(&self, runtime_name: &str) -> String {
+        self.name.as_deref().unwrap_or(runtime_name).to_string()
+    }
+}
+
+/// Parse a CPU list in the format of the Linux kernel, e.g. `0-3,8,10-11`.
+///
+/// The returned CPUs are sorted and deduplicated.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid CPU list: {list}"));
+    let mut cpus = vec![];
+    for item in list.trim().split(',').map(str::trim) {
+        if item.is_empty() {
+            continue;
+        }
+        match item.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.trim().parse().map_err(|_| invalid())?;
+                let end: usize = end.trim().parse().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(item.parse().map_err(|_| invalid())?),
+        }
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// The CPUs of the given NUMA node, read from sysfs.
+pub fn numa_node_cpus(node: usize) -> Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{node}/cpulist");
+    let list = std::fs::read_to_string(&path)
+        .map_err(|e| Error::new(e.kind(), format!("NUMA node {node}: {e}")))?;
+    parse_cpu_list(&list)
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpus: &[usize]) -> Result<()> {
+    // safety: cpu_set_t is a plain bit mask
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus.iter().copied() {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("CPU {cpu} is out of range"),
+            ));
+        }
+        // safety: the cpu is checked to be within the set
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // safety: 0 means the current thread, the set is valid for its size
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cpus: &[usize]) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> Result<()> {
+    // on Linux the priority set for a thread id only applies to that thread
+    // safety: gettid() always succeeds
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+    // safety: no pointer involved
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i32) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "thread priority is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8, 10-11\n").unwrap(),
+            [0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list("2,1,1-2").unwrap(), [1, 2]);
+        assert!(parse_cpu_list("").unwrap().is_empty());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply() {
+        // run in its own thread not to affect the other tests
+        std::thread::spawn(|| {
+            let options = ThreadOptions {
+                cpus: vec![0],
+                pin_threads: true,
+                nice: Some(1),
+                ..Default::default()
+            };
+            options.apply(3).unwrap();
+            // safety: 0 means the current thread
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            let ret = unsafe {
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set)
+            };
+            assert_eq!(ret, 0);
+            assert!(unsafe { libc::CPU_ISSET(0, &set) });
+            assert_eq!(unsafe { libc::CPU_COUNT(&set) }, 1);
+
+            let options = ThreadOptions {
+                cpus: vec![libc::CPU_SETSIZE as usize],
+                ..Default::default()
+            };
+            assert!(options.apply(0).is_err());
+        })
+        .join()
+        .unwrap();
+    }
+}
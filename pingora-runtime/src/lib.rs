@@ -23,8 +23,15 @@
 //! This flavor is as efficient as the single-threaded runtime while allows the async
 //! program to use multiple cores.
 
+pub mod affinity;
+pub mod metrics;
+
+pub use affinity::ThreadOptions;
+
+use log::error;
 use once_cell::sync::{Lazy, OnceCell};
 use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 use std::time::Duration;
@@ -45,14 +52,31 @@ pub enum Runtime {
 impl Runtime {
     /// Create a `Steal` flavor runtime. This just a regular tokio runtime
     pub fn new_steal(threads: usize, name: &str) -> Self {
-        Self::Steal(
-            Builder::new_multi_thread()
-                .enable_all()
-                .worker_threads(threads)
-                .thread_name(name)
-                .build()
-                .unwrap(),
-        )
+        Self::new_steal_with_options(threads, name, &ThreadOptions::default())
+    }
+
+    /// Create a `Steal` flavor runtime whose threads are set up by the given [ThreadOptions]
+    pub fn new_steal_with_options(threads: usize, name: &str, options: &ThreadOptions) -> Self {
+        let mut builder = Builder::new_multi_thread();
+        builder
+            .enable_all()
+            .worker_threads(threads)
+            .thread_name(options.thread_name(name));
+        if !options.is_noop() {
+            let options = options.clone();
+            let name = name.to_string();
+            let next_index = AtomicUsize::new(0);
+            builder.on_thread_start(move || {
+                let index = next_index.fetch_add(1, Ordering::Relaxed);
+                if let Err(e) = options.apply(index) {
+                    error!("Failed to set up thread {index} of {name}: {e}");
+                }
+            });
+        }
+        metrics::instrument(&mut builder, name, None);
+        let rt = builder.build().unwrap();
+        rt.spawn(metrics::probe(name.to_string(), None));
+        Self::Steal(rt)
     }
 
     /// Create a `NoSteal` flavor runtime. This is backed by multiple tokio current-thread runtime
@@ -60,6 +84,11 @@ impl Runtime {
         Self::NoSteal(NoStealRuntime::new(threads, name))
     }
 
+    /// Create a `NoSteal` flavor runtime whose threads are set up by the given [ThreadOptions]
+    pub fn new_no_steal_with_options(threads: usize, name: &str, options: &ThreadOptions) -> Self {
+        Self::NoSteal(NoStealRuntime::with_options(threads, name, options))
+    }
+
     /// Return the &[Handle] of the [Runtime].
     /// For `Steal` flavor, it will just return the &[Handle].
     /// For `NoSteal` flavor, it will return the &[Handle] of a random thread in its pool.
@@ -109,6 +138,7 @@ type Pools = Arc<OnceCell<Box<[Handle]>>>;
 pub struct NoStealRuntime {
     threads: usize,
     name: String,
+    options: ThreadOptions,
     // Lazily init the runtimes so that they are created after pingora
     // daemonize itself. Otherwise the runtime threads are lost.
     pools: Arc<OnceCell<Box<[Handle]>>>,
@@ -118,10 +148,17 @@ pub struct NoStealRuntime {
 impl NoStealRuntime {
     /// Create a new [NoStealRuntime]. Panic if `threads` is 0
     pub fn new(threads: usize, name: &str) -> Self {
+        Self::with_options(threads, name, &ThreadOptions::default())
+    }
+
+    /// Create a new [NoStealRuntime] whose threads are set up by the given [ThreadOptions].
+    /// Panic if `threads` is 0
+    pub fn with_options(threads: usize, name: &str, options: &ThreadOptions) -> Self {
         assert!(threads != 0);
         NoStealRuntime {
             threads,
             name: name.to_string(),
+            options: options.clone(),
             pools: Arc::new(OnceCell::new()),
             controls: OnceCell::new(),
         }
@@ -130,14 +167,23 @@ impl NoStealRuntime {
     fn init_pools(&self) -> (Box<[Handle]>, Vec<Control>) {
         let mut pools = Vec::with_capacity(self.threads);
         let mut controls = Vec::with_capacity(self.threads);
-        for _ in 0..self.threads {
-            let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        for index in 0..self.threads {
+            let mut builder = Builder::new_current_thread();
+            builder.enable_all();
+            metrics::instrument(&mut builder, &self.name, Some(index));
+            let rt = builder.build().unwrap();
+            rt.spawn(metrics::probe(self.name.clone(), Some(index)));
             let handler = rt.handle().clone();
             let (tx, rx) = channel::<Duration>();
             let pools_ref = self.pools.clone();
+            let options = self.options.clone();
+            let name = self.name.clone();
             let join = std::thread::Builder::new()
-                .name(self.name.clone())
+                .name(self.options.thread_name(&self.name))
                 .spawn(move || {
+                    if let Err(e) = options.apply(index) {
+                        error!("Failed to set up thread {index} of {name}: {e}");
+                    }
                     CURRENT_HANDLE.get_or(|| pools_ref);
                     if let Ok(timeout) = rt.block_on(rx) {
                         rt.shutdown_timeout(timeout);
@@ -202,8 +248,6 @@ impl NoStealRuntime {
             }
         } // else, the controls and the runtimes are not even init yet, just return;
     }
-
-    // TODO: runtime metrics
 }
 
 #[test]
@@ -263,3 +307,23 @@ fn test_no_steal_shutdown() {
 
     rt.shutdown_timeout(Duration::from_secs(1));
 }
+
+#[test]
+fn test_thread_options() {
+    let options = ThreadOptions {
+        name: Some("custom".to_string()),
+        ..Default::default()
+    };
+    for rt in [
+        Runtime::new_steal_with_options(1, "test", &options),
+        Runtime::new_no_steal_with_options(1, "test", &options),
+    ] {
+        let name = rt.get_handle().block_on(async {
+            let join = current_handle()
+                .spawn(async { std::thread::current().name().map(|n| n.to_string()) });
+            join.await.unwrap()
+        });
+        assert_eq!(name.as_deref(), Some("custom"));
+        rt.shutdown_timeout(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per worker thread metrics of the runtimes
+//!
+//! The metrics are registered to the default Prometheus registry, labeled by `service` (the name
+//! of the runtime) and `worker` (the index of the thread in the runtime):
+//! - `pingora_runtime_worker_busy_seconds_total`: the time the worker spends running tasks
+//! - `pingora_runtime_worker_parks_total`: how many times the worker runs out of work and parks
+//! - `pingora_runtime_event_loop_lag_seconds`: how late a periodic probe task wakes up
+//! - `pingora_runtime_alive_tasks`: the number of tasks not finished yet
+//! - `pingora_runtime_global_queue_depth`: the number of tasks scheduled to run from outside
+//!   the workers
+//!
+//! tokio only reports the per worker poll counts, the spawned task counts and the per worker
+//! queue depths when built with `RUSTFLAGS="--cfg tokio_unstable"`. Default builds don't register
+//! or export the following ones:
+//! - `pingora_runtime_worker_polls_total`: the number of task polls
+//! - `pingora_runtime_tasks_spawned_total`: the number of tasks spawned
+//! - `pingora_runtime_worker_queue_depth`: the number of tasks in the local queue of the worker
+//!
+//! The workers of the work stealing runtime are indexed in the order they first run, which may
+//! differ from the index tokio uses for the metrics it reports. The probe of such a runtime can
+//! run on any of its threads, so its lag is labeled with `worker="all"`.
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_counter_vec, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge_vec, Counter, CounterVec, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec,
+};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Builder, RuntimeMetrics};
+
+static BUSY_SECONDS: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "pingora_runtime_worker_busy_seconds_total",
+        "the time the worker spends running tasks",
+        &["service", "worker"]
+    )
+    .unwrap()
+});
+
+static PARKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pingora_runtime_worker_parks_total",
+        "how many times the worker runs out of work and parks",
+        &["service", "worker"]
+    )
+    .unwrap()
+});
+
+static EVENT_LOOP_LAG: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "pingora_runtime_event_loop_lag_seconds",
+        "how late a periodic probe task wakes up",
+        &["service", "worker"],
+        // 1ms to ~4s
+        exponential_buckets(0.001, 2.0, 13).unwrap()
+    )
+    .unwrap()
+});
+
+static ALIVE_TASKS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pingora_runtime_alive_tasks",
+        "the number of tasks not finished yet",
+        &["service", "worker"]
+    )
+    .unwrap()
+});
+
+static GLOBAL_QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pingora_runtime_global_queue_depth",
+        "the number of tasks scheduled to run from outside the workers",
+        &["service", "worker"]
+    )
+    .unwrap()
+});
+
+// The metrics below are only registered with `--cfg tokio_unstable`, which the tokio metrics
+// they are sampled from require.
+
+#[cfg(tokio_unstable)]
+static POLLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pingora_runtime_worker_polls_total",
+        "the number of task polls",
+        &["service", "worker"]
+    )
+    .unwrap()
+});
+
+#[cfg(tokio_unstable)]
+static SPAWNED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pingora_runtime_tasks_spawned_total",
+        "the number of tasks spawned",
+        &["service", "worker"]
+    )
+    .unwrap()
+});
+
+#[cfg(tokio_unstable)]
+static QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pingora_runtime_worker_queue_depth",
+        "the number of tasks in the local queue of the worker",
+        &["service", "worker"]
+    )
+    .unwrap()
+});
+
+/// How often the probe wakes up to measure the event loop lag
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+// the metrics of the worker running on the current thread
+struct Worker {
+    busy: Counter,
+    parks: IntCounter,
+    unparked: Option<Instant>,
+}
+
+thread_local! {
+    static WORKER: RefCell<Option<Worker>> = const { RefCell::new(None) };
+}
+
+/// Install the hooks measuring the busy time of the workers to the runtime `builder`.
+///
+/// `index` is the worker index of a single threaded runtime. The threads of a multi-threaded
+/// runtime are indexed in the order they first run.
+pub(crate) fn instrument(builder: &mut Builder, service: &str, index: Option<usize>) {
+    let service: Arc<str> = service.into();
+    let next_index = AtomicUsize::new(0);
+    builder
+        .on_thread_unpark(move || {
+            WORKER.with(|worker| {
+                let mut worker = worker.borrow_mut();
+                let worker = worker.get_or_insert_with(|| {
+                    let index = index
+                        .unwrap_or_else(|| next_index.fetch_add(1, Ordering::Relaxed))
+                        .to_string();
+                    let labels = [&*service, index.as_str()];
+                    Worker {
+                        busy: BUSY_SECONDS.with_label_values(&labels),
+                        parks: PARKS.with_label_values(&labels),
+                        unparked: None,
+                    }
+                });
+                worker.unparked = Some(Instant::now());
+            })
+        })
+        .on_thread_park(|| {
+            WORKER.with(|worker| {
+                if let Some(worker) = worker.borrow_mut().as_mut() {
+                    if let Some(unparked) = worker.unparked.take() {
+                        worker.busy.inc_by(unparked.elapsed().as_secs_f64());
+                    }
+                    worker.parks.inc();
+                }
+            })
+        });
+}
+
+// how late a sleep of `interval` wakes up
+async fn measure_lag(interval: Duration) -> Duration {
+    let start = Instant::now();
+    tokio::time::sleep(interval).await;
+    start.elapsed().saturating_sub(interval)
+}
+
+// sample the task metrics that tokio reports without `--cfg tokio_unstable`
+fn sample_tasks(metrics: &RuntimeMetrics, alive_tasks: &IntGauge, global_queue_depth: &IntGauge) {
+    alive_tasks.set(metrics.num_alive_tasks() as i64);
+    global_queue_depth.set(metrics.global_queue_depth() as i64);
+}
+
+/// The probe task that measures the event loop lag, and samples the metrics reported by tokio.
+///
+/// `worker` is the index of a single threaded runtime, `None` for a multi-threaded one.
+pub(crate) async fn probe(service: String, worker: Option<usize>) {
+    let label = worker.map_or_else(|| "all".to_string(), |i| i.to_string());
+    let labels = [service.as_str(), label.as_str()];
+    let lag = EVENT_LOOP_LAG.with_label_values(&labels);
+    let alive_tasks = ALIVE_TASKS.with_label_values(&labels);
+    let global_queue_depth = GLOBAL_QUEUE_DEPTH.with_label_values(&labels);
+    #[cfg(tokio_unstable)]
+    let mut sampler = Sampler::new(service.clone(), worker);
+    loop {
+        lag.observe(measure_lag(PROBE_INTERVAL).await.as_secs_f64());
+        let metrics = tokio::runtime::Handle::current().metrics();
+        sample_tasks(&metrics, &alive_tasks, &global_queue_depth);
+        #[cfg(tokio_unstable)]
+        sampler.sample(&metrics);
+    }
+}
+
+// turn the cumulative numbers of tokio into the increments of the counters
+#[cfg(tokio_unstable)]
+struct Sampler {
+    service: String,
+    worker: Option<usize>,
+    polls: Vec<u64>,
+    spawned: u64,
+}
+
+#[cfg(tokio_unstable)]
+impl Sampler {
+    fn new(service: String, worker: Option<usize>) -> Self {
+        Sampler {
+            service,
+            worker,
+            polls: vec![],
+            spawned: 0,
+        }
+    }
+
+    fn sample(&mut self, metrics: &RuntimeMetrics) {
+        let workers = metrics.num_workers();
+        self.polls.resize(workers, 0);
+        for i in 0..workers {
+            // a single threaded runtime is one of the workers of a NoStealRuntime
+            let label = self.worker.unwrap_or(i).to_string();
+            let labels = [self.service.as_str(), label.as_str()];
+            let polls = metrics.worker_poll_count(i);
+            POLLS
+                .with_label_values(&labels)
+                .inc_by(polls.saturating_sub(self.polls[i]));
+            self.polls[i] = polls;
+            let depth = metrics.worker_local_queue_depth(i);
+            QUEUE_DEPTH.with_label_values(&labels).set(depth as i64);
+        }
+        let label = self
+            .worker
+            .map_or_else(|| "all".to_string(), |i| i.to_string());
+        let spawned = metrics.spawned_tasks_count();
+        SPAWNED
+            .with_label_values(&[self.service.as_str(), label.as_str()])
+            .inc_by(spawned.saturating_sub(self.spawned));
+        self.spawned = spawned;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NoStealRuntime;
+
+    fn metric_value(name: &str, service: &str) -> Vec<(String, f64)> {
+        let mut values = vec![];
+        for family in prometheus::gather() {
+            if family.get_name() != name {
+                continue;
+            }
+            for metric in family.get_metric() {
+                let labels = metric.get_label();
+                if !labels.iter().any(|l| l.get_value() == service) {
+                    continue;
+                }
+                let worker = labels
+                    .iter()
+                    .find(|l| l.get_name() == "worker")
+                    .unwrap()
+                    .get_value()
+                    .to_string();
+                let value = if metric.has_counter() {
+                    metric.get_counter().get_value()
+                } else {
+                    metric.get_histogram().get_sample_count() as f64
+                };
+                values.push((worker, value));
+            }
+        }
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        values
+    }
+
+    #[test]
+    fn test_worker_metrics() {
+        let rt = NoStealRuntime::new(2, "test_metrics");
+        for i in 0..2 {
+            rt.get_runtime_at(i).block_on(async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            });
+        }
+        // wait for the workers to park again
+        std::thread::sleep(Duration::from_millis(100));
+        let parks = metric_value("pingora_runtime_worker_parks_total", "test_metrics");
+        assert_eq!(parks.len(), 2);
+        assert_eq!(parks[0].0, "0");
+        assert_eq!(parks[1].0, "1");
+        assert!(parks.iter().all(|(_, v)| *v > 0.0));
+        let busy = metric_value("pingora_runtime_worker_busy_seconds_total", "test_metrics");
+        assert_eq!(busy.len(), 2);
+        rt.shutdown_timeout(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_event_loop_lag() {
+        let rt = Builder::new_current_thread().enable_time().build().unwrap();
+        let lag = rt.block_on(async {
+            let handle = tokio::spawn(measure_lag(Duration::from_millis(10)));
+            // let the probe start, then block the thread so that it wakes up late
+            tokio::task::yield_now().await;
+            std::thread::sleep(Duration::from_millis(100));
+            handle.await.unwrap()
+        });
+        assert!(lag >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_sample_tasks() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let labels = ["test_sample_tasks", "0"];
+        let alive_tasks = ALIVE_TASKS.with_label_values(&labels);
+        let global_queue_depth = GLOBAL_QUEUE_DEPTH.with_label_values(&labels);
+        rt.block_on(async {
+            for _ in 0..3 {
+                tokio::spawn(std::future::pending::<()>());
+            }
+            sample_tasks(
+                &tokio::runtime::Handle::current().metrics(),
+                &alive_tasks,
+                &global_queue_depth,
+            );
+        });
+        assert_eq!(alive_tasks.get(), 3);
+    }
+}